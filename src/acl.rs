@@ -0,0 +1,283 @@
+use crate::topic::{covers, matches};
+use std::{fs, io, path::PathBuf, sync::RwLock, time::SystemTime};
+
+/// Who a client is, as far as access rules are concerned.
+#[derive(Debug, Clone, Copy)]
+pub struct Identity<'a> {
+    pub client_id: &'a str,
+    pub user_name: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Scope {
+    All,
+    User(String),
+    Client(String),
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    scope: Scope,
+    read: bool,
+    write: bool,
+    topic_filter: String,
+}
+
+/// Topic access rules. Once an ACL is in place, a client may only publish to topics and
+/// subscribe to filters some rule grants it.
+///
+/// The text form follows mosquitto's:
+///
+/// ```text
+/// # Rules before any section apply to every client.
+/// topic read public/#
+/// pattern readwrite clients/%c/#
+///
+/// user alice
+/// topic readwrite home/alice/#
+///
+/// client probe-7
+/// topic write probes/7
+/// ```
+///
+/// `topic` rules belong to the section they appear in, `pattern` rules always apply to every
+/// client. The access is `read`, `write` or `readwrite`, the latter when omitted. In both kinds
+/// of rules `%u` stands for the client's user name and `%c` for its client identifier; a rule
+/// whose substitution is missing, or would bring in `/`, `+` or `#`, does not apply.
+///
+/// The broker serves no TLS, so there are no client certificates to match: `cert` sections are
+/// refused rather than left to never apply.
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    pub fn parse(text: &str) -> Result<Acl, String> {
+        let mut rules = Vec::new();
+        let mut scope = Scope::All;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (keyword, rest) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| format!("line {}: missing value", number + 1))?;
+            let rest = rest.trim();
+            match keyword {
+                "user" => scope = Scope::User(String::from(rest)),
+                "client" => scope = Scope::Client(String::from(rest)),
+                "cert" => {
+                    return Err(format!(
+                        "line {}: certificate identities need TLS, which the broker does not serve",
+                        number + 1
+                    ))
+                }
+                "topic" | "pattern" => {
+                    let (read, write, topic_filter) = match rest.split_once(char::is_whitespace) {
+                        Some(("read", topic_filter)) => (true, false, topic_filter),
+                        Some(("write", topic_filter)) => (false, true, topic_filter),
+                        Some(("readwrite", topic_filter)) => (true, true, topic_filter),
+                        _ => (true, true, rest),
+                    };
+                    rules.push(Rule {
+                        scope: if keyword == "pattern" { Scope::All } else { scope.clone() },
+                        read,
+                        write,
+                        topic_filter: String::from(topic_filter.trim()),
+                    });
+                }
+                _ => return Err(format!("line {}: unknown keyword {:?}", number + 1, keyword)),
+            }
+        }
+        Ok(Acl { rules })
+    }
+
+    /// Rules for a single client: it may publish to the `publish` filters and subscribe within
+    /// the `subscribe` ones. `%u` and `%c` are substituted as in the text form.
+    pub fn with_topics<P, S>(publish: P, subscribe: S) -> Acl
+    where
+        P: IntoIterator<Item = String>,
+        S: IntoIterator<Item = String>,
+    {
+        let rule = |read, write, topic_filter| Rule {
+            scope: Scope::All,
+            read,
+            write,
+            topic_filter,
+        };
+        let publish = publish.into_iter().map(|topic_filter| rule(false, true, topic_filter));
+        let subscribe = subscribe.into_iter().map(|topic_filter| rule(true, false, topic_filter));
+        Acl {
+            rules: publish.chain(subscribe).collect(),
+        }
+    }
+
+    /// Whether `identity` may publish to the topic name `topic`.
+    pub fn allows_publish(&self, identity: &Identity, topic: &str) -> bool {
+        self.applicable_filters(identity, |rule| rule.write)
+            .any(|topic_filter| matches(topic_filter, topic))
+    }
+
+    /// Whether `identity` may subscribe to `topic_filter`, which requires a rule covering every
+    /// topic the filter can match.
+    pub fn allows_subscribe(&self, identity: &Identity, topic_filter: &str) -> bool {
+        self.applicable_filters(identity, |rule| rule.read)
+            .any(|rule_filter| covers(rule_filter, topic_filter))
+    }
+
+    fn applicable_filters<'a, F: Fn(&Rule) -> bool + 'a>(&'a self, identity: &'a Identity, access: F) -> impl Iterator<Item = String> + 'a {
+        self.rules
+            .iter()
+            .filter(move |rule| access(rule) && rule.scope.includes(identity))
+            .filter_map(move |rule| substitute(&rule.topic_filter, identity))
+    }
+}
+
+impl Scope {
+    fn includes(&self, identity: &Identity) -> bool {
+        match self {
+            Scope::All => true,
+            Scope::User(user_name) => identity.user_name == Some(user_name.as_str()),
+            Scope::Client(client_id) => identity.client_id == client_id,
+        }
+    }
+}
+
+fn substitute(topic_filter: &str, identity: &Identity) -> Option<String> {
+    let mut result = String::from(topic_filter);
+    for (placeholder, value) in [("%u", identity.user_name), ("%c", Some(identity.client_id))] {
+        if result.contains(placeholder) {
+            let value = value.filter(|value| !value.is_empty() && !value.contains(['/', '+', '#']))?;
+            result = result.replace(placeholder, value);
+        }
+    }
+    Some(result)
+}
+
+/// An `Acl` read from a file, which can be read again while the broker runs.
+#[derive(Debug)]
+pub struct AclFile {
+    path: PathBuf,
+    state: RwLock<(Option<SystemTime>, Acl)>,
+}
+
+impl AclFile {
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<AclFile> {
+        let path = path.into();
+        let state = RwLock::new(AclFile::read(&path)?);
+        Ok(AclFile { path, state })
+    }
+
+    fn read(path: &PathBuf) -> io::Result<(Option<SystemTime>, Acl)> {
+        let modified = fs::metadata(path)?.modified().ok();
+        let acl = Acl::parse(&fs::read_to_string(path)?).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok((modified, acl))
+    }
+
+    /// Reads the file again. On failure the rules in use are kept.
+    pub fn reload(&self) -> io::Result<()> {
+        let state = AclFile::read(&self.path)?;
+        *self.state.write().unwrap() = state;
+        Ok(())
+    }
+
+    /// Reads the file again if its modification time changed since it was last read. A file
+    /// that fails to load is not tried again until it changes once more.
+    pub fn reload_if_changed(&self) -> io::Result<bool> {
+        let modified = fs::metadata(&self.path)?.modified().ok();
+        if modified == self.state.read().unwrap().0 {
+            return Ok(false);
+        }
+        self.reload().map(|_| true).inspect_err(|_| self.state.write().unwrap().0 = modified)
+    }
+
+    pub fn allows_publish(&self, identity: &Identity, topic: &str) -> bool {
+        self.state.read().unwrap().1.allows_publish(identity, topic)
+    }
+
+    pub fn allows_subscribe(&self, identity: &Identity, topic_filter: &str) -> bool {
+        self.state.read().unwrap().1.allows_subscribe(identity, topic_filter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread, time::Duration};
+
+    const RULES: &str = "
+        # shared
+        topic read public/#
+        pattern readwrite clients/%c/#
+
+        user alice
+        topic home/%u/#
+        topic write alerts
+
+        client probe-7
+        topic write probes/7
+    ";
+
+    fn identity<'a>(client_id: &'a str, user_name: Option<&'a str>) -> Identity<'a> {
+        Identity { client_id, user_name }
+    }
+
+    #[test]
+    fn rules_apply_to_their_section() {
+        let acl = Acl::parse(RULES).unwrap();
+        let alice = identity("phone", Some("alice"));
+        let anonymous = identity("probe-7", None);
+
+        assert!(acl.allows_subscribe(&alice, "public/news/+"));
+        assert!(!acl.allows_publish(&alice, "public/news"));
+        assert!(acl.allows_publish(&alice, "home/alice/door"));
+        assert!(acl.allows_subscribe(&alice, "home/alice/#"));
+        assert!(acl.allows_publish(&alice, "alerts"));
+        assert!(!acl.allows_subscribe(&alice, "alerts"));
+        assert!(!acl.allows_subscribe(&alice, "#"));
+
+        assert!(acl.allows_publish(&anonymous, "probes/7"));
+        assert!(!acl.allows_publish(&anonymous, "home/alice/door"));
+    }
+
+    #[test]
+    fn substitution_is_per_client_and_refuses_wildcards() {
+        let acl = Acl::parse(RULES).unwrap();
+        assert!(acl.allows_publish(&identity("probe-7", None), "clients/probe-7/status"));
+        assert!(!acl.allows_publish(&identity("probe-7", None), "clients/probe-8/status"));
+        assert!(!acl.allows_subscribe(&identity("+", None), "clients/+/status"));
+        assert!(!acl.allows_subscribe(&identity("c", Some("#")), "home/#"));
+    }
+
+    #[test]
+    fn unknown_keyword_is_an_error() {
+        assert!(Acl::parse("group admins").is_err());
+        assert!(Acl::parse("cert CN=device-7\ntopic read devices/7/#").is_err());
+    }
+
+    #[test]
+    fn file_is_reloaded_when_it_changes() {
+        let path = std::env::temp_dir().join(format!("mt-mqtt-acl-{}", std::process::id()));
+        fs::write(&path, "topic read a/#").unwrap();
+        let acl_file = AclFile::open(&path).unwrap();
+        let anyone = identity("c", None);
+        assert!(!acl_file.allows_publish(&anyone, "a/b"));
+        assert!(!acl_file.reload_if_changed().unwrap());
+
+        thread::sleep(Duration::from_millis(20));
+        fs::write(&path, "topic readwrite a/#").unwrap();
+        assert!(acl_file.reload_if_changed().unwrap());
+        assert!(acl_file.allows_publish(&anyone, "a/b"));
+
+        // A broken file leaves the rules in use untouched.
+        thread::sleep(Duration::from_millis(20));
+        fs::write(&path, "group admins").unwrap();
+        assert!(acl_file.reload_if_changed().is_err());
+        assert!(acl_file.allows_publish(&anyone, "a/b"));
+        // It is reported once, not on every check until it is fixed.
+        assert!(!acl_file.reload_if_changed().unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,245 @@
+//! The admin API, a JSON HTTP interface to inspect and manage the broker:
+//!
+//! - `GET /clients` lists the sessions with their address, subscriptions and queue depth;
+//! - `DELETE /clients/{client_id}` disconnects a client with `AdministrativeAction`;
+//! - `DELETE /sessions/{client_id}` does the same and discards the session;
+//! - `GET /retained` lists the retained messages;
+//! - `DELETE /retained/{topic}` clears the retained message of a topic;
+//! - `POST /publish` publishes `{"topic": ..., "payload": ..., "qos": 0, "retain": false}`;
+//! - `GET /bans` lists the bans with the seconds they have left;
+//! - `POST /bans` bans `{"address": "192.0.2.0/24"}`, `{"client_id": ...}` or
+//!   `{"user_name": ...}`, for `"seconds": ...` or until the ban is lifted;
+//! - `DELETE /bans` lifts the ban given the same way.
+//!
+//! It has no authentication of its own, so it should only listen on a local address.
+
+use crate::{
+    ban::{Ban, BanList},
+    broker::{BrokerMessage, ClientInfo},
+    definitions::Qos,
+    http::{self, percent_decode, Request, Response},
+    message::Message,
+};
+use bytes::Bytes;
+use num_traits::FromPrimitive;
+use serde::{Deserialize, Serialize};
+use std::{io, sync::Arc, time::Duration};
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc::Sender, oneshot},
+};
+
+#[derive(Serialize)]
+struct Client {
+    client_id: String,
+    address: Option<String>,
+    connected: bool,
+    subscriptions: Vec<Subscription>,
+    queued: usize,
+}
+
+#[derive(Serialize)]
+struct Subscription {
+    topic_filter: String,
+    qos: u8,
+}
+
+#[derive(Serialize)]
+struct Retained {
+    topic: String,
+    qos: u8,
+    /// The payload as text, with invalid UTF-8 replaced.
+    payload: String,
+    size: usize,
+}
+
+#[derive(Deserialize)]
+struct Publish {
+    topic: String,
+    #[serde(default)]
+    payload: String,
+    #[serde(default)]
+    qos: u8,
+    #[serde(default)]
+    retain: bool,
+}
+
+/// A ban, naming exactly one of an address or network, a client identifier or a user name.
+#[derive(Serialize, Deserialize)]
+struct BanEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user_name: Option<String>,
+    /// How long the ban lasts, or has left, `None` for no end.
+    #[serde(default)]
+    seconds: Option<u64>,
+}
+
+impl BanEntry {
+    fn ban(self) -> Result<Ban, String> {
+        match (self.address, self.client_id, self.user_name) {
+            (Some(address), None, None) => address.parse().map(Ban::Address).map_err(|err| err.to_string()),
+            (None, Some(client_id), None) => Ok(Ban::ClientId(client_id)),
+            (None, None, Some(user_name)) => Ok(Ban::UserName(user_name)),
+            _ => Err(String::from("a ban needs one of address, client_id or user_name")),
+        }
+    }
+}
+
+impl From<(Ban, Option<Duration>)> for BanEntry {
+    fn from((ban, left): (Ban, Option<Duration>)) -> BanEntry {
+        let mut entry = BanEntry {
+            address: None,
+            client_id: None,
+            user_name: None,
+            seconds: left.map(|left| left.as_secs()),
+        };
+        match ban {
+            Ban::Address(network) => entry.address = Some(network.to_string()),
+            Ban::ClientId(client_id) => entry.client_id = Some(client_id),
+            Ban::UserName(user_name) => entry.user_name = Some(user_name),
+        }
+        entry
+    }
+}
+
+impl From<ClientInfo> for Client {
+    fn from(client: ClientInfo) -> Client {
+        Client {
+            client_id: client.client_id,
+            address: client.peer_address.map(|peer_address| peer_address.to_string()),
+            connected: client.connected,
+            subscriptions: client
+                .subscriptions
+                .into_iter()
+                .map(|(topic_filter, qos)| Subscription {
+                    topic_filter,
+                    qos: qos as u8,
+                })
+                .collect(),
+            queued: client.queued,
+        }
+    }
+}
+
+/// Serves the admin API on `listener`, acting on the broker behind `broker` and its `bans`.
+pub async fn serve(listener: TcpListener, broker: Sender<BrokerMessage>, bans: Arc<BanList>) -> io::Result<()> {
+    http::serve(listener, move |request: Request| handle(request, broker.clone(), bans.clone())).await
+}
+
+async fn handle(request: Request, broker: Sender<BrokerMessage>, bans: Arc<BanList>) -> Response {
+    let (resource, name) = match request.path.trim_start_matches('/').split_once('/') {
+        Some((resource, name)) => (resource, percent_decode(name)),
+        None => (request.path.trim_start_matches('/'), None),
+    };
+    let result = match (request.method.as_str(), resource, name) {
+        ("GET", "clients", None) => ask(&broker, |reply| BrokerMessage::ListClients { reply }).await.map(|clients| {
+            let mut clients: Vec<Client> = clients.into_iter().map(Client::from).collect();
+            clients.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+            json(200, &clients)
+        }),
+        ("DELETE", "clients", Some(client_id)) => kick(&broker, client_id, false).await,
+        ("DELETE", "sessions", Some(client_id)) => kick(&broker, client_id, true).await,
+        ("GET", "retained", None) => ask(&broker, |reply| BrokerMessage::ListRetained { reply }).await.map(|messages| {
+            let mut retained: Vec<Retained> = messages
+                .into_iter()
+                .map(|message| Retained {
+                    payload: String::from_utf8_lossy(&message.payload).into_owned(),
+                    size: message.payload.len(),
+                    qos: message.qos as u8,
+                    topic: message.topic,
+                })
+                .collect();
+            retained.sort_by(|a, b| a.topic.cmp(&b.topic));
+            json(200, &retained)
+        }),
+        ("DELETE", "retained", Some(topic)) => ask(&broker, |reply| BrokerMessage::DeleteRetained { topic, reply }).await.map(found),
+        ("POST", "publish", None) => publish(&broker, &request.body).await,
+        ("GET", "bans", None) => Ok(json(200, &bans.bans().into_iter().map(BanEntry::from).collect::<Vec<_>>())),
+        ("POST", "bans", None) => Ok(change_bans(&bans, &request.body, true)),
+        ("DELETE", "bans", None) => Ok(change_bans(&bans, &request.body, false)),
+        _ => Ok(Response::not_found()),
+    };
+    result.unwrap_or_else(|_| error(503, "broker stopped"))
+}
+
+async fn kick(broker: &Sender<BrokerMessage>, client_id: String, delete_session: bool) -> Result<Response, ()> {
+    ask(broker, |reply| BrokerMessage::Kick {
+        client_id,
+        delete_session,
+        reply,
+    })
+    .await
+    .map(found)
+}
+
+async fn publish(broker: &Sender<BrokerMessage>, body: &[u8]) -> Result<Response, ()> {
+    let publish: Publish = match serde_json::from_slice(body) {
+        Ok(publish) => publish,
+        Err(err) => return Ok(error(400, &err.to_string())),
+    };
+    if publish.topic.is_empty() || publish.topic.contains(['+', '#']) {
+        return Ok(error(400, "topic must be a non-empty topic name without wildcards"));
+    }
+    let qos = match Qos::from_u8(publish.qos) {
+        Some(qos) => qos,
+        None => return Ok(error(400, "qos must be 0, 1 or 2")),
+    };
+    let message = Message {
+        topic: publish.topic,
+        payload: Bytes::from(publish.payload),
+        qos,
+        retain: publish.retain,
+        properties: Vec::new(),
+    };
+    broker
+        .send(BrokerMessage::Publish {
+            client_id: String::new(),
+            message,
+            accepted: None,
+        })
+        .await
+        .map_err(|_| ())?;
+    Ok(Response::new(202, "application/json", String::new()))
+}
+
+fn change_bans(bans: &BanList, body: &[u8], ban: bool) -> Response {
+    let entry: BanEntry = match serde_json::from_slice(body) {
+        Ok(entry) => entry,
+        Err(err) => return error(400, &err.to_string()),
+    };
+    let duration = entry.seconds.map(Duration::from_secs);
+    match entry.ban() {
+        Ok(banned) if ban => {
+            bans.ban(banned, duration);
+            Response::new(204, "application/json", String::new())
+        }
+        Ok(banned) => found(bans.unban(&banned)),
+        Err(message) => error(400, &message),
+    }
+}
+
+/// Sends the request built by `message` to the broker and waits for its reply.
+async fn ask<T, F: FnOnce(oneshot::Sender<T>) -> BrokerMessage>(broker: &Sender<BrokerMessage>, message: F) -> Result<T, ()> {
+    let (reply, answer) = oneshot::channel();
+    broker.send(message(reply)).await.map_err(|_| ())?;
+    answer.await.map_err(|_| ())
+}
+
+fn found(found: bool) -> Response {
+    match found {
+        true => Response::new(204, "application/json", String::new()),
+        false => Response::not_found(),
+    }
+}
+
+fn json<T: Serialize>(status: u16, value: &T) -> Response {
+    Response::new(status, "application/json", serde_json::to_string(value).unwrap_or_default())
+}
+
+fn error(status: u16, message: &str) -> Response {
+    json(status, &serde_json::json!({ "error": message }))
+}
@@ -0,0 +1,264 @@
+use crate::{acl::Acl, definitions::ConnAckReasonCode};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use bytes::Bytes;
+use rand::RngCore;
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs, io,
+    path::PathBuf,
+    sync::{Mutex, RwLock},
+    time::SystemTime,
+};
+use tracing::warn;
+
+pub(crate) mod jwt;
+pub(crate) mod scram;
+
+pub use jwt::JwtAuthenticator;
+pub use scram::{ScramCredentials, ScramSha256};
+
+/// Why an authenticator refused a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// The user name is unknown or the password does not match it.
+    BadUserNameOrPassword,
+    /// The client is not allowed to connect at all, e.g. it sent no credentials.
+    NotAuthorized,
+}
+
+impl From<AuthError> for ConnAckReasonCode {
+    fn from(err: AuthError) -> ConnAckReasonCode {
+        match err {
+            AuthError::BadUserNameOrPassword => ConnAckReasonCode::BadUserNameOrPassword,
+            AuthError::NotAuthorized => ConnAckReasonCode::NotAuthorized,
+        }
+    }
+}
+
+/// What a successful authentication established about a client.
+#[derive(Debug, Clone, Default)]
+pub struct Grant {
+    /// The user access rules apply to, instead of the User Name of CONNECT.
+    pub user_name: Option<String>,
+    /// Rules that apply to this client instead of the broker's ACL.
+    pub acl: Option<Acl>,
+    /// When the credentials stop being valid. The client is disconnected with `NotAuthorized`
+    /// unless it re-authenticates before.
+    pub expires_at: Option<SystemTime>,
+}
+
+/// Decides whether a client may connect with the User Name and Password of its CONNECT.
+///
+/// It runs on a blocking thread, so implementations are free to do slow work such as
+/// verifying password hashes.
+pub trait Authenticator: Debug + Send + Sync {
+    fn authenticate(&self, client_id: &str, user_name: Option<&str>, password: Option<&[u8]>) -> Result<Grant, AuthError>;
+}
+
+/// Where an enhanced authentication exchange stands after a step.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthStep {
+    /// Send this Authentication Data in AUTH with `ContinueAuthentication` and wait for the
+    /// client's answer.
+    Continue(Bytes),
+    /// The client is authenticated. The Authentication Data, if any, goes back to it in CONNACK
+    /// or in AUTH with `Success`.
+    Success(Option<Bytes>),
+}
+
+/// An enhanced authentication mechanism, picked by the Authentication Method of CONNECT.
+pub trait AuthMechanism: Debug + Send + Sync {
+    /// The Authentication Method this mechanism answers to, e.g. `SCRAM-SHA-256`.
+    fn method(&self) -> &str;
+
+    /// Starts an exchange for a client connecting or re-authenticating.
+    fn start(&self, client_id: &str) -> Box<dyn AuthExchange>;
+}
+
+/// One challenge/response exchange with a client.
+///
+/// Steps run on the connection task, so they should not block.
+pub trait AuthExchange: Send + Sync {
+    /// Consumes the Authentication Data of the client's CONNECT or AUTH packet.
+    fn step(&mut self, data: Option<&[u8]>) -> Result<AuthStep, AuthError>;
+
+    /// What the exchange established, once it succeeded.
+    fn grant(&self) -> Grant {
+        Grant::default()
+    }
+}
+
+/// Users and plain text passwords kept in memory, meant for tests and embedding.
+#[derive(Debug, Default)]
+pub struct InMemoryAuthenticator {
+    users: RwLock<HashMap<String, Vec<u8>>>,
+}
+
+impl InMemoryAuthenticator {
+    pub fn new() -> InMemoryAuthenticator {
+        Default::default()
+    }
+
+    pub fn with_user<S: Into<String>, P: Into<Vec<u8>>>(self, user_name: S, password: P) -> InMemoryAuthenticator {
+        self.add_user(user_name, password);
+        self
+    }
+
+    pub fn add_user<S: Into<String>, P: Into<Vec<u8>>>(&self, user_name: S, password: P) {
+        self.users.write().unwrap().insert(user_name.into(), password.into());
+    }
+
+    pub fn remove_user(&self, user_name: &str) -> bool {
+        self.users.write().unwrap().remove(user_name).is_some()
+    }
+}
+
+impl Authenticator for InMemoryAuthenticator {
+    fn authenticate(&self, _client_id: &str, user_name: Option<&str>, password: Option<&[u8]>) -> Result<Grant, AuthError> {
+        let user_name = user_name.ok_or(AuthError::NotAuthorized)?;
+        match self.users.read().unwrap().get(user_name) {
+            Some(expected) if Some(expected.as_slice()) == password => Ok(Grant::default()),
+            _ => Err(AuthError::BadUserNameOrPassword),
+        }
+    }
+}
+
+/// Users read from a text file with one `user_name:hash` entry per line. The hash is either
+/// bcrypt, as written by `htpasswd -nbB user_name password`, or an Argon2 PHC string such as
+/// the ones `hash_password` returns. Empty lines and lines starting with `#` are skipped.
+///
+/// The file is read again whenever its modification time changes, so users can be added or
+/// removed while the broker runs.
+#[derive(Debug)]
+pub struct PasswordFileAuthenticator {
+    path: PathBuf,
+    password_file: Mutex<PasswordFile>,
+}
+
+#[derive(Debug)]
+struct PasswordFile {
+    modified: Option<SystemTime>,
+    users: HashMap<String, String>,
+}
+
+impl PasswordFileAuthenticator {
+    /// Reads the password file at `path`, failing if it cannot be read.
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<PasswordFileAuthenticator> {
+        let path = path.into();
+        let password_file = PasswordFile::read(&path)?;
+        Ok(PasswordFileAuthenticator {
+            path,
+            password_file: Mutex::new(password_file),
+        })
+    }
+
+    fn hash_of(&self, user_name: &str) -> Option<String> {
+        let mut password_file = self.password_file.lock().unwrap();
+        let modified = fs::metadata(&self.path).and_then(|metadata| metadata.modified()).ok();
+        if modified != password_file.modified {
+            // Keep the users we know about if the file is being replaced right now.
+            match PasswordFile::read(&self.path) {
+                Ok(reloaded) => *password_file = reloaded,
+                Err(err) => warn!(path = ?self.path, error = %err, "could not reload password file"),
+            }
+        }
+        password_file.users.get(user_name).cloned()
+    }
+}
+
+impl PasswordFile {
+    fn read(path: &PathBuf) -> io::Result<PasswordFile> {
+        let modified = fs::metadata(path)?.modified().ok();
+        let users = fs::read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_once(':'))
+            .map(|(user_name, hash)| (String::from(user_name), String::from(hash)))
+            .collect();
+        Ok(PasswordFile { modified, users })
+    }
+}
+
+impl Authenticator for PasswordFileAuthenticator {
+    fn authenticate(&self, _client_id: &str, user_name: Option<&str>, password: Option<&[u8]>) -> Result<Grant, AuthError> {
+        let user_name = user_name.ok_or(AuthError::NotAuthorized)?;
+        match (self.hash_of(user_name), password) {
+            (Some(hash), Some(password)) if verify_password(password, &hash) => Ok(Grant::default()),
+            _ => Err(AuthError::BadUserNameOrPassword),
+        }
+    }
+}
+
+/// Hashes `password` with Argon2id and a random salt, in the form `PasswordFileAuthenticator`
+/// expects after `user_name:`.
+pub fn hash_password(password: &[u8]) -> String {
+    let mut salt = [0; 16];
+    rand::rng().fill_bytes(&mut salt);
+    let salt = SaltString::encode_b64(&salt).unwrap();
+    Argon2::default().hash_password(password, &salt).unwrap().to_string()
+}
+
+fn verify_password(password: &[u8], hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        match PasswordHash::new(hash) {
+            Ok(hash) => Argon2::default().verify_password(password, &hash).is_ok(),
+            Err(_) => false,
+        }
+    } else {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Write, thread, time::Duration};
+
+    #[test]
+    fn in_memory_users() {
+        let authenticator = InMemoryAuthenticator::new().with_user("alice", "secret");
+        assert!(authenticator.authenticate("c", Some("alice"), Some(b"secret")).is_ok());
+        assert_eq!(
+            authenticator.authenticate("c", Some("alice"), Some(b"wrong")).unwrap_err(),
+            AuthError::BadUserNameOrPassword
+        );
+        assert_eq!(authenticator.authenticate("c", None, None).unwrap_err(), AuthError::NotAuthorized);
+        assert!(authenticator.remove_user("alice"));
+        assert_eq!(
+            authenticator.authenticate("c", Some("alice"), Some(b"secret")).unwrap_err(),
+            AuthError::BadUserNameOrPassword
+        );
+    }
+
+    #[test]
+    fn password_file_accepts_bcrypt_and_argon2_and_picks_up_changes() {
+        let path = std::env::temp_dir().join(format!("mt-mqtt-passwords-{}", std::process::id()));
+        let mut file = fs::File::create(&path).unwrap();
+        writeln!(file, "# users").unwrap();
+        writeln!(file, "alice:{}", bcrypt::hash("secret", 4).unwrap()).unwrap();
+        drop(file);
+        let authenticator = PasswordFileAuthenticator::open(&path).unwrap();
+        assert!(authenticator.authenticate("c", Some("alice"), Some(b"secret")).is_ok());
+        assert_eq!(
+            authenticator.authenticate("c", Some("bob"), Some(b"hunter2")).unwrap_err(),
+            AuthError::BadUserNameOrPassword
+        );
+
+        // Make sure the modification time moves even on coarse-grained filesystems.
+        thread::sleep(Duration::from_millis(20));
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        writeln!(file, "bob:{}", hash_password(b"hunter2")).unwrap();
+        drop(file);
+        assert!(authenticator.authenticate("c", Some("bob"), Some(b"hunter2")).is_ok());
+        assert_eq!(
+            authenticator.authenticate("c", Some("bob"), Some(b"secret")).unwrap_err(),
+            AuthError::BadUserNameOrPassword
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
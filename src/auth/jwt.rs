@@ -0,0 +1,211 @@
+//! JSON Web Token authentication, for clients that already hold a token from an identity
+//! provider.
+
+use super::{AuthError, AuthExchange, AuthMechanism, AuthStep, Authenticator, Grant};
+use crate::acl::Acl;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+
+#[derive(Deserialize)]
+struct Claims {
+    exp: u64,
+    sub: Option<String>,
+    acl: Option<AclClaim>,
+}
+
+#[derive(Deserialize)]
+struct AclClaim {
+    #[serde(default, rename = "pub")]
+    publish: Vec<String>,
+    #[serde(default, rename = "sub")]
+    subscribe: Vec<String>,
+}
+
+/// Verifies JSON Web Tokens signed with HS256, RS256 or ES256 by one of the configured keys.
+/// As an `Authenticator` it reads the token from the Password of CONNECT, as an
+/// `AuthMechanism` (method `JWT`) from the Authentication Data, which also lets a client
+/// present a fresh token by re-authenticating.
+///
+/// `exp` is required and `nbf` is checked when present. The `sub` claim becomes the user name
+/// the ACL applies to. An optional `acl` claim such as `{"pub": ["devices/%c/#"], "sub":
+/// ["commands/%c"]}` lists the filters the client may publish to and subscribe within, and
+/// replaces the broker's ACL for that client.
+#[derive(Clone, Default)]
+pub struct JwtAuthenticator {
+    keys: Arc<Vec<(Algorithm, DecodingKey)>>,
+}
+
+impl JwtAuthenticator {
+    pub fn new() -> JwtAuthenticator {
+        Default::default()
+    }
+
+    pub fn with_hs256_secret(self, secret: &[u8]) -> JwtAuthenticator {
+        self.with_key(Algorithm::HS256, DecodingKey::from_secret(secret))
+    }
+
+    /// Adds an RSA public key in PEM form, for RS256 tokens.
+    pub fn with_rs256_pem(self, pem: &[u8]) -> Result<JwtAuthenticator, String> {
+        let key = DecodingKey::from_rsa_pem(pem).map_err(|err| err.to_string())?;
+        Ok(self.with_key(Algorithm::RS256, key))
+    }
+
+    /// Adds a P-256 public key in PEM form, for ES256 tokens.
+    pub fn with_es256_pem(self, pem: &[u8]) -> Result<JwtAuthenticator, String> {
+        let key = DecodingKey::from_ec_pem(pem).map_err(|err| err.to_string())?;
+        Ok(self.with_key(Algorithm::ES256, key))
+    }
+
+    fn with_key(mut self, algorithm: Algorithm, key: DecodingKey) -> JwtAuthenticator {
+        Arc::make_mut(&mut self.keys).push((algorithm, key));
+        self
+    }
+
+    fn verify(&self, token: &[u8]) -> Result<Grant, AuthError> {
+        let token = std::str::from_utf8(token).map_err(|_| AuthError::BadUserNameOrPassword)?;
+        let header = jsonwebtoken::decode_header(token).map_err(|_| AuthError::BadUserNameOrPassword)?;
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = 0;
+        validation.validate_nbf = true;
+        validation.validate_aud = false;
+        let claims = self
+            .keys
+            .iter()
+            .filter(|(algorithm, _)| *algorithm == header.alg)
+            .find_map(|(_, key)| jsonwebtoken::decode::<Claims>(token, key, &validation).ok())
+            .ok_or(AuthError::BadUserNameOrPassword)?
+            .claims;
+        Ok(Grant {
+            user_name: claims.sub,
+            acl: claims.acl.map(|acl| Acl::with_topics(acl.publish, acl.subscribe)),
+            expires_at: Some(UNIX_EPOCH + Duration::from_secs(claims.exp)),
+        })
+    }
+}
+
+impl fmt::Debug for JwtAuthenticator {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let algorithms: Vec<&Algorithm> = self.keys.iter().map(|(algorithm, _)| algorithm).collect();
+        fmt.debug_struct("JwtAuthenticator").field("keys", &algorithms).finish()
+    }
+}
+
+impl Authenticator for JwtAuthenticator {
+    fn authenticate(&self, _client_id: &str, _user_name: Option<&str>, password: Option<&[u8]>) -> Result<Grant, AuthError> {
+        self.verify(password.ok_or(AuthError::NotAuthorized)?)
+    }
+}
+
+impl AuthMechanism for JwtAuthenticator {
+    fn method(&self) -> &str {
+        "JWT"
+    }
+
+    fn start(&self, _client_id: &str) -> Box<dyn AuthExchange> {
+        Box::new(JwtExchange {
+            authenticator: self.clone(),
+            grant: Grant::default(),
+        })
+    }
+}
+
+struct JwtExchange {
+    authenticator: JwtAuthenticator,
+    grant: Grant,
+}
+
+impl AuthExchange for JwtExchange {
+    fn step(&mut self, data: Option<&[u8]>) -> Result<AuthStep, AuthError> {
+        self.grant = self.authenticator.verify(data.ok_or(AuthError::NotAuthorized)?)?;
+        Ok(AuthStep::Success(None))
+    }
+
+    fn grant(&self) -> Grant {
+        self.grant.clone()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::acl::Identity;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+    use std::time::SystemTime;
+
+    pub(crate) const HS256_SECRET: &[u8] = b"shared secret";
+
+    pub(crate) fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    pub(crate) fn hs256_token(claims: serde_json::Value) -> String {
+        jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(HS256_SECRET)).unwrap()
+    }
+
+    #[test]
+    fn tokens_signed_by_each_configured_key_are_accepted() {
+        let authenticator = JwtAuthenticator::new()
+            .with_hs256_secret(HS256_SECRET)
+            .with_rs256_pem(include_bytes!("testdata/rs256_public.pem"))
+            .unwrap()
+            .with_es256_pem(include_bytes!("testdata/es256_public.pem"))
+            .unwrap();
+        let claims = json!({"sub": "alice", "exp": now() + 60});
+        let rs256 = EncodingKey::from_rsa_pem(include_bytes!("testdata/rs256_private.pem")).unwrap();
+        let es256 = EncodingKey::from_ec_pem(include_bytes!("testdata/es256_private.pem")).unwrap();
+        let tokens = [
+            hs256_token(claims.clone()),
+            jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &rs256).unwrap(),
+            jsonwebtoken::encode(&Header::new(Algorithm::ES256), &claims, &es256).unwrap(),
+        ];
+        for token in tokens {
+            let grant = authenticator.authenticate("c", None, Some(token.as_bytes())).unwrap();
+            assert_eq!(grant.user_name.as_deref(), Some("alice"));
+            assert_eq!(grant.expires_at, Some(UNIX_EPOCH + Duration::from_secs(now() + 60)));
+        }
+    }
+
+    #[test]
+    fn invalid_tokens_are_refused() {
+        let authenticator = JwtAuthenticator::new().with_hs256_secret(HS256_SECRET);
+        let forged = jsonwebtoken::encode(
+            &Header::new(Algorithm::HS256),
+            &json!({"exp": now() + 60}),
+            &EncodingKey::from_secret(b"guess"),
+        )
+        .unwrap();
+        let expired = hs256_token(json!({"exp": now() - 10}));
+        let not_yet_valid = hs256_token(json!({"exp": now() + 60, "nbf": now() + 30}));
+        let without_expiry = hs256_token(json!({"sub": "alice"}));
+        for token in [forged, expired, not_yet_valid, without_expiry, String::from("garbage")] {
+            assert_eq!(
+                authenticator.authenticate("c", None, Some(token.as_bytes())).unwrap_err(),
+                AuthError::BadUserNameOrPassword
+            );
+        }
+        assert_eq!(authenticator.authenticate("c", None, None).unwrap_err(), AuthError::NotAuthorized);
+    }
+
+    #[test]
+    fn acl_claim_becomes_the_client_rules() {
+        let authenticator = JwtAuthenticator::new().with_hs256_secret(HS256_SECRET);
+        let token = hs256_token(json!({"exp": now() + 60, "acl": {"pub": ["devices/%c/#"], "sub": ["commands/%c"]}}));
+        let mut exchange = AuthMechanism::start(&authenticator, "probe");
+        assert_eq!(exchange.step(Some(token.as_bytes())), Ok(AuthStep::Success(None)));
+        let acl = exchange.grant().acl.unwrap();
+        let probe = Identity {
+            client_id: "probe",
+            user_name: None,
+        };
+        assert!(acl.allows_publish(&probe, "devices/probe/temperature"));
+        assert!(!acl.allows_publish(&probe, "commands/probe"));
+        assert!(acl.allows_subscribe(&probe, "commands/probe"));
+        assert!(!acl.allows_subscribe(&probe, "devices/probe/#"));
+    }
+}
@@ -0,0 +1,335 @@
+//! SCRAM-SHA-256 (RFC 5802 and RFC 7677) without channel binding. The password never crosses
+//! the wire and the broker only keeps keys derived from it.
+
+use super::{AuthError, AuthExchange, AuthMechanism, AuthStep, Grant};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use rand::{distr::Alphanumeric, Rng, RngCore};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, RwLock},
+};
+use subtle::ConstantTimeEq;
+
+const GS2_HEADER: &str = "n,,";
+
+/// What the broker stores about a SCRAM user, derived from the password.
+#[derive(Debug, Clone)]
+pub struct ScramCredentials {
+    salt: Vec<u8>,
+    iterations: u32,
+    stored_key: Vec<u8>,
+    server_key: Vec<u8>,
+}
+
+impl ScramCredentials {
+    /// Number of PBKDF2 iterations used by `ScramSha256::with_user`.
+    pub const DEFAULT_ITERATIONS: u32 = 4096;
+
+    /// Derives the credentials of `password` with a random salt.
+    pub fn new(password: &[u8], iterations: u32) -> ScramCredentials {
+        let mut salt = vec![0; 16];
+        rand::rng().fill_bytes(&mut salt);
+        let salted_password = salted_password(password, &salt, iterations);
+        ScramCredentials {
+            stored_key: Sha256::digest(hmac(&salted_password, b"Client Key")).to_vec(),
+            server_key: hmac(&salted_password, b"Server Key"),
+            salt,
+            iterations,
+        }
+    }
+
+    /// Stand-in credentials for a user that does not exist, so the exchange carries on and only
+    /// fails at client-final (RFC 5802 section 5.1). The salt is derived from the user name so
+    /// that repeated attempts see the same one; the keys are random and match no proof.
+    fn unknown(user_name: &str, secret: &[u8]) -> ScramCredentials {
+        let mut stored_key = vec![0; 32];
+        let mut server_key = vec![0; 32];
+        rand::rng().fill_bytes(&mut stored_key);
+        rand::rng().fill_bytes(&mut server_key);
+        ScramCredentials {
+            salt: hmac(secret, user_name.as_bytes())[..16].to_vec(),
+            iterations: ScramCredentials::DEFAULT_ITERATIONS,
+            stored_key,
+            server_key,
+        }
+    }
+}
+
+/// The `SCRAM-SHA-256` Authentication Method, checking clients against the users it holds.
+pub struct ScramSha256 {
+    users: Arc<RwLock<HashMap<String, ScramCredentials>>>,
+    /// Key the salts of unknown users are derived from.
+    unknown_user_secret: [u8; 32],
+}
+
+impl Default for ScramSha256 {
+    fn default() -> ScramSha256 {
+        let mut unknown_user_secret = [0; 32];
+        rand::rng().fill_bytes(&mut unknown_user_secret);
+        ScramSha256 {
+            users: Default::default(),
+            unknown_user_secret,
+        }
+    }
+}
+
+impl fmt::Debug for ScramSha256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScramSha256").field("users", &self.users).finish_non_exhaustive()
+    }
+}
+
+impl ScramSha256 {
+    pub fn new() -> ScramSha256 {
+        Default::default()
+    }
+
+    pub fn with_user<S: Into<String>>(self, user_name: S, password: &[u8]) -> ScramSha256 {
+        self.add_user(user_name, ScramCredentials::new(password, ScramCredentials::DEFAULT_ITERATIONS));
+        self
+    }
+
+    pub fn add_user<S: Into<String>>(&self, user_name: S, credentials: ScramCredentials) {
+        self.users.write().unwrap().insert(user_name.into(), credentials);
+    }
+
+    pub fn remove_user(&self, user_name: &str) -> bool {
+        self.users.write().unwrap().remove(user_name).is_some()
+    }
+}
+
+impl AuthMechanism for ScramSha256 {
+    fn method(&self) -> &str {
+        "SCRAM-SHA-256"
+    }
+
+    fn start(&self, _client_id: &str) -> Box<dyn AuthExchange> {
+        Box::new(ScramExchange {
+            users: self.users.clone(),
+            unknown_user_secret: self.unknown_user_secret,
+            state: ScramState::ClientFirst,
+            user_name: None,
+        })
+    }
+}
+
+enum ScramState {
+    ClientFirst,
+    ClientFinal {
+        credentials: ScramCredentials,
+        client_first_bare: String,
+        server_first: String,
+        nonce: String,
+    },
+    Done,
+}
+
+struct ScramExchange {
+    users: Arc<RwLock<HashMap<String, ScramCredentials>>>,
+    unknown_user_secret: [u8; 32],
+    state: ScramState,
+    user_name: Option<String>,
+}
+
+impl ScramExchange {
+    fn client_first(&mut self, message: &str) -> Result<AuthStep, AuthError> {
+        // Channel binding is not supported, so only the `n` flag is acceptable.
+        let client_first_bare = message.strip_prefix(GS2_HEADER).ok_or(AuthError::NotAuthorized)?;
+        let attributes = attributes(client_first_bare);
+        let user_name = attributes
+            .get("n")
+            .map(|user_name| user_name.replace("=2C", ",").replace("=3D", "="))
+            .ok_or(AuthError::NotAuthorized)?;
+        let client_nonce = attributes.get("r").ok_or(AuthError::NotAuthorized)?;
+        // Refusing an unknown user here would tell the client which user names exist.
+        let credentials = self
+            .users
+            .read()
+            .unwrap()
+            .get(&user_name)
+            .cloned()
+            .unwrap_or_else(|| ScramCredentials::unknown(&user_name, &self.unknown_user_secret));
+        self.user_name = Some(user_name);
+
+        let server_nonce: String = rand::rng().sample_iter(&Alphanumeric).take(24).map(char::from).collect();
+        let nonce = format!("{}{}", client_nonce, server_nonce);
+        let server_first = format!("r={},s={},i={}", nonce, STANDARD.encode(&credentials.salt), credentials.iterations);
+        self.state = ScramState::ClientFinal {
+            credentials,
+            client_first_bare: String::from(client_first_bare),
+            server_first: server_first.clone(),
+            nonce,
+        };
+        Ok(AuthStep::Continue(Bytes::from(server_first)))
+    }
+
+    fn client_final(
+        message: &str,
+        credentials: &ScramCredentials,
+        client_first_bare: &str,
+        server_first: &str,
+        nonce: &str,
+    ) -> Result<AuthStep, AuthError> {
+        let (without_proof, proof) = message.rsplit_once(",p=").ok_or(AuthError::NotAuthorized)?;
+        let attributes = attributes(without_proof);
+        if attributes.get("c").copied() != Some(STANDARD.encode(GS2_HEADER).as_str()) || attributes.get("r").copied() != Some(nonce) {
+            return Err(AuthError::NotAuthorized);
+        }
+        let proof = STANDARD.decode(proof).map_err(|_| AuthError::NotAuthorized)?;
+
+        let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+        let client_signature = hmac(&credentials.stored_key, auth_message.as_bytes());
+        if proof.len() != client_signature.len() {
+            return Err(AuthError::BadUserNameOrPassword);
+        }
+        let client_key: Vec<u8> = proof.iter().zip(&client_signature).map(|(a, b)| a ^ b).collect();
+        if !bool::from(Sha256::digest(&client_key).as_slice().ct_eq(&credentials.stored_key)) {
+            return Err(AuthError::BadUserNameOrPassword);
+        }
+        let server_signature = hmac(&credentials.server_key, auth_message.as_bytes());
+        Ok(AuthStep::Success(Some(Bytes::from(format!("v={}", STANDARD.encode(server_signature))))))
+    }
+}
+
+impl AuthExchange for ScramExchange {
+    fn step(&mut self, data: Option<&[u8]>) -> Result<AuthStep, AuthError> {
+        let message = std::str::from_utf8(data.unwrap_or_default()).map_err(|_| AuthError::NotAuthorized)?;
+        match std::mem::replace(&mut self.state, ScramState::Done) {
+            ScramState::ClientFirst => self.client_first(message),
+            ScramState::ClientFinal {
+                credentials,
+                client_first_bare,
+                server_first,
+                nonce,
+            } => Self::client_final(message, &credentials, &client_first_bare, &server_first, &nonce),
+            ScramState::Done => Err(AuthError::NotAuthorized),
+        }
+    }
+
+    fn grant(&self) -> Grant {
+        Grant {
+            user_name: self.user_name.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Splits `k=v,k=v` into its attributes.
+fn attributes(message: &str) -> HashMap<&str, &str> {
+    message.split(',').filter_map(|attribute| attribute.split_once('=')).collect()
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn salted_password(password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8> {
+    let mut salted_password = vec![0; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password, salt, iterations, &mut salted_password);
+    salted_password
+}
+
+/// The client side of the exchange, for tests.
+#[cfg(test)]
+pub(crate) struct ScramClient {
+    password: Vec<u8>,
+    client_first_bare: String,
+    server_signature: Vec<u8>,
+}
+
+#[cfg(test)]
+impl ScramClient {
+    pub(crate) fn new(user_name: &str, password: &[u8]) -> ScramClient {
+        ScramClient {
+            password: password.to_vec(),
+            client_first_bare: format!("n={},r=clientnonce", user_name),
+            server_signature: Vec::new(),
+        }
+    }
+
+    pub(crate) fn client_first(&self) -> Bytes {
+        Bytes::from(format!("{}{}", GS2_HEADER, self.client_first_bare))
+    }
+
+    pub(crate) fn client_final(&mut self, server_first: &[u8]) -> Bytes {
+        let server_first = std::str::from_utf8(server_first).unwrap();
+        let attributes = attributes(server_first);
+        let salt = STANDARD.decode(attributes["s"]).unwrap();
+        let salted_password = salted_password(&self.password, &salt, attributes["i"].parse().unwrap());
+        let client_key = hmac(&salted_password, b"Client Key");
+        let without_proof = format!("c={},r={}", STANDARD.encode(GS2_HEADER), attributes["r"]);
+        let auth_message = format!("{},{},{}", self.client_first_bare, server_first, without_proof);
+        let client_signature = hmac(&Sha256::digest(&client_key), auth_message.as_bytes());
+        let proof: Vec<u8> = client_key.iter().zip(&client_signature).map(|(a, b)| a ^ b).collect();
+        self.server_signature = hmac(&hmac(&salted_password, b"Server Key"), auth_message.as_bytes());
+        Bytes::from(format!("{},p={}", without_proof, STANDARD.encode(proof)))
+    }
+
+    pub(crate) fn verify_server_final(&self, server_final: &[u8]) -> bool {
+        server_final == format!("v={}", STANDARD.encode(&self.server_signature)).as_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_exchange_proves_both_sides() {
+        let mechanism = ScramSha256::new().with_user("alice", b"secret");
+        let mut client = ScramClient::new("alice", b"secret");
+        let mut exchange = mechanism.start("client");
+
+        let server_first = match exchange.step(Some(&client.client_first())).unwrap() {
+            AuthStep::Continue(server_first) => server_first,
+            other => panic!("expected a challenge, got {:?}", other),
+        };
+        assert!(server_first.starts_with(b"r=clientnonce"));
+        match exchange.step(Some(&client.client_final(&server_first))).unwrap() {
+            AuthStep::Success(Some(server_final)) => assert!(client.verify_server_final(&server_final)),
+            other => panic!("expected success, got {:?}", other),
+        }
+        assert_eq!(exchange.step(None), Err(AuthError::NotAuthorized));
+    }
+
+    #[test]
+    fn wrong_password_or_user_is_refused() {
+        let mechanism = ScramSha256::new().with_user("alice", b"secret");
+        let mut client = ScramClient::new("alice", b"guess");
+        let mut exchange = mechanism.start("client");
+        let server_first = match exchange.step(Some(&client.client_first())).unwrap() {
+            AuthStep::Continue(server_first) => server_first,
+            other => panic!("expected a challenge, got {:?}", other),
+        };
+        assert_eq!(
+            exchange.step(Some(&client.client_final(&server_first))),
+            Err(AuthError::BadUserNameOrPassword)
+        );
+    }
+
+    #[test]
+    fn unknown_users_are_refused_only_at_client_final() {
+        let mechanism = ScramSha256::new().with_user("alice", b"secret");
+        let mut salts = Vec::new();
+        for _ in 0..2 {
+            let mut client = ScramClient::new("mallory", b"secret");
+            let mut exchange = mechanism.start("client");
+            let server_first = match exchange.step(Some(&client.client_first())).unwrap() {
+                AuthStep::Continue(server_first) => server_first,
+                other => panic!("expected a challenge, got {:?}", other),
+            };
+            salts.push(String::from(attributes(std::str::from_utf8(&server_first).unwrap())["s"]));
+            assert_eq!(
+                exchange.step(Some(&client.client_final(&server_first))),
+                Err(AuthError::BadUserNameOrPassword)
+            );
+        }
+        assert_eq!(salts[0], salts[1]);
+    }
+}
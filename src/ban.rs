@@ -0,0 +1,250 @@
+//! Who may not connect: addresses, client identifiers and user names banned by hand, and the
+//! addresses banned for a while after failing to authenticate too often.
+
+use crate::config::AuthFailureBan;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// An IP address or a network in CIDR notation, such as `192.0.2.0/24` or `2001:db8::/32`.
+/// IPv4 clients connecting over IPv6 are matched by their IPv4 address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    /// The network of the first `prefix_len` bits of `address`, `None` when the address does not
+    /// have that many bits.
+    pub fn new(address: IpAddr, prefix_len: u8) -> Option<IpNetwork> {
+        let address = address.to_canonical();
+        match prefix_len <= max_prefix_len(address) {
+            true => Some(IpNetwork { address, prefix_len }),
+            false => None,
+        }
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        let (network, address) = match (self.address, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => (u32::from(network) as u128, u32::from(address) as u128),
+            (IpAddr::V6(network), IpAddr::V6(address)) => (u128::from(network), u128::from(address)),
+            _ => return false,
+        };
+        let host_bits = max_prefix_len(self.address) - self.prefix_len;
+        // Shifting all 128 bits out leaves nothing to compare.
+        (network ^ address).checked_shr(host_bits as u32).unwrap_or(0) == 0
+    }
+}
+
+fn max_prefix_len(address: IpAddr) -> u8 {
+    match address {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+impl From<IpAddr> for IpNetwork {
+    fn from(address: IpAddr) -> IpNetwork {
+        let address = address.to_canonical();
+        IpNetwork {
+            address,
+            prefix_len: max_prefix_len(address),
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = InvalidNetwork;
+
+    fn from_str(s: &str) -> Result<IpNetwork, InvalidNetwork> {
+        match s.split_once('/') {
+            Some((address, prefix_len)) => {
+                let address = address.parse().map_err(|_| InvalidNetwork)?;
+                let prefix_len = prefix_len.parse().map_err(|_| InvalidNetwork)?;
+                IpNetwork::new(address, prefix_len).ok_or(InvalidNetwork)
+            }
+            None => s.parse::<IpAddr>().map(IpNetwork::from).map_err(|_| InvalidNetwork),
+        }
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.prefix_len == max_prefix_len(self.address) {
+            true => write!(f, "{}", self.address),
+            false => write!(f, "{}/{}", self.address, self.prefix_len),
+        }
+    }
+}
+
+/// The text given for an `IpNetwork` is neither an address nor a network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidNetwork;
+
+impl fmt::Display for InvalidNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid IP address or network")
+    }
+}
+
+impl std::error::Error for InvalidNetwork {}
+
+/// Who a ban keeps out. Client identifiers and user names are matched exactly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ban {
+    Address(IpNetwork),
+    ClientId(String),
+    UserName(String),
+}
+
+/// The bans of a broker, refused with `Banned` in CONNACK. Bans apply to new connections, the
+/// ones already open stay until they are closed, for instance through the admin API.
+#[derive(Debug, Default)]
+pub struct BanList {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // The bans and when they are lifted, `None` for never.
+    bans: Vec<(Ban, Option<Instant>)>,
+    // When the authentication failures of an address started to count, and how many there are.
+    auth_failures: HashMap<IpAddr, (Instant, u32)>,
+}
+
+impl State {
+    fn ban(&mut self, ban: Ban, until: Option<Instant>) {
+        self.bans.retain(|(banned, _)| *banned != ban);
+        self.bans.push((ban, until));
+    }
+
+    fn bans(&mut self) -> &[(Ban, Option<Instant>)] {
+        let now = Instant::now();
+        self.bans.retain(|(_, until)| until.is_none_or(|until| until > now));
+        &self.bans
+    }
+}
+
+impl BanList {
+    pub fn new() -> BanList {
+        Default::default()
+    }
+
+    pub fn with_ban(self, ban: Ban, duration: Option<Duration>) -> BanList {
+        self.ban(ban, duration);
+        self
+    }
+
+    /// Bans for `duration`, or until the ban is lifted for `None`. Banning again replaces the
+    /// duration.
+    pub fn ban(&self, ban: Ban, duration: Option<Duration>) {
+        let until = duration.map(|duration| Instant::now() + duration);
+        self.state.lock().unwrap().ban(ban, until);
+    }
+
+    /// Lifts a ban, returns whether there was one.
+    pub fn unban(&self, ban: &Ban) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let len = state.bans().len();
+        state.bans.retain(|(banned, _)| banned != ban);
+        state.bans.len() < len
+    }
+
+    /// The bans in place with the time they have left, `None` for the ones without an end.
+    pub fn bans(&self) -> Vec<(Ban, Option<Duration>)> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state
+            .bans()
+            .iter()
+            .map(|(ban, until)| (ban.clone(), until.map(|until| until - now)))
+            .collect()
+    }
+
+    pub fn is_address_banned(&self, address: IpAddr) -> bool {
+        self.state.lock().unwrap().bans().iter().any(|(ban, _)| match ban {
+            Ban::Address(network) => network.contains(address),
+            _ => false,
+        })
+    }
+
+    pub fn is_client_banned(&self, client_id: &str, user_name: Option<&str>) -> bool {
+        self.state.lock().unwrap().bans().iter().any(|(ban, _)| match ban {
+            Ban::ClientId(banned) => banned == client_id,
+            Ban::UserName(banned) => Some(banned.as_str()) == user_name,
+            Ban::Address(_) => false,
+        })
+    }
+
+    /// Counts an authentication failure from `address` and bans the address once `policy` says
+    /// so. Returns whether it did.
+    pub(crate) fn count_auth_failure(&self, address: IpAddr, policy: &AuthFailureBan) -> bool {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.auth_failures.retain(|_, (since, _)| now.duration_since(*since) < policy.within);
+        let (_, failures) = state.auth_failures.entry(address).or_insert((now, 0));
+        *failures += 1;
+        if *failures < policy.max_failures {
+            return false;
+        }
+        state.auth_failures.remove(&address);
+        state.ban(Ban::Address(IpNetwork::from(address)), Some(now + policy.ban_for));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn networks_contain_their_addresses() {
+        let network: IpNetwork = "192.0.2.0/24".parse().unwrap();
+        assert!(network.contains("192.0.2.77".parse().unwrap()));
+        assert!(network.contains("::ffff:192.0.2.1".parse().unwrap()), "IPv4 over IPv6");
+        assert!(!network.contains("192.0.3.1".parse().unwrap()));
+        assert!(!network.contains("2001:db8::1".parse().unwrap()));
+        assert_eq!(network.to_string(), "192.0.2.0/24");
+
+        let everything: IpNetwork = "::/0".parse().unwrap();
+        assert!(everything.contains("2001:db8::1".parse().unwrap()));
+        let host: IpNetwork = "2001:db8::1".parse().unwrap();
+        assert!(host.contains("2001:db8::1".parse().unwrap()));
+        assert!(!host.contains("2001:db8::2".parse().unwrap()));
+        assert_eq!("10.0.0.0/33".parse::<IpNetwork>(), Err(InvalidNetwork));
+        assert_eq!("example.com".parse::<IpNetwork>(), Err(InvalidNetwork));
+    }
+
+    #[test]
+    fn bans_expire_and_repeated_auth_failures_ban_the_address() {
+        let bans = BanList::new()
+            .with_ban(Ban::ClientId(String::from("intruder")), None)
+            .with_ban(Ban::UserName(String::from("guest")), Some(Duration::ZERO));
+        assert!(bans.is_client_banned("intruder", None));
+        assert!(!bans.is_client_banned("sensor", Some("guest")), "the ban is over");
+        assert_eq!(bans.bans().len(), 1);
+
+        let policy = AuthFailureBan {
+            max_failures: 3,
+            within: Duration::from_secs(60),
+            ban_for: Duration::from_secs(60),
+        };
+        let address = "198.51.100.7".parse().unwrap();
+        assert!(!bans.count_auth_failure(address, &policy));
+        assert!(!bans.count_auth_failure(address, &policy));
+        assert!(!bans.is_address_banned(address));
+        assert!(bans.count_auth_failure(address, &policy));
+        assert!(bans.is_address_banned(address));
+        assert!(!bans.is_address_banned("198.51.100.8".parse().unwrap()));
+
+        assert!(bans.unban(&Ban::Address(IpNetwork::from(address))));
+        assert!(!bans.is_address_banned(address));
+        assert!(!bans.unban(&Ban::Address(IpNetwork::from(address))));
+    }
+}
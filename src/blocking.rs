@@ -0,0 +1,178 @@
+//! A blocking client for code that does not run in an async runtime. It owns a small runtime
+//! running the [`EventLoop`](crate::EventLoop) in the background and blocks the calling thread
+//! on the async client for each call.
+//!
+//! ```no_run
+//! use mt_mqtt::{blocking::Client, ConnectOptions, Qos};
+//!
+//! let client = Client::connect("127.0.0.1:1883", ConnectOptions::new("tool-1"))?;
+//! let messages = client.subscribe("alerts/#", Qos::AtleastOnce)?;
+//! client.publish("alerts/disk", "full", Qos::AtleastOnce, false, Vec::new())?;
+//! for message in messages {
+//!     println!("{}: {:?}", message.topic, message.payload);
+//! }
+//! # Ok::<(), mt_mqtt::ClientError>(())
+//! ```
+//!
+//! The calls must not be made from within an async runtime, where blocking would panic.
+
+use crate::{
+    definitions::{Property, Qos},
+    message::Message,
+    publisher::{ClientError, ConnectOptions, MqttClient},
+    request::Response,
+    subscriber::MessageStream,
+};
+use bytes::Bytes;
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    net::ToSocketAddrs,
+    runtime::{self, Runtime},
+    sync::Mutex,
+    task::JoinHandle,
+    time,
+};
+
+/// A connection to a broker, used from any number of threads. Dropping it, and every
+/// [`Messages`], stops the runtime, which may close the connection before DISCONNECT is
+/// written and have the broker send the will; [`Client::disconnect`] ends it cleanly.
+#[derive(Debug)]
+pub struct Client {
+    runtime: Arc<Runtime>,
+    client: MqttClient,
+    event_loop: Mutex<Option<JoinHandle<Result<(), ClientError>>>>,
+}
+
+/// The messages of a subscription, see [`MessageStream`]. Iterating blocks until the next
+/// message and ends once the subscription is gone.
+#[derive(Debug)]
+pub struct Messages {
+    runtime: Arc<Runtime>,
+    stream: MessageStream,
+}
+
+impl Messages {
+    /// Waits at most `timeout` for the next message, `None` when none came or the subscription
+    /// is gone.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Option<Message> {
+        let stream = &mut self.stream;
+        self.runtime
+            .block_on(async { time::timeout(timeout, stream.recv()).await.ok().flatten() })
+    }
+}
+
+impl Iterator for Messages {
+    type Item = Message;
+
+    fn next(&mut self) -> Option<Message> {
+        self.runtime.block_on(self.stream.recv())
+    }
+}
+
+impl Client {
+    /// Connects to the broker at `addr` and waits for CONNACK, see [`MqttClient::connect`].
+    pub fn connect<A: ToSocketAddrs>(addr: A, options: ConnectOptions) -> Result<Client, ClientError> {
+        let runtime = runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("mqtt-client")
+            .enable_all()
+            .build()?;
+        let (client, event_loop) = runtime.block_on(MqttClient::connect(addr, options))?;
+        let event_loop = runtime.spawn(event_loop.run());
+        Ok(Client {
+            runtime: Arc::new(runtime),
+            client,
+            event_loop: Mutex::new(Some(event_loop)),
+        })
+    }
+
+    /// Publishes a message, see [`MqttClient::publish`].
+    pub fn publish<T: Into<String>, P: Into<Bytes>>(
+        &self,
+        topic: T,
+        payload: P,
+        qos: Qos,
+        retain: bool,
+        properties: Vec<Property>,
+    ) -> Result<(), ClientError> {
+        self.runtime.block_on(self.client.publish(topic, payload, qos, retain, properties))
+    }
+
+    /// Subscribes to `topic_filter`, see [`MqttClient::subscribe`].
+    pub fn subscribe<T: Into<String>>(&self, topic_filter: T, maximum_qos: Qos) -> Result<Messages, ClientError> {
+        let stream = self.runtime.block_on(self.client.subscribe(topic_filter, maximum_qos))?;
+        Ok(Messages {
+            runtime: self.runtime.clone(),
+            stream,
+        })
+    }
+
+    /// Unsubscribes from `topic_filter`, see [`MqttClient::unsubscribe`].
+    pub fn unsubscribe<T: Into<String>>(&self, topic_filter: T) -> Result<(), ClientError> {
+        self.runtime.block_on(self.client.unsubscribe(topic_filter))
+    }
+
+    /// Sends a request and waits for its response, see [`MqttClient::request`].
+    pub fn request<T: Into<String>, P: Into<Bytes>>(&self, topic: T, payload: P, timeout: Duration) -> Result<Response, ClientError> {
+        self.runtime.block_on(self.client.request(topic, payload, timeout))
+    }
+
+    /// Sends DISCONNECT and waits for the event loop to finish. Returns how the connection
+    /// ended if it was already lost.
+    pub fn disconnect(&self) -> Result<(), ClientError> {
+        self.runtime.block_on(async {
+            let event_loop = match self.event_loop.lock().await.take() {
+                Some(event_loop) => event_loop,
+                None => return Ok(()),
+            };
+            // A lost connection is reported by the event loop.
+            let _ = self.client.disconnect().await;
+            event_loop.await.map_err(|_| ClientError::ConnectionLost)?
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::BrokerConfig, server::MqttServer};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn blocking_calls_go_through_the_async_client() {
+        let broker_runtime = Runtime::new().unwrap();
+        let broker = broker_runtime.block_on(async {
+            let config = BrokerConfig {
+                sys_interval: None,
+                ..Default::default()
+            };
+            MqttServer::serve(TcpListener::bind("127.0.0.1:0").await.unwrap(), config).await.unwrap()
+        });
+
+        let subscriber = Client::connect(broker.local_addr(), ConnectOptions::new("tool")).unwrap();
+        let mut messages = subscriber.subscribe("alerts/#", Qos::AtleastOnce).unwrap();
+        let publisher = std::thread::spawn({
+            let addr = broker.local_addr();
+            move || {
+                let publisher = Client::connect(addr, ConnectOptions::new("disk")).unwrap();
+                for level in ["90", "95", "99"] {
+                    publisher.publish("alerts/disk", level, Qos::ExactlyOnce, false, Vec::new()).unwrap();
+                }
+                publisher.disconnect().unwrap();
+            }
+        });
+        let levels: Vec<Bytes> = messages.by_ref().take(3).map(|message| message.payload).collect();
+        assert_eq!(levels, ["90", "95", "99"]);
+        publisher.join().unwrap();
+        assert!(messages.recv_timeout(Duration::from_millis(100)).is_none());
+
+        subscriber.unsubscribe("alerts/#").unwrap();
+        assert!(messages.next().is_none(), "unsubscribing ends the iterator");
+        subscriber.disconnect().unwrap();
+        assert!(matches!(
+            subscriber.publish("alerts/disk", "x", Qos::AtMostOnce, false, Vec::new()),
+            Err(ClientError::ConnectionLost)
+        ));
+        broker_runtime.block_on(broker.shutdown());
+    }
+}
@@ -1,27 +1,1048 @@
-use crate::client::Client;
-use std::collections::HashMap;
-use tokio::sync::mpsc::{self, Receiver, Sender};
+use crate::{
+    config::{BrokerConfig, OfflineQueueConfig, QueueFullPolicy, SlowConsumerPolicy},
+    definitions::*,
+    message::Message,
+    packet::{RetainHandlingOption, SubscriptionOptions},
+    persistence::{Record, Wal},
+    queue::{unix_time, OfflineQueue, Queued},
+    stats::{Snapshot, Stats},
+    topic::*,
+};
+use bytes::Bytes;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError, Receiver, Sender, UnboundedSender},
+        oneshot,
+    },
+    time::{self, Instant},
+};
+use tracing::{info, warn};
 
+// How often the broker tries again to hand their messages to the connections that fell behind.
+const RESUME_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Requests sent by the client tasks to the broker task.
 #[derive(Debug)]
 pub enum BrokerMessage {
-    AddPublisher(Client),
-    AddSubscriber(Client),
-    RemovePublisher(Client),
-    RemoveSubscriber(Client),
+    /// Attaches a connection to the session of `client_id`, taking it over from any connection
+    /// still attached. `session_present` receives whether an existing session was resumed.
+    Connect {
+        client_id: String,
+        connection_id: u64,
+        peer_address: Option<SocketAddr>,
+        // How many messages the client task holds back for lack of room in the client's
+        // Receive Maximum.
+        queue_depth: Arc<AtomicUsize>,
+        clean_start: bool,
+        session_expiry_interval: u32,
+        // Messages for the client, bounded so that a slow one cannot take the broker's memory,
+        // and DISCONNECT requests, which always go through.
+        sender: Sender<ClientMessage>,
+        control: UnboundedSender<ClientMessage>,
+        session_present: oneshot::Sender<bool>,
+    },
+    /// Detaches a connection from its session. `pending` holds the QoS 1/2 messages the client
+    /// had not acknowledged yet and `receiver` the ones it never got to read, they go back to
+    /// the session.
+    Disconnect {
+        client_id: String,
+        connection_id: u64,
+        session_expiry_interval: u32,
+        pending: Vec<Message>,
+        receiver: Receiver<ClientMessage>,
+    },
+    /// The client acknowledged a message the broker keeps until then.
+    Acknowledged {
+        client_id: String,
+        connection_id: u64,
+        delivery: u64,
+    },
+    Subscribe {
+        client_id: String,
+        topic_filter: String,
+        subscription_options: SubscriptionOptions,
+    },
+    Unsubscribe {
+        client_id: String,
+        topic_filter: String,
+    },
+    Publish {
+        client_id: String,
+        message: Message,
+        /// Told whether the message was accepted, which it is unless full offline queues
+        /// reject it or, when the state is persisted, it could not be written.
+        accepted: Option<oneshot::Sender<Result<(), Refused>>>,
+    },
+    /// Subscribes an in-process subscriber to `topic_filter`: the retained messages it matches
+    /// and every message published to it from now on go to `sender`, until it is dropped.
+    LocalSubscribe {
+        topic_filter: String,
+        sender: Sender<Message>,
+    },
+    /// Describes every session.
+    ListClients {
+        reply: oneshot::Sender<Vec<ClientInfo>>,
+    },
+    /// Closes the connection of `client_id` with `AdministrativeAction` and, with
+    /// `delete_session`, discards its session. `reply` receives whether there was a connection
+    /// to close or a session to discard.
+    Kick {
+        client_id: String,
+        delete_session: bool,
+        reply: oneshot::Sender<bool>,
+    },
+    ListRetained {
+        reply: oneshot::Sender<Vec<Message>>,
+    },
+    /// Clears the retained message of `topic`, `reply` receives whether there was one.
+    DeleteRetained {
+        topic: String,
+        reply: oneshot::Sender<bool>,
+    },
+    /// Stops the broker once the messages sent before are handled, `stopped` is told when it is
+    /// done.
+    Stop {
+        stopped: oneshot::Sender<()>,
+    },
+}
+
+/// Why the broker refused a published message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refused {
+    /// Offline queues with the Reject policy have no room for it.
+    QueuesFull,
+    /// The changes it made to the state could not be written to disk.
+    NotStored,
+}
+
+/// An in-process subscription. With no session to hold messages back in, the ones its consumer
+/// is too slow to take are dropped, or end the subscription under `SlowConsumerPolicy::Disconnect`.
+struct LocalSubscription {
+    topic_filter: String,
+    sender: Sender<Message>,
+    // Whether messages are being dropped, to warn once each time the consumer falls behind.
+    lagging: bool,
+}
+
+impl LocalSubscription {
+    /// Hands `message` to the consumer, returns whether the subscription goes on.
+    fn send(&mut self, message: Message, config: &BrokerConfig, stats: &Stats) -> bool {
+        match self.sender.try_send(message) {
+            Ok(()) => {
+                self.lagging = false;
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                Stats::add(&stats.slow_consumer_dropped, 1);
+                if !self.lagging {
+                    warn!(topic_filter = %self.topic_filter, policy = ?config.slow_consumer_policy, "in-process subscriber too slow to take its messages");
+                    self.lagging = true;
+                }
+                config.slow_consumer_policy != SlowConsumerPolicy::Disconnect
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+/// What the broker knows about a client's session.
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub client_id: String,
+    /// Address of the connection attached to the session, `None` when the client is away.
+    pub peer_address: Option<SocketAddr>,
+    pub connected: bool,
+    /// Topic filters with the maximum QoS granted for them.
+    pub subscriptions: Vec<(String, Qos)>,
+    /// Messages waiting for the client, in the session or in the connection.
+    pub queued: usize,
+}
+
+/// Messages the broker task pushes to a client task.
+#[derive(Debug)]
+pub enum ClientMessage {
+    /// A message for the client, with the delivery to report acknowledged when the broker keeps
+    /// the message on disk until then.
+    Publish(Message, Option<u64>),
+    /// Close the connection with a DISCONNECT carrying this reason code.
+    Disconnect(DisconnectReasonCode),
+}
+
+struct Connection {
+    connection_id: u64,
+    peer_address: Option<SocketAddr>,
+    queue_depth: Arc<AtomicUsize>,
+    sender: Sender<ClientMessage>,
+    control: UnboundedSender<ClientMessage>,
+}
+
+struct Session {
+    connection: Option<Connection>,
+    // The connection this session was taken over from, whose unacknowledged messages are
+    // handed to the new connection once it is gone.
+    taken_over_from: Option<u64>,
+    subscriptions: HashMap<String, SubscriptionOptions>,
+    // Messages waiting for a connection to be attached, or for the connection to take them.
+    queue: OfflineQueue,
+    // The connection fell behind, messages for it wait in `queue` until it takes them again.
+    paused: bool,
+    // QoS 1/2 messages of a persisted session sent to a connection and not acknowledged yet,
+    // by delivery, with the connection they went to.
+    in_flight: BTreeMap<u64, (u64, Queued)>,
+    next_delivery: u64,
+    session_expiry_interval: u32,
+    // When a session without connection ends, `None` while connected or if it never expires.
+    expires_at: Option<Instant>,
 }
 
+impl Session {
+    fn new() -> Session {
+        Session {
+            connection: None,
+            taken_over_from: None,
+            subscriptions: HashMap::new(),
+            queue: OfflineQueue::default(),
+            paused: false,
+            in_flight: BTreeMap::new(),
+            next_delivery: 0,
+            session_expiry_interval: 0,
+            expires_at: None,
+        }
+    }
+
+    /// Hands `message` to the client, or holds it back in the session while the client is away
+    /// or too slow to take it. The changes go to `records` when the session is persisted.
+    fn send(&mut self, client_id: &str, message: Message, config: &BrokerConfig, stats: &Stats, records: &mut Vec<Record>) {
+        if self.connection.is_none() {
+            if OfflineQueue::accepts(&config.offline_queue, &message) {
+                self.enqueue(client_id, Queued::new(message, unix_time()), &config.offline_queue, stats, records);
+            }
+            return;
+        }
+        let message = match self.paused {
+            true => message,
+            false => {
+                let now = unix_time();
+                match self.hand_over(client_id, Queued::new(message, now), now, config, records) {
+                    Ok(()) => return,
+                    Err(TrySendError::Full(queued)) => {
+                        warn!(client_id, policy = ?config.slow_consumer_policy, "client too slow to take its messages");
+                        if let Some(connection) = self
+                            .connection
+                            .as_ref()
+                            .filter(|_| config.slow_consumer_policy == SlowConsumerPolicy::Disconnect)
+                        {
+                            let _ = connection.control.send(ClientMessage::Disconnect(DisconnectReasonCode::QuotaExceeded));
+                        }
+                        self.set_paused(true, stats);
+                        queued.message
+                    }
+                    // The connection is closing, the message waits with what it hands back.
+                    Err(TrySendError::Closed(queued)) => queued.message,
+                }
+            }
+        };
+        if config.slow_consumer_policy == SlowConsumerPolicy::DropQos0 && message.qos == Qos::AtMostOnce {
+            Stats::add(&stats.slow_consumer_dropped, 1);
+            return;
+        }
+        self.enqueue(client_id, Queued::new(message, unix_time()), &config.offline_queue, stats, records);
+    }
+
+    /// Hands the messages held back to the connection, as many as it takes.
+    fn flush(&mut self, client_id: &str, config: &BrokerConfig, stats: &Stats, records: &mut Vec<Record>) {
+        if self.connection.is_none() {
+            return;
+        }
+        let now = unix_time();
+        let mut dequeued = 0;
+        while let Some(queued) = self.queue.pop_front() {
+            if queued.is_expired(now) {
+                Stats::add(&stats.dropped, 1);
+            } else if let Err(err) = self.hand_over(client_id, queued, now, config, records) {
+                self.queue.push_front(err.into_inner());
+                break;
+            }
+            dequeued += 1;
+        }
+        Stats::adjust(&stats.queued, -(dequeued as i64));
+        if dequeued > 0 && self.session_expiry_interval > 0 {
+            records.push(Record::Dequeue {
+                client_id: client_id.to_string(),
+                count: dequeued,
+            });
+        }
+        self.set_paused(!self.queue.is_empty(), stats);
+    }
+
+    /// Hands a message to the connection, or gives it back when the connection does not take
+    /// it. QoS 1/2 messages of a persisted session stay in `in_flight`, and on disk, until the
+    /// client acknowledges them.
+    fn hand_over(
+        &mut self,
+        client_id: &str,
+        queued: Queued,
+        now: u64,
+        config: &BrokerConfig,
+        records: &mut Vec<Record>,
+    ) -> Result<(), TrySendError<Queued>> {
+        let connection = match &self.connection {
+            Some(connection) => connection,
+            None => return Err(TrySendError::Closed(queued)),
+        };
+        let kept = config.persistence.is_some() && self.session_expiry_interval > 0 && queued.message.qos != Qos::AtMostOnce;
+        let delivery = Some(self.next_delivery).filter(|_| kept);
+        match connection.sender.try_send(ClientMessage::Publish(queued.message_at(now), delivery)) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => return Err(TrySendError::Full(queued)),
+            Err(TrySendError::Closed(_)) => return Err(TrySendError::Closed(queued)),
+        }
+        if let Some(delivery) = delivery {
+            self.next_delivery += 1;
+            records.push(Record::Deliver {
+                client_id: client_id.to_string(),
+                delivery,
+                queued: queued.clone(),
+            });
+            self.in_flight.insert(delivery, (connection.connection_id, queued));
+        }
+        Ok(())
+    }
+
+    /// Forgets a message in flight once the client acknowledged it.
+    fn acknowledged(&mut self, client_id: &str, connection_id: u64, delivery: u64, records: &mut Vec<Record>) {
+        if self.in_flight.get(&delivery).is_some_and(|(sent_to, _)| *sent_to == connection_id) {
+            self.in_flight.remove(&delivery);
+            if self.session_expiry_interval > 0 {
+                records.push(Record::Acknowledge {
+                    client_id: client_id.to_string(),
+                    delivery,
+                });
+            }
+        }
+    }
 
+    /// Forgets the messages in flight on a connection that ended, which hands back the ones the
+    /// client did not get.
+    fn end_in_flight(&mut self, client_id: &str, connection_id: u64, records: &mut Vec<Record>) {
+        let ended: Vec<u64> = self
+            .in_flight
+            .iter()
+            .filter(|(_, (sent_to, _))| *sent_to == connection_id)
+            .map(|(delivery, _)| *delivery)
+            .collect();
+        for delivery in ended {
+            self.acknowledged(client_id, connection_id, delivery, records);
+        }
+    }
+
+    fn set_paused(&mut self, paused: bool, stats: &Stats) {
+        if self.paused != paused {
+            Stats::adjust(&stats.slow_consumers, if paused { 1 } else { -1 });
+            self.paused = paused;
+        }
+    }
+
+    /// Queues a message within the bounds of `config`. The changes go to `records` when the
+    /// session is persisted.
+    fn enqueue(&mut self, client_id: &str, queued: Queued, config: &OfflineQueueConfig, stats: &Stats, records: &mut Vec<Record>) {
+        let now = unix_time();
+        let durable = self.session_expiry_interval > 0;
+        // Too big for even an empty queue.
+        if !OfflineQueue::default().fits(config, &queued) {
+            Stats::add(&stats.dropped, 1);
+            return;
+        }
+        if !self.queue.fits(config, &queued) {
+            let expired = self.queue.purge_expired(now);
+            if expired > 0 {
+                Stats::adjust(&stats.queued, -(expired as i64));
+                Stats::add(&stats.dropped, expired);
+                if durable {
+                    records.push(Record::PurgeExpired {
+                        client_id: client_id.to_string(),
+                        now,
+                    });
+                }
+            }
+        }
+        while config.policy == QueueFullPolicy::DropOldest && !self.queue.fits(config, &queued) {
+            self.queue.pop_front();
+            Stats::adjust(&stats.queued, -1);
+            Stats::add(&stats.dropped, 1);
+            if durable {
+                records.push(Record::Dequeue {
+                    client_id: client_id.to_string(),
+                    count: 1,
+                });
+            }
+        }
+        if !self.queue.fits(config, &queued) {
+            Stats::add(&stats.dropped, 1);
+            return;
+        }
+        Stats::adjust(&stats.queued, 1);
+        if durable {
+            records.push(Record::Enqueue {
+                client_id: client_id.to_string(),
+                queued: queued.clone(),
+            });
+        }
+        self.queue.push_back(queued);
+    }
+}
+
+/// Owns the subscription tree, the sessions of the clients and the retained messages, and
+/// routes every published message to the matching subscribers.
 pub struct Broker {
-    publisher: Vec<Client>,
-    subscriber: HashMap<Vec<Client>>,
+    config: Arc<BrokerConfig>,
+    stats: Arc<Stats>,
+    topic_tree: TopicTree,
+    sessions: HashMap<String, Session>,
+    // The last retained message of each topic.
+    retained: HashMap<String, Message>,
+    local_subscriptions: Vec<LocalSubscription>,
+    // Where the changes to the state go when it is persisted.
+    wal: Option<Wal>,
     sender: Sender<BrokerMessage>,
-    receiver: Receiver<BrokerMessage>
+    receiver: Receiver<BrokerMessage>,
 }
 
 impl Broker {
-    pub fn new() -> Self {
+    /// Creates the broker, with the state recovered from disk when it is persisted.
+    pub fn new(config: Arc<BrokerConfig>, stats: Arc<Stats>) -> io::Result<Self> {
         let (sender, receiver) = mpsc::channel(100);
 
-        self { publisher: Vec::new(), subscriber: HashMap::new(), sender, receiver}
+        let mut broker = Self {
+            config,
+            stats,
+            topic_tree: TopicTree::new_root(),
+            sessions: HashMap::new(),
+            retained: HashMap::new(),
+            local_subscriptions: Vec::new(),
+            wal: None,
+            sender,
+            receiver,
+        };
+        if let Some(persistence) = &broker.config.persistence {
+            let (wal, state) = Wal::open(persistence)?;
+            broker.wal = Some(wal);
+            broker.retained = state.retained;
+            broker.stats.retained.store(broker.retained.len() as i64, Ordering::Relaxed);
+            // The sessions count their expiry from now, their clients are all away.
+            let now = Instant::now();
+            let mut was_in_flight = false;
+            for (client_id, mut stored) in state.sessions.into_iter().filter(|(_, stored)| stored.session_expiry_interval > 0) {
+                for topic_filter in stored.subscriptions.keys() {
+                    broker.topic_tree.subscribe(topic_filter, &client_id);
+                }
+                // What was sent and not acknowledged goes out again first.
+                was_in_flight |= !stored.in_flight.is_empty();
+                for queued in stored.in_flight.into_values().rev() {
+                    stored.queue.push_front(queued);
+                }
+                Stats::adjust(&broker.stats.queued, stored.queue.len() as i64);
+                let mut session = Session::new();
+                session.subscriptions = stored.subscriptions;
+                session.queue = stored.queue;
+                session.session_expiry_interval = stored.session_expiry_interval;
+                if stored.session_expiry_interval != u32::MAX {
+                    session.expires_at = Some(now + Duration::from_secs(stored.session_expiry_interval as u64));
+                }
+                broker.sessions.insert(client_id, session);
+            }
+            // The log still has them in flight, under deliveries that start over from now.
+            if was_in_flight {
+                broker.snapshot();
+            }
+            info!(sessions = broker.sessions.len(), retained = broker.retained.len(), "state recovered");
+        }
+        Ok(broker)
     }
-}
\ No newline at end of file
+
+    pub fn sender(&self) -> Sender<BrokerMessage> {
+        self.sender.clone()
+    }
+
+    pub async fn run(mut self) {
+        let mut expiry_check = time::interval(Duration::from_secs(1));
+        let sys_interval = self.config.sys_interval;
+        let mut sys_update = time::interval(sys_interval.unwrap_or(Duration::from_secs(1)));
+        let mut last_snapshot = self.stats.snapshot();
+        let mut resume = time::interval(RESUME_INTERVAL);
+        let stopped = loop {
+            tokio::select! {
+                broker_message = self.receiver.recv() => match broker_message {
+                    Some(BrokerMessage::Stop { stopped }) => break Some(stopped),
+                    Some(broker_message) => self.handle_message(broker_message),
+                    None => break None,
+                },
+                _ = expiry_check.tick() => {
+                    self.remove_expired_sessions();
+                    // A snapshot writes again what a failed append left out.
+                    if self.wal.as_ref().is_some_and(Wal::is_broken) {
+                        self.snapshot();
+                    }
+                }
+                _ = resume.tick() => self.resume_slow_consumers(),
+                _ = sys_update.tick(), if sys_interval.is_some() => {
+                    last_snapshot = self.publish_sys_topics(&last_snapshot);
+                }
+            }
+        };
+        // The next start loads the snapshot alone.
+        if self.wal.is_some() {
+            self.snapshot();
+        }
+        if let Some(wal) = self.wal.take() {
+            wal.close().await;
+        }
+        if let Some(stopped) = stopped {
+            let _ = stopped.send(());
+        }
+    }
+
+    /// Publishes the broker statistics as retained messages under `$SYS/broker`, with rates
+    /// computed since the `previous` snapshot. Returns the snapshot to use next time.
+    fn publish_sys_topics(&mut self, previous: &Snapshot) -> Snapshot {
+        let snapshot = self.stats.snapshot();
+        let connected = self.sessions.values().filter(|session| session.connection.is_some()).count();
+        let subscriptions: usize = self.sessions.values().map(|session| session.subscriptions.len()).sum();
+        let values = [
+            ("version", String::from(env!("CARGO_PKG_VERSION"))),
+            ("uptime", format!("{} seconds", self.stats.started_at.elapsed().as_secs())),
+            ("clients/connected", connected.to_string()),
+            ("clients/disconnected", (self.sessions.len() - connected).to_string()),
+            ("clients/total", self.sessions.len().to_string()),
+            ("subscriptions/count", subscriptions.to_string()),
+            ("retained/count", self.retained.len().to_string()),
+            ("messages/inflight", self.stats.in_flight.load(Ordering::Relaxed).to_string()),
+            ("messages/received", snapshot.messages_received.to_string()),
+            ("messages/sent", snapshot.messages_sent.to_string()),
+            ("messages/dropped", self.stats.dropped.load(Ordering::Relaxed).to_string()),
+            ("clients/slow", self.stats.slow_consumers.load(Ordering::Relaxed).to_string()),
+            (
+                "messages/slow-consumer-dropped",
+                self.stats.slow_consumer_dropped.load(Ordering::Relaxed).to_string(),
+            ),
+            ("bytes/received", snapshot.bytes_received.to_string()),
+            ("bytes/sent", snapshot.bytes_sent.to_string()),
+            ("load/messages/received", rate(snapshot.rate(previous, |s| s.messages_received))),
+            ("load/messages/sent", rate(snapshot.rate(previous, |s| s.messages_sent))),
+            ("load/bytes/received", rate(snapshot.rate(previous, |s| s.bytes_received))),
+            ("load/bytes/sent", rate(snapshot.rate(previous, |s| s.bytes_sent))),
+        ];
+        for (topic, value) in values {
+            let message = Message {
+                topic: format!("$SYS/broker/{}", topic),
+                payload: Bytes::from(value),
+                qos: Qos::AtMostOnce,
+                retain: true,
+                properties: Vec::new(),
+            };
+            let _ = self.publish("", message);
+        }
+        snapshot
+    }
+
+    fn handle_message(&mut self, broker_message: BrokerMessage) {
+        match broker_message {
+            BrokerMessage::Connect {
+                client_id,
+                connection_id,
+                peer_address,
+                queue_depth,
+                clean_start,
+                session_expiry_interval,
+                sender,
+                control,
+                session_present,
+            } => self.connect(
+                client_id,
+                Connection {
+                    connection_id,
+                    peer_address,
+                    queue_depth,
+                    sender,
+                    control,
+                },
+                clean_start,
+                session_expiry_interval,
+                session_present,
+            ),
+            BrokerMessage::Disconnect {
+                client_id,
+                connection_id,
+                session_expiry_interval,
+                mut pending,
+                mut receiver,
+            } => {
+                // Nothing is sent to this connection past this point, so the channel holds all
+                // that is left for it.
+                while let Ok(client_message) = receiver.try_recv() {
+                    if let ClientMessage::Publish(message, _) = client_message {
+                        if message.qos != Qos::AtMostOnce {
+                            pending.push(message);
+                        }
+                    }
+                }
+                self.disconnect(&client_id, connection_id, session_expiry_interval, pending)
+            }
+            BrokerMessage::Acknowledged {
+                client_id,
+                connection_id,
+                delivery,
+            } => {
+                let mut records = Vec::new();
+                if let Some(session) = self.sessions.get_mut(&client_id) {
+                    session.acknowledged(&client_id, connection_id, delivery, &mut records);
+                }
+                for record in records {
+                    self.persist(record);
+                }
+            }
+            BrokerMessage::Subscribe {
+                client_id,
+                topic_filter,
+                subscription_options,
+            } => {
+                if let Some(session) = self.sessions.get_mut(&client_id) {
+                    self.topic_tree.subscribe(&topic_filter, &client_id);
+                    let durable = session.session_expiry_interval > 0;
+                    let is_new = session.subscriptions.insert(topic_filter.clone(), subscription_options.clone()).is_none();
+                    let send_retained = match subscription_options.retain_handling {
+                        RetainHandlingOption::SendRetainedMessageSubTime => true,
+                        RetainHandlingOption::SendRetainedMessageSubNotExist => is_new,
+                        RetainHandlingOption::NotSendRetainedMessage => false,
+                    };
+                    let mut records = Vec::new();
+                    if send_retained && session.connection.is_some() {
+                        for message in self.retained.values().filter(|message| matches(&topic_filter, &message.topic)) {
+                            let mut outgoing = message.clone();
+                            outgoing.qos = message.qos.min(subscription_options.maximum_qos);
+                            session.send(&client_id, outgoing, &self.config, &self.stats, &mut records);
+                        }
+                    }
+                    if durable {
+                        records.push(Record::Subscribe {
+                            client_id,
+                            topic_filter,
+                            subscription_options,
+                        });
+                    }
+                    for record in records {
+                        self.persist(record);
+                    }
+                }
+            }
+            BrokerMessage::Unsubscribe { client_id, topic_filter } => {
+                if let Some(session) = self.sessions.get_mut(&client_id) {
+                    self.topic_tree.unsubscribe(&topic_filter, &client_id);
+                    session.subscriptions.remove(&topic_filter);
+                    if session.session_expiry_interval > 0 {
+                        self.persist(Record::Unsubscribe { client_id, topic_filter });
+                    }
+                }
+            }
+            BrokerMessage::Publish {
+                client_id,
+                message,
+                accepted,
+            } => {
+                let started = Instant::now();
+                let published = self.publish(&client_id, message);
+                self.stats.routing_latency.observe(started.elapsed());
+                match (accepted, &self.wal) {
+                    // Accepted once what it changed is written.
+                    (Some(accepted), Some(wal)) if published.is_ok() => wal.confirm(move |written| {
+                        let _ = accepted.send(if written { Ok(()) } else { Err(Refused::NotStored) });
+                    }),
+                    (Some(accepted), _) => {
+                        let _ = accepted.send(published);
+                    }
+                    (None, _) => (),
+                }
+            }
+            BrokerMessage::LocalSubscribe { topic_filter, sender } => {
+                let retained: Vec<Message> = self
+                    .retained
+                    .values()
+                    .filter(|message| matches(&topic_filter, &message.topic))
+                    .cloned()
+                    .collect();
+                let mut subscription = LocalSubscription {
+                    topic_filter,
+                    sender,
+                    lagging: false,
+                };
+                if retained.into_iter().all(|message| subscription.send(message, &self.config, &self.stats)) {
+                    self.local_subscriptions.push(subscription);
+                }
+            }
+            BrokerMessage::ListClients { reply } => {
+                let _ = reply.send(self.clients());
+            }
+            BrokerMessage::Kick {
+                client_id,
+                delete_session,
+                reply,
+            } => {
+                let _ = reply.send(self.kick(&client_id, delete_session));
+            }
+            BrokerMessage::ListRetained { reply } => {
+                let _ = reply.send(self.retained.values().cloned().collect());
+            }
+            BrokerMessage::DeleteRetained { topic, reply } => {
+                let deleted = self.retained.remove(&topic).is_some();
+                self.stats.retained.store(self.retained.len() as i64, Ordering::Relaxed);
+                if deleted {
+                    self.persist(Record::ClearRetained(topic));
+                }
+                let _ = reply.send(deleted);
+            }
+            BrokerMessage::Stop { .. } => unreachable!("the broker loop stops on Stop"),
+        }
+    }
+
+    fn connect(
+        &mut self,
+        client_id: String,
+        connection: Connection,
+        clean_start: bool,
+        session_expiry_interval: u32,
+        session_present: oneshot::Sender<bool>,
+    ) {
+        let mut taken_over_from = None;
+        if let Some(previous) = self.sessions.get_mut(&client_id).and_then(|session| session.connection.take()) {
+            let _ = previous.control.send(ClientMessage::Disconnect(DisconnectReasonCode::SessionTakenOver));
+            taken_over_from = Some(previous.connection_id);
+        }
+        if clean_start {
+            self.remove_session(&client_id);
+        }
+        let _ = session_present.send(self.sessions.contains_key(&client_id));
+
+        let session = self.sessions.entry(client_id.clone()).or_insert_with(Session::new);
+        let was_durable = session.session_expiry_interval > 0;
+        session.connection = Some(connection);
+        session.taken_over_from = taken_over_from.filter(|_| !clean_start);
+        session.session_expiry_interval = session_expiry_interval;
+        session.expires_at = None;
+        // Only the sessions meant to outlive their connection are persisted.
+        let mut records = Vec::new();
+        if session_expiry_interval > 0 {
+            records.push(Record::Session {
+                client_id: client_id.clone(),
+                session_expiry_interval,
+            });
+        } else if was_durable {
+            records.push(Record::RemoveSession(client_id.clone()));
+        }
+        // Whatever is queued goes out right after the CONNACK the client task is about to write.
+        session.set_paused(false, &self.stats);
+        session.flush(&client_id, &self.config, &self.stats, &mut records);
+        for record in records {
+            self.persist(record);
+        }
+    }
+
+    fn disconnect(&mut self, client_id: &str, connection_id: u64, session_expiry_interval: u32, pending: Vec<Message>) {
+        let session = match self.sessions.get_mut(client_id) {
+            Some(session) => session,
+            None => return,
+        };
+        match &session.connection {
+            Some(connection) if connection.connection_id == connection_id => {
+                if session_expiry_interval == 0 {
+                    self.remove_session(client_id);
+                    return;
+                }
+                session.connection = None;
+                session.taken_over_from = None;
+                session.session_expiry_interval = session_expiry_interval;
+                if session_expiry_interval != u32::MAX {
+                    session.expires_at = Some(Instant::now() + Duration::from_secs(session_expiry_interval as u64));
+                }
+                session.set_paused(false, &self.stats);
+                let mut records = vec![Record::Session {
+                    client_id: client_id.to_string(),
+                    session_expiry_interval,
+                }];
+                // What the connection did not get is older than what was held back for it.
+                let mut held = std::mem::take(&mut session.queue);
+                if !held.is_empty() {
+                    Stats::adjust(&self.stats.queued, -(held.len() as i64));
+                    records.push(Record::Dequeue {
+                        client_id: client_id.to_string(),
+                        count: held.len() as u32,
+                    });
+                }
+                let now = unix_time();
+                let offline_queue = &self.config.offline_queue;
+                let pending = pending.into_iter().map(|message| Queued::new(message, now));
+                for queued in pending.chain(std::iter::from_fn(|| held.pop_front())) {
+                    if OfflineQueue::accepts(offline_queue, &queued.message) {
+                        session.enqueue(client_id, queued, offline_queue, &self.stats, &mut records);
+                    }
+                }
+                // Only once they are queued again are the messages in flight forgotten.
+                session.end_in_flight(client_id, connection_id, &mut records);
+                for record in records {
+                    self.persist(record);
+                }
+            }
+            // The session moved to a newer connection, which picks up what this one left.
+            Some(_) if session.taken_over_from == Some(connection_id) => {
+                let mut records = Vec::new();
+                for message in pending {
+                    session.send(client_id, message, &self.config, &self.stats, &mut records);
+                }
+                session.end_in_flight(client_id, connection_id, &mut records);
+                session.taken_over_from = None;
+                for record in records {
+                    self.persist(record);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn clients(&self) -> Vec<ClientInfo> {
+        self.sessions
+            .iter()
+            .map(|(client_id, session)| ClientInfo {
+                client_id: client_id.clone(),
+                peer_address: session.connection.as_ref().and_then(|connection| connection.peer_address),
+                connected: session.connection.is_some(),
+                subscriptions: session
+                    .subscriptions
+                    .iter()
+                    .map(|(topic_filter, subscription_options)| (topic_filter.clone(), subscription_options.maximum_qos))
+                    .collect(),
+                queued: session.queue.len()
+                    + session
+                        .connection
+                        .as_ref()
+                        .map_or(0, |connection| connection.queue_depth.load(Ordering::Relaxed)),
+            })
+            .collect()
+    }
+
+    fn kick(&mut self, client_id: &str, delete_session: bool) -> bool {
+        let session = match self.sessions.get_mut(client_id) {
+            Some(session) => session,
+            None => return false,
+        };
+        // The connection stays attached until it reports back with what it had pending.
+        let connected = match &session.connection {
+            Some(connection) => {
+                let _ = connection
+                    .control
+                    .send(ClientMessage::Disconnect(DisconnectReasonCode::AdministrativeAction));
+                true
+            }
+            None => false,
+        };
+        if delete_session {
+            self.remove_session(client_id);
+            return true;
+        }
+        connected
+    }
+
+    fn remove_expired_sessions(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.connection.is_none() && session.expires_at.is_some_and(|expires_at| expires_at <= now))
+            .map(|(client_id, _)| client_id.clone())
+            .collect();
+        for client_id in expired {
+            self.remove_session(&client_id);
+        }
+    }
+
+    /// Hands the connections that fell behind what they can take of their messages.
+    fn resume_slow_consumers(&mut self) {
+        let mut records = Vec::new();
+        for (client_id, session) in self.sessions.iter_mut().filter(|(_, session)| session.paused) {
+            session.flush(client_id, &self.config, &self.stats, &mut records);
+        }
+        for record in records {
+            self.persist(record);
+        }
+    }
+
+    fn remove_session(&mut self, client_id: &str) {
+        if let Some(mut session) = self.sessions.remove(client_id) {
+            session.set_paused(false, &self.stats);
+            Stats::adjust(&self.stats.queued, -(session.queue.len() as i64));
+            for topic_filter in session.subscriptions.keys() {
+                self.topic_tree.unsubscribe(topic_filter, client_id);
+            }
+            if session.session_expiry_interval > 0 {
+                self.persist(Record::RemoveSession(client_id.to_string()));
+            }
+        }
+    }
+
+    /// Appends a change of the state to the log, when the state is persisted. The broker keeps
+    /// running if the disk fails it, refusing the messages published meanwhile.
+    fn persist(&mut self, record: Record) {
+        let wal = match &mut self.wal {
+            Some(wal) => wal,
+            None => return,
+        };
+        wal.append(record);
+        if wal.wants_snapshot() {
+            self.snapshot();
+        }
+    }
+
+    /// Replaces the log with a snapshot of the retained messages and the persisted sessions.
+    fn snapshot(&mut self) {
+        let mut records: Vec<Record> = self
+            .retained
+            .values()
+            .filter(|message| !is_system_topic(&message.topic))
+            .map(|message| Record::Retain(message.clone()))
+            .collect();
+        for (client_id, session) in self.sessions.iter().filter(|(_, session)| session.session_expiry_interval > 0) {
+            records.push(Record::Session {
+                client_id: client_id.clone(),
+                session_expiry_interval: session.session_expiry_interval,
+            });
+            records.extend(
+                session
+                    .subscriptions
+                    .iter()
+                    .map(|(topic_filter, subscription_options)| Record::Subscribe {
+                        client_id: client_id.clone(),
+                        topic_filter: topic_filter.clone(),
+                        subscription_options: subscription_options.clone(),
+                    }),
+            );
+            records.extend(session.in_flight.iter().map(|(delivery, (_, queued))| Record::Deliver {
+                client_id: client_id.clone(),
+                delivery: *delivery,
+                queued: queued.clone(),
+            }));
+            records.extend(session.queue.iter().map(|queued| Record::Enqueue {
+                client_id: client_id.clone(),
+                queued: queued.clone(),
+            }));
+        }
+        if let Some(wal) = &mut self.wal {
+            wal.snapshot(records);
+        }
+    }
+
+    /// Routes `message` to its subscribers, returns whether it was accepted.
+    fn publish(&mut self, client_id: &str, message: Message) -> Result<(), Refused> {
+        let deliveries = self.deliveries(client_id, &message);
+        // With the Reject policy a message goes to all its subscribers or to none of them.
+        let offline_queue = &self.config.offline_queue;
+        if offline_queue.policy == QueueFullPolicy::Reject {
+            let now = unix_time();
+            let refused = deliveries.iter().any(|(subscriber_id, outgoing)| {
+                let session = &self.sessions[subscriber_id];
+                let held = match session.connection {
+                    Some(_) => session.paused,
+                    None => OfflineQueue::accepts(offline_queue, outgoing),
+                };
+                held && !session.queue.has_room(offline_queue, &Queued::new(outgoing.clone(), now), now)
+            });
+            if refused {
+                Stats::add(&self.stats.dropped, 1);
+                return Err(Refused::QueuesFull);
+            }
+        }
+        if message.retain {
+            // An empty retained message clears the topic, and is not kept itself.
+            match message.payload.is_empty() {
+                true => self.retained.remove(&message.topic),
+                false => self.retained.insert(message.topic.clone(), message.clone()),
+            };
+            self.stats.retained.store(self.retained.len() as i64, Ordering::Relaxed);
+            // The $SYS topics are published again on startup.
+            if !is_system_topic(&message.topic) {
+                self.persist(match message.payload.is_empty() {
+                    true => Record::ClearRetained(message.topic.clone()),
+                    false => Record::Retain(message.clone()),
+                });
+            }
+        }
+        // In-process subscribers get every message as published, like a subscription with QoS 2
+        // and Retain As Published unset. The ones that went away are forgotten.
+        let (config, stats) = (&self.config, &self.stats);
+        self.local_subscriptions.retain_mut(|subscription| {
+            if matches(&subscription.topic_filter, &message.topic) {
+                let mut outgoing = message.clone();
+                outgoing.retain = false;
+                if !subscription.send(outgoing, config, stats) {
+                    return false;
+                }
+            }
+            !subscription.sender.is_closed()
+        });
+        let mut records = Vec::new();
+        for (subscriber_id, outgoing) in deliveries {
+            let session = match self.sessions.get_mut(&subscriber_id) {
+                Some(session) => session,
+                None => continue,
+            };
+            session.send(&subscriber_id, outgoing, &self.config, &self.stats, &mut records);
+        }
+        for record in records {
+            self.persist(record);
+        }
+        Ok(())
+    }
+
+    /// The clients subscribed to the topic of `message`, each with the copy it gets.
+    fn deliveries(&self, client_id: &str, message: &Message) -> Vec<(String, Message)> {
+        let subscribers_id = match self.topic_tree.get_subscribers_id(&message.topic) {
+            Some(subscribers_id) => subscribers_id,
+            None => return Vec::new(),
+        };
+        let mut deliveries = Vec::new();
+        for subscriber_id in subscribers_id {
+            let session = match self.sessions.get(&subscriber_id) {
+                Some(session) => session,
+                None => continue,
+            };
+            // With overlapping filters the client gets one copy at the highest granted QoS.
+            let subscription_options = session
+                .subscriptions
+                .iter()
+                .filter(|(topic_filter, _)| matches(topic_filter, &message.topic))
+                .map(|(_, subscription_options)| subscription_options)
+                .max_by_key(|subscription_options| subscription_options.maximum_qos);
+            if let Some(subscription_options) = subscription_options {
+                if subscription_options.no_local_option && subscriber_id == client_id {
+                    continue;
+                }
+                let mut outgoing = message.clone();
+                outgoing.qos = message.qos.min(subscription_options.maximum_qos);
+                outgoing.retain = message.retain && subscription_options.retain_as_published;
+                deliveries.push((subscriber_id, outgoing));
+            }
+        }
+        deliveries
+    }
+}
+
+fn rate(per_second: f64) -> String {
+    format!("{:.2}", per_second)
+}
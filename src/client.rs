@@ -1,38 +1,270 @@
-use crate::{definitions::*, frame::*};
-use bytes::{Buf, BytesMut};
-use std::io::Cursor;
+use crate::{
+    acl::{Acl, Identity},
+    auth::{AuthExchange, AuthMechanism, AuthStep, Grant},
+    broker::{BrokerMessage, ClientMessage, Refused},
+    config::{BrokerConfig, QueueFullPolicy},
+    definitions::*,
+    frame::*,
+    handle,
+    message::Message,
+    ratelimit::{RateLimiter, Verdict},
+    redirect::{Redirect, RedirectRules},
+    stats::Stats,
+    topic::is_system_topic,
+};
+use bytes::{Buf, Bytes, BytesMut};
+use num_traits::ToPrimitive;
+use rand::{distr::Alphanumeric, Rng};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    io::Cursor,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
     net::TcpStream,
+    sync::{
+        mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender},
+        oneshot, watch,
+    },
+    task,
+    time::{self, Instant},
 };
+use tracing::{debug, field, info, info_span, trace, warn, Instrument, Span};
+
+/// How long a connection refused on accept, for a ban or the accept rate, has to send the
+/// CONNECT that gets the reason back. Holding sockets open is what such peers are refused for.
+const REFUSED_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Why the connection loop of a client stopped.
+#[derive(Debug)]
+enum CloseReason {
+    /// The client sent DISCONNECT with this reason code.
+    Client(DisconnectReasonCode),
+    /// The broker sent DISCONNECT with this reason code.
+    Server(DisconnectReasonCode),
+    /// The connection failed or was closed without a DISCONNECT.
+    Error(Error),
+}
+
+impl From<Error> for CloseReason {
+    fn from(err: Error) -> CloseReason {
+        CloseReason::Error(err)
+    }
+}
+
+/// Detaches the connection from the broker however its task ends. The connection loop takes
+/// the receiver back when it ends normally; when the task panics, the guard publishes the will
+/// and detaches the session itself, losing only the messages the client had in flight.
+struct DetachGuard {
+    stats: Arc<Stats>,
+    broker: Sender<BrokerMessage>,
+    receiver: Option<Receiver<ClientMessage>>,
+    // Set once the connection is attached to its session.
+    attached: Option<Attached>,
+}
+
+struct Attached {
+    client_id: String,
+    connection_id: u64,
+    session_expiry_interval: u32,
+    will: Option<Message>,
+}
+
+impl Drop for DetachGuard {
+    fn drop(&mut self) {
+        Stats::adjust(&self.stats.connections_active, -1);
+        let (attached, receiver) = match (self.attached.take(), self.receiver.take()) {
+            (Some(attached), Some(receiver)) => (attached, receiver),
+            _ => return,
+        };
+        warn!(client_id = %attached.client_id, "connection task failed, detaching its session");
+        let broker = self.broker.clone();
+        // Dropping cannot wait for room in the broker's channel.
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                if let Some(will) = attached.will {
+                    let _ = broker
+                        .send(BrokerMessage::Publish {
+                            client_id: attached.client_id.clone(),
+                            message: will,
+                            accepted: None,
+                        })
+                        .await;
+                }
+                let _ = broker
+                    .send(BrokerMessage::Disconnect {
+                        client_id: attached.client_id,
+                        connection_id: attached.connection_id,
+                        session_expiry_interval: attached.session_expiry_interval,
+                        pending: Vec::new(),
+                        receiver,
+                    })
+                    .await;
+            });
+        }
+    }
+}
 
 pub struct Client {
     read: ReadHalf<TcpStream>,
     write: WriteHalf<TcpStream>,
     buffer: BytesMut,
     id: String,
+    connection_id: u64,
+    connected: bool,
+    config: Arc<BrokerConfig>,
+    stats: Arc<Stats>,
+    // The `connection` span everything about this connection is logged in.
+    span: Span,
+    peer_address: Option<SocketAddr>,
+    // Length of `outbound_queue`, as shown to the broker.
+    queue_depth: Arc<AtomicUsize>,
+    broker: Sender<BrokerMessage>,
+    // Messages from the broker, and its DISCONNECT requests.
+    sender: Sender<ClientMessage>,
+    receiver: Option<Receiver<ClientMessage>>,
+    control: UnboundedSender<ClientMessage>,
+    control_receiver: Option<UnboundedReceiver<ClientMessage>>,
+    // Turns true when the server shuts down.
+    shutdown: Option<watch::Receiver<bool>>,
+    // Changes of the redirect rules, which may send the client to another server.
+    redirects: Option<watch::Receiver<RedirectRules>>,
+    // Keep Alive in seconds, 0 when disabled, and when the last packet was received.
+    keep_alive: u16,
+    last_packet_received: Instant,
+    will: Option<Message>,
+    clean_start: bool,
+    // User Name of CONNECT, or the user an enhanced authentication exchange authenticated.
+    user_name: Option<String>,
+    // Access rules that came with the credentials, in place of the broker's, and when the
+    // credentials expire.
+    permissions: Option<Acl>,
+    credentials_expire_at: Option<Instant>,
+    // Authentication Method picked in CONNECT, the exchange waiting for the client's next AUTH
+    // and the CONNACK properties held back until the exchange succeeds.
+    authentication_method: Option<String>,
+    auth_exchange: Option<Box<dyn AuthExchange>>,
+    conn_ack_properties: Vec<Option<Property>>,
+    // Session Expiry Interval in seconds, from CONNECT and possibly updated by DISCONNECT.
+    session_expiry_interval: u32,
+    // The client asked for Response Information in CONNECT.
+    request_response_information: bool,
+    // Receive Maximum and Maximum Packet Size announced by the client in CONNECT.
+    receive_maximum: u16,
+    maximum_packet_size: u32,
+    next_packet_identifier: u16,
+    // Outbound QoS 1/2 messages not yet acknowledged by the client, and the ones waiting for a
+    // free slot because `receive_maximum` messages are already in flight. Each comes with the
+    // delivery to report acknowledged to the broker, if any.
+    outbound_in_flight: HashMap<u16, (Message, Option<u64>)>,
+    outbound_queue: VecDeque<(Message, Option<u64>)>,
+    // Outbound QoS 2 packet identifiers the client sent PUBREC for, still in flight until PUBCOMP.
+    outbound_released: HashSet<u16>,
+    // Inbound QoS 2 packet identifiers waiting for PUBREL.
+    inbound_in_flight: HashSet<u16>,
+    // How fast the client may publish, set once it is connected.
+    rate_limiter: Option<RateLimiter>,
+    // Why the server refuses the connection whatever its CONNECT says, if it does.
+    refusal: Option<ConnAckReasonCode>,
+    // How many in-flight and queued messages of this connection are counted in `stats`.
+    reported_in_flight: usize,
+    reported_queued: usize,
 }
 
 impl Client {
-    pub fn new(stream: TcpStream) -> Client {
+    pub fn new(
+        stream: TcpStream,
+        connection_id: u64,
+        config: Arc<BrokerConfig>,
+        stats: Arc<Stats>,
+        broker: Sender<BrokerMessage>,
+        shutdown: watch::Receiver<bool>,
+        refusal: Option<ConnAckReasonCode>,
+    ) -> Client {
+        let span = info_span!(
+            "connection",
+            connection_id,
+            peer = field::Empty,
+            client_id = field::Empty,
+            protocol_version = field::Empty
+        );
+        let peer_address = stream.peer_addr().ok();
+        if let Some(peer_address) = peer_address {
+            span.record("peer", field::display(peer_address));
+        }
         let (rd, wr) = tokio::io::split(stream);
+        let (sender, receiver) = mpsc::channel(config.client_channel_capacity.max(1));
+        let (control, control_receiver) = mpsc::unbounded_channel();
+        let redirects = config.redirects.subscribe();
         Client {
             read: rd,
             write: wr,
             // Allocate the buffer with 4kb of capacity.
             buffer: BytesMut::with_capacity(4096),
             id: String::from(""),
+            connection_id,
+            connected: false,
+            config,
+            stats,
+            span,
+            peer_address,
+            queue_depth: Arc::new(AtomicUsize::new(0)),
+            broker,
+            sender,
+            receiver: Some(receiver),
+            control,
+            control_receiver: Some(control_receiver),
+            shutdown: Some(shutdown),
+            redirects: Some(redirects),
+            keep_alive: 0,
+            last_packet_received: Instant::now(),
+            will: None,
+            clean_start: false,
+            user_name: None,
+            permissions: None,
+            credentials_expire_at: None,
+            authentication_method: None,
+            auth_exchange: None,
+            conn_ack_properties: Vec::new(),
+            session_expiry_interval: 0,
+            request_response_information: false,
+            receive_maximum: u16::MAX,
+            maximum_packet_size: u32::MAX,
+            next_packet_identifier: 1,
+            outbound_in_flight: HashMap::new(),
+            outbound_queue: VecDeque::new(),
+            outbound_released: HashSet::new(),
+            inbound_in_flight: HashSet::new(),
+            rate_limiter: None,
+            refusal,
+            reported_in_flight: 0,
+            reported_queued: 0,
         }
     }
 
     pub async fn read_frame(&mut self) -> Result<Frame, Error> {
         loop {
+            // Attempt to parse a frame from the buffered data. If
+            // enough data has been buffered, the frame is
+            // returned.
+            if let Some(frame) = self.deserialize_frame()? {
+                return Ok(frame);
+            }
             // There is not enough buffered data to read a frame.
             // Attempt to read more data from the socket.
             //
             // On success, the number of bytes is returned. `0`
             // indicates "end of stream".
-            if 0 == self.read.read_buf(&mut self.buffer).await? {
+            let received = self.read.read_buf(&mut self.buffer).await?;
+            Stats::add(&self.stats.bytes_received, received);
+            if 0 == received {
                 // The remote closed the connection. For this to be
                 // a clean shutdown, there should be no data in the
                 // read buffer. If there is, this means that the
@@ -43,16 +275,18 @@ impl Client {
                     return Err(Error::Other("connection reset by peer".into()));
                 }
             }
-            // Attempt to parse a frame from the buffered data. If
-            // enough data has been buffered, the frame is
-            // returned.
-            if let Some(frame) = self.deserialize_frame()? {
-                return Ok(frame);
-            }
         }
     }
 
     fn deserialize_frame(&mut self) -> Result<Option<Frame>, Error> {
+        // Refuse an oversized packet as soon as its fixed header is in, instead of
+        // buffering it whole first.
+        if let Some(packet_size) = Frame::packet_size(&self.buffer[..])? {
+            if packet_size > self.config.maximum_packet_size as usize {
+                return Err(Error::PacketTooLarge(packet_size));
+            }
+        }
+
         // Create the `T: Buf` type.
         let mut buf = Cursor::new(&self.buffer[..]);
 
@@ -64,6 +298,8 @@ impl Client {
 
                 // Discard the frame from the buffer
                 self.buffer.advance(len);
+                Stats::count_packet(&self.stats.packets_received, frame.fix_header.control_packet_type);
+                self.log_frame("received", &frame);
 
                 // Return the frame to the caller.
                 Ok(Some(frame))
@@ -76,101 +312,2201 @@ impl Client {
     }
 
     pub async fn write_value(&mut self, src: &mut BytesMut) -> std::io::Result<()> {
-        println!("write_value: {:?}", src);
-        self.write.write_buf(src).await?;
+        Stats::add(&self.stats.bytes_sent, src.len());
+        self.write.write_all_buf(src).await?;
         Ok(())
     }
 
-    pub async fn run(mut self) {
-        loop {
-            match self.read_frame().await {
-                Ok(msg) => {
-                    println!("connection_packet: {:?}", msg.control_packet);
-                    match msg.control_packet {
-                        ControlPacket::Connect(control_packet) => {
-                            self.id = control_packet.payload.client_identifier;
-                            self.write_value(&mut Frame::serialize(Frame::new(ControlPacketType::CONNACK)).unwrap())
-                                .await
-                                .unwrap();
-                        }
-                        ControlPacket::Publish(control_packet) => match msg.fix_header.flags.1 {
-                            1 => {
-                                let pub_ack_control_packet = PubAckControlPacket {
-                                    variable_header: PubAckVariableHeader::from(
-                                        control_packet.variable_header.packet_identifier.unwrap(),
-                                        PubAckReasonCode::Success,
-                                        Vec::new(),
-                                    ),
-                                };
-                                let pub_ack = Frame {
-                                    fix_header: FixHeader::new(ControlPacketType::PUBACK, Flags(0, 0, 0, 0)),
-                                    control_packet: ControlPacket::PubAck(pub_ack_control_packet),
-                                };
-                                self.write_value(&mut Frame::serialize(pub_ack).unwrap()).await.unwrap()
-                            }
-                            2 => {
-                                let pub_rec_control_packet = PubRecControlPacket {
-                                    variable_header: PubRecVariableHeader::from(
-                                        control_packet.variable_header.packet_identifier.unwrap(),
-                                        PubRecReasonCode::Success,
-                                        Vec::new(),
-                                    ),
-                                };
-                                let pub_ack = Frame {
-                                    fix_header: FixHeader::new(ControlPacketType::PUBREC, Flags(0, 0, 0, 0)),
-                                    control_packet: ControlPacket::PubRec(pub_rec_control_packet),
-                                };
-                                self.write_value(&mut Frame::serialize(pub_ack).unwrap()).await.unwrap()
-                            }
-                            _ => (),
-                        },
-                        ControlPacket::PubRel(control_packet) => {
-                            let pub_comp_control_packet = PubCompControlPacket {
-                                variable_header: PubCompVariableHeader::from(
-                                    control_packet.variable_header.packet_identifier,
-                                    PubCompReasonCode::Success,
-                                    Vec::new(),
-                                ),
-                            };
-                            let pub_ack = Frame {
-                                fix_header: FixHeader::new(ControlPacketType::PUBCOMP, Flags(0, 0, 0, 0)),
-                                control_packet: ControlPacket::PubComp(pub_comp_control_packet),
-                            };
-                            self.write_value(&mut Frame::serialize(pub_ack).unwrap()).await.unwrap()
-                        }
-                        ControlPacket::Subscribe(control_packet) => {
-                            let mut sub_ack_payload = SubAckPayload::default();
-                            for iter in control_packet.variable_header.subscribe_payload {
-                                sub_ack_payload.sub_ack_reason_codes.push(SubAckReasonCode::GrantedQoS0);
+    /// Logs a packet at `debug` with what identifies it, and whole at `trace` unless payloads
+    /// are redacted.
+    fn log_frame(&self, direction: &'static str, frame: &Frame) {
+        let packet = frame.fix_header.control_packet_type;
+        match &frame.control_packet {
+            ControlPacket::Publish(control_packet) => debug!(
+                direction,
+                ?packet,
+                topic = %control_packet.variable_header.topic_name,
+                qos = frame.fix_header.flags.1,
+                retain = frame.fix_header.flags.0 == 1,
+                packet_identifier = control_packet.variable_header.packet_identifier,
+                payload_size = control_packet.payload.data.len(),
+                "packet"
+            ),
+            _ => debug!(direction, ?packet, "packet"),
+        }
+        if !self.config.redact_payloads {
+            trace!(direction, frame = ?Redacted(frame), "packet content");
+        }
+    }
+
+    async fn write_frame(&mut self, frame: Frame) -> Result<(), Error> {
+        Stats::count_packet(&self.stats.packets_sent, frame.fix_header.control_packet_type);
+        self.log_frame("sent", &frame);
+        self.write_value(&mut Frame::serialize(frame)?).await?;
+        Ok(())
+    }
+
+    /// Sends DISCONNECT with `reason_code` and returns the matching close reason.
+    async fn disconnect(&mut self, reason_code: DisconnectReasonCode) -> CloseReason {
+        self.disconnect_with(reason_code, Vec::new()).await
+    }
+
+    async fn disconnect_with(&mut self, reason_code: DisconnectReasonCode, properties: Vec<Option<Property>>) -> CloseReason {
+        let disconnect = Frame {
+            fix_header: FixHeader::new(ControlPacketType::DISCONNECT, Flags(0, 0, 0, 0)),
+            control_packet: ControlPacket::Disconnect(DisconnectControlPacket {
+                variable_header: DisconnectVariableHeader::from(reason_code, properties),
+            }),
+        };
+        if let Err(err) = self.write_frame(disconnect).await {
+            return CloseReason::Error(err);
+        }
+        CloseReason::Server(reason_code)
+    }
+
+    async fn send_to_broker(&self, broker_message: BrokerMessage) -> Result<(), CloseReason> {
+        self.broker
+            .send(broker_message)
+            .await
+            .map_err(|_| CloseReason::Error(Error::Other("broker stopped".into())))
+    }
+
+    pub async fn run(self) {
+        let span = self.span.clone();
+        self.run_connection().instrument(span).await
+    }
+
+    async fn run_connection(mut self) {
+        debug!("connection accepted");
+        let mut guard = DetachGuard {
+            stats: self.stats.clone(),
+            broker: self.broker.clone(),
+            receiver: self.receiver.take(),
+            attached: None,
+        };
+        let receiver = guard.receiver.as_mut().unwrap();
+        let mut control = self.control_receiver.take().unwrap();
+        let mut shutdown = self.shutdown.take().unwrap();
+        let mut redirects = self.redirects.take().unwrap();
+        Stats::adjust(&self.stats.connections_active, 1);
+        let connect_timeout = match self.refusal {
+            Some(_) => self.config.connect_timeout.min(REFUSED_CONNECT_TIMEOUT),
+            None => self.config.connect_timeout,
+        };
+        let connect_deadline = Instant::now() + connect_timeout;
+        let close_reason = loop {
+            // The client has one and a half times its Keep Alive to send something.
+            let keep_alive_deadline = self.last_packet_received + Duration::from_millis(self.keep_alive as u64 * 1500);
+            let credentials_expire_at = self.credentials_expire_at;
+            let result = tokio::select! {
+                frame = self.read_frame() => match frame {
+                    Ok(frame) => {
+                        self.last_packet_received = Instant::now();
+                        self.handle_frame(frame).await
+                    }
+                    Err(Error::PacketTooLarge(_)) => Err(self.disconnect(DisconnectReasonCode::PacketTooLarge).await),
+                    Err(Error::Malformed(err)) if self.connected => {
+                        warn!(error = %err, "malformed packet");
+                        Err(self.disconnect(DisconnectReasonCode::MalformedPacket).await)
+                    }
+                    Err(err) => Err(CloseReason::Error(err)),
+                },
+                Some(client_message) = control.recv() => self.handle_client_message(client_message).await,
+                // Messages held back by the client's Receive Maximum are bounded too, past them
+                // the broker sees the channel fill up.
+                Some(client_message) = receiver.recv(), if self.outbound_queue.len() < self.config.client_channel_capacity => {
+                    self.handle_client_message(client_message).await
+                }
+                _ = time::sleep_until(connect_deadline), if !self.connected => {
+                    Err(CloseReason::Error(Error::Other("no CONNECT in time".into())))
+                }
+                _ = time::sleep_until(keep_alive_deadline), if self.keep_alive > 0 => {
+                    Err(self.disconnect(DisconnectReasonCode::KeepAliveTimeout).await)
+                }
+                _ = time::sleep_until(credentials_expire_at.unwrap_or(keep_alive_deadline)), if credentials_expire_at.is_some() => {
+                    Err(self.disconnect(DisconnectReasonCode::NotAuthorized).await)
+                }
+                Ok(()) = redirects.changed(), if self.connected => {
+                    let redirect = redirects.borrow_and_update().redirect_for(&self.id, self.user_name.as_deref()).cloned();
+                    match redirect {
+                        Some(redirect) => Err(self.redirect(redirect).await),
+                        None => Ok(()),
+                    }
+                }
+                _ = handle::stopping(&mut shutdown) => match self.connected {
+                    true => Err(self.disconnect(DisconnectReasonCode::ServerShuttingDown).await),
+                    false => Err(CloseReason::Error(Error::Other("server shutting down".into()))),
+                },
+            };
+            self.report_gauges(self.outbound_in_flight.len() + self.inbound_in_flight.len(), self.outbound_queue.len());
+            if self.connected && guard.attached.is_none() {
+                guard.attached = Some(Attached {
+                    client_id: self.id.clone(),
+                    connection_id: self.connection_id,
+                    session_expiry_interval: self.session_expiry_interval,
+                    will: self.will.clone(),
+                });
+            }
+            if let Err(close_reason) = result {
+                break close_reason;
+            }
+        };
+        // Whatever is left goes back to the broker, which accounts for it from now on.
+        self.report_gauges(0, 0);
+        guard.attached = None;
+        let receiver = guard.receiver.take().unwrap();
+        match &close_reason {
+            CloseReason::Client(reason_code) => {
+                info!(reason = ?reason_code, "client disconnected");
+                self.stats.count_disconnect("client", format!("{:?}", reason_code));
+            }
+            CloseReason::Server(reason_code) => {
+                info!(reason = ?reason_code, "disconnected by the server");
+                self.stats.count_disconnect("server", format!("{:?}", reason_code));
+            }
+            CloseReason::Error(err) => {
+                info!(error = %err, "connection closed");
+                self.stats.count_disconnect("none", String::from("ConnectionClosed"));
+            }
+        }
+        if self.connected {
+            // Only a DISCONNECT with Normal Disconnection from the client discards the will.
+            let will = self
+                .will
+                .take()
+                .filter(|_| !matches!(close_reason, CloseReason::Client(DisconnectReasonCode::NormalDisconnection)));
+            if let Some(will) = will {
+                let _ = self
+                    .send_to_broker(BrokerMessage::Publish {
+                        client_id: self.id.clone(),
+                        message: will,
+                        accepted: None,
+                    })
+                    .await;
+            }
+            let pending = self.take_pending();
+            let _ = self
+                .send_to_broker(BrokerMessage::Disconnect {
+                    client_id: self.id.clone(),
+                    connection_id: self.connection_id,
+                    session_expiry_interval: self.session_expiry_interval,
+                    pending,
+                    receiver,
+                })
+                .await;
+        }
+    }
+
+    async fn handle_frame(&mut self, msg: Frame) -> Result<(), CloseReason> {
+        match msg.control_packet {
+            ControlPacket::Connect(control_packet) if !self.connected && self.authentication_method.is_none() => {
+                self.handle_connect(control_packet).await
+            }
+            ControlPacket::Auth(control_packet) if self.authentication_method.is_some() => self.handle_auth(control_packet).await,
+            // Nothing but CONNECT is allowed before CONNACK, not even DISCONNECT.
+            _ if !self.connected => Err(CloseReason::Error(Error::Other("first packet was not CONNECT".into()))),
+            ControlPacket::Publish(control_packet) => self.handle_publish(control_packet, msg.fix_header.flags).await,
+            ControlPacket::PubAck(control_packet) => {
+                self.acknowledged(control_packet.variable_header.packet_identifier).await?;
+                self.deliver_queued().await
+            }
+            ControlPacket::PubRec(control_packet) => {
+                let packet_identifier = control_packet.variable_header.packet_identifier;
+                if control_packet.variable_header.reason_code.to_u8().unwrap() >= 0x80 {
+                    // A failed PUBREC ends the exchange, no PUBREL follows.
+                    self.acknowledged(packet_identifier).await?;
+                    return self.deliver_queued().await;
+                }
+                let reason_code = match self.outbound_in_flight.contains_key(&packet_identifier) {
+                    true => {
+                        self.outbound_released.insert(packet_identifier);
+                        PubRelReasonCode::Success
+                    }
+                    false => PubRelReasonCode::PacketIdentifierNotFound,
+                };
+                let mut pub_rel = Frame::new(ControlPacketType::PUBREL);
+                pub_rel.control_packet = ControlPacket::PubRel(PubRelControlPacket {
+                    variable_header: PubRelVariableHeader::from(packet_identifier, reason_code, Vec::new()),
+                });
+                Ok(self.write_frame(pub_rel).await?)
+            }
+            ControlPacket::PubRel(control_packet) => {
+                let packet_identifier = control_packet.variable_header.packet_identifier;
+                let reason_code = match self.inbound_in_flight.remove(&packet_identifier) {
+                    true => PubCompReasonCode::Success,
+                    false => PubCompReasonCode::PacketIdentifierNotFound,
+                };
+                let pub_comp_control_packet = PubCompControlPacket {
+                    variable_header: PubCompVariableHeader::from(packet_identifier, reason_code, Vec::new()),
+                };
+                let pub_ack = Frame {
+                    fix_header: FixHeader::new(ControlPacketType::PUBCOMP, Flags(0, 0, 0, 0)),
+                    control_packet: ControlPacket::PubComp(pub_comp_control_packet),
+                };
+                Ok(self.write_frame(pub_ack).await?)
+            }
+            ControlPacket::PubComp(control_packet) => {
+                self.acknowledged(control_packet.variable_header.packet_identifier).await?;
+                self.outbound_released.remove(&control_packet.variable_header.packet_identifier);
+                self.deliver_queued().await
+            }
+            ControlPacket::Subscribe(control_packet) => {
+                let mut sub_ack_payload = SubAckPayload::default();
+                for iter in control_packet.variable_header.subscribe_payload {
+                    if iter.subscription_options.reserved != 0 {
+                        return Err(self.disconnect(DisconnectReasonCode::MalformedPacket).await);
+                    }
+                    if !self.may_subscribe(&iter.topic_filter) {
+                        sub_ack_payload.sub_ack_reason_codes.push(SubAckReasonCode::NotAuthorized);
+                        continue;
+                    }
+                    sub_ack_payload.sub_ack_reason_codes.push(match iter.subscription_options.maximum_qos {
+                        Qos::AtMostOnce => SubAckReasonCode::GrantedQoS0,
+                        Qos::AtleastOnce => SubAckReasonCode::GrantedQoS1,
+                        Qos::ExactlyOnce => SubAckReasonCode::GrantedQoS2,
+                    });
+                    self.send_to_broker(BrokerMessage::Subscribe {
+                        client_id: self.id.clone(),
+                        topic_filter: iter.topic_filter,
+                        subscription_options: iter.subscription_options,
+                    })
+                    .await?;
+                }
+                let sub_ack_control_packet = SubAckControlPacket {
+                    variable_header: SubAckVariableHeader::from(control_packet.variable_header.packet_identifier, sub_ack_payload, Vec::new()),
+                };
+                let sub_ack = Frame {
+                    fix_header: FixHeader::new(ControlPacketType::SUBACK, Flags(0, 0, 0, 0)),
+                    control_packet: ControlPacket::SubAck(sub_ack_control_packet),
+                };
+                Ok(self.write_frame(sub_ack).await?)
+            }
+            ControlPacket::Unsubscribe(control_packet) => {
+                let mut unsub_ack_payload = UnsubAckPayload::default();
+                for topic_filter in control_packet.variable_header.unsubscribe_payload.topic_filters {
+                    unsub_ack_payload.un_sub_ack_reason_code.push(UnSubAckReasonCode::Success);
+                    self.send_to_broker(BrokerMessage::Unsubscribe {
+                        client_id: self.id.clone(),
+                        topic_filter,
+                    })
+                    .await?;
+                }
+                let unsub_ack_control_packet = UnsubAckControlPacket {
+                    variable_header: UnsubAckVariableHeader::from(control_packet.variable_header.packet_identifier, unsub_ack_payload, Vec::new()),
+                };
+                let unsub_ack = Frame {
+                    fix_header: FixHeader::new(ControlPacketType::UNSUBACK, Flags(0, 0, 0, 0)),
+                    control_packet: ControlPacket::UnsubAck(unsub_ack_control_packet),
+                };
+                Ok(self.write_frame(unsub_ack).await?)
+            }
+            ControlPacket::PingReq => Ok(self.write_frame(Frame::new(ControlPacketType::PINGRESP)).await?),
+            ControlPacket::Disconnect(control_packet) => {
+                for property in control_packet.variable_header.get_properties().into_iter().flatten() {
+                    if let Property::SessionExpiryInterval(session_expiry_interval) = property {
+                        self.session_expiry_interval = session_expiry_interval;
+                    }
+                }
+                Err(CloseReason::Client(control_packet.variable_header.disconnect_reason_code))
+            }
+            _ => Err(self.disconnect(DisconnectReasonCode::ProtocolError).await),
+        }
+    }
+
+    async fn handle_connect(&mut self, control_packet: ConnectControlPacket) -> Result<(), CloseReason> {
+        let will = Message::from_will(&control_packet.variable_header.connect_flag, &control_packet.payload);
+        self.id = control_packet.payload.client_identifier;
+        self.span.record("protocol_version", control_packet.variable_header.protocol_version);
+        self.keep_alive = control_packet.variable_header.keep_alive;
+        self.clean_start = control_packet.variable_header.connect_flag.clean_start;
+        self.user_name = control_packet.payload.user_name.clone();
+        let mut authentication_data = None;
+        for property in control_packet.variable_header.properties.iter().flatten() {
+            match property {
+                Property::SessionExpiryInterval(session_expiry_interval) => self.session_expiry_interval = *session_expiry_interval,
+                Property::ReceiveMaximum(receive_maximum) => self.receive_maximum = *receive_maximum,
+                Property::MaximumPacketSize(maximum_packet_size) => self.maximum_packet_size = *maximum_packet_size,
+                Property::AuthenticationMethod(authentication_method) => self.authentication_method = Some(authentication_method.clone()),
+                Property::AuthenticationData(data) => authentication_data = Some(data.clone()),
+                Property::RequestResponseInformation(request) => self.request_response_information = *request == 1,
+                _ => (),
+            }
+        }
+        if self.receive_maximum == 0 || self.maximum_packet_size == 0 {
+            return Err(self
+                .refuse_connect(ConnAckReasonCode::ProtocolError, "receive maximum or maximum packet size of 0")
+                .await);
+        }
+        self.will = match will {
+            Ok(will) => will,
+            Err(err) => return Err(self.refuse_connect(ConnAckReasonCode::ProtocolError, &err).await),
+        };
+        let mut properties = Vec::new();
+        if self.id.is_empty() {
+            self.id = assign_client_id();
+            properties.push(Some(Property::AssignedClientIdentifier(self.id.clone())));
+        }
+        self.span.record("client_id", self.id.as_str());
+        if let Some(reason_code) = self.refusal {
+            return Err(self.refuse_connect(reason_code, "connection refused").await);
+        }
+        if !self.is_valid_client_id() {
+            return Err(self
+                .refuse_connect(ConnAckReasonCode::ClientIdentifierNotValid, "invalid client identifier")
+                .await);
+        }
+        // Redirected clients authenticate with the server they are sent to.
+        if let Some(redirect) = self.config.redirects.redirect_for(&self.id, self.user_name.as_deref()) {
+            info!(server_reference = %redirect.server_reference, "redirecting the client");
+            let properties = vec![Some(Property::ServerReference(redirect.server_reference.clone()))];
+            return Err(self.refuse_connect_with(redirect.conn_ack_reason_code(), properties, "redirected").await);
+        }
+
+        if let Some(authentication_method) = self.authentication_method.clone() {
+            let mechanism = match self.auth_mechanism(&authentication_method) {
+                Some(mechanism) => mechanism,
+                None => {
+                    return Err(self
+                        .refuse_connect(ConnAckReasonCode::BadAuthenticationMethod, "unknown authentication method")
+                        .await)
+                }
+            };
+            // The CONNACK waits for the end of the exchange.
+            self.conn_ack_properties = properties;
+            return self.authentication_step(mechanism.start(&self.id), authentication_data).await;
+        }
+        if let Some(authenticator) = self.config.authenticator.clone() {
+            let client_id = self.id.clone();
+            let user_name = control_packet.payload.user_name.clone();
+            let password = control_packet.payload.password.clone();
+            let authenticated = task::spawn_blocking(move || authenticator.authenticate(&client_id, user_name.as_deref(), password.as_deref()))
+                .await
+                .map_err(|err| Error::Other(err.to_string()))?;
+            match authenticated {
+                Ok(grant) => self.apply_grant(grant),
+                Err(err) => return Err(self.refuse_connect(err.into(), "authentication failed").await),
+            }
+        }
+        self.accept_connect(properties).await
+    }
+
+    /// Answers CONNECT with a CONNACK carrying `reason_code` and returns the close reason.
+    async fn refuse_connect(&mut self, reason_code: ConnAckReasonCode, reason: &str) -> CloseReason {
+        self.refuse_connect_with(reason_code, Vec::new(), reason).await
+    }
+
+    async fn refuse_connect_with(&mut self, reason_code: ConnAckReasonCode, properties: Vec<Option<Property>>, reason: &str) -> CloseReason {
+        if matches!(
+            reason_code,
+            ConnAckReasonCode::BadUserNameOrPassword | ConnAckReasonCode::NotAuthorized | ConnAckReasonCode::BadAuthenticationMethod
+        ) {
+            self.auth_failed(reason_code);
+        }
+        let mut conn_ack = Frame::new(ControlPacketType::CONNACK);
+        if let ControlPacket::ConnAck(conn_ack_control_packet) = &mut conn_ack.control_packet {
+            conn_ack_control_packet.variable_header.reason_code = reason_code;
+            conn_ack_control_packet.variable_header.properties = properties;
+        }
+        if let Err(err) = self.write_frame(conn_ack).await {
+            return CloseReason::Error(err);
+        }
+        info!(reason = ?reason_code, "connection refused");
+        CloseReason::Error(Error::Other(format!("{} for client {:?}", reason, self.id)))
+    }
+
+    /// Counts a failed authentication, and bans the address it came from once that happened
+    /// too often.
+    fn auth_failed(&self, reason_code: ConnAckReasonCode) {
+        self.stats.count_auth_failure(format!("{:?}", reason_code));
+        if let (Some(policy), Some(peer_address)) = (&self.config.connection_limits.auth_failure_ban, self.peer_address) {
+            if self.config.bans.count_auth_failure(peer_address.ip(), policy) {
+                warn!(address = %peer_address.ip(), ban_for = ?policy.ban_for, "banned after repeated authentication failures");
+            }
+        }
+    }
+
+    /// Attaches the connection to its session and sends the successful CONNACK.
+    async fn accept_connect(&mut self, mut properties: Vec<Option<Property>>) -> Result<(), CloseReason> {
+        // The User Name may only be known once the authentication is over.
+        if self.config.bans.is_client_banned(&self.id, self.user_name.as_deref()) {
+            Stats::add(&self.stats.connections_banned, 1);
+            return Err(self.refuse_connect(ConnAckReasonCode::Banned, "banned").await);
+        }
+        properties.push(Some(Property::ReceiveMaximum(self.config.receive_maximum)));
+        properties.push(Some(Property::MaximumPacketSize(self.config.maximum_packet_size)));
+        if let Some(server_keep_alive) = self.config.server_keep_alive {
+            if self.keep_alive == 0 || self.keep_alive > server_keep_alive {
+                self.keep_alive = server_keep_alive;
+                properties.push(Some(Property::ServerKeepAlive(server_keep_alive)));
+            }
+        }
+        if let Some(prefix) = self.config.response_information.as_ref().filter(|_| self.request_response_information) {
+            properties.push(Some(Property::ResponseInformation(format!("{}{}", prefix, self.id))));
+        }
+        let rate_limits = &self.config.rate_limits;
+        self.rate_limiter = rate_limits
+            .limit_for(&self.id, self.user_name.as_deref())
+            .map(|limit| RateLimiter::new(limit, rate_limits.disconnect_after, Instant::now()));
+        let (session_present_sender, session_present) = oneshot::channel();
+        self.send_to_broker(BrokerMessage::Connect {
+            client_id: self.id.clone(),
+            connection_id: self.connection_id,
+            peer_address: self.peer_address,
+            queue_depth: self.queue_depth.clone(),
+            clean_start: self.clean_start,
+            session_expiry_interval: self.session_expiry_interval,
+            sender: self.sender.clone(),
+            control: self.control.clone(),
+            session_present: session_present_sender,
+        })
+        .await?;
+        let session_present = session_present
+            .await
+            .map_err(|_| CloseReason::Error(Error::Other("broker stopped".into())))?;
+        let mut conn_ack = Frame::new(ControlPacketType::CONNACK);
+        if let ControlPacket::ConnAck(conn_ack_control_packet) = &mut conn_ack.control_packet {
+            conn_ack_control_packet.variable_header.conn_ack_flag.session_present_flag = session_present;
+            conn_ack_control_packet.variable_header.properties = properties;
+        }
+        self.connected = true;
+        info!(session_present, user_name = self.user_name.as_deref(), "client connected");
+        Ok(self.write_frame(conn_ack).await?)
+    }
+
+    /// Sends the connected client to the server of `redirect`.
+    async fn redirect(&mut self, redirect: Redirect) -> CloseReason {
+        info!(server_reference = %redirect.server_reference, "redirecting the client");
+        let reason_code = redirect.disconnect_reason_code();
+        let properties = vec![Some(Property::ServerReference(redirect.server_reference))];
+        self.disconnect_with(reason_code, properties).await
+    }
+
+    fn auth_mechanism(&self, authentication_method: &str) -> Option<Arc<dyn AuthMechanism>> {
+        self.config
+            .auth_mechanisms
+            .iter()
+            .find(|mechanism| mechanism.method() == authentication_method)
+            .cloned()
+    }
+
+    async fn handle_auth(&mut self, control_packet: AuthControlPacket) -> Result<(), CloseReason> {
+        let mut authentication_method = None;
+        let mut authentication_data = None;
+        for property in control_packet.variable_header.get_properties().into_iter().flatten() {
+            match property {
+                Property::AuthenticationMethod(method) => authentication_method = Some(method),
+                Property::AuthenticationData(data) => authentication_data = Some(data),
+                _ => (),
+            }
+        }
+        let exchange = match (control_packet.variable_header.auth_reason_code, self.auth_exchange.take()) {
+            _ if authentication_method != self.authentication_method => None,
+            (AuthReasonCode::ContinueAuthentication, Some(exchange)) => Some(exchange),
+            (AuthReasonCode::ReAuthenticate, None) if self.connected => authentication_method
+                .as_deref()
+                .and_then(|method| self.auth_mechanism(method))
+                .map(|mechanism| mechanism.start(&self.id)),
+            _ => None,
+        };
+        match exchange {
+            Some(exchange) => self.authentication_step(exchange, authentication_data).await,
+            None if self.connected => Err(self.disconnect(DisconnectReasonCode::ProtocolError).await),
+            None => Err(CloseReason::Error(Error::Other("unexpected AUTH".into()))),
+        }
+    }
+
+    /// Feeds the client's Authentication Data to `exchange` and answers with AUTH while the
+    /// exchange goes on, then with CONNACK or AUTH once it is over.
+    async fn authentication_step(&mut self, mut exchange: Box<dyn AuthExchange>, data: Option<Bytes>) -> Result<(), CloseReason> {
+        let authentication_method = self.authentication_method.clone().unwrap_or_default();
+        let step = exchange.step(data.as_deref());
+        if let Ok(AuthStep::Success(_)) = step {
+            self.apply_grant(exchange.grant());
+        }
+        match step {
+            Ok(AuthStep::Continue(data)) => {
+                self.auth_exchange = Some(exchange);
+                let auth = auth_frame(AuthReasonCode::ContinueAuthentication, authentication_method, Some(data));
+                Ok(self.write_frame(auth).await?)
+            }
+            Ok(AuthStep::Success(data)) if !self.connected => {
+                let mut properties = std::mem::take(&mut self.conn_ack_properties);
+                properties.push(Some(Property::AuthenticationMethod(authentication_method)));
+                properties.push(data.map(Property::AuthenticationData));
+                self.accept_connect(properties).await
+            }
+            Ok(AuthStep::Success(data)) => Ok(self.write_frame(auth_frame(AuthReasonCode::Success, authentication_method, data)).await?),
+            Err(err) if !self.connected => Err(self.refuse_connect(err.into(), "authentication failed").await),
+            Err(err) => {
+                self.auth_failed(ConnAckReasonCode::from(err));
+                Err(self.disconnect(DisconnectReasonCode::NotAuthorized).await)
+            }
+        }
+    }
+
+    fn is_valid_client_id(&self) -> bool {
+        self.id.len() <= self.config.client_id_max_length
+            && (!self.config.client_id_strict_charset || self.id.chars().all(|c| c.is_ascii_alphanumeric()))
+    }
+
+    async fn handle_publish(&mut self, control_packet: PublishControlPacket, flags: Flags) -> Result<(), CloseReason> {
+        let packet_identifier = control_packet.variable_header.packet_identifier;
+        let topic_name = &control_packet.variable_header.topic_name;
+        // Wildcards belong in topic filters; a topic name carrying one is a protocol error.
+        if topic_name.contains(['+', '#']) {
+            return Err(self.disconnect(DisconnectReasonCode::TopicNameInvalid).await);
+        }
+        // Topics starting with `$` belong to the broker.
+        let authorized = !is_system_topic(topic_name) && self.may_publish(topic_name);
+        // Retransmissions of a QoS 2 message already taken are not counted again.
+        let retransmitted = flags.1 == 2 && packet_identifier.is_some_and(|packet_identifier| self.inbound_in_flight.contains(&packet_identifier));
+        let within_limits = match self.rate_limiter.as_mut().filter(|_| authorized && !retransmitted) {
+            Some(rate_limiter) => match rate_limiter.check(control_packet.payload.data.len(), Instant::now()) {
+                Verdict::Allow => true,
+                Verdict::Refuse => {
+                    Stats::add(&self.stats.rate_limited, 1);
+                    false
+                }
+                Verdict::Disconnect => {
+                    warn!("client kept exceeding its rate limit");
+                    return Err(self.disconnect(DisconnectReasonCode::MessageRateTooHigh).await);
+                }
+            },
+            None => true,
+        };
+        match (flags.1, packet_identifier) {
+            // There is no way to tell a QoS 0 publisher, the message is just dropped.
+            (0, _) if !authorized || !within_limits => Ok(()),
+            (0, _) => self.route(control_packet, &flags).await.map(|_| ()),
+            (1, Some(packet_identifier)) => {
+                if self.inbound_in_flight.len() >= self.config.receive_maximum as usize {
+                    return Err(self.disconnect(DisconnectReasonCode::ReceiveMaximumExceeded).await);
+                }
+                let reason_code = match (authorized, within_limits) {
+                    (true, true) => match self.route(control_packet, &flags).await? {
+                        Ok(()) => PubAckReasonCode::Success,
+                        Err(Refused::QueuesFull) => PubAckReasonCode::QuotaExceeded,
+                        Err(Refused::NotStored) => PubAckReasonCode::ImplementationSpecificError,
+                    },
+                    (true, false) => PubAckReasonCode::QuotaExceeded,
+                    (false, _) => PubAckReasonCode::NotAuthorized,
+                };
+                let pub_ack_control_packet = PubAckControlPacket {
+                    variable_header: PubAckVariableHeader::from(packet_identifier, reason_code, Vec::new()),
+                };
+                let pub_ack = Frame {
+                    fix_header: FixHeader::new(ControlPacketType::PUBACK, Flags(0, 0, 0, 0)),
+                    control_packet: ControlPacket::PubAck(pub_ack_control_packet),
+                };
+                Ok(self.write_frame(pub_ack).await?)
+            }
+            (2, Some(packet_identifier)) => {
+                let mut reason_code = match (authorized, within_limits) {
+                    (true, true) => PubRecReasonCode::Success,
+                    (true, false) => PubRecReasonCode::QuotaExceeded,
+                    (false, _) => PubRecReasonCode::NotAuthorized,
+                };
+                // A retransmission of a message we already routed only needs a new PUBREC.
+                if !self.inbound_in_flight.contains(&packet_identifier) {
+                    if self.inbound_in_flight.len() >= self.config.receive_maximum as usize {
+                        return Err(self.disconnect(DisconnectReasonCode::ReceiveMaximumExceeded).await);
+                    }
+                    // A refused message gets a failed PUBREC, which ends the exchange.
+                    if authorized && within_limits {
+                        match self.route(control_packet, &flags).await? {
+                            Ok(()) => {
+                                self.inbound_in_flight.insert(packet_identifier);
                             }
-                            let sub_ack_control_packet = SubAckControlPacket {
-                                variable_header: SubAckVariableHeader::from(
-                                    control_packet.variable_header.packet_identifier,
-                                    sub_ack_payload,
-                                    Vec::new(),
-                                ),
-                            };
-                            let pub_ack = Frame {
-                                fix_header: FixHeader::new(ControlPacketType::PUBCOMP, Flags(0, 0, 0, 0)),
-                                control_packet: ControlPacket::SubAck(sub_ack_control_packet),
-                            };
-                            self.write_value(&mut Frame::serialize(pub_ack).unwrap()).await.unwrap()
+                            Err(Refused::QueuesFull) => reason_code = PubRecReasonCode::QuotaExceeded,
+                            Err(Refused::NotStored) => reason_code = PubRecReasonCode::ImplementationSpecificError,
                         }
-                        ControlPacket::PingReq => self
-                            .write_value(&mut Frame::serialize(Frame::new(ControlPacketType::PINGRESP)).unwrap())
-                            .await
-                            .unwrap(),
-                        _ => break,
                     }
                 }
-                // Not enough data has been buffered
-                Err(Error::Incomplete(_)) => println!("Not enough data has been buffered"),
-                // An error was encountered
-                Err(Error::Other(err)) => {
-                    println!("{}", err);
-                    break;
+                let pub_rec_control_packet = PubRecControlPacket {
+                    variable_header: PubRecVariableHeader::from(packet_identifier, reason_code, Vec::new()),
+                };
+                let pub_rec = Frame {
+                    fix_header: FixHeader::new(ControlPacketType::PUBREC, Flags(0, 0, 0, 0)),
+                    control_packet: ControlPacket::PubRec(pub_rec_control_packet),
+                };
+                Ok(self.write_frame(pub_rec).await?)
+            }
+            _ => Err(self.disconnect(DisconnectReasonCode::MalformedPacket).await),
+        }
+    }
+
+    fn apply_grant(&mut self, grant: Grant) {
+        if grant.user_name.is_some() {
+            self.user_name = grant.user_name;
+        }
+        self.permissions = grant.acl;
+        self.credentials_expire_at = grant.expires_at.map(|expires_at| {
+            let remaining = expires_at.duration_since(SystemTime::now()).unwrap_or_default();
+            Instant::now() + remaining
+        });
+    }
+
+    fn identity(&self) -> Identity<'_> {
+        Identity {
+            client_id: &self.id,
+            user_name: self.user_name.as_deref(),
+        }
+    }
+
+    fn may_publish(&self, topic: &str) -> bool {
+        match &self.permissions {
+            Some(permissions) => permissions.allows_publish(&self.identity(), topic),
+            None => self.config.acl.as_ref().is_none_or(|acl| acl.allows_publish(&self.identity(), topic)),
+        }
+    }
+
+    fn may_subscribe(&self, topic_filter: &str) -> bool {
+        match &self.permissions {
+            Some(permissions) => permissions.allows_subscribe(&self.identity(), topic_filter),
+            None => self
+                .config
+                .acl
+                .as_ref()
+                .is_none_or(|acl| acl.allows_subscribe(&self.identity(), topic_filter)),
+        }
+    }
+
+    /// Brings the broker-wide gauges in line with the `in_flight` and `queued` messages of this
+    /// connection.
+    fn report_gauges(&mut self, in_flight: usize, queued: usize) {
+        Stats::adjust(&self.stats.in_flight, in_flight as i64 - self.reported_in_flight as i64);
+        Stats::adjust(&self.stats.queued, queued as i64 - self.reported_queued as i64);
+        self.reported_in_flight = in_flight;
+        self.reported_queued = queued;
+        self.queue_depth.store(queued, Ordering::Relaxed);
+    }
+
+    /// Hands a published message to the broker, returns whether the broker accepted it. Only
+    /// full offline queues with the Reject policy and a failing disk refuse messages, and only
+    /// QoS 1/2 publishers wait to be told.
+    async fn route(&mut self, control_packet: PublishControlPacket, flags: &Flags) -> Result<Result<(), Refused>, CloseReason> {
+        Stats::add(&self.stats.messages_received, 1);
+        let message = Message::from_publish(control_packet, flags);
+        let may_refuse = self.config.offline_queue.policy == QueueFullPolicy::Reject || self.config.persistence.is_some();
+        if !may_refuse || message.qos == Qos::AtMostOnce {
+            self.send_to_broker(BrokerMessage::Publish {
+                client_id: self.id.clone(),
+                message,
+                accepted: None,
+            })
+            .await?;
+            return Ok(Ok(()));
+        }
+        let (accepted, reply) = oneshot::channel();
+        self.send_to_broker(BrokerMessage::Publish {
+            client_id: self.id.clone(),
+            message,
+            accepted: Some(accepted),
+        })
+        .await?;
+        Ok(reply.await.unwrap_or(Err(Refused::NotStored)))
+    }
+
+    async fn handle_client_message(&mut self, client_message: ClientMessage) -> Result<(), CloseReason> {
+        match client_message {
+            ClientMessage::Publish(message, delivery) => self.deliver(message, delivery).await,
+            ClientMessage::Disconnect(reason_code) => Err(self.disconnect(reason_code).await),
+        }
+    }
+
+    /// Sends `message` to the client, or queues it when the client's Receive Maximum worth of
+    /// QoS 1/2 messages is already waiting for acknowledgement. Messages that would exceed the
+    /// client's Maximum Packet Size are dropped for this client.
+    async fn deliver(&mut self, message: Message, delivery: Option<u64>) -> Result<(), CloseReason> {
+        let packet_identifier = match message.qos {
+            Qos::AtMostOnce => None,
+            _ if self.outbound_in_flight.len() >= self.receive_maximum as usize => {
+                self.outbound_queue.push_back((message, delivery));
+                return Ok(());
+            }
+            // Only taken once the message is known to fit.
+            _ => Some(self.free_packet_identifier()),
+        };
+        let mut publish = match message.to_frame(packet_identifier, false).and_then(Frame::serialize) {
+            Ok(publish) => publish,
+            Err(err) => {
+                warn!(error = %err, topic = %message.topic, "dropping a message that cannot be sent");
+                return self.report_acknowledged(delivery).await;
+            }
+        };
+        if publish.len() > self.maximum_packet_size as usize {
+            warn!(
+                size = publish.len(),
+                topic = %message.topic,
+                "dropping a message larger than the client's maximum packet size"
+            );
+            return self.report_acknowledged(delivery).await;
+        }
+        if let Ok(frame) = message.to_frame(packet_identifier, false) {
+            self.log_frame("sent", &frame);
+        }
+        if let Some(packet_identifier) = packet_identifier {
+            self.next_packet_identifier = packet_identifier.checked_add(1).unwrap_or(1);
+            self.outbound_in_flight.insert(packet_identifier, (message, delivery));
+        }
+        self.write_value(&mut publish).await.map_err(Error::from)?;
+        Stats::count_packet(&self.stats.packets_sent, ControlPacketType::PUBLISH);
+        Stats::add(&self.stats.messages_sent, 1);
+        Ok(())
+    }
+
+    async fn deliver_queued(&mut self) -> Result<(), CloseReason> {
+        while self.outbound_in_flight.len() < self.receive_maximum as usize {
+            match self.outbound_queue.pop_front() {
+                Some((message, delivery)) => self.deliver(message, delivery).await?,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Ends the exchange of an outbound message the client acknowledged.
+    async fn acknowledged(&mut self, packet_identifier: u16) -> Result<(), CloseReason> {
+        match self.outbound_in_flight.remove(&packet_identifier) {
+            Some((_, delivery)) => self.report_acknowledged(delivery).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Lets the broker forget a message it keeps until the client has it, or until it is
+    /// dropped.
+    async fn report_acknowledged(&self, delivery: Option<u64>) -> Result<(), CloseReason> {
+        match delivery {
+            Some(delivery) => {
+                self.send_to_broker(BrokerMessage::Acknowledged {
+                    client_id: self.id.clone(),
+                    connection_id: self.connection_id,
+                    delivery,
+                })
+                .await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Takes the QoS 1/2 messages the client has not received yet, oldest first. Messages the
+    /// client already sent PUBREC for are left out, it has them.
+    fn take_pending(&mut self) -> Vec<Message> {
+        let released = std::mem::take(&mut self.outbound_released);
+        let mut in_flight: Vec<(u16, (Message, Option<u64>))> = self
+            .outbound_in_flight
+            .drain()
+            .filter(|(packet_identifier, _)| !released.contains(packet_identifier))
+            .collect();
+        in_flight.sort_by_key(|(packet_identifier, _)| *packet_identifier);
+        in_flight
+            .into_iter()
+            .map(|(_, (message, _))| message)
+            .chain(self.outbound_queue.drain(..).map(|(message, _)| message))
+            .collect()
+    }
+
+    /// The first packet identifier from `next_packet_identifier` on that is not in flight.
+    fn free_packet_identifier(&self) -> u16 {
+        let mut packet_identifier = self.next_packet_identifier;
+        while self.outbound_in_flight.contains_key(&packet_identifier) {
+            packet_identifier = packet_identifier.checked_add(1).unwrap_or(1);
+        }
+        packet_identifier
+    }
+}
+
+fn auth_frame(auth_reason_code: AuthReasonCode, authentication_method: String, authentication_data: Option<Bytes>) -> Frame {
+    let mut auth = Frame::new(ControlPacketType::AUTH);
+    auth.control_packet = ControlPacket::Auth(AuthControlPacket {
+        variable_header: AuthVariableHeader::from(
+            auth_reason_code,
+            vec![
+                Some(Property::AuthenticationMethod(authentication_method)),
+                authentication_data.map(Property::AuthenticationData),
+            ],
+        ),
+    });
+    auth
+}
+
+/// Formats a frame for the logs without the password or authentication data it carries.
+struct Redacted<'a>(&'a Frame);
+
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0.control_packet {
+            ControlPacket::Connect(control_packet) => {
+                let (variable_header, payload) = (&control_packet.variable_header, &control_packet.payload);
+                f.debug_struct("Connect")
+                    .field("protocol_name", &variable_header.protocol_name)
+                    .field("protocol_version", &variable_header.protocol_version)
+                    .field("connect_flag", &variable_header.connect_flag)
+                    .field("keep_alive", &variable_header.keep_alive)
+                    .field("properties", &RedactedProperties(&variable_header.properties))
+                    .field("client_identifier", &payload.client_identifier)
+                    .field("will_properties", &payload.will_properties)
+                    .field("will_topic", &payload.will_topic)
+                    .field("will_payload", &payload.will_payload)
+                    .field("user_name", &payload.user_name)
+                    .field("password", &payload.password.as_ref().map(|_| "<redacted>"))
+                    .finish()
+            }
+            ControlPacket::Auth(control_packet) => f
+                .debug_struct("Auth")
+                .field("auth_reason_code", &control_packet.variable_header.auth_reason_code)
+                .field("properties", &RedactedProperties(&control_packet.variable_header.get_properties()))
+                .finish(),
+            _ => self.0.fmt(f),
+        }
+    }
+}
+
+struct RedactedProperties<'a>(&'a [Option<Property>]);
+
+impl fmt::Debug for RedactedProperties<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|property| match property {
+                Some(Property::AuthenticationData(_)) => &"AuthenticationData(<redacted>)" as &dyn fmt::Debug,
+                property => property as &dyn fmt::Debug,
+            }))
+            .finish()
+    }
+}
+
+/// Generates an identifier for a client that connected without one. It only uses the
+/// characters every server must accept.
+fn assign_client_id() -> String {
+    rand::rng().sample_iter(&Alphanumeric).take(23).map(char::from).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        acl::AclFile,
+        auth::{jwt, scram::ScramClient, InMemoryAuthenticator, JwtAuthenticator, ScramSha256},
+        ban::{Ban, BanList},
+        config::{
+            AuthFailureBan, ClientPattern, ConnectionLimits, FsyncPolicy, OfflineQueueConfig, PersistenceConfig, RateLimit, RateLimits,
+            SlowConsumerPolicy,
+        },
+        logging::{self, CapturedLogs, LogConfig, LogFormat},
+        packet::SubscriptionOptions,
+        redirect::{Redirect, RedirectRules, Redirects},
+        server::MqttServer,
+        BrokerError, BrokerHandle,
+    };
+    use std::time::Duration;
+    use tokio::{net::TcpListener, time::timeout};
+
+    struct TestConnection {
+        stream: TcpStream,
+        buffer: BytesMut,
+    }
+
+    impl TestConnection {
+        async fn connect(addr: std::net::SocketAddr, client_identifier: &str, properties: Vec<Option<Property>>) -> (TestConnection, Frame) {
+            TestConnection::connect_with(addr, client_identifier, |control_packet| {
+                control_packet.variable_header.properties = properties;
+            })
+            .await
+        }
+
+        async fn connect_with<F: FnOnce(&mut ConnectControlPacket)>(
+            addr: std::net::SocketAddr,
+            client_identifier: &str,
+            customize: F,
+        ) -> (TestConnection, Frame) {
+            let mut connection = TestConnection {
+                stream: TcpStream::connect(addr).await.unwrap(),
+                buffer: BytesMut::new(),
+            };
+            let mut connect = Frame::new(ControlPacketType::CONNECT);
+            if let ControlPacket::Connect(control_packet) = &mut connect.control_packet {
+                control_packet.variable_header.protocol_name = String::from("MQTT");
+                control_packet.variable_header.protocol_version = 5;
+                control_packet.payload.client_identifier = String::from(client_identifier);
+                customize(control_packet);
+            }
+            connection.send(connect).await;
+            let conn_ack = connection.recv().await.unwrap();
+            (connection, conn_ack)
+        }
+
+        async fn send(&mut self, frame: Frame) {
+            self.stream.write_all(&Frame::serialize(frame).unwrap()).await.unwrap();
+        }
+
+        async fn recv(&mut self) -> Option<Frame> {
+            loop {
+                let mut buf = Cursor::new(&self.buffer[..]);
+                if let Ok(frame) = Frame::deserialize(&mut buf) {
+                    let len = buf.position() as usize;
+                    self.buffer.advance(len);
+                    return Some(frame);
+                }
+                match timeout(Duration::from_millis(300), self.stream.read_buf(&mut self.buffer)).await {
+                    Ok(Ok(n)) if n > 0 => (),
+                    _ => return None,
+                }
+            }
+        }
+
+        async fn subscribe(&mut self, topic_filter: &str, maximum_qos: Qos) -> Vec<SubAckReasonCode> {
+            let subscribe = Frame {
+                fix_header: FixHeader::new(ControlPacketType::SUBSCRIBE, Flags(0, 1, 0, 0)),
+                control_packet: ControlPacket::Subscribe(SubscribeControlPacket {
+                    variable_header: SubscribeVariableHeader::from(
+                        1,
+                        vec![SubscribePayload {
+                            topic_filter: String::from(topic_filter),
+                            subscription_options: SubscriptionOptions {
+                                maximum_qos,
+                                ..Default::default()
+                            },
+                        }],
+                        Vec::new(),
+                    ),
+                }),
+            };
+            self.send(subscribe).await;
+            match self.recv().await.unwrap().control_packet {
+                ControlPacket::SubAck(control_packet) => control_packet.variable_header.sub_ack_payload.sub_ack_reason_codes,
+                other => panic!("expected SUBACK, got {:?}", other),
+            }
+        }
+
+        async fn publish(&mut self, topic: &str, qos: Qos, packet_identifier: Option<u16>) {
+            self.publish_payload(topic, qos, packet_identifier, Bytes::from_static(b"payload")).await;
+        }
+
+        async fn publish_payload(&mut self, topic: &str, qos: Qos, packet_identifier: Option<u16>, payload: Bytes) {
+            let message = Message {
+                topic: String::from(topic),
+                payload,
+                qos,
+                retain: false,
+                properties: Vec::new(),
+            };
+            self.send(message.to_frame(packet_identifier, false).unwrap()).await;
+        }
+
+        /// Receives the next packet, which must be a PUBLISH.
+        async fn expect_publish(&mut self) -> PublishControlPacket {
+            match self.recv().await.map(|frame| frame.control_packet) {
+                Some(ControlPacket::Publish(control_packet)) => control_packet,
+                other => panic!("expected PUBLISH, got {:?}", other),
+            }
+        }
+
+        /// Receives the next packet, which must be a DISCONNECT with `reason_code`.
+        async fn expect_disconnect(&mut self, reason_code: DisconnectReasonCode) -> DisconnectVariableHeader {
+            match self.recv().await.map(|frame| frame.control_packet) {
+                Some(ControlPacket::Disconnect(control_packet)) => {
+                    assert_eq!(control_packet.variable_header.disconnect_reason_code, reason_code);
+                    control_packet.variable_header
+                }
+                other => panic!("expected DISCONNECT, got {:?}", other),
+            }
+        }
+    }
+
+    async fn start_test_broker(config: BrokerConfig) -> std::net::SocketAddr {
+        start_test_broker_handle(config).await.local_addr()
+    }
+
+    async fn start_test_broker_handle(config: BrokerConfig) -> BrokerHandle {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        MqttServer::serve(listener, config).await.unwrap()
+    }
+
+    fn publish_packet_identifier(frame: Option<Frame>) -> u16 {
+        match frame.map(|frame| frame.control_packet) {
+            Some(ControlPacket::Publish(control_packet)) => control_packet.variable_header.packet_identifier.unwrap(),
+            other => panic!("expected PUBLISH, got {:?}", other),
+        }
+    }
+
+    fn conn_ack_variable_header(frame: Frame) -> ConnAckVariableHeader {
+        match frame.control_packet {
+            ControlPacket::ConnAck(control_packet) => control_packet.variable_header,
+            other => panic!("expected CONNACK, got {:?}", other),
+        }
+    }
+
+    /// Fills in CONNECT for the user `alice` with `password`.
+    fn login(password: &'static str) -> impl FnOnce(&mut ConnectControlPacket) {
+        move |control_packet| {
+            control_packet.variable_header.connect_flag.user_name_flag = true;
+            control_packet.variable_header.connect_flag.password_flag = true;
+            control_packet.payload.user_name = Some(String::from("alice"));
+            control_packet.payload.password = Some(Bytes::from_static(password.as_bytes()));
+        }
+    }
+
+    fn pub_ack(packet_identifier: u16) -> Frame {
+        Frame {
+            fix_header: FixHeader::new(ControlPacketType::PUBACK, Flags(0, 0, 0, 0)),
+            control_packet: ControlPacket::PubAck(PubAckControlPacket {
+                variable_header: PubAckVariableHeader::from(packet_identifier, PubAckReasonCode::Success, Vec::new()),
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn outbound_publishes_respect_client_receive_maximum() {
+        let addr = start_test_broker(BrokerConfig::default()).await;
+        let (mut subscriber, _) = TestConnection::connect(addr, "subscriber", vec![Some(Property::ReceiveMaximum(2))]).await;
+        subscriber.subscribe("flow/control", Qos::AtleastOnce).await;
+        let (mut publisher, _) = TestConnection::connect(addr, "publisher", Vec::new()).await;
+        for packet_identifier in 1..=3 {
+            publisher.publish("flow/control", Qos::AtleastOnce, Some(packet_identifier)).await;
+        }
+
+        let first = publish_packet_identifier(subscriber.recv().await);
+        publish_packet_identifier(subscriber.recv().await);
+        assert!(subscriber.recv().await.is_none(), "third message must wait for a free slot");
+
+        subscriber.send(pub_ack(first)).await;
+        publish_packet_identifier(subscriber.recv().await);
+    }
+
+    #[tokio::test]
+    async fn publishers_over_their_rate_limit_are_refused_then_disconnected() {
+        let config = BrokerConfig {
+            rate_limits: RateLimits {
+                default: Some(RateLimit {
+                    messages_per_second: Some(1),
+                    bytes_per_second: None,
+                    burst_seconds: 2,
+                }),
+                overrides: vec![(ClientPattern::ClientId(String::from("trusted-*")), None)],
+                disconnect_after: Duration::from_millis(300),
+            },
+            ..Default::default()
+        };
+        let addr = start_test_broker(config).await;
+        for (client_id, last_reason_code) in [
+            ("device", PubAckReasonCode::QuotaExceeded),
+            ("trusted-gateway", PubAckReasonCode::Success),
+        ] {
+            let (mut publisher, _) = TestConnection::connect(addr, client_id, Vec::new()).await;
+            let mut reason_codes = Vec::new();
+            for packet_identifier in 1..=3 {
+                publisher.publish("flood", Qos::AtleastOnce, Some(packet_identifier)).await;
+                match publisher.recv().await.map(|frame| frame.control_packet) {
+                    Some(ControlPacket::PubAck(control_packet)) => reason_codes.push(control_packet.variable_header.reason_code),
+                    other => panic!("expected PUBACK, got {:?}", other),
+                }
+            }
+            assert_eq!(reason_codes, [PubAckReasonCode::Success, PubAckReasonCode::Success, last_reason_code]);
+        }
+
+        // A flood that goes on ends the connection.
+        let (mut device, _) = TestConnection::connect(addr, "device-2", Vec::new()).await;
+        for _ in 0..20 {
+            for _ in 0..10 {
+                device.publish("flood", Qos::AtMostOnce, None).await;
+            }
+            if let Some(frame) = device.recv().await {
+                match frame.control_packet {
+                    ControlPacket::Disconnect(control_packet) => {
+                        assert_eq!(
+                            control_packet.variable_header.disconnect_reason_code,
+                            DisconnectReasonCode::MessageRateTooHigh
+                        );
+                        return;
+                    }
+                    other => panic!("expected DISCONNECT, got {:?}", other),
+                }
+            }
+        }
+        panic!("the flood went on");
+    }
+
+    #[tokio::test]
+    async fn slow_consumers_are_paused_or_disconnected() {
+        for policy in [SlowConsumerPolicy::Pause, SlowConsumerPolicy::Disconnect] {
+            let config = BrokerConfig {
+                sys_interval: Some(Duration::from_millis(50)),
+                client_channel_capacity: 2,
+                slow_consumer_policy: policy,
+                ..Default::default()
+            };
+            let broker = start_test_broker_handle(config).await;
+            let addr = broker.local_addr();
+            // With a Receive Maximum of 1 and no PUBACK, the client takes one message and the
+            // others back up.
+            let (mut subscriber, _) = TestConnection::connect(addr, "slow", vec![Some(Property::ReceiveMaximum(1))]).await;
+            subscriber.subscribe("fast/feed", Qos::AtleastOnce).await;
+            let (mut publisher, _) = TestConnection::connect(addr, "publisher", Vec::new()).await;
+            for packet_identifier in 1..=10 {
+                let payload = Bytes::from(packet_identifier.to_string());
+                publisher
+                    .publish_payload("fast/feed", Qos::AtleastOnce, Some(packet_identifier), payload)
+                    .await;
+            }
+
+            if policy == SlowConsumerPolicy::Disconnect {
+                publish_packet_identifier(subscriber.recv().await);
+                subscriber.expect_disconnect(DisconnectReasonCode::QuotaExceeded).await;
+                continue;
+            }
+            let mut slow = broker.subscribe("$SYS/broker/clients/slow").await.unwrap();
+            while slow.recv().await.unwrap().payload != "1" {}
+            // Once the client acknowledges its messages, the held back ones follow in order.
+            let mut payloads = Vec::new();
+            for _ in 1..=10 {
+                let publish = subscriber.expect_publish().await;
+                payloads.push(publish.payload.data);
+                subscriber.send(pub_ack(publish.variable_header.packet_identifier.unwrap())).await;
+            }
+            assert_eq!(payloads, (1..=10).map(|payload| payload.to_string()).collect::<Vec<_>>());
+            assert!(subscriber.recv().await.is_none());
+            while slow.recv().await.unwrap().payload != "0" {}
+        }
+    }
+
+    #[tokio::test]
+    async fn client_exceeding_receive_maximum_is_disconnected() {
+        let config = BrokerConfig {
+            receive_maximum: 1,
+            ..Default::default()
+        };
+        let addr = start_test_broker(config).await;
+        let (mut publisher, conn_ack) = TestConnection::connect(addr, "publisher", Vec::new()).await;
+        assert!(conn_ack_variable_header(conn_ack)
+            .properties
+            .iter()
+            .any(|property| matches!(property, Some(Property::ReceiveMaximum(1)))));
+
+        publisher.publish("flow/control", Qos::ExactlyOnce, Some(1)).await;
+        assert!(matches!(publisher.recv().await.unwrap().control_packet, ControlPacket::PubRec(_)));
+        publisher.publish("flow/control", Qos::ExactlyOnce, Some(2)).await;
+        publisher.expect_disconnect(DisconnectReasonCode::ReceiveMaximumExceeded).await;
+    }
+
+    #[test]
+    fn logged_frames_never_show_credentials() {
+        let mut connect = Frame::new(ControlPacketType::CONNECT);
+        if let ControlPacket::Connect(control_packet) = &mut connect.control_packet {
+            control_packet.variable_header.properties = vec![
+                Some(Property::AuthenticationMethod(String::from("SCRAM-SHA-256"))),
+                Some(Property::AuthenticationData(Bytes::from_static(b"n,,n=alice,r=secretnonce"))),
+            ];
+            control_packet.payload.user_name = Some(String::from("alice"));
+            control_packet.payload.password = Some(Bytes::from_static(b"secretpassword"));
+        }
+        let auth = auth_frame(
+            AuthReasonCode::ContinueAuthentication,
+            String::from("SCRAM-SHA-256"),
+            Some(Bytes::from_static(b"c=biws,r=secretnonce,p=proof")),
+        );
+        for frame in [connect, auth] {
+            let logged = format!("{:?}", Redacted(&frame));
+            assert!(logged.contains("SCRAM-SHA-256") && logged.contains("<redacted>"), "{}", logged);
+            assert!(!logged.contains("secret"), "{}", logged);
+        }
+    }
+
+    #[tokio::test]
+    async fn publishing_to_a_wildcard_topic_disconnects() {
+        let addr = start_test_broker(Default::default()).await;
+        let (mut publisher, _) = TestConnection::connect(addr, "publisher", Vec::new()).await;
+        publisher.publish("sensors/+/temperature", Qos::AtleastOnce, Some(1)).await;
+        publisher.expect_disconnect(DisconnectReasonCode::TopicNameInvalid).await;
+    }
+
+    #[tokio::test]
+    async fn oversized_inbound_packet_is_refused() {
+        let config = BrokerConfig {
+            maximum_packet_size: 64,
+            ..Default::default()
+        };
+        let addr = start_test_broker(config).await;
+        let (mut publisher, conn_ack) = TestConnection::connect(addr, "publisher", Vec::new()).await;
+        assert!(conn_ack_variable_header(conn_ack)
+            .properties
+            .iter()
+            .any(|property| matches!(property, Some(Property::MaximumPacketSize(64)))));
+
+        // Only the fixed header needs to arrive for the broker to refuse the packet.
+        publisher.stream.write_all(&[0x30, 0xe8, 0x07]).await.unwrap();
+        publisher.expect_disconnect(DisconnectReasonCode::PacketTooLarge).await;
+    }
+
+    #[tokio::test]
+    async fn outbound_message_above_client_maximum_is_dropped() {
+        let addr = start_test_broker(BrokerConfig::default()).await;
+        let (mut subscriber, _) = TestConnection::connect(addr, "subscriber", vec![Some(Property::MaximumPacketSize(64))]).await;
+        subscriber.subscribe("packet/size", Qos::AtleastOnce).await;
+        let (mut publisher, _) = TestConnection::connect(addr, "publisher", Vec::new()).await;
+        publisher
+            .publish_payload("packet/size", Qos::AtleastOnce, Some(1), Bytes::from(vec![0; 100]))
+            .await;
+        publisher.publish("packet/size", Qos::AtleastOnce, Some(2)).await;
+
+        let publish = subscriber.expect_publish().await;
+        assert_eq!(publish.payload.data, Bytes::from_static(b"payload"));
+        // The dropped message did not use up a packet identifier.
+        assert_eq!(publish.variable_header.packet_identifier, Some(1));
+        assert!(subscriber.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn idle_client_times_out_and_will_is_published() {
+        let config = BrokerConfig {
+            server_keep_alive: Some(2),
+            ..Default::default()
+        };
+        let addr = start_test_broker(config).await;
+        // Asking for no Keep Alive at all gets the server cap imposed.
+        let (mut subscriber, conn_ack) = TestConnection::connect(addr, "subscriber", Vec::new()).await;
+        assert!(conn_ack_variable_header(conn_ack)
+            .properties
+            .iter()
+            .any(|property| matches!(property, Some(Property::ServerKeepAlive(2)))));
+        subscriber.subscribe("will/topic", Qos::AtMostOnce).await;
+        let (mut idle, _) = TestConnection::connect_with(addr, "idle", |control_packet| {
+            control_packet.variable_header.keep_alive = 1;
+            control_packet.variable_header.connect_flag.will_flag = true;
+            control_packet.payload.will_topic = Some(String::from("will/topic"));
+            control_packet.payload.will_payload = Some(Bytes::from_static(b"gone"));
+        })
+        .await;
+
+        time::sleep(Duration::from_millis(1600)).await;
+        idle.expect_disconnect(DisconnectReasonCode::KeepAliveTimeout).await;
+        assert_eq!(subscriber.expect_publish().await.payload.data, Bytes::from_static(b"gone"));
+    }
+
+    #[tokio::test]
+    async fn connections_without_connect_are_closed() {
+        let config = BrokerConfig {
+            connect_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let addr = start_test_broker(config).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let read = timeout(Duration::from_secs(2), stream.read(&mut [0; 1])).await;
+        assert_eq!(read.expect("the connection is closed").unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn refused_connections_without_connect_are_closed_early() {
+        let config = BrokerConfig {
+            bans: Arc::new(BanList::new().with_ban(Ban::Address("127.0.0.1".parse().unwrap()), None)),
+            ..Default::default()
+        };
+        let addr = start_test_broker(config).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let read = timeout(REFUSED_CONNECT_TIMEOUT * 2, stream.read(&mut [0; 1])).await;
+        assert_eq!(read.expect("the connection is closed").unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn malformed_packets_disconnect_the_client_and_publish_its_will() {
+        let addr = start_test_broker(BrokerConfig::default()).await;
+        let (mut subscriber, _) = TestConnection::connect(addr, "subscriber", Vec::new()).await;
+        subscriber.subscribe("will/#", Qos::AtMostOnce).await;
+        let packets: [&[u8]; 3] = [
+            // The topic name runs past the end of the packet.
+            &[0x30, 0x02, 0x00, 0x05],
+            // Session Expiry Interval has no place in PUBLISH.
+            &[0x30, 0x09, 0x00, 0x01, b't', 0x05, 0x11, 0x00, 0x00, 0x00, 0x05],
+            // QoS 3 in the subscription options.
+            &[0x82, 0x07, 0x00, 0x01, 0x00, 0x00, 0x01, b't', 0x03],
+        ];
+        for (index, packet) in packets.iter().enumerate() {
+            let will_topic = format!("will/{}", index);
+            let (mut client, _) = TestConnection::connect_with(addr, &format!("broken-{}", index), |control_packet| {
+                control_packet.variable_header.connect_flag.will_flag = true;
+                control_packet.payload.will_topic = Some(will_topic.clone());
+                control_packet.payload.will_payload = Some(Bytes::from_static(b"gone"));
+            })
+            .await;
+            client.stream.write_all(packet).await.unwrap();
+            client.expect_disconnect(DisconnectReasonCode::MalformedPacket).await;
+            let (topic, payload, _) = published(subscriber.recv().await);
+            assert_eq!((topic, payload.as_ref()), (will_topic, &b"gone"[..]));
+        }
+    }
+
+    #[tokio::test]
+    async fn wills_with_properties_a_message_cannot_carry_are_refused() {
+        let addr = start_test_broker(BrokerConfig::default()).await;
+        let (_, conn_ack) = TestConnection::connect_with(addr, "expiring-will", |control_packet| {
+            control_packet.variable_header.connect_flag.will_flag = true;
+            control_packet.payload.will_topic = Some(String::from("will/topic"));
+            control_packet.payload.will_payload = Some(Bytes::from_static(b"gone"));
+            control_packet.payload.will_properties = vec![Some(Property::SessionExpiryInterval(60))];
+        })
+        .await;
+        assert_eq!(conn_ack_variable_header(conn_ack).reason_code, ConnAckReasonCode::ProtocolError);
+
+        let (_, conn_ack) = TestConnection::connect_with(addr, "delayed-will", |control_packet| {
+            control_packet.variable_header.connect_flag.will_flag = true;
+            control_packet.payload.will_topic = Some(String::from("will/topic"));
+            control_packet.payload.will_payload = Some(Bytes::from_static(b"gone"));
+            control_packet.payload.will_properties = vec![
+                Some(Property::WillDelayInterval(0)),
+                Some(Property::ContentType(String::from("text/plain"))),
+            ];
+        })
+        .await;
+        assert_eq!(conn_ack_variable_header(conn_ack).reason_code, ConnAckReasonCode::Success);
+    }
+
+    #[tokio::test]
+    async fn empty_client_id_gets_an_assigned_one() {
+        let addr = start_test_broker(BrokerConfig::default()).await;
+        let mut assigned = Vec::new();
+        for _ in 0..2 {
+            let (_, conn_ack) = TestConnection::connect(addr, "", Vec::new()).await;
+            let variable_header = conn_ack_variable_header(conn_ack);
+            assert_eq!(variable_header.reason_code, ConnAckReasonCode::Success);
+            assigned.extend(variable_header.properties.into_iter().flatten().filter_map(|property| match property {
+                Property::AssignedClientIdentifier(client_id) => Some(client_id),
+                _ => None,
+            }));
+        }
+        assert_eq!(assigned.len(), 2);
+        assert_ne!(assigned[0], assigned[1]);
+        assert!(assigned.iter().all(|client_id| client_id.len() == 23));
+
+        // A client that picked its own identifier is not assigned one.
+        let (_, conn_ack) = TestConnection::connect(addr, "named", Vec::new()).await;
+        assert!(conn_ack_variable_header(conn_ack)
+            .properties
+            .iter()
+            .all(|property| !matches!(property, Some(Property::AssignedClientIdentifier(_)))));
+    }
+
+    #[tokio::test]
+    async fn invalid_client_id_is_refused() {
+        let config = BrokerConfig {
+            client_id_max_length: 8,
+            client_id_strict_charset: true,
+            ..Default::default()
+        };
+        let addr = start_test_broker(config).await;
+        for client_id in ["much-too-long", "a/b"] {
+            let (mut connection, conn_ack) = TestConnection::connect(addr, client_id, Vec::new()).await;
+            assert_eq!(
+                conn_ack_variable_header(conn_ack).reason_code,
+                ConnAckReasonCode::ClientIdentifierNotValid
+            );
+            assert!(connection.recv().await.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn second_connection_takes_the_session_over() {
+        let addr = start_test_broker(BrokerConfig::default()).await;
+        let (mut first, conn_ack) = TestConnection::connect(addr, "owner", Vec::new()).await;
+        assert!(!conn_ack_variable_header(conn_ack).conn_ack_flag.session_present_flag);
+        first.subscribe("take/over", Qos::AtleastOnce).await;
+
+        let (mut second, conn_ack) = TestConnection::connect(addr, "owner", Vec::new()).await;
+        assert!(conn_ack_variable_header(conn_ack).conn_ack_flag.session_present_flag);
+        first.expect_disconnect(DisconnectReasonCode::SessionTakenOver).await;
+
+        // The subscription moved with the session.
+        let (mut publisher, _) = TestConnection::connect(addr, "publisher", Vec::new()).await;
+        publisher.publish("take/over", Qos::AtleastOnce, Some(1)).await;
+        publish_packet_identifier(second.recv().await);
+    }
+
+    #[tokio::test]
+    async fn clean_start_discards_the_session_it_takes_over() {
+        let addr = start_test_broker(BrokerConfig::default()).await;
+        let (mut first, _) = TestConnection::connect(addr, "owner", Vec::new()).await;
+        first.subscribe("take/over", Qos::AtleastOnce).await;
+
+        let (mut second, conn_ack) = TestConnection::connect_with(addr, "owner", |control_packet| {
+            control_packet.variable_header.connect_flag.clean_start = true;
+        })
+        .await;
+        assert!(!conn_ack_variable_header(conn_ack).conn_ack_flag.session_present_flag);
+        assert!(matches!(first.recv().await.unwrap().control_packet, ControlPacket::Disconnect(_)));
+
+        let (mut publisher, _) = TestConnection::connect(addr, "publisher", Vec::new()).await;
+        publisher.publish("take/over", Qos::AtleastOnce, Some(1)).await;
+        assert!(second.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn session_with_expiry_keeps_messages_until_reconnect() {
+        let addr = start_test_broker(BrokerConfig::default()).await;
+        let (mut subscriber, _) = TestConnection::connect(addr, "away", vec![Some(Property::SessionExpiryInterval(60))]).await;
+        subscriber.subscribe("while/away", Qos::AtleastOnce).await;
+        subscriber.send(Frame::new(ControlPacketType::DISCONNECT)).await;
+        assert!(subscriber.recv().await.is_none());
+
+        let (mut publisher, _) = TestConnection::connect(addr, "publisher", Vec::new()).await;
+        publisher.publish("while/away", Qos::AtleastOnce, Some(1)).await;
+        publisher.publish("while/away", Qos::AtMostOnce, None).await;
+        assert!(matches!(publisher.recv().await.unwrap().control_packet, ControlPacket::PubAck(_)));
+
+        let (mut subscriber, conn_ack) = TestConnection::connect(addr, "away", Vec::new()).await;
+        assert!(conn_ack_variable_header(conn_ack).conn_ack_flag.session_present_flag);
+        publish_packet_identifier(subscriber.recv().await);
+        assert!(subscriber.recv().await.is_none(), "QoS 0 messages are not queued");
+    }
+
+    #[tokio::test]
+    async fn full_offline_queues_drop_or_reject_messages() {
+        for policy in [QueueFullPolicy::DropOldest, QueueFullPolicy::DropNewest, QueueFullPolicy::Reject] {
+            let config = BrokerConfig {
+                sys_interval: Some(Duration::from_millis(50)),
+                offline_queue: OfflineQueueConfig {
+                    max_messages: 2,
+                    policy,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let broker = start_test_broker_handle(config).await;
+            let addr = broker.local_addr();
+            let (mut subscriber, _) = TestConnection::connect(addr, "away", vec![Some(Property::SessionExpiryInterval(60))]).await;
+            subscriber.subscribe("while/away", Qos::AtleastOnce).await;
+            subscriber.send(Frame::new(ControlPacketType::DISCONNECT)).await;
+            assert!(subscriber.recv().await.is_none());
+
+            let (mut publisher, _) = TestConnection::connect(addr, "publisher", Vec::new()).await;
+            let mut reason_codes = Vec::new();
+            for (packet_identifier, payload) in [(1, "1"), (2, "2"), (3, "3")] {
+                publisher
+                    .publish_payload("while/away", Qos::AtleastOnce, Some(packet_identifier), Bytes::from(payload))
+                    .await;
+                match publisher.recv().await.map(|frame| frame.control_packet) {
+                    Some(ControlPacket::PubAck(control_packet)) => reason_codes.push(control_packet.variable_header.reason_code),
+                    other => panic!("expected PUBACK, got {:?}", other),
                 }
             }
+            let mut dropped = broker.subscribe("$SYS/broker/messages/dropped").await.unwrap();
+            while dropped.recv().await.unwrap().payload != "1" {}
+            if policy == QueueFullPolicy::Reject {
+                // In-process publishers are refused the same way.
+                assert_eq!(
+                    broker.publish("while/away", "4", Qos::AtleastOnce, false, Vec::new()).await,
+                    Err(BrokerError::QueuesFull)
+                );
+            }
+
+            let (mut subscriber, _) = TestConnection::connect(addr, "away", vec![Some(Property::SessionExpiryInterval(60))]).await;
+            let mut payloads = Vec::new();
+            for _ in 0..2 {
+                payloads.push(published(subscriber.recv().await).1);
+            }
+            assert!(subscriber.recv().await.is_none());
+            let (expected_payloads, last_reason_code) = match policy {
+                QueueFullPolicy::DropOldest => (["2", "3"], PubAckReasonCode::Success),
+                QueueFullPolicy::DropNewest => (["1", "2"], PubAckReasonCode::Success),
+                QueueFullPolicy::Reject => (["1", "2"], PubAckReasonCode::QuotaExceeded),
+            };
+            assert_eq!(payloads, expected_payloads, "{:?}", policy);
+            assert_eq!(reason_codes[2], last_reason_code, "{:?}", policy);
+            broker.shutdown().await;
+        }
+    }
+
+    fn persistent_config(directory: &std::path::Path) -> BrokerConfig {
+        BrokerConfig {
+            sys_interval: None,
+            persistence: Some(PersistenceConfig {
+                fsync: FsyncPolicy::Always,
+                // Small enough for the log to go through a few generations.
+                snapshot_after: 3,
+                ..PersistenceConfig::new(directory)
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn sessions_and_retained_messages_survive_a_crash_and_a_restart() {
+        let directory = std::env::temp_dir().join(format!("mt-mqtt-persistence-{}", std::process::id()));
+        let crashed = std::env::temp_dir().join(format!("mt-mqtt-persistence-crashed-{}", std::process::id()));
+        for directory in [&directory, &crashed] {
+            let _ = std::fs::remove_dir_all(directory);
+        }
+        let broker = start_test_broker_handle(persistent_config(&directory)).await;
+        let (mut subscriber, _) = TestConnection::connect(broker.local_addr(), "away", vec![Some(Property::SessionExpiryInterval(60))]).await;
+        subscriber.subscribe("while/away", Qos::AtleastOnce).await;
+        subscriber.send(Frame::new(ControlPacketType::DISCONNECT)).await;
+        assert!(subscriber.recv().await.is_none());
+        let (mut publisher, _) = TestConnection::connect(broker.local_addr(), "publisher", Vec::new()).await;
+        for packet_identifier in 1..=3 {
+            publisher.publish("while/away", Qos::AtleastOnce, Some(packet_identifier)).await;
+            assert!(matches!(publisher.recv().await.unwrap().control_packet, ControlPacket::PubAck(_)));
+        }
+        publisher.send(retained("status/door", b"open")).await;
+        // PUBACK comes once every record made before is written, a copy of the files then is
+        // what a crash would leave.
+        publisher.publish("barrier", Qos::AtleastOnce, Some(4)).await;
+        assert!(matches!(publisher.recv().await.unwrap().control_packet, ControlPacket::PubAck(_)));
+        std::fs::create_dir_all(&crashed).unwrap();
+        for entry in std::fs::read_dir(&directory).unwrap() {
+            let path = entry.unwrap().path();
+            std::fs::copy(&path, crashed.join(path.file_name().unwrap())).unwrap();
+        }
+        broker.shutdown().await;
+
+        for directory in [&crashed, &directory] {
+            let broker = start_test_broker_handle(persistent_config(directory)).await;
+            let (mut subscriber, conn_ack) =
+                TestConnection::connect(broker.local_addr(), "away", vec![Some(Property::SessionExpiryInterval(60))]).await;
+            assert!(conn_ack_variable_header(conn_ack).conn_ack_flag.session_present_flag);
+            for _ in 1..=3 {
+                publish_packet_identifier(subscriber.recv().await);
+            }
+            subscriber.subscribe("status/+", Qos::AtleastOnce).await;
+            assert_eq!(
+                published(subscriber.recv().await),
+                (String::from("status/door"), Bytes::from_static(b"open"), true)
+            );
+            broker.shutdown().await;
+        }
+
+        // The messages sent and not acknowledged when the broker crashed are sent again.
+        let broker = start_test_broker_handle(persistent_config(&crashed)).await;
+        let (mut subscriber, _) = TestConnection::connect(broker.local_addr(), "away", vec![Some(Property::SessionExpiryInterval(60))]).await;
+        let first = publish_packet_identifier(subscriber.recv().await);
+        for _ in 2..=3 {
+            publish_packet_identifier(subscriber.recv().await);
+        }
+        subscriber.send(pub_ack(first)).await;
+        subscriber.publish("barrier", Qos::AtleastOnce, Some(1)).await;
+        assert!(matches!(subscriber.recv().await.unwrap().control_packet, ControlPacket::PubAck(_)));
+        let crashed_again = std::env::temp_dir().join(format!("mt-mqtt-persistence-crashed-again-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&crashed_again);
+        std::fs::create_dir_all(&crashed_again).unwrap();
+        for entry in std::fs::read_dir(&crashed).unwrap() {
+            let path = entry.unwrap().path();
+            std::fs::copy(&path, crashed_again.join(path.file_name().unwrap())).unwrap();
+        }
+        broker.shutdown().await;
+        let broker = start_test_broker_handle(persistent_config(&crashed_again)).await;
+        let (mut subscriber, _) = TestConnection::connect(broker.local_addr(), "away", vec![Some(Property::SessionExpiryInterval(60))]).await;
+        for _ in 2..=3 {
+            publish_packet_identifier(subscriber.recv().await);
+        }
+        assert!(subscriber.recv().await.is_none(), "the acknowledged message is gone");
+        broker.shutdown().await;
+        std::fs::remove_dir_all(&crashed_again).unwrap();
+
+        // The messages left unacknowledged were put back in the session when the broker stopped.
+        let broker = start_test_broker_handle(persistent_config(&directory)).await;
+        let (mut subscriber, _) = TestConnection::connect(broker.local_addr(), "away", vec![Some(Property::SessionExpiryInterval(60))]).await;
+        for _ in 1..=3 {
+            publish_packet_identifier(subscriber.recv().await);
+        }
+        broker.shutdown().await;
+        for directory in [&directory, &crashed] {
+            std::fs::remove_dir_all(directory).unwrap();
+        }
+    }
+
+    fn retained(topic: &str, payload: &'static [u8]) -> Frame {
+        let message = Message {
+            topic: String::from(topic),
+            payload: Bytes::from_static(payload),
+            qos: Qos::AtMostOnce,
+            retain: true,
+            properties: Vec::new(),
+        };
+        message.to_frame(None, false).unwrap()
+    }
+
+    /// Topic, payload and retain flag of a PUBLISH frame.
+    fn published(frame: Option<Frame>) -> (String, Bytes, bool) {
+        match frame {
+            Some(Frame {
+                fix_header,
+                control_packet: ControlPacket::Publish(control_packet),
+            }) => (
+                control_packet.variable_header.topic_name,
+                control_packet.payload.data,
+                fix_header.flags.0 == 1,
+            ),
+            other => panic!("expected PUBLISH, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn retained_messages_reach_later_subscribers() {
+        let addr = start_test_broker(BrokerConfig::default()).await;
+        let (mut publisher, _) = TestConnection::connect(addr, "publisher", Vec::new()).await;
+        publisher.send(retained("status/door", b"open")).await;
+        publisher.send(retained("status/window", b"closed")).await;
+        publisher.send(retained("status/window", b"")).await;
+        // Once the PINGRESP is back, the messages are on their way to the broker.
+        publisher.send(Frame::new(ControlPacketType::PINGREQ)).await;
+        publisher.recv().await.unwrap();
+
+        let (mut subscriber, _) = TestConnection::connect(addr, "subscriber", Vec::new()).await;
+        subscriber.subscribe("status/+", Qos::AtleastOnce).await;
+        assert_eq!(
+            published(subscriber.recv().await),
+            (String::from("status/door"), Bytes::from_static(b"open"), true)
+        );
+        assert!(subscriber.recv().await.is_none(), "an empty retained message clears the topic");
+    }
+
+    #[tokio::test]
+    async fn sys_topics_report_broker_statistics() {
+        let config = BrokerConfig {
+            sys_interval: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let addr = start_test_broker(config).await;
+        let (mut monitor, _) = TestConnection::connect(addr, "monitor", Vec::new()).await;
+        monitor.subscribe("#", Qos::AtMostOnce).await;
+        monitor.subscribe("$SYS/broker/clients/connected", Qos::AtMostOnce).await;
+        // The retained value may predate the connection, the next update counts it.
+        loop {
+            let (topic, payload, _) = published(monitor.recv().await);
+            assert_eq!(topic, "$SYS/broker/clients/connected", "wildcards do not match $SYS topics");
+            if payload == "1" {
+                break;
+            }
+        }
+
+        monitor.publish("$SYS/broker/uptime", Qos::AtleastOnce, Some(1)).await;
+        loop {
+            if let ControlPacket::PubAck(control_packet) = monitor.recv().await.unwrap().control_packet {
+                assert_eq!(control_packet.variable_header.reason_code, PubAckReasonCode::NotAuthorized);
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn metrics_count_client_traffic() {
+        // Find a free port for the metrics listener.
+        let metrics_addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let config = BrokerConfig {
+            metrics_bind_address: Some(metrics_addr.to_string()),
+            authenticator: Some(Arc::new(InMemoryAuthenticator::new())),
+            ..Default::default()
+        };
+        let addr = start_test_broker(config).await;
+        let (mut refused, _) = TestConnection::connect(addr, "anonymous", Vec::new()).await;
+        assert!(refused.recv().await.is_none());
+
+        let (status, body) = crate::http::request(metrics_addr, "GET", "/metrics", "").await;
+        assert_eq!(status, 200);
+        for line in [
+            "mqtt_connections_total 1",
+            "mqtt_connections_active 0",
+            "mqtt_packets_received_total{type=\"CONNECT\"} 1",
+            "mqtt_packets_sent_total{type=\"CONNACK\"} 1",
+            "mqtt_auth_failures_total{reason=\"NotAuthorized\"} 1",
+            "mqtt_disconnects_total{initiator=\"none\",reason=\"ConnectionClosed\"} 1",
+        ] {
+            assert!(body.lines().any(|body_line| body_line == line), "missing {:?} in\n{}", line, body);
+        }
+    }
+
+    #[tokio::test]
+    async fn admin_api_manages_clients_and_retained_messages() {
+        let admin_addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let config = BrokerConfig {
+            admin_bind_address: Some(admin_addr.to_string()),
+            sys_interval: None,
+            ..Default::default()
+        };
+        let addr = start_test_broker(config).await;
+        let admin = |method: &'static str, target: &'static str, body: &'static str| crate::http::request(admin_addr, method, target, body);
+        let get_json = |target: &'static str| async move {
+            let (status, body) = crate::http::request(admin_addr, "GET", target, "").await;
+            assert_eq!(status, 200);
+            serde_json::from_str::<serde_json::Value>(&body).unwrap()
+        };
+
+        let (mut watcher, _) = TestConnection::connect(addr, "watcher", vec![Some(Property::SessionExpiryInterval(60))]).await;
+        watcher.subscribe("news/#", Qos::AtleastOnce).await;
+        let publish = r#"{"topic": "news/today", "payload": "hello", "qos": 1, "retain": true}"#;
+        assert_eq!(admin("POST", "/publish", publish).await.0, 202);
+        let (topic, payload, _) = published(watcher.recv().await);
+        assert_eq!((topic.as_str(), payload.as_ref()), ("news/today", &b"hello"[..]));
+        assert_eq!(admin("POST", "/publish", r#"{"topic": "news/#"}"#).await.0, 400);
+
+        let clients = get_json("/clients").await;
+        assert_eq!(clients[0]["client_id"], "watcher");
+        assert_eq!(clients[0]["connected"], true);
+        assert!(clients[0]["address"].as_str().unwrap().starts_with("127.0.0.1:"));
+        assert_eq!(clients[0]["subscriptions"], serde_json::json!([{"topic_filter": "news/#", "qos": 1}]));
+
+        assert_eq!(get_json("/retained").await[0]["payload"], "hello");
+        assert_eq!(admin("DELETE", "/retained/news%2Ftoday", "").await.0, 204);
+        assert_eq!(admin("DELETE", "/retained/news/today", "").await.0, 404);
+        assert_eq!(get_json("/retained").await, serde_json::json!([]));
+
+        // The unacknowledged message goes back to the session when the client is kicked.
+        assert_eq!(admin("DELETE", "/clients/watcher", "").await.0, 204);
+        watcher.expect_disconnect(DisconnectReasonCode::AdministrativeAction).await;
+        time::sleep(Duration::from_millis(100)).await;
+        let clients = get_json("/clients").await;
+        assert_eq!(clients[0]["connected"], false);
+        assert_eq!(clients[0]["queued"], 1);
+
+        assert_eq!(admin("DELETE", "/sessions/watcher", "").await.0, 204);
+        assert_eq!(get_json("/clients").await, serde_json::json!([]));
+        assert_eq!(admin("DELETE", "/clients/watcher", "").await.0, 404);
+
+        assert_eq!(admin("POST", "/bans", r#"{"client_id": "watcher"}"#).await.0, 204);
+        assert_eq!(admin("POST", "/bans", r#"{"address": "10.0.0.0/8", "seconds": 60}"#).await.0, 204);
+        assert_eq!(admin("POST", "/bans", r#"{"address": "10.0.0.0/40"}"#).await.0, 400);
+        let bans = get_json("/bans").await;
+        assert_eq!(bans[0], serde_json::json!({"client_id": "watcher", "seconds": null}));
+        assert_eq!(bans[1]["address"], "10.0.0.0/8");
+        let (_, conn_ack) = TestConnection::connect(addr, "watcher", Vec::new()).await;
+        assert_eq!(conn_ack_variable_header(conn_ack).reason_code, ConnAckReasonCode::Banned);
+        assert_eq!(admin("DELETE", "/bans", r#"{"client_id": "watcher"}"#).await.0, 204);
+        assert_eq!(admin("DELETE", "/bans", r#"{"client_id": "watcher"}"#).await.0, 404);
+    }
+
+    #[tokio::test]
+    async fn shutdown_disconnects_clients_and_stops_accepting() {
+        let broker = start_test_broker_handle(BrokerConfig::default()).await;
+        let addr = broker.local_addr();
+        let (mut connection, _) = TestConnection::connect(addr, "leaving", Vec::new()).await;
+        // Not connected yet, it is just dropped.
+        let mut silent = TcpStream::connect(addr).await.unwrap();
+
+        timeout(Duration::from_secs(5), broker.shutdown()).await.expect("shutdown timed out");
+        connection.expect_disconnect(DisconnectReasonCode::ServerShuttingDown).await;
+        // Closed, or reset if the listener went away before accepting it.
+        assert!(matches!(silent.read(&mut [0; 16]).await, Ok(0) | Err(_)));
+        assert!(TcpStream::connect(addr).await.is_err());
+        // Shutting down again is harmless.
+        broker.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn in_process_publish_and_subscribe_go_through_the_broker() {
+        let path = std::env::temp_dir().join(format!("mt-mqtt-handle-acl-{}", std::process::id()));
+        std::fs::write(&path, "user gateway\ntopic readwrite plant/#\n").unwrap();
+        let config = BrokerConfig {
+            acl: Some(Arc::new(AclFile::open(&path).unwrap())),
+            sys_interval: None,
+            ..Default::default()
+        };
+        std::fs::remove_file(&path).unwrap();
+        let broker = start_test_broker_handle(config).await;
+        let anonymous = broker.clone();
+        let broker = broker.with_identity("gateway", Some("gateway"));
+        assert_eq!(
+            anonymous.publish("plant/a", "x", Qos::AtMostOnce, false, Vec::new()).await,
+            Err(BrokerError::NotAuthorized)
+        );
+        assert_eq!(anonymous.subscribe("plant/#").await.err(), Some(BrokerError::NotAuthorized));
+        for topic_filter in ["plant/#/a", "plant+/a"] {
+            assert_eq!(broker.subscribe(topic_filter).await.err(), Some(BrokerError::InvalidTopic));
         }
+        assert_eq!(
+            broker.publish("plant/+", "x", Qos::AtMostOnce, false, Vec::new()).await,
+            Err(BrokerError::InvalidTopic)
+        );
+        for property in [Property::TopicAlias(1), Property::SessionExpiryInterval(60)] {
+            assert_eq!(
+                broker.publish("plant/a", "x", Qos::AtMostOnce, false, vec![property]).await,
+                Err(BrokerError::InvalidProperties)
+            );
+        }
+        assert_eq!(
+            broker.publish("$SYS/x", "x", Qos::AtMostOnce, false, Vec::new()).await,
+            Err(BrokerError::NotAuthorized)
+        );
+
+        broker
+            .publish(
+                "plant/temperature",
+                "21",
+                Qos::AtleastOnce,
+                true,
+                vec![Property::ContentType(String::from("text/plain"))],
+            )
+            .await
+            .unwrap();
+        let mut subscription = broker.subscribe("plant/#").await.unwrap();
+        let retained = subscription.recv().await.unwrap();
+        assert_eq!(
+            (retained.topic.as_str(), retained.payload.as_ref(), retained.retain),
+            ("plant/temperature", &b"21"[..], true)
+        );
+        assert!(matches!(retained.properties[..], [Some(Property::ContentType(_))]));
+
+        // Messages of network clients reach in-process subscribers and the other way around.
+        let (mut client, _) = TestConnection::connect_with(broker.local_addr(), "sensor", |control_packet| {
+            control_packet.variable_header.connect_flag.user_name_flag = true;
+            control_packet.payload.user_name = Some(String::from("gateway"));
+        })
+        .await;
+        client.subscribe("plant/commands", Qos::AtleastOnce).await;
+        client.publish("plant/humidity", Qos::AtMostOnce, None).await;
+        let message = timeout(Duration::from_secs(1), subscription.recv()).await.unwrap().unwrap();
+        assert_eq!((message.topic.as_str(), message.retain), ("plant/humidity", false));
+
+        broker
+            .publish("plant/commands", "stop", Qos::AtleastOnce, false, Vec::new())
+            .await
+            .unwrap();
+        let (topic, payload, _) = published(client.recv().await);
+        assert_eq!((topic.as_str(), payload.as_ref()), ("plant/commands", &b"stop"[..]));
+        let message = subscription.recv().await.unwrap();
+        assert_eq!(message.topic, "plant/commands");
+
+        broker.shutdown().await;
+        assert!(subscription.recv().await.is_none());
+        assert_eq!(broker.subscribe("plant/#").await.err(), Some(BrokerError::Stopped));
+    }
+
+    #[tokio::test]
+    async fn slow_in_process_subscribers_lose_messages_or_their_subscription() {
+        for policy in [SlowConsumerPolicy::Pause, SlowConsumerPolicy::Disconnect] {
+            let config = BrokerConfig {
+                sys_interval: None,
+                client_channel_capacity: 2,
+                slow_consumer_policy: policy,
+                ..Default::default()
+            };
+            let broker = start_test_broker_handle(config).await;
+            let mut subscription = broker.subscribe("feed").await.unwrap();
+            for payload in ["1", "2", "3"] {
+                broker.publish("feed", payload, Qos::AtMostOnce, false, Vec::new()).await.unwrap();
+            }
+            for payload in ["1", "2"] {
+                assert_eq!(subscription.recv().await.unwrap().payload, payload);
+            }
+            if policy == SlowConsumerPolicy::Disconnect {
+                assert!(subscription.recv().await.is_none());
+                continue;
+            }
+            // The third message did not fit, the subscription goes on with the next one.
+            broker.publish("feed", "4", Qos::AtMostOnce, false, Vec::new()).await.unwrap();
+            assert_eq!(subscription.recv().await.unwrap().payload, "4");
+            broker.shutdown().await;
+        }
+    }
+
+    async fn publish_logged(redact_payloads: bool) -> String {
+        let logs = CapturedLogs::default();
+        let log_config = LogConfig {
+            format: LogFormat::Json,
+            filter: String::from("mt_mqtt=trace"),
+        };
+        let _subscriber = tracing::subscriber::set_default(logging::subscriber(&log_config, logs.clone()).unwrap());
+        let config = BrokerConfig {
+            redact_payloads,
+            ..Default::default()
+        };
+        let addr = start_test_broker(config).await;
+        let (mut connection, _) = TestConnection::connect(addr, "logged", Vec::new()).await;
+        connection.subscribe("logged/topic", Qos::AtMostOnce).await;
+        connection
+            .publish_payload("logged/topic", Qos::AtMostOnce, None, Bytes::from_static(b"top-secret"))
+            .await;
+        connection.recv().await.unwrap();
+        logs.contents()
+    }
+
+    #[tokio::test]
+    async fn packets_are_logged_in_the_connection_span_without_payloads() {
+        let logs = publish_logged(true).await;
+        let events: Vec<serde_json::Value> = logs.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let published = events
+            .iter()
+            .find(|event| event["fields"]["packet"] == "PUBLISH" && event["fields"]["direction"] == "received")
+            .expect("PUBLISH is logged");
+        assert_eq!(published["fields"]["topic"], "logged/topic");
+        assert_eq!(published["fields"]["payload_size"], 10);
+        assert_eq!(published["span"]["client_id"], "logged");
+        assert_eq!(published["span"]["protocol_version"], 5);
+        assert!(events.iter().any(|event| event["fields"]["message"] == "client connected"));
+        assert!(!logs.contains("top-secret"));
+
+        assert!(publish_logged(false).await.contains("top-secret"));
+    }
+
+    #[tokio::test]
+    async fn credentials_are_checked_by_the_authenticator() {
+        let config = BrokerConfig {
+            authenticator: Some(Arc::new(InMemoryAuthenticator::new().with_user("alice", "secret"))),
+            ..Default::default()
+        };
+        let addr = start_test_broker(config).await;
+
+        let (_, conn_ack) = TestConnection::connect_with(addr, "good", login("secret")).await;
+        assert_eq!(conn_ack_variable_header(conn_ack).reason_code, ConnAckReasonCode::Success);
+        let (mut connection, conn_ack) = TestConnection::connect_with(addr, "bad", login("wrong")).await;
+        assert_eq!(conn_ack_variable_header(conn_ack).reason_code, ConnAckReasonCode::BadUserNameOrPassword);
+        assert!(connection.recv().await.is_none());
+        let (_, conn_ack) = TestConnection::connect(addr, "anonymous", Vec::new()).await;
+        assert_eq!(conn_ack_variable_header(conn_ack).reason_code, ConnAckReasonCode::NotAuthorized);
+    }
+
+    #[tokio::test]
+    async fn connections_past_the_accept_rate_are_refused() {
+        let config = BrokerConfig {
+            connection_limits: ConnectionLimits {
+                per_address_per_second: Some(1),
+                burst_seconds: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let addr = start_test_broker(config).await;
+        let mut reason_codes = Vec::new();
+        for client_id in ["first", "second", "third"] {
+            let (_, conn_ack) = TestConnection::connect(addr, client_id, Vec::new()).await;
+            reason_codes.push(conn_ack_variable_header(conn_ack).reason_code);
+        }
+        assert_eq!(
+            reason_codes,
+            [
+                ConnAckReasonCode::Success,
+                ConnAckReasonCode::Success,
+                ConnAckReasonCode::ConnectionRateExceeded
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn banned_clients_and_addresses_failing_to_authenticate_are_refused() {
+        let config = BrokerConfig {
+            authenticator: Some(Arc::new(InMemoryAuthenticator::new().with_user("alice", "secret"))),
+            connection_limits: ConnectionLimits {
+                auth_failure_ban: Some(AuthFailureBan {
+                    max_failures: 2,
+                    within: Duration::from_secs(60),
+                    ban_for: Duration::from_secs(60),
+                }),
+                ..Default::default()
+            },
+            bans: Arc::new(BanList::new().with_ban(Ban::ClientId(String::from("intruder")), None)),
+            ..Default::default()
+        };
+        let broker = start_test_broker_handle(config).await;
+        let addr = broker.local_addr();
+        let mut reason_codes = Vec::new();
+        for (client_id, password) in [("intruder", "secret"), ("guess", "wrong"), ("guess", "wrong"), ("good", "secret")] {
+            let (_, conn_ack) = TestConnection::connect_with(addr, client_id, login(password)).await;
+            reason_codes.push(conn_ack_variable_header(conn_ack).reason_code);
+        }
+        assert_eq!(
+            reason_codes,
+            [
+                ConnAckReasonCode::Banned,
+                ConnAckReasonCode::BadUserNameOrPassword,
+                ConnAckReasonCode::BadUserNameOrPassword,
+                ConnAckReasonCode::Banned,
+            ]
+        );
+
+        // Bans are managed while the broker runs.
+        let loopback = Ban::Address("127.0.0.0/8".parse().unwrap());
+        assert!(!broker.bans().unban(&loopback), "the automatic ban is for one address");
+        assert!(broker.bans().unban(&Ban::Address("127.0.0.1".parse().unwrap())));
+        let (_, conn_ack) = TestConnection::connect_with(addr, "good", login("secret")).await;
+        assert_eq!(conn_ack_variable_header(conn_ack).reason_code, ConnAckReasonCode::Success);
+        broker.bans().ban(Ban::UserName(String::from("alice")), Some(Duration::from_secs(60)));
+        let (_, conn_ack) = TestConnection::connect_with(addr, "other", login("secret")).await;
+        assert_eq!(conn_ack_variable_header(conn_ack).reason_code, ConnAckReasonCode::Banned);
+    }
+
+    #[tokio::test]
+    async fn redirected_clients_get_the_server_reference_on_connect_or_disconnect() {
+        let rules = RedirectRules {
+            default: None,
+            rules: vec![(
+                ClientPattern::ClientId(String::from("legacy-*")),
+                Some(Redirect::permanent("mqtt2.example.com:1883")),
+            )],
+        };
+        let config = BrokerConfig {
+            redirects: Arc::new(Redirects::new(rules)),
+            ..Default::default()
+        };
+        let broker = start_test_broker_handle(config).await;
+        let addr = broker.local_addr();
+        let (_, conn_ack) = TestConnection::connect(addr, "legacy-7", Vec::new()).await;
+        let conn_ack = conn_ack_variable_header(conn_ack);
+        assert_eq!(conn_ack.reason_code, ConnAckReasonCode::ServerMoved);
+        assert!(matches!(&conn_ack.properties[..], [Some(Property::ServerReference(reference))] if reference == "mqtt2.example.com:1883"));
+
+        let (mut sensor, _) = TestConnection::connect(addr, "sensor", Vec::new()).await;
+        let (mut console, _) = TestConnection::connect_with(addr, "console", |control_packet| {
+            control_packet.variable_header.connect_flag.user_name_flag = true;
+            control_packet.payload.user_name = Some(String::from("ops"));
+        })
+        .await;
+        // A maintenance window sends everyone but the operators away.
+        broker.redirects().set(RedirectRules {
+            default: Some(Redirect::temporary("standby.example.com:1883")),
+            rules: vec![(ClientPattern::UserName(String::from("ops")), None)],
+        });
+        let disconnect = sensor.expect_disconnect(DisconnectReasonCode::UseAnotherServer).await;
+        assert!(disconnect
+            .get_properties()
+            .iter()
+            .flatten()
+            .any(|property| matches!(property, Property::ServerReference(reference) if reference == "standby.example.com:1883")));
+        assert!(console.recv().await.is_none(), "the operators stay");
+        let (_, conn_ack) = TestConnection::connect(addr, "sensor", Vec::new()).await;
+        assert_eq!(conn_ack_variable_header(conn_ack).reason_code, ConnAckReasonCode::UseAnotherServer);
+
+        broker.redirects().set(RedirectRules::default());
+        let (_, conn_ack) = TestConnection::connect(addr, "sensor", Vec::new()).await;
+        assert_eq!(conn_ack_variable_header(conn_ack).reason_code, ConnAckReasonCode::Success);
+    }
+
+    fn authentication_data(properties: Vec<Option<Property>>) -> Bytes {
+        properties
+            .into_iter()
+            .flatten()
+            .find_map(|property| match property {
+                Property::AuthenticationData(data) => Some(data),
+                _ => None,
+            })
+            .expect("Authentication Data")
+    }
+
+    fn auth_challenge(frame: Option<Frame>, expected: AuthReasonCode) -> Bytes {
+        match frame.map(|frame| frame.control_packet) {
+            Some(ControlPacket::Auth(control_packet)) => {
+                assert_eq!(control_packet.variable_header.auth_reason_code, expected);
+                authentication_data(control_packet.variable_header.get_properties())
+            }
+            other => panic!("expected AUTH, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn scram_authentication_and_reauthentication_over_auth_packets() {
+        let config = BrokerConfig {
+            auth_mechanisms: vec![Arc::new(ScramSha256::new().with_user("alice", b"secret"))],
+            ..Default::default()
+        };
+        let addr = start_test_broker(config).await;
+        let method = || String::from("SCRAM-SHA-256");
+
+        let mut scram = ScramClient::new("alice", b"secret");
+        let properties = vec![
+            Some(Property::AuthenticationMethod(method())),
+            Some(Property::AuthenticationData(scram.client_first())),
+        ];
+        let (mut connection, auth) = TestConnection::connect(addr, "scram", properties).await;
+        let server_first = auth_challenge(Some(auth), AuthReasonCode::ContinueAuthentication);
+        let client_final = scram.client_final(&server_first);
+        connection
+            .send(auth_frame(AuthReasonCode::ContinueAuthentication, method(), Some(client_final)))
+            .await;
+        let conn_ack = conn_ack_variable_header(connection.recv().await.unwrap());
+        assert_eq!(conn_ack.reason_code, ConnAckReasonCode::Success);
+        assert!(scram.verify_server_final(&authentication_data(conn_ack.properties)));
+
+        let mut scram = ScramClient::new("alice", b"secret");
+        connection
+            .send(auth_frame(AuthReasonCode::ReAuthenticate, method(), Some(scram.client_first())))
+            .await;
+        let server_first = auth_challenge(connection.recv().await, AuthReasonCode::ContinueAuthentication);
+        let client_final = scram.client_final(&server_first);
+        connection
+            .send(auth_frame(AuthReasonCode::ContinueAuthentication, method(), Some(client_final)))
+            .await;
+        let server_final = auth_challenge(connection.recv().await, AuthReasonCode::Success);
+        assert!(scram.verify_server_final(&server_final));
+
+        // A failed re-authentication ends the connection.
+        let mut scram = ScramClient::new("alice", b"guess");
+        connection
+            .send(auth_frame(AuthReasonCode::ReAuthenticate, method(), Some(scram.client_first())))
+            .await;
+        let server_first = auth_challenge(connection.recv().await, AuthReasonCode::ContinueAuthentication);
+        let client_final = scram.client_final(&server_first);
+        connection
+            .send(auth_frame(AuthReasonCode::ContinueAuthentication, method(), Some(client_final)))
+            .await;
+        connection.expect_disconnect(DisconnectReasonCode::NotAuthorized).await;
+
+        let (_, conn_ack) = TestConnection::connect(addr, "plain", vec![Some(Property::AuthenticationMethod(String::from("PLAIN")))]).await;
+        assert_eq!(conn_ack_variable_header(conn_ack).reason_code, ConnAckReasonCode::BadAuthenticationMethod);
+    }
+
+    #[tokio::test]
+    async fn access_rules_refuse_publish_and_subscribe() {
+        let path = std::env::temp_dir().join(format!("mt-mqtt-client-acl-{}", std::process::id()));
+        std::fs::write(&path, "user alice\ntopic readwrite home/%u/#\n").unwrap();
+        let config = BrokerConfig {
+            acl: Some(Arc::new(AclFile::open(&path).unwrap())),
+            ..Default::default()
+        };
+        std::fs::remove_file(&path).unwrap();
+        let addr = start_test_broker(config).await;
+        let (mut alice, _) = TestConnection::connect_with(addr, "alice-phone", |control_packet| {
+            control_packet.variable_header.connect_flag.user_name_flag = true;
+            control_packet.payload.user_name = Some(String::from("alice"));
+        })
+        .await;
+
+        assert_eq!(
+            alice.subscribe("home/alice/#", Qos::AtleastOnce).await,
+            vec![SubAckReasonCode::GrantedQoS1]
+        );
+        assert_eq!(alice.subscribe("home/#", Qos::AtleastOnce).await, vec![SubAckReasonCode::NotAuthorized]);
+
+        alice.publish("home/bob/door", Qos::AtleastOnce, Some(1)).await;
+        match alice.recv().await.unwrap().control_packet {
+            ControlPacket::PubAck(control_packet) => assert_eq!(control_packet.variable_header.reason_code, PubAckReasonCode::NotAuthorized),
+            other => panic!("expected PUBACK, got {:?}", other),
+        }
+        alice.publish("home/bob/door", Qos::ExactlyOnce, Some(2)).await;
+        match alice.recv().await.unwrap().control_packet {
+            ControlPacket::PubRec(control_packet) => assert_eq!(control_packet.variable_header.reason_code, PubRecReasonCode::NotAuthorized),
+            other => panic!("expected PUBREC, got {:?}", other),
+        }
+        alice.publish("home/alice/door", Qos::AtleastOnce, Some(3)).await;
+        // The message comes back through alice's own subscription, around the PUBACK.
+        let mut reason_codes = Vec::new();
+        for _ in 0..2 {
+            if let ControlPacket::PubAck(control_packet) = alice.recv().await.unwrap().control_packet {
+                reason_codes.push(control_packet.variable_header.reason_code);
+            }
+        }
+        assert_eq!(reason_codes, vec![PubAckReasonCode::Success]);
+    }
+
+    #[tokio::test]
+    async fn jwt_grants_topics_and_expires_the_session() {
+        let config = BrokerConfig {
+            authenticator: Some(Arc::new(JwtAuthenticator::new().with_hs256_secret(jwt::tests::HS256_SECRET))),
+            ..Default::default()
+        };
+        let addr = start_test_broker(config).await;
+        let token = jwt::tests::hs256_token(serde_json::json!({
+            "sub": "probe",
+            "exp": jwt::tests::now() + 2,
+            "acl": {"pub": ["probes/%c"]},
+        }));
+        let (mut probe, conn_ack) = TestConnection::connect_with(addr, "probe-7", |control_packet| {
+            control_packet.variable_header.connect_flag.password_flag = true;
+            control_packet.payload.password = Some(Bytes::from(token));
+        })
+        .await;
+        assert_eq!(conn_ack_variable_header(conn_ack).reason_code, ConnAckReasonCode::Success);
+        assert_eq!(probe.subscribe("probes/#", Qos::AtMostOnce).await, vec![SubAckReasonCode::NotAuthorized]);
+        probe.publish("probes/probe-7", Qos::AtleastOnce, Some(1)).await;
+        match probe.recv().await.unwrap().control_packet {
+            ControlPacket::PubAck(control_packet) => assert_eq!(control_packet.variable_header.reason_code, PubAckReasonCode::Success),
+            other => panic!("expected PUBACK, got {:?}", other),
+        }
+
+        time::sleep(Duration::from_secs(2)).await;
+        probe.expect_disconnect(DisconnectReasonCode::NotAuthorized).await;
     }
 }
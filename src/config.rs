@@ -0,0 +1,309 @@
+use crate::{
+    acl::AclFile,
+    auth::{AuthMechanism, Authenticator},
+    ban::BanList,
+    redirect::Redirects,
+    server::UNSECURE_TCP_PORT,
+};
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+/// Runtime settings of the broker. `Default` gives a broker listening on the standard
+/// unsecured MQTT port.
+#[derive(Debug, Clone)]
+pub struct BrokerConfig {
+    /// Address the plain TCP listener binds to.
+    pub bind_address: String,
+    /// How many QoS 1/2 PUBLISH packets a client may have unacknowledged towards the broker,
+    /// advertised in CONNACK as `ReceiveMaximum`.
+    pub receive_maximum: u16,
+    /// Largest packet, in bytes, the broker accepts from a client, advertised in CONNACK as
+    /// `MaximumPacketSize`. Bigger packets are refused with `PacketTooLarge` before they are read.
+    pub maximum_packet_size: u32,
+    /// How long a new connection has to get through CONNECT, and any authentication exchange,
+    /// before it is closed. Until then no Keep Alive applies.
+    pub connect_timeout: Duration,
+    /// Upper bound, in seconds, for the Keep Alive a client may ask for. When a client asks for
+    /// more, or for none at all, the broker imposes this value through `ServerKeepAlive`.
+    pub server_keep_alive: Option<u16>,
+    /// Longest client identifier, in bytes, accepted in CONNECT. Longer ones are refused with
+    /// `ClientIdentifierNotValid`.
+    pub client_id_max_length: usize,
+    /// Only accept client identifiers made of `0-9a-zA-Z`, the characters every server must
+    /// support, instead of any UTF-8 string.
+    pub client_id_strict_charset: bool,
+    /// Checks the User Name and Password of every CONNECT. Without one, any client may connect.
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    /// Enhanced authentication mechanisms clients can pick with the Authentication Method of
+    /// CONNECT. A client using one skips `authenticator`.
+    pub auth_mechanisms: Vec<Arc<dyn AuthMechanism>>,
+    /// Topic access rules. Without them, every client may publish and subscribe to anything.
+    /// The file is checked for changes every few seconds.
+    pub acl: Option<Arc<AclFile>>,
+    /// How often the broker statistics are published under `$SYS/broker`, `None` to not
+    /// publish them.
+    pub sys_interval: Option<Duration>,
+    /// Address of the HTTP listener serving Prometheus metrics on `/metrics`, `None` to not
+    /// serve them.
+    pub metrics_bind_address: Option<String>,
+    /// Address of the admin JSON API, `None` to not serve it. The API has no authentication,
+    /// so this should be a loopback address.
+    pub admin_bind_address: Option<String>,
+    /// Keep whole packets, with their payloads, out of the `trace` logs. Packets are still logged
+    /// at `debug` by type, topic and size. Passwords and authentication data are never logged.
+    pub redact_payloads: bool,
+    /// How long a shutdown waits for the connections to flush their last packets before
+    /// dropping them.
+    pub shutdown_timeout: Duration,
+    /// Sent in CONNACK, followed by the client identifier, as the Response Information of the
+    /// clients asking for it, which base their response topics on it. The access rules have to
+    /// let clients subscribe under it. `None` to not send any.
+    pub response_information: Option<String>,
+    /// Keeps retained messages, sessions, their subscriptions and queued messages on disk so
+    /// that they survive a restart, `None` to keep them in memory only.
+    pub persistence: Option<PersistenceConfig>,
+    /// Bounds of the queue of messages kept for each client that is away, or too slow to take
+    /// them.
+    pub offline_queue: OfflineQueueConfig,
+    /// How many messages a connection may have waiting for its client, on their way from the
+    /// broker as well as held back by the client's Receive Maximum, before
+    /// `slow_consumer_policy` applies. In-process subscriptions hold as many.
+    pub client_channel_capacity: usize,
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    /// How fast clients may publish.
+    pub rate_limits: RateLimits,
+    pub connection_limits: ConnectionLimits,
+    /// Addresses, client identifiers and user names refused with `Banned`. The list can be
+    /// changed while the broker runs, through a clone of it, `BrokerHandle::bans` or the admin
+    /// API.
+    pub bans: Arc<BanList>,
+    /// Clients sent to another server rather than served. The rules can be changed while the
+    /// broker runs, through a clone of them or `BrokerHandle::redirects`.
+    pub redirects: Arc<Redirects>,
+}
+
+/// Limits on new connections. Connections past the accept rates are refused with
+/// `ConnectionRateExceeded`.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimits {
+    /// Connections accepted a second from one IP address, `None` for no limit.
+    pub per_address_per_second: Option<u32>,
+    /// Connections accepted a second from all addresses, `None` for no limit.
+    pub per_second: Option<u32>,
+    /// How many seconds worth of the rates may connect at once.
+    pub burst_seconds: u32,
+    /// Bans the addresses that fail to authenticate too often, `None` to not ban them.
+    pub auth_failure_ban: Option<AuthFailureBan>,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        ConnectionLimits {
+            per_address_per_second: None,
+            per_second: None,
+            burst_seconds: 1,
+            auth_failure_ban: None,
+        }
+    }
+}
+
+/// Bans an address for `ban_for` once `max_failures` authentications from it failed within
+/// `within`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthFailureBan {
+    pub max_failures: u32,
+    pub within: Duration,
+    pub ban_for: Duration,
+}
+
+/// Picks clients by their client identifier or User Name. `*` in the pattern matches any run
+/// of characters, e.g. `sensor-*`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientPattern {
+    ClientId(String),
+    UserName(String),
+}
+
+impl ClientPattern {
+    pub fn matches(&self, client_id: &str, user_name: Option<&str>) -> bool {
+        match self {
+            ClientPattern::ClientId(pattern) => glob_matches(pattern, client_id),
+            ClientPattern::UserName(pattern) => user_name.is_some_and(|user_name| glob_matches(pattern, user_name)),
+        }
+    }
+}
+
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => match text.strip_prefix(prefix) {
+            // The star takes as many characters as it needs for the rest to match.
+            Some(text) => text
+                .char_indices()
+                .map(|(index, _)| index)
+                .chain([text.len()])
+                .any(|index| glob_matches(rest, &text[index..])),
+            None => false,
+        },
+    }
+}
+
+/// Token bucket limits on what a client publishes. Messages past them are refused with
+/// `QuotaExceeded`, or dropped when they are QoS 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// `None` for no limit on the number of messages, 0 to refuse them all.
+    pub messages_per_second: Option<u32>,
+    /// Bytes of payload, `None` for no limit, 0 to refuse every message.
+    pub bytes_per_second: Option<u32>,
+    /// How many seconds worth of the rates a client may publish at once after being quiet.
+    pub burst_seconds: u32,
+}
+
+/// The rate limits of the clients.
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    /// The limit of the clients no override picks, `None` for no limit.
+    pub default: Option<RateLimit>,
+    /// Limits of the clients matching a pattern, the first matching one applies. `None` lifts
+    /// the limit.
+    pub overrides: Vec<(ClientPattern, Option<RateLimit>)>,
+    /// How long a client may have all its messages refused before it is disconnected with
+    /// `MessageRateTooHigh`.
+    pub disconnect_after: Duration,
+}
+
+impl RateLimits {
+    pub fn limit_for(&self, client_id: &str, user_name: Option<&str>) -> Option<RateLimit> {
+        self.overrides
+            .iter()
+            .find(|(pattern, _)| pattern.matches(client_id, user_name))
+            .map_or(self.default, |(_, limit)| *limit)
+    }
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            default: None,
+            overrides: Vec::new(),
+            disconnect_after: Duration::from_secs(10),
+        }
+    }
+}
+
+/// What happens to the messages for a client that does not read them as fast as they come.
+/// Messages held back wait in the session, within the bounds of `offline_queue`, and go out
+/// as soon as the connection takes them again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    /// QoS 0 messages are dropped, QoS 1/2 messages held back.
+    DropQos0,
+    /// Every message is held back.
+    Pause,
+    /// The connection is closed with `QuotaExceeded`, what it did not get stays in the session
+    /// when there is one.
+    Disconnect,
+}
+
+/// What happens to a message for a client that is away when its queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueFullPolicy {
+    /// The oldest messages of the queue make room for the new one.
+    DropOldest,
+    /// The new message is dropped for this client.
+    DropNewest,
+    /// The new message goes to none of its subscribers, and a QoS 1/2 publisher gets
+    /// `QuotaExceeded` in its PUBACK or PUBREC.
+    Reject,
+}
+
+/// Bounds of the queue each session keeps while its client is away. Every message dropped or
+/// refused, as well as those expiring in a queue, is counted under `messages/dropped`.
+#[derive(Debug, Clone)]
+pub struct OfflineQueueConfig {
+    pub max_messages: usize,
+    /// Most bytes of topics and payloads a queue holds.
+    pub max_bytes: usize,
+    pub policy: QueueFullPolicy,
+    /// Queue QoS 0 messages too, which are otherwise only sent to connected clients.
+    pub queue_qos0: bool,
+}
+
+impl Default for OfflineQueueConfig {
+    fn default() -> Self {
+        OfflineQueueConfig {
+            max_messages: 1000,
+            max_bytes: 16 * 1024 * 1024,
+            policy: QueueFullPolicy::DropOldest,
+            queue_qos0: false,
+        }
+    }
+}
+
+/// When the write-ahead log is synced to disk. Records are written to the file as they happen,
+/// and QoS 1/2 publishers acknowledged once they are, so a crash of the broker process loses
+/// nothing either way; syncing protects against a crash of the machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// After every record.
+    Always,
+    /// Every so often, the most the machine crashing may lose.
+    Interval(Duration),
+    /// Only when the broker stops, the operating system decides otherwise.
+    Never,
+}
+
+/// Where and how the broker state is kept on disk: a log of every change, replaced by a
+/// snapshot of the whole state once it grows long. QoS 1/2 messages sent to a connected client
+/// are kept until it acknowledges them, and sent again once the broker is back.
+#[derive(Debug, Clone)]
+pub struct PersistenceConfig {
+    /// Directory of the log and the snapshot, created if it does not exist. Only one broker
+    /// may use it at a time.
+    pub directory: PathBuf,
+    pub fsync: FsyncPolicy,
+    /// How many records the log takes before a snapshot replaces it.
+    pub snapshot_after: usize,
+}
+
+impl PersistenceConfig {
+    /// Persistence in `directory`, synced every second, with a snapshot every 10000 changes.
+    pub fn new<P: Into<PathBuf>>(directory: P) -> PersistenceConfig {
+        PersistenceConfig {
+            directory: directory.into(),
+            fsync: FsyncPolicy::Interval(Duration::from_secs(1)),
+            snapshot_after: 10_000,
+        }
+    }
+}
+
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        BrokerConfig {
+            bind_address: format!("0.0.0.0:{}", UNSECURE_TCP_PORT),
+            receive_maximum: 1024,
+            maximum_packet_size: 1024 * 1024,
+            connect_timeout: Duration::from_secs(10),
+            server_keep_alive: None,
+            client_id_max_length: 256,
+            client_id_strict_charset: false,
+            authenticator: None,
+            auth_mechanisms: Vec::new(),
+            acl: None,
+            sys_interval: Some(Duration::from_secs(10)),
+            metrics_bind_address: None,
+            admin_bind_address: None,
+            redact_payloads: true,
+            shutdown_timeout: Duration::from_secs(5),
+            response_information: Some(String::from("responses/")),
+            persistence: None,
+            offline_queue: OfflineQueueConfig::default(),
+            client_channel_capacity: 1000,
+            slow_consumer_policy: SlowConsumerPolicy::Pause,
+            rate_limits: RateLimits::default(),
+            connection_limits: ConnectionLimits::default(),
+            bans: Arc::new(BanList::new()),
+            redirects: Arc::new(Redirects::default()),
+        }
+    }
+}
@@ -4,7 +4,8 @@ use std::io::Cursor;
 use strum_macros::Display;
 
 #[repr(u8)]
-#[derive(Debug, FromPrimitive, ToPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive, ToPrimitive)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum ControlPacketType {
     CONNECT = 1,
     CONNACK = 2,
@@ -23,9 +24,10 @@ pub enum ControlPacketType {
     AUTH = 15,
 }
 #[repr(u8)]
-#[derive(Debug, FromPrimitive, ToPrimitive)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, FromPrimitive, ToPrimitive)]
 #[allow(dead_code)]
 pub enum ConnAckReasonCode {
+    #[default]
     Success = 0,
     UnspecifiedError = 128,
     MalformedPacket = 129,
@@ -49,15 +51,11 @@ pub enum ConnAckReasonCode {
     ServerMoved = 157,
     ConnectionRateExceeded = 159,
 }
-impl Default for ConnAckReasonCode {
-    fn default() -> Self {
-        Self::Success
-    }
-}
 #[repr(u8)]
-#[derive(Debug, FromPrimitive, ToPrimitive)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, FromPrimitive, ToPrimitive)]
 #[allow(dead_code)]
 pub enum PubAckReasonCode {
+    #[default]
     Success = 0,
     NoMatchingSubscribers = 16,
     UnspecifiedError = 128,
@@ -68,15 +66,11 @@ pub enum PubAckReasonCode {
     QuotaExceeded = 151,
     PayloadFormatInvalid = 153,
 }
-impl Default for PubAckReasonCode {
-    fn default() -> Self {
-        Self::Success
-    }
-}
 #[repr(u8)]
-#[derive(Debug, FromPrimitive, ToPrimitive)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, FromPrimitive, ToPrimitive)]
 #[allow(dead_code)]
 pub enum PubRecReasonCode {
+    #[default]
     Success = 0,
     NoMatchingSubscribers = 16,
     UnspecifiedError = 128,
@@ -87,42 +81,30 @@ pub enum PubRecReasonCode {
     QuotaExceeded = 151,
     PayloadFormatInvalid = 153,
 }
-impl Default for PubRecReasonCode {
-    fn default() -> Self {
-        Self::Success
-    }
-}
 #[repr(u8)]
-#[derive(Debug, FromPrimitive, ToPrimitive)]
+#[derive(Debug, Default, FromPrimitive, ToPrimitive)]
 #[allow(dead_code)]
 pub enum PubRelReasonCode {
+    #[default]
     Success = 0,
     PacketIdentifierNotFound = 146,
 }
-impl Default for PubRelReasonCode {
-    fn default() -> Self {
-        Self::Success
-    }
-}
 #[repr(u8)]
-#[derive(Debug, FromPrimitive, ToPrimitive)]
+#[derive(Debug, Default, FromPrimitive, ToPrimitive)]
 #[allow(dead_code)]
 pub enum PubCompReasonCode {
+    #[default]
     Success = 0,
     PacketIdentifierNotFound = 146,
 }
-impl Default for PubCompReasonCode {
-    fn default() -> Self {
-        Self::Success
-    }
-}
 #[repr(u8)]
-#[derive(Debug, FromPrimitive, ToPrimitive)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, FromPrimitive, ToPrimitive)]
 #[allow(dead_code)]
 pub enum SubAckReasonCode {
     GrantedQoS0 = 0,
     GrantedQoS1 = 1,
     GrantedQoS2 = 2,
+    #[default]
     UnspecifiedError = 128,
     ImplementationSpecificError = 131,
     NotAuthorized = 135,
@@ -133,15 +115,11 @@ pub enum SubAckReasonCode {
     SubscriptionIdentifiersNotSupported = 161,
     WildcardSubscriptionsNotSupported = 162,
 }
-impl Default for SubAckReasonCode {
-    fn default() -> Self {
-        Self::UnspecifiedError
-    }
-}
 #[repr(u8)]
-#[derive(Debug, FromPrimitive, ToPrimitive)]
+#[derive(Debug, Default, FromPrimitive, ToPrimitive)]
 #[allow(dead_code)]
 pub enum UnSubAckReasonCode {
+    #[default]
     Success = 0,
     UnspecifiedError = 128,
     ImplementationSpecificError = 131,
@@ -149,15 +127,11 @@ pub enum UnSubAckReasonCode {
     TopicFilterInvalid = 143,
     PacketIdentifierInUse = 145,
 }
-impl Default for UnSubAckReasonCode {
-    fn default() -> Self {
-        Self::Success
-    }
-}
 #[repr(u8)]
-#[derive(Debug, FromPrimitive, ToPrimitive)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, FromPrimitive, ToPrimitive)]
 #[allow(dead_code)]
 pub enum DisconnectReasonCode {
+    #[default]
     NormalDisconnection = 0,
     DisconnectWithWillMessage = 4,
     UnspecifiedError = 128,
@@ -188,24 +162,14 @@ pub enum DisconnectReasonCode {
     SubscriptionIdentifiersNotSupported = 161,
     WildcardSubscriptionsNotSupported = 162,
 }
-impl Default for DisconnectReasonCode {
-    fn default() -> Self {
-        Self::NormalDisconnection
-    }
-}
 #[repr(u8)]
-#[derive(Debug, FromPrimitive, ToPrimitive)]
-#[allow(dead_code)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, FromPrimitive, ToPrimitive)]
 pub enum AuthReasonCode {
+    #[default]
     Success = 0,
     ContinueAuthentication = 24,
     ReAuthenticate = 25,
 }
-impl Default for AuthReasonCode {
-    fn default() -> Self {
-        Self::Success
-    }
-}
 #[derive(Debug, Default, Copy, Clone)]
 pub struct VariableByteInteger {
     pub data: u32,
@@ -215,10 +179,10 @@ impl VariableByteInteger {
         VariableByteInteger { data: 0 }
     }
 
-    pub fn from(encoded_byte: &mut Cursor<&[u8]>) -> VariableByteInteger {
-        VariableByteInteger {
-            data: VariableByteInteger::decode(encoded_byte),
-        }
+    pub fn from(encoded_byte: &mut Cursor<&[u8]>) -> Result<VariableByteInteger, String> {
+        Ok(VariableByteInteger {
+            data: VariableByteInteger::decode(encoded_byte)?,
+        })
     }
 
     pub fn encode(self) -> Vec<u8> {
@@ -242,25 +206,27 @@ impl VariableByteInteger {
         encoded_bytes
     }
 
-    pub fn decode(encoded_byte: &mut Cursor<&[u8]>) -> u32 {
+    pub fn decode(encoded_byte: &mut Cursor<&[u8]>) -> Result<u32, String> {
         let mut multiplier: u32 = 1;
         let mut data = 0;
         loop {
-            let read_byte = encoded_byte.get_u8();
-            data += (read_byte & 127) as u32 * multiplier;
+            let read_byte = encoded_byte.try_get_u8().map_err(|_| String::from("Malformed Variable Byte Integer"))?;
+            // Four bytes at most.
             if multiplier > 128 * 128 * 128 {
-                panic!("Malformed Variable Byte Integer");
+                return Err(String::from("Malformed Variable Byte Integer"));
             }
+            data += (read_byte & 127) as u32 * multiplier;
             multiplier *= 128;
             if (read_byte & 128) == 0 {
                 break;
             }
         }
-        data
+        Ok(data)
     }
 }
 #[repr(u8)]
 #[derive(Display, Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum Property {
     PayloadFormatIndicator(u8) = 1,
     MessageExpiryInterval(u32) = 2,
@@ -307,9 +273,7 @@ pub fn have_packet_identifier(fix_header: FixHeader) -> bool {
         | ControlPacketType::SUBACK
         | ControlPacketType::UNSUBSCRIBE
         | ControlPacketType::UNSUBACK => true,
-        ControlPacketType::PUBLISH => {
-            fix_header.flags.1 > 0 
-        }
+        ControlPacketType::PUBLISH => fix_header.flags.1 > 0,
     }
 }
 #[derive(Debug)]
@@ -324,23 +288,21 @@ impl FixHeader {
         FixHeader { control_packet_type, flags }
     }
 }
+#[allow(dead_code)]
 pub enum PayloadCondition {
     Required,
     Optional,
     None,
 }
 #[repr(u8)]
-#[derive(Display, Debug, Clone, FromPrimitive, ToPrimitive)]
+#[derive(Display, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, FromPrimitive, ToPrimitive)]
+#[allow(clippy::enum_variant_names)]
 pub enum Qos {
+    #[default]
     AtMostOnce = 0,
     AtleastOnce = 1,
     ExactlyOnce = 2,
 }
-impl Default for Qos {
-    fn default() -> Self {
-        Self::AtMostOnce
-    }
-}
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -353,6 +315,8 @@ mod tests {
     fn variable_byte_integer_decode() {
         let test_vec = vec![0x80, 0x1, 0, 0];
         let mut buff = Cursor::new(test_vec.as_slice());
-        assert_eq!(VariableByteInteger::decode(&mut buff), 128);
+        assert_eq!(VariableByteInteger::decode(&mut buff), Ok(128));
+        let mut truncated = Cursor::new(&[0x80, 0x80][..]);
+        assert!(VariableByteInteger::decode(&mut truncated).is_err());
     }
 }
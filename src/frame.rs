@@ -1,7 +1,7 @@
 use crate::definitions::*;
 pub use crate::packet::*;
 use bytes::{Buf, BufMut, BytesMut};
-use std::{convert::TryFrom, fmt, io::Cursor};
+use std::{fmt, io::Cursor};
 mod decoder;
 mod encoder;
 use decoder::*;
@@ -11,6 +11,12 @@ pub enum Error {
     /// Not enough data is available to parse a message
     Incomplete(usize),
 
+    /// The packet is larger than the Maximum Packet Size allowed on this connection
+    PacketTooLarge(usize),
+
+    /// The packet breaks the encoding rules of its type
+    Malformed(String),
+
     /// Invalid message encoding
     Other(String),
 }
@@ -26,7 +32,7 @@ pub enum ControlPacket {
     PubComp(PubCompControlPacket),
     Subscribe(SubscribeControlPacket),
     SubAck(SubAckControlPacket),
-    Unsubscribe(UnsubAckControlPacket),
+    Unsubscribe(UnsubscribeControlPacket),
     UnsubAck(UnsubAckControlPacket),
     PingReq,
     PingResp,
@@ -49,10 +55,6 @@ impl Frame {
             },
             ControlPacketType::CONNACK => {
                 let mut conn_ack_control_packet: ConnAckControlPacket = Default::default();
-                conn_ack_control_packet
-                    .variable_header
-                    .properties
-                    .push(Some(Property::AssignedClientIdentifier(String::from("Assigned"))));
                 conn_ack_control_packet.variable_header.conn_ack_flag.session_present_flag = false;
                 Frame {
                     fix_header: FixHeader::new(control_packet_type, Flags(0, 0, 0, 0)),
@@ -67,90 +69,185 @@ impl Frame {
                 fix_header: FixHeader::new(control_packet_type, Flags(0, 0, 0, 0)),
                 control_packet: ControlPacket::PubRec(Default::default()),
             },
+            ControlPacketType::PUBREL => Frame {
+                fix_header: FixHeader::new(control_packet_type, Flags(0, 1, 0, 0)),
+                control_packet: ControlPacket::PubRel(Default::default()),
+            },
+            ControlPacketType::PUBCOMP => Frame {
+                fix_header: FixHeader::new(control_packet_type, Flags(0, 0, 0, 0)),
+                control_packet: ControlPacket::PubComp(Default::default()),
+            },
+            ControlPacketType::PINGREQ => Frame {
+                fix_header: FixHeader::new(control_packet_type, Flags(0, 0, 0, 0)),
+                control_packet: ControlPacket::PingReq,
+            },
             ControlPacketType::PINGRESP => Frame {
                 fix_header: FixHeader::new(control_packet_type, Flags(0, 0, 0, 0)),
                 control_packet: ControlPacket::PingResp,
             },
+            ControlPacketType::DISCONNECT => Frame {
+                fix_header: FixHeader::new(control_packet_type, Flags(0, 0, 0, 0)),
+                control_packet: ControlPacket::Disconnect(Default::default()),
+            },
+            ControlPacketType::AUTH => Frame {
+                fix_header: FixHeader::new(control_packet_type, Flags(0, 0, 0, 0)),
+                control_packet: ControlPacket::Auth(Default::default()),
+            },
             _ => panic!("not implemented yet"),
-            /*ControlPacketType::PUBREL = 6,
-            ControlPacketType::PUBCOMP = 7,
-            ControlPacketType::SUBSCRIBE = 8,
+            /*ControlPacketType::SUBSCRIBE = 8,
             ControlPacketType::SUBACK = 9,
             ControlPacketType::UNSUBSCRIBE = 10,
-            ControlPacketType::UNSUBACK = 11,
-            ControlPacketType::PINGREQ = 12,
-            ControlPacketType::DISCONNECT = 14,
-            ControlPacketType::AUTH = 15,*/
+            ControlPacketType::UNSUBACK = 11,*/
         }
     }
 
+    /// Returns the size in bytes of the packet starting at `src`, fixed header included, as
+    /// soon as its Remaining Length is buffered, or `None` while it is still incomplete.
+    pub fn packet_size(src: &[u8]) -> Result<Option<usize>, Error> {
+        let mut remianing_lenght: usize = 0;
+        for (index, encoded_byte) in src.iter().skip(1).take(4).enumerate() {
+            remianing_lenght += ((encoded_byte & 127) as usize) << (7 * index);
+            if encoded_byte & 128 == 0 {
+                return Ok(Some(1 + index + 1 + remianing_lenght));
+            }
+        }
+        if src.len() >= 5 {
+            return Err(Error::Malformed("Malformed Variable Byte Integer".to_string()));
+        }
+        Ok(None)
+    }
+
     pub fn deserialize(src: &mut Cursor<&[u8]>) -> Result<Frame, Error> {
-        if src.remaining() < 2 {
+        let pos = src.position();
+        if Frame::packet_size(&src.get_ref()[pos as usize..])?.is_none() {
             return Err(Error::Incomplete(src.remaining()));
         }
-        println!("start deserialize");
-        let pos = src.position();
-        let fix_header = decode_fix_header(src).unwrap();
-        println!("fix_header: {:?}", fix_header);
-        let remianing_lenght = VariableByteInteger::from(src);
-        if src.remaining() < usize::try_from(remianing_lenght.data).unwrap() {
+        let fix_header = decode_fix_header(src).map_err(Error::malformed)?;
+        let remianing_lenght = VariableByteInteger::from(src).map_err(Error::Malformed)?.data as usize;
+        if src.remaining() < remianing_lenght {
             src.set_position(pos);
-            return Err(Error::Incomplete(usize::try_from(remianing_lenght.data).unwrap()));
+            return Err(Error::Incomplete(remianing_lenght));
         }
+        // Decode from a view limited to this packet so that packets which read "until the
+        // end" (PUBLISH payload, SUBSCRIBE topic list, ...) never consume the next frame.
+        let data: &[u8] = src.get_ref();
+        let start = src.position() as usize;
+        let mut packet = Cursor::new(&data[start..start + remianing_lenght]);
+        src.advance(remianing_lenght);
+        // The whole packet is there, so whatever it lacks is malformed.
+        Frame::decode(fix_header, &mut packet).map_err(Error::malformed)
+    }
+
+    fn decode(fix_header: FixHeader, src: &mut Cursor<&[u8]>) -> Result<Frame, Error> {
         match fix_header.control_packet_type {
             ControlPacketType::CONNECT => Ok(Frame {
-                control_packet: ControlPacket::Connect(decode_connect_packet(src).unwrap()),
+                control_packet: ControlPacket::Connect(decode_connect_packet(src)?),
+                fix_header,
+            }),
+            ControlPacketType::CONNACK => Ok(Frame {
+                control_packet: ControlPacket::ConnAck(decode_conn_ack_packet(src)?),
                 fix_header,
             }),
             ControlPacketType::PUBLISH => Ok(Frame {
-                control_packet: ControlPacket::Publish(decode_publish_packet(src, fix_header.flags.1).unwrap()),
+                control_packet: ControlPacket::Publish(decode_publish_packet(src, fix_header.flags.1)?),
+                fix_header,
+            }),
+            ControlPacketType::PUBACK => Ok(Frame {
+                control_packet: ControlPacket::PubAck(decode_pub_ack_packet(src)?),
+                fix_header,
+            }),
+            ControlPacketType::PUBREC => Ok(Frame {
+                control_packet: ControlPacket::PubRec(decode_pub_rec_packet(src)?),
                 fix_header,
             }),
             ControlPacketType::PUBREL => Ok(Frame {
-                control_packet: ControlPacket::PubRel(decode_pub_rel_packet(src).unwrap()),
+                control_packet: ControlPacket::PubRel(decode_pub_rel_packet(src)?),
+                fix_header,
+            }),
+            ControlPacketType::PUBCOMP => Ok(Frame {
+                control_packet: ControlPacket::PubComp(decode_pub_comp_packet(src)?),
                 fix_header,
             }),
             ControlPacketType::SUBSCRIBE => Ok(Frame {
-                control_packet: ControlPacket::Subscribe(decode_subscribe_packet(src).unwrap()),
+                control_packet: ControlPacket::Subscribe(decode_subscribe_packet(src)?),
+                fix_header,
+            }),
+            ControlPacketType::SUBACK => Ok(Frame {
+                control_packet: ControlPacket::SubAck(decode_sub_ack_packet(src)?),
+                fix_header,
+            }),
+            ControlPacketType::UNSUBSCRIBE => Ok(Frame {
+                control_packet: ControlPacket::Unsubscribe(decode_unsubscribe_packet(src)?),
+                fix_header,
+            }),
+            ControlPacketType::UNSUBACK => Ok(Frame {
+                control_packet: ControlPacket::UnsubAck(decode_unsub_ack_packet(src)?),
                 fix_header,
             }),
             ControlPacketType::DISCONNECT => Ok(Frame {
-                control_packet: ControlPacket::Disconnect(decode_disconnect_packet(src).unwrap()),
+                control_packet: ControlPacket::Disconnect(decode_disconnect_packet(src)?),
                 fix_header,
             }),
             ControlPacketType::PINGREQ => Ok(Frame {
                 control_packet: ControlPacket::PingReq,
                 fix_header,
             }),
-            _ => Err(Error::Other("Not Implemented yet".to_string())),
+            ControlPacketType::PINGRESP => Ok(Frame {
+                control_packet: ControlPacket::PingResp,
+                fix_header,
+            }),
+            ControlPacketType::AUTH => Ok(Frame {
+                control_packet: ControlPacket::Auth(decode_auth_packet(src)?),
+                fix_header,
+            }),
         }
     }
 
     pub fn serialize(frame: Frame) -> Result<BytesMut, Error> {
-        println!("start serialize: \n{:?}", frame);
         let mut data: BytesMut = BytesMut::new();
         encode_fix_header(frame.fix_header, &mut data);
         let mut src: BytesMut = BytesMut::new();
         match frame.control_packet {
+            ControlPacket::Connect(control_packet) => {
+                encode_connect_packet(control_packet, &mut src);
+            }
             ControlPacket::ConnAck(control_packet) => {
                 encode_conn_ack_packet(control_packet, &mut src);
             }
+            ControlPacket::Publish(control_packet) => {
+                encode_publish_packet(control_packet, &mut src);
+            }
             ControlPacket::PubAck(control_packet) => {
                 encode_pub_ack_packet(control_packet, &mut src);
             }
             ControlPacket::PubRec(control_packet) => {
                 encode_pub_rec_packet(control_packet, &mut src);
             }
+            ControlPacket::PubRel(control_packet) => {
+                encode_pub_rel_packet(control_packet, &mut src);
+            }
             ControlPacket::PubComp(control_packet) => {
                 encode_pub_comp_packet(control_packet, &mut src);
             }
+            ControlPacket::Subscribe(control_packet) => {
+                encode_subscribe_packet(control_packet, &mut src);
+            }
             ControlPacket::SubAck(control_packet) => {
                 encode_sub_ack_packet(control_packet, &mut src);
             }
-            ControlPacket::PingResp => (),
-            _ => {
-                return Err(Error::Other("Not Implemented yet".to_string()));
+            ControlPacket::Unsubscribe(control_packet) => {
+                encode_unsubscribe_packet(control_packet, &mut src);
+            }
+            ControlPacket::UnsubAck(control_packet) => {
+                encode_unsub_ack_packet(control_packet, &mut src);
+            }
+            ControlPacket::Disconnect(control_packet) => {
+                encode_disconnect_packet(control_packet, &mut src);
+            }
+            ControlPacket::Auth(control_packet) => {
+                encode_auth_packet(control_packet, &mut src);
             }
+            ControlPacket::PingReq | ControlPacket::PingResp => (),
         };
         data.put_slice(&VariableByteInteger::encode_u32(src.len() as u32));
         data.extend(src);
@@ -164,13 +261,29 @@ impl From<String> for Error {
     }
 }
 
-impl std::error::Error for Error {
+impl Error {
+    fn malformed(err: Error) -> Error {
+        match err {
+            Error::Malformed(_) => err,
+            err => Error::Malformed(err.to_string()),
+        }
+    }
+}
+
+impl From<bytes::TryGetError> for Error {
+    fn from(_: bytes::TryGetError) -> Error {
+        Error::Other("packet ends early".to_string())
+    }
 }
 
+impl std::error::Error for Error {}
+
 impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::Incomplete(no) => format!("stream ended early {}", no).fmt(fmt),
+            Error::PacketTooLarge(size) => format!("packet of {} bytes exceeds the maximum packet size", size).fmt(fmt),
+            Error::Malformed(err) => format!("malformed packet: {}", err).fmt(fmt),
             Error::Other(err) => err.fmt(fmt),
         }
     }
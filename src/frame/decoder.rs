@@ -9,84 +9,127 @@ fn decode_flag(tag: u8, data: u8) -> Flags {
         _ => Flags(data & 1, (data & 2) >> 1, (data & 4) >> 2, (data & 8) >> 3),
     }
 }
-pub fn decode_fix_header(src: &mut Cursor<&[u8]>) -> Option<FixHeader> {
-    let data = src.get_u8();
+pub fn decode_fix_header(src: &mut Cursor<&[u8]>) -> Result<FixHeader, Error> {
+    let data = src.try_get_u8()?;
     let packet_type = (data & 0b11110000) >> 4;
     let flags = decode_flag(packet_type, data);
-    Some(FixHeader {
-        control_packet_type: ControlPacketType::from_u8(packet_type).unwrap(),
+    Ok(FixHeader {
+        control_packet_type: ControlPacketType::from_u8(packet_type).ok_or_else(|| Error::Other("reserved packet type".to_string()))?,
         flags,
     })
 }
 pub fn decode_connect_packet(src: &mut Cursor<&[u8]>) -> Result<ConnectControlPacket, Error> {
-    let mut connect_control_packet: ConnectControlPacket = Default::default();
-    connect_control_packet.variable_header = decode_connect_variable_header(src).unwrap();
-    connect_control_packet.payload = decode_connect_payload(src, connect_control_packet.variable_header.connect_flag.clone()).unwrap();
-    Ok(connect_control_packet)
+    let variable_header = decode_connect_variable_header(src)?;
+    let payload = decode_connect_payload(src, variable_header.connect_flag.clone())?;
+    Ok(ConnectControlPacket { variable_header, payload })
 }
 pub fn decode_connect_variable_header(src: &mut Cursor<&[u8]>) -> Result<ConnectVariableHeader, Error> {
-    let mut connect_variable_header: ConnectVariableHeader = Default::default();
-    connect_variable_header.protocol_name = decode_string(src).unwrap();
-    connect_variable_header.protocol_version = src.get_u8();
-    connect_variable_header.connect_flag = ConnectFlags::new(src.get_u8());
-    connect_variable_header.keep_alive = src.get_u16();
-    connect_variable_header.properties = decode_properties(src).unwrap();
-    Ok(connect_variable_header)
+    Ok(ConnectVariableHeader {
+        protocol_name: decode_string(src)?,
+        protocol_version: src.try_get_u8()?,
+        connect_flag: ConnectFlags::new(src.try_get_u8()?),
+        keep_alive: src.try_get_u16()?,
+        properties: decode_properties(src)?,
+    })
 }
 pub fn decode_connect_payload(src: &mut Cursor<&[u8]>, connect_flag: ConnectFlags) -> Result<ConnectPayload, Error> {
-    let mut connect_payload: ConnectPayload = Default::default();
-    connect_payload.client_identifier = decode_string(src).unwrap();
+    let mut connect_payload = ConnectPayload {
+        client_identifier: decode_string(src)?,
+        ..Default::default()
+    };
     if connect_flag.will_flag {
-        connect_payload.will_properties = decode_properties(src).unwrap();
-        connect_payload.will_topic = Some(decode_string(src).unwrap());
-        connect_payload.will_payload = Some(decode_binary_data(src).unwrap());
+        connect_payload.will_properties = decode_properties(src)?;
+        connect_payload.will_topic = Some(decode_string(src)?);
+        connect_payload.will_payload = Some(decode_binary_data(src)?);
     }
     if connect_flag.user_name_flag {
-        connect_payload.user_name = Some(decode_string(src).unwrap());
+        connect_payload.user_name = Some(decode_string(src)?);
     }
     if connect_flag.password_flag {
-        connect_payload.password = Some(decode_binary_data(src).unwrap());
+        connect_payload.password = Some(decode_binary_data(src)?);
     }
     Ok(connect_payload)
 }
 
+pub fn decode_conn_ack_packet(src: &mut Cursor<&[u8]>) -> Result<ConnAckControlPacket, Error> {
+    let conn_ack_flag = ConnAckFlags::new(src.try_get_u8()?);
+    let reason_code = ConnAckReasonCode::from_u8(src.try_get_u8()?).ok_or_else(|| Error::Other("invalid connack reason code".to_string()))?;
+    Ok(ConnAckControlPacket {
+        variable_header: ConnAckVariableHeader {
+            conn_ack_flag,
+            reason_code,
+            properties: decode_properties(src)?,
+        },
+    })
+}
+
 pub fn decode_publish_packet(src: &mut Cursor<&[u8]>, qos: u8) -> Result<PublishControlPacket, Error> {
-    let mut publish_control_packet: PublishControlPacket = Default::default();
-    publish_control_packet.variable_header = decode_publish_variable_header(src, qos).unwrap();
-    publish_control_packet.payload = decode_publish_payload(src).unwrap();
-    Ok(publish_control_packet)
+    let variable_header = decode_publish_variable_header(src, qos)?;
+    let payload = decode_publish_payload(src)?;
+    Ok(PublishControlPacket { variable_header, payload })
 }
 pub fn decode_publish_variable_header(src: &mut Cursor<&[u8]>, qos: u8) -> Result<PublishVariableHeader, Error> {
-    let mut publish_variable_header: PublishVariableHeader = Default::default();
-    publish_variable_header.topic_name = decode_string(src).unwrap();
-    if qos > 0 {
-        publish_variable_header.packet_identifier = Some(src.get_u16());
-    } else {
-        publish_variable_header.packet_identifier = None;
-    }
-    publish_variable_header.set_properties(decode_properties(src).unwrap());
-    Ok(publish_variable_header)
+    let topic_name = decode_string(src)?;
+    let packet_identifier = if qos > 0 { Some(src.try_get_u16()?) } else { None };
+    PublishVariableHeader::from(topic_name, packet_identifier, decode_properties(src)?)
 }
 pub fn decode_publish_payload(src: &mut Cursor<&[u8]>) -> Result<PublishPayload, Error> {
-    let mut public_payload: PublishPayload = Default::default();
     let position = src.position() as usize;
-    public_payload.data = BytesMut::from(&src.get_ref()[position..]).freeze();
+    let public_payload = PublishPayload {
+        data: BytesMut::from(&src.get_ref()[position..]).freeze(),
+    };
     src.advance(src.remaining());
     Ok(public_payload)
 }
 
+pub fn decode_pub_ack_packet(src: &mut Cursor<&[u8]>) -> Result<PubAckControlPacket, Error> {
+    let mut variable_header = PubAckVariableHeader::from(src.try_get_u16()?, PubAckReasonCode::Success, Vec::new());
+    if src.has_remaining() {
+        variable_header.reason_code =
+            PubAckReasonCode::from_u8(src.try_get_u8()?).ok_or_else(|| Error::Other("invalid puback reason code".to_string()))?;
+        if src.has_remaining() {
+            variable_header.set_properties(decode_properties(src)?)?;
+        }
+    }
+    Ok(PubAckControlPacket { variable_header })
+}
+
+pub fn decode_pub_rec_packet(src: &mut Cursor<&[u8]>) -> Result<PubRecControlPacket, Error> {
+    let mut variable_header = PubRecVariableHeader::from(src.try_get_u16()?, PubRecReasonCode::Success, Vec::new());
+    if src.has_remaining() {
+        variable_header.reason_code =
+            PubRecReasonCode::from_u8(src.try_get_u8()?).ok_or_else(|| Error::Other("invalid pubrec reason code".to_string()))?;
+        if src.has_remaining() {
+            variable_header.set_properties(decode_properties(src)?)?;
+        }
+    }
+    Ok(PubRecControlPacket { variable_header })
+}
+
+pub fn decode_pub_comp_packet(src: &mut Cursor<&[u8]>) -> Result<PubCompControlPacket, Error> {
+    let mut variable_header = PubCompVariableHeader::from(src.try_get_u16()?, PubCompReasonCode::Success, Vec::new());
+    if src.has_remaining() {
+        variable_header.reason_code =
+            PubCompReasonCode::from_u8(src.try_get_u8()?).ok_or_else(|| Error::Other("invalid pubcomp reason code".to_string()))?;
+        if src.has_remaining() {
+            variable_header.set_properties(decode_properties(src)?)?;
+        }
+    }
+    Ok(PubCompControlPacket { variable_header })
+}
+
 pub fn decode_pub_rel_packet(src: &mut Cursor<&[u8]>) -> Result<PubRelControlPacket, Error> {
-    let mut pub_rel_control_packet: PubRelControlPacket = Default::default();
-    pub_rel_control_packet.variable_header = decode_pub_rel_variable_header(src).unwrap();
-    Ok(pub_rel_control_packet)
+    Ok(PubRelControlPacket {
+        variable_header: decode_pub_rel_variable_header(src)?,
+    })
 }
 pub fn decode_pub_rel_variable_header(src: &mut Cursor<&[u8]>) -> Result<PubRelVariableHeader, Error> {
-    let mut pub_rel_variable_header: PubRelVariableHeader = Default::default();
-    pub_rel_variable_header.packet_identifier = src.get_u16();
+    let mut pub_rel_variable_header = PubRelVariableHeader::from(src.try_get_u16()?, PubRelReasonCode::Success, Vec::new());
     if src.has_remaining() {
-        pub_rel_variable_header.reason_code = PubRelReasonCode::from_u8(src.get_u8()).unwrap();
+        pub_rel_variable_header.reason_code =
+            PubRelReasonCode::from_u8(src.try_get_u8()?).ok_or_else(|| Error::Other("invalid pubrel reason code".to_string()))?;
         if src.has_remaining() {
-            pub_rel_variable_header.set_properties(decode_properties(src).unwrap());
+            pub_rel_variable_header.set_properties(decode_properties(src)?)?;
         }
     } else {
         pub_rel_variable_header.reason_code = PubRelReasonCode::Success;
@@ -95,110 +138,152 @@ pub fn decode_pub_rel_variable_header(src: &mut Cursor<&[u8]>) -> Result<PubRelV
 }
 
 pub fn decode_subscribe_packet(src: &mut Cursor<&[u8]>) -> Result<SubscribeControlPacket, Error> {
-    let mut pub_rel_control_packet: SubscribeControlPacket = Default::default();
-    pub_rel_control_packet.variable_header = decode_subscribe_variable_header(src).unwrap();
-    Ok(pub_rel_control_packet)
+    Ok(SubscribeControlPacket {
+        variable_header: decode_subscribe_variable_header(src)?,
+    })
 }
 pub fn decode_subscribe_variable_header(src: &mut Cursor<&[u8]>) -> Result<SubscribeVariableHeader, Error> {
-    let mut subscribe_variable_header: SubscribeVariableHeader = Default::default();
-    subscribe_variable_header.packet_identifier = src.get_u16();
-    subscribe_variable_header.set_properties(decode_properties(src).unwrap());
-    subscribe_variable_header.subscribe_payload = decode_subscribe_payload(src).unwrap();
+    let mut subscribe_variable_header = SubscribeVariableHeader::from(src.try_get_u16()?, Vec::new(), Vec::new());
+    subscribe_variable_header.set_properties(decode_properties(src)?)?;
+    subscribe_variable_header.subscribe_payload = decode_subscribe_payload(src)?;
     Ok(subscribe_variable_header)
 }
 pub fn decode_subscribe_payload(src: &mut Cursor<&[u8]>) -> Result<Vec<SubscribePayload>, Error> {
     let mut subscribe_payload: Vec<SubscribePayload> = Vec::new();
     while src.has_remaining() {
         subscribe_payload.push(SubscribePayload {
-            topic_filter: decode_string(src).unwrap(),
-            subscription_options: decode_subscription_options(src).unwrap(),
+            topic_filter: decode_string(src)?,
+            subscription_options: decode_subscription_options(src)?,
         })
     }
     Ok(subscribe_payload)
 }
 
+pub fn decode_sub_ack_packet(src: &mut Cursor<&[u8]>) -> Result<SubAckControlPacket, Error> {
+    let mut variable_header = SubAckVariableHeader::from(src.try_get_u16()?, SubAckPayload::default(), Vec::new());
+    variable_header.set_properties(decode_properties(src)?)?;
+    while src.has_remaining() {
+        let reason_code = SubAckReasonCode::from_u8(src.try_get_u8()?).ok_or_else(|| Error::Other("invalid suback reason code".to_string()))?;
+        variable_header.sub_ack_payload.sub_ack_reason_codes.push(reason_code);
+    }
+    Ok(SubAckControlPacket { variable_header })
+}
+
+pub fn decode_unsubscribe_packet(src: &mut Cursor<&[u8]>) -> Result<UnsubscribeControlPacket, Error> {
+    let mut variable_header = UnsubscribeVariableHeader::from(src.try_get_u16()?, UnsubscribePayload::default(), Vec::new());
+    variable_header.set_properties(decode_properties(src)?)?;
+    while src.has_remaining() {
+        variable_header.unsubscribe_payload.topic_filters.push(decode_string(src)?);
+    }
+    Ok(UnsubscribeControlPacket { variable_header })
+}
+
+pub fn decode_unsub_ack_packet(src: &mut Cursor<&[u8]>) -> Result<UnsubAckControlPacket, Error> {
+    let mut variable_header = UnsubAckVariableHeader::from(src.try_get_u16()?, UnsubAckPayload::default(), Vec::new());
+    variable_header.set_properties(decode_properties(src)?)?;
+    while src.has_remaining() {
+        let reason_code = UnSubAckReasonCode::from_u8(src.try_get_u8()?).ok_or_else(|| Error::Other("invalid unsuback reason code".to_string()))?;
+        variable_header.unsub_ack_payload.un_sub_ack_reason_code.push(reason_code);
+    }
+    Ok(UnsubAckControlPacket { variable_header })
+}
+
 pub fn decode_disconnect_packet(src: &mut Cursor<&[u8]>) -> Result<DisconnectControlPacket, Error> {
-    let mut pub_rel_control_packet: DisconnectControlPacket = Default::default();
-    pub_rel_control_packet.variable_header = decode_disconnect_variable_header(src).unwrap();
-    Ok(pub_rel_control_packet)
+    Ok(DisconnectControlPacket {
+        variable_header: decode_disconnect_variable_header(src)?,
+    })
 }
 pub fn decode_disconnect_variable_header(src: &mut Cursor<&[u8]>) -> Result<DisconnectVariableHeader, Error> {
     let mut disconnect_variable_header: DisconnectVariableHeader = Default::default();
     if src.has_remaining() {
-        disconnect_variable_header.disconnect_reason_code = DisconnectReasonCode::from_u8(src.get_u8()).unwrap();
+        disconnect_variable_header.disconnect_reason_code =
+            DisconnectReasonCode::from_u8(src.try_get_u8()?).ok_or_else(|| Error::Other("invalid disconnect reason code".to_string()))?;
         if src.has_remaining() {
-            disconnect_variable_header.set_properties(decode_properties(src).unwrap());
+            disconnect_variable_header.set_properties(decode_properties(src)?)?;
         }
     } else {
         disconnect_variable_header.disconnect_reason_code = DisconnectReasonCode::NormalDisconnection;
     }
     Ok(disconnect_variable_header)
 }
+pub fn decode_auth_packet(src: &mut Cursor<&[u8]>) -> Result<AuthControlPacket, Error> {
+    let mut auth_variable_header: AuthVariableHeader = Default::default();
+    // A Remaining Length of 0 means Success without properties.
+    if src.has_remaining() {
+        auth_variable_header.auth_reason_code =
+            AuthReasonCode::from_u8(src.try_get_u8()?).ok_or_else(|| Error::Other("invalid AUTH reason code".into()))?;
+        if src.has_remaining() {
+            auth_variable_header.set_properties(decode_properties(src)?)?;
+        }
+    }
+    Ok(AuthControlPacket {
+        variable_header: auth_variable_header,
+    })
+}
 
 pub fn decode_subscription_options(src: &mut Cursor<&[u8]>) -> Result<SubscriptionOptions, Error> {
-    Ok(SubscriptionOptions::new(src.get_u8()))
+    let byte = src.try_get_u8()?;
+    // QoS 3, Retain Handling 3 and the reserved bits are malformed.
+    if byte & 0b0000_0011 == 3 || byte & 0b0011_0000 == 0b0011_0000 || byte & 0b1100_0000 != 0 {
+        return Err(Error::Other("invalid subscription options".to_string()));
+    }
+    Ok(SubscriptionOptions::new(byte))
 }
 
 pub fn decode_string(src: &mut Cursor<&[u8]>) -> Result<String, Error> {
-    let str_size_bytes = src.get_u16() as usize;
-
-    let position = src.position() as usize;
-
+    let bytes = decode_length_prefixed(src)?;
     // TODO - Use Cow<str> and from_utf8_lossy later for less copying
-    match String::from_utf8(src.get_ref()[position..(position + str_size_bytes)].into()) {
-        Ok(string) => {
-            src.advance(str_size_bytes);
-            Ok(string)
-        }
-        Err(_) => Err(Error::Other("decode string err".to_string())),
-    }
+    String::from_utf8(bytes.into()).map_err(|_| Error::Other("decode string err".to_string()))
 }
 pub fn decode_binary_data(src: &mut Cursor<&[u8]>) -> Result<Bytes, Error> {
-    let data_size_bytes = src.get_u16() as usize;
-
+    Ok(BytesMut::from(decode_length_prefixed(src)?).freeze())
+}
+/// Reads the two byte length and the bytes after it, which must be in `src`.
+fn decode_length_prefixed<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
+    let size = src.try_get_u16()? as usize;
     let position = src.position() as usize;
-
-    let payload_bytes = BytesMut::from(&src.get_ref()[position..(position + data_size_bytes)]).freeze();
-    let result = Ok(payload_bytes);
-    src.advance(data_size_bytes);
-
-    result
+    let data: &'a [u8] = src.get_ref();
+    let bytes = data
+        .get(position..position + size)
+        .ok_or_else(|| Error::Other("length beyond the end of the packet".to_string()))?;
+    src.advance(size);
+    Ok(bytes)
 }
 pub fn decode_properties(src: &mut Cursor<&[u8]>) -> Result<Vec<Option<Property>>, Error> {
-    let variable_byte_integer = VariableByteInteger::from(src);
+    let variable_byte_integer = VariableByteInteger::from(src)?;
     let lenght = variable_byte_integer.data as u64;
     let mut properties: Vec<Option<Property>> = Vec::new();
     let current_pos = src.position();
     while src.position() - current_pos < lenght {
-        let identifier = src.get_u8();
+        let identifier = src.try_get_u8()?;
         properties.push(Some(match identifier {
-            1 => Property::PayloadFormatIndicator(src.get_u8()),
-            2 => Property::MessageExpiryInterval(src.get_u32()),
-            3 => Property::ContentType(decode_string(src).unwrap()),
-            8 => Property::ResponseTopic(decode_string(src).unwrap()),
-            9 => Property::CorrelationData(decode_binary_data(src).unwrap()),
-            11 => Property::SubscriptionIdentifier(VariableByteInteger::from(src)),
-            17 => Property::SessionExpiryInterval(src.get_u32()),
-            18 => Property::AssignedClientIdentifier(decode_string(src).unwrap()),
-            19 => Property::ServerKeepAlive(src.get_u16()),
-            21 => Property::AuthenticationMethod(decode_string(src).unwrap()),
-            22 => Property::AuthenticationData(decode_binary_data(src).unwrap()),
-            23 => Property::RequestProblemInformation(src.get_u8()),
-            24 => Property::WillDelayInterval(src.get_u32()),
-            25 => Property::RequestResponseInformation(src.get_u8()),
-            26 => Property::ResponseInformation(decode_string(src).unwrap()),
-            28 => Property::ServerReference(decode_string(src).unwrap()),
-            31 => Property::ReasonString(decode_string(src).unwrap()),
-            33 => Property::ReceiveMaximum(src.get_u16()),
-            34 => Property::TopicAliasMaximum(src.get_u16()),
-            35 => Property::TopicAlias(src.get_u16()),
-            36 => Property::MaximumQoS(Qos::from_u8(src.get_u8()).unwrap()),
-            37 => Property::RetainAvailable(src.get_u8()),
-            38 => Property::UserProperty(decode_string(src).unwrap()),
-            39 => Property::MaximumPacketSize(src.get_u32()),
-            40 => Property::WildcardSubscriptionAvailable(src.get_u8()),
-            41 => Property::SubscriptionIdentifierAvailable(src.get_u8()),
-            42 => Property::SharedSubscriptionAvailable(src.get_u8()),
+            1 => Property::PayloadFormatIndicator(src.try_get_u8()?),
+            2 => Property::MessageExpiryInterval(src.try_get_u32()?),
+            3 => Property::ContentType(decode_string(src)?),
+            8 => Property::ResponseTopic(decode_string(src)?),
+            9 => Property::CorrelationData(decode_binary_data(src)?),
+            11 => Property::SubscriptionIdentifier(VariableByteInteger::from(src)?),
+            17 => Property::SessionExpiryInterval(src.try_get_u32()?),
+            18 => Property::AssignedClientIdentifier(decode_string(src)?),
+            19 => Property::ServerKeepAlive(src.try_get_u16()?),
+            21 => Property::AuthenticationMethod(decode_string(src)?),
+            22 => Property::AuthenticationData(decode_binary_data(src)?),
+            23 => Property::RequestProblemInformation(src.try_get_u8()?),
+            24 => Property::WillDelayInterval(src.try_get_u32()?),
+            25 => Property::RequestResponseInformation(src.try_get_u8()?),
+            26 => Property::ResponseInformation(decode_string(src)?),
+            28 => Property::ServerReference(decode_string(src)?),
+            31 => Property::ReasonString(decode_string(src)?),
+            33 => Property::ReceiveMaximum(src.try_get_u16()?),
+            34 => Property::TopicAliasMaximum(src.try_get_u16()?),
+            35 => Property::TopicAlias(src.try_get_u16()?),
+            36 => Property::MaximumQoS(Qos::from_u8(src.try_get_u8()?).ok_or_else(|| Error::Other("invalid maximum QoS".to_string()))?),
+            37 => Property::RetainAvailable(src.try_get_u8()?),
+            38 => Property::UserProperty(decode_string(src)?),
+            39 => Property::MaximumPacketSize(src.try_get_u32()?),
+            40 => Property::WildcardSubscriptionAvailable(src.try_get_u8()?),
+            41 => Property::SubscriptionIdentifierAvailable(src.try_get_u8()?),
+            42 => Property::SharedSubscriptionAvailable(src.try_get_u8()?),
             _ => return Err(Error::Other(format!("Unknow Identifier {}", identifier))),
         }))
     }
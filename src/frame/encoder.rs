@@ -5,11 +5,38 @@ use num_traits::ToPrimitive;
 pub fn encode_fix_header(src: FixHeader, bytes: &mut BytesMut) {
     bytes.put_u8((src.control_packet_type.to_u8().unwrap() << 4) | src.flags.0 | (src.flags.1 << 1) | (src.flags.2 << 2) | (src.flags.3 << 3));
 }
+pub fn encode_connect_packet(src: ConnectControlPacket, bytes: &mut BytesMut) {
+    encode_string(&src.variable_header.protocol_name, bytes);
+    bytes.put_u8(src.variable_header.protocol_version);
+    bytes.put_u8(src.variable_header.connect_flag.to_byte());
+    bytes.put_u16(src.variable_header.keep_alive);
+    encode_properties(src.variable_header.properties, bytes);
+    encode_string(&src.payload.client_identifier, bytes);
+    if src.variable_header.connect_flag.will_flag {
+        encode_properties(src.payload.will_properties, bytes);
+        encode_string(&src.payload.will_topic.unwrap_or_default(), bytes);
+        encode_binary_data(&src.payload.will_payload.unwrap_or_default(), bytes);
+    }
+    if let Some(user_name) = src.payload.user_name {
+        encode_string(&user_name, bytes);
+    }
+    if let Some(password) = src.payload.password {
+        encode_binary_data(&password, bytes);
+    }
+}
 pub fn encode_conn_ack_packet(src: ConnAckControlPacket, bytes: &mut BytesMut) {
     bytes.put_u8(src.variable_header.conn_ack_flag.session_present_flag as u8);
     bytes.put_u8(src.variable_header.reason_code.to_u8().unwrap());
     encode_properties(src.variable_header.properties, bytes);
 }
+pub fn encode_publish_packet(src: PublishControlPacket, bytes: &mut BytesMut) {
+    encode_string(&src.variable_header.topic_name, bytes);
+    if let Some(packet_identifier) = src.variable_header.packet_identifier {
+        bytes.put_u16(packet_identifier);
+    }
+    encode_properties(src.variable_header.get_properties(), bytes);
+    bytes.put_slice(&src.payload.data);
+}
 pub fn encode_pub_ack_packet(src: PubAckControlPacket, bytes: &mut BytesMut) {
     bytes.put_u16(src.variable_header.packet_identifier);
     bytes.put_u8(src.variable_header.reason_code.to_u8().unwrap());
@@ -20,11 +47,24 @@ pub fn encode_pub_rec_packet(src: PubRecControlPacket, bytes: &mut BytesMut) {
     bytes.put_u8(src.variable_header.reason_code.to_u8().unwrap());
     encode_properties(src.variable_header.get_properties(), bytes);
 }
+pub fn encode_pub_rel_packet(src: PubRelControlPacket, bytes: &mut BytesMut) {
+    bytes.put_u16(src.variable_header.packet_identifier);
+    bytes.put_u8(src.variable_header.reason_code.to_u8().unwrap());
+    encode_properties(src.variable_header.get_properties(), bytes);
+}
 pub fn encode_pub_comp_packet(src: PubCompControlPacket, bytes: &mut BytesMut) {
     bytes.put_u16(src.variable_header.packet_identifier);
     bytes.put_u8(src.variable_header.reason_code.to_u8().unwrap());
     encode_properties(src.variable_header.get_properties(), bytes);
 }
+pub fn encode_subscribe_packet(src: SubscribeControlPacket, bytes: &mut BytesMut) {
+    bytes.put_u16(src.variable_header.packet_identifier);
+    encode_properties(src.variable_header.get_properties(), bytes);
+    for subscribe_payload in src.variable_header.subscribe_payload {
+        encode_string(&subscribe_payload.topic_filter, bytes);
+        bytes.put_u8(subscribe_payload.subscription_options.to_byte());
+    }
+}
 pub fn encode_sub_ack_packet(src: SubAckControlPacket, bytes: &mut BytesMut) {
     bytes.put_u16(src.variable_header.packet_identifier);
     encode_properties(src.variable_header.get_properties(), bytes);
@@ -35,13 +75,32 @@ pub fn encode_sub_ack_payload(src: SubAckPayload, bytes: &mut BytesMut) {
         bytes.put_u8(iter.to_u8().unwrap());
     }
 }
+pub fn encode_unsubscribe_packet(src: UnsubscribeControlPacket, bytes: &mut BytesMut) {
+    bytes.put_u16(src.variable_header.packet_identifier);
+    encode_properties(src.variable_header.get_properties(), bytes);
+    for topic_filter in src.variable_header.unsubscribe_payload.topic_filters {
+        encode_string(&topic_filter, bytes);
+    }
+}
+pub fn encode_unsub_ack_packet(src: UnsubAckControlPacket, bytes: &mut BytesMut) {
+    bytes.put_u16(src.variable_header.packet_identifier);
+    encode_properties(src.variable_header.get_properties(), bytes);
+    for iter in src.variable_header.unsub_ack_payload.un_sub_ack_reason_code {
+        bytes.put_u8(iter.to_u8().unwrap());
+    }
+}
+pub fn encode_disconnect_packet(src: DisconnectControlPacket, bytes: &mut BytesMut) {
+    bytes.put_u8(src.variable_header.disconnect_reason_code.to_u8().unwrap());
+    encode_properties(src.variable_header.get_properties(), bytes);
+}
+pub fn encode_auth_packet(src: AuthControlPacket, bytes: &mut BytesMut) {
+    bytes.put_u8(src.variable_header.auth_reason_code.to_u8().unwrap());
+    encode_properties(src.variable_header.get_properties(), bytes);
+}
 pub fn encode_properties(src: Vec<Option<Property>>, bytes: &mut BytesMut) {
     let mut data: BytesMut = BytesMut::new();
-    for elem in src.iter() {
-        match elem {
-            Some(property) => encode_property(property, &mut data),
-            None => (),
-        }
+    for property in src.iter().flatten() {
+        encode_property(property, &mut data);
     }
     bytes.extend(VariableByteInteger::encode_u32(data.len() as u32));
     bytes.extend(data);
@@ -0,0 +1,250 @@
+use crate::{
+    acl::Identity,
+    ban::BanList,
+    broker::{BrokerMessage, Refused},
+    config::{BrokerConfig, QueueFullPolicy},
+    definitions::{Property, Qos},
+    message::Message,
+    redirect::Redirects,
+    topic::{is_system_topic, is_valid_topic_filter},
+};
+use bytes::Bytes;
+use futures_core::Stream;
+use std::{
+    fmt,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        oneshot, watch, Mutex,
+    },
+    task::JoinHandle,
+};
+
+/// Controls a running broker and lets the process publish and subscribe without a connection.
+/// Dropping it leaves the broker running; clones control the same broker.
+///
+/// In-process messages go through the same routing as the ones of network clients and are
+/// subject to the same access rules, checked against the identity set with
+/// [`BrokerHandle::with_identity`]: by default an empty client identifier and no user name.
+#[derive(Debug, Clone)]
+pub struct BrokerHandle {
+    local_addr: SocketAddr,
+    config: Arc<BrokerConfig>,
+    broker: Sender<BrokerMessage>,
+    client_id: String,
+    user_name: Option<String>,
+    shutdown: Arc<watch::Sender<bool>>,
+    // The task accepting connections, which winds everything down once told to.
+    server: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+/// Why an in-process publish or subscribe was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BrokerError {
+    /// The topic name is empty or has wildcards, or the topic filter is empty or has a wildcard
+    /// that is not a whole level, or a `#` before the last level.
+    InvalidTopic,
+    /// A property PUBLISH does not carry, or one belonging to a connection such as a Topic
+    /// Alias or Subscription Identifier.
+    InvalidProperties,
+    /// The access rules do not grant it, or the topic belongs to the broker.
+    NotAuthorized,
+    /// Offline queues with the Reject policy have no room for the QoS 1/2 message.
+    QueuesFull,
+    /// The QoS 1/2 message could not be written to disk.
+    NotStored,
+    /// The broker has shut down.
+    Stopped,
+}
+
+impl fmt::Display for BrokerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BrokerError::InvalidTopic => write!(f, "invalid topic"),
+            BrokerError::InvalidProperties => write!(f, "invalid properties"),
+            BrokerError::NotAuthorized => write!(f, "not authorized"),
+            BrokerError::QueuesFull => write!(f, "offline queues full"),
+            BrokerError::NotStored => write!(f, "not stored"),
+            BrokerError::Stopped => write!(f, "broker stopped"),
+        }
+    }
+}
+
+impl std::error::Error for BrokerError {}
+
+/// Messages matching an in-process subscription, starting with the retained ones. Dropping it
+/// unsubscribes.
+///
+/// Up to `BrokerConfig::client_channel_capacity` messages wait to be taken. Past that, new ones
+/// are dropped and counted as slow consumer drops, or the subscription ends under
+/// `SlowConsumerPolicy::Disconnect`.
+#[derive(Debug)]
+pub struct Subscription {
+    receiver: Receiver<Message>,
+}
+
+impl Subscription {
+    /// Waits for the next message, `None` once the broker has stopped.
+    pub async fn recv(&mut self) -> Option<Message> {
+        self.receiver.recv().await
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Message;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+impl BrokerHandle {
+    pub(crate) fn new(
+        local_addr: SocketAddr,
+        config: Arc<BrokerConfig>,
+        broker: Sender<BrokerMessage>,
+        shutdown: Arc<watch::Sender<bool>>,
+        server: JoinHandle<()>,
+    ) -> BrokerHandle {
+        BrokerHandle {
+            local_addr,
+            config,
+            broker,
+            client_id: String::new(),
+            user_name: None,
+            shutdown,
+            server: Arc::new(Mutex::new(Some(server))),
+        }
+    }
+
+    /// Address the MQTT listener is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The bans of the broker, which can be changed while it runs.
+    pub fn bans(&self) -> &BanList {
+        &self.config.bans
+    }
+
+    /// The redirect rules of the broker. Setting them disconnects the connected clients they
+    /// send to another server.
+    pub fn redirects(&self) -> &Redirects {
+        &self.config.redirects
+    }
+
+    /// A handle publishing and subscribing as `client_id` and `user_name` as far as access rules
+    /// and No Local go.
+    pub fn with_identity(&self, client_id: &str, user_name: Option<&str>) -> BrokerHandle {
+        BrokerHandle {
+            client_id: String::from(client_id),
+            user_name: user_name.map(String::from),
+            ..self.clone()
+        }
+    }
+
+    /// Publishes a message as if a client had sent it in a PUBLISH packet. A retained message
+    /// with an empty payload clears the retained message of the topic. QoS 1/2 messages resolve
+    /// once the broker has accepted them, and fail where a client would get a failed PUBACK.
+    pub async fn publish<T: Into<String>, P: Into<Bytes>>(
+        &self,
+        topic: T,
+        payload: P,
+        qos: Qos,
+        retain: bool,
+        properties: Vec<Property>,
+    ) -> Result<(), BrokerError> {
+        let topic = topic.into();
+        if topic.is_empty() || topic.contains(['+', '#']) {
+            return Err(BrokerError::InvalidTopic);
+        }
+        Message::check_properties(&properties).map_err(|_| BrokerError::InvalidProperties)?;
+        let allowed = self.config.acl.as_ref().is_none_or(|acl| acl.allows_publish(&self.identity(), &topic));
+        if is_system_topic(&topic) || !allowed {
+            return Err(BrokerError::NotAuthorized);
+        }
+        let message = Message {
+            topic,
+            payload: payload.into(),
+            qos,
+            retain,
+            properties: properties.into_iter().map(Some).collect(),
+        };
+        let may_refuse = self.config.offline_queue.policy == QueueFullPolicy::Reject || self.config.persistence.is_some();
+        let (accepted, reply) = match qos {
+            Qos::AtMostOnce => (None, None),
+            _ if !may_refuse => (None, None),
+            _ => {
+                let (accepted, reply) = oneshot::channel();
+                (Some(accepted), Some(reply))
+            }
+        };
+        self.broker
+            .send(BrokerMessage::Publish {
+                client_id: self.client_id.clone(),
+                message,
+                accepted,
+            })
+            .await
+            .map_err(|_| BrokerError::Stopped)?;
+        match reply {
+            Some(reply) => match reply.await {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(Refused::QueuesFull)) => Err(BrokerError::QueuesFull),
+                Ok(Err(Refused::NotStored)) => Err(BrokerError::NotStored),
+                Err(_) => Err(BrokerError::Stopped),
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Subscribes to `topic_filter`. Messages keep the QoS they were published with.
+    pub async fn subscribe<T: Into<String>>(&self, topic_filter: T) -> Result<Subscription, BrokerError> {
+        let topic_filter = topic_filter.into();
+        if !is_valid_topic_filter(&topic_filter) {
+            return Err(BrokerError::InvalidTopic);
+        }
+        if !self
+            .config
+            .acl
+            .as_ref()
+            .is_none_or(|acl| acl.allows_subscribe(&self.identity(), &topic_filter))
+        {
+            return Err(BrokerError::NotAuthorized);
+        }
+        let (sender, receiver) = mpsc::channel(self.config.client_channel_capacity.max(1));
+        self.broker
+            .send(BrokerMessage::LocalSubscribe { topic_filter, sender })
+            .await
+            .map_err(|_| BrokerError::Stopped)?;
+        Ok(Subscription { receiver })
+    }
+
+    /// Stops accepting connections, closes every connection with `ServerShuttingDown` and
+    /// resolves once the clients are gone, or `BrokerConfig::shutdown_timeout` passed, and the
+    /// broker has stopped. Later calls resolve right away.
+    pub async fn shutdown(&self) {
+        let _ = self.shutdown.send(true);
+        if let Some(server) = self.server.lock().await.take() {
+            let _ = server.await;
+        }
+    }
+
+    fn identity(&self) -> Identity<'_> {
+        Identity {
+            client_id: &self.client_id,
+            user_name: self.user_name.as_deref(),
+        }
+    }
+}
+
+/// Resolves once `shutdown` turns true, or when nothing can signal a shutdown anymore.
+pub(crate) async fn stopping(shutdown: &mut watch::Receiver<bool>) {
+    // Dropping the guard `wait_for` returns here keeps it out of the callers' futures.
+    let _ = shutdown.wait_for(|stopping| *stopping).await;
+}
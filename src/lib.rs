@@ -1,14 +1,49 @@
+pub mod acl;
+mod admin;
+pub mod auth;
+pub mod ban;
+pub mod blocking;
+mod broker;
 mod client;
+pub mod config;
 mod definitions;
 mod frame;
+mod handle;
+mod http;
+pub mod logging;
+mod message;
+mod metrics;
 mod packet;
+mod persistence;
+mod publisher;
+mod queue;
+mod ratelimit;
+pub mod redirect;
+mod request;
 mod server;
+mod stats;
+pub mod store;
+mod subscriber;
 pub mod topic;
 extern crate strum;
 extern crate strum_macros;
 
-pub async fn start_broker() -> Result<(), Box<dyn std::error::Error>> {
-    server::MqttServer::start().await
+pub use config::BrokerConfig;
+pub use definitions::{ConnAckReasonCode, DisconnectReasonCode, Property, Qos};
+pub use handle::{BrokerError, BrokerHandle, Subscription};
+pub use message::Message;
+pub use publisher::{ClientError, ConnectOptions, EventLoop, MqttClient, OverflowPolicy, ReconnectOptions, Will};
+pub use request::Response;
+pub use subscriber::MessageStream;
+
+/// Starts a broker with the default configuration. It runs in the background until the returned
+/// handle shuts it down.
+pub async fn start_broker() -> Result<BrokerHandle, Box<dyn std::error::Error>> {
+    start_broker_with_config(BrokerConfig::default()).await
+}
+
+pub async fn start_broker_with_config(config: BrokerConfig) -> Result<BrokerHandle, Box<dyn std::error::Error>> {
+    server::MqttServer::start(config).await
 }
 
 #[cfg(test)]
@@ -16,6 +51,13 @@ mod tests {
     use super::*;
     #[tokio::test]
     async fn simple_mqtt_server_test() {
-        start_broker().await.expect("my function");
+        let config = BrokerConfig {
+            bind_address: String::from("127.0.0.1:0"),
+            ..Default::default()
+        };
+        let broker = start_broker_with_config(config).await.expect("my function");
+        tokio::net::TcpStream::connect(broker.local_addr()).await.unwrap();
+        broker.shutdown().await;
+        assert!(tokio::net::TcpStream::connect(broker.local_addr()).await.is_err());
     }
 }
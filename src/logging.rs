@@ -0,0 +1,134 @@
+//! Log output of the broker. Everything is emitted through `tracing`: each connection runs in
+//! a `connection` span carrying its peer address, client identifier and protocol version,
+//! connections and disconnections are logged at `info`, packets at `debug`, and whole packets
+//! at `trace` unless `BrokerConfig::redact_payloads` is set.
+
+use std::error::Error;
+use tracing::Subscriber;
+use tracing_subscriber::{fmt::MakeWriter, EnvFilter};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields of the event and of its spans.
+    Json,
+}
+
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// Which events to keep, in `RUST_LOG` syntax, e.g. `info` or `mt_mqtt=debug`.
+    pub filter: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            format: LogFormat::Text,
+            filter: String::from("info"),
+        }
+    }
+}
+
+impl LogConfig {
+    /// Reads the filter from `RUST_LOG` and the format from `MT_MQTT_LOG_FORMAT` (`text` or
+    /// `json`), falling back to the defaults.
+    pub fn from_env() -> LogConfig {
+        let mut config = LogConfig::default();
+        if let Ok(filter) = std::env::var("RUST_LOG") {
+            config.filter = filter;
+        }
+        if std::env::var("MT_MQTT_LOG_FORMAT").is_ok_and(|format| format.eq_ignore_ascii_case("json")) {
+            config.format = LogFormat::Json;
+        }
+        config
+    }
+}
+
+/// Installs the process-wide subscriber writing to standard output. Fails if the filter is
+/// invalid or a subscriber is already installed.
+pub fn init(config: &LogConfig) -> Result<(), Box<dyn Error + Send + Sync>> {
+    tracing::subscriber::set_global_default(subscriber(config, std::io::stdout)?)?;
+    Ok(())
+}
+
+pub(crate) fn subscriber<W>(config: &LogConfig, writer: W) -> Result<Box<dyn Subscriber + Send + Sync>, Box<dyn Error + Send + Sync>>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_new(&config.filter)?)
+        .with_writer(writer);
+    Ok(match config.format {
+        LogFormat::Text => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(builder.json().with_current_span(true).with_span_list(true).finish()),
+    })
+}
+
+/// Collects the output of a subscriber, for tests.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct CapturedLogs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl CapturedLogs {
+    pub(crate) fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl<'a> MakeWriter<'a> for CapturedLogs {
+    type Writer = CapturedLogs;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_lines_carry_event_and_span_fields() {
+        let logs = CapturedLogs::default();
+        let config = LogConfig {
+            format: LogFormat::Json,
+            filter: String::from("debug"),
+        };
+        tracing::subscriber::with_default(subscriber(&config, logs.clone()).unwrap(), || {
+            let span = tracing::info_span!("connection", client_id = "probe");
+            let _entered = span.enter();
+            tracing::debug!(packet = "PINGREQ", "received");
+            tracing::trace!("filtered out");
+        });
+        let lines: Vec<serde_json::Value> = logs.contents().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["fields"]["packet"], "PINGREQ");
+        assert_eq!(lines[0]["span"]["client_id"], "probe");
+    }
+
+    #[test]
+    fn invalid_filter_is_an_error() {
+        let config = LogConfig {
+            filter: String::from("mt_mqtt=loud"),
+            ..Default::default()
+        };
+        assert!(subscriber(&config, std::io::sink).is_err());
+    }
+}
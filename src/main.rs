@@ -0,0 +1,31 @@
+use mt_mqtt::logging::{self, LogConfig};
+use tracing::info;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    if let Err(err) = logging::init(&LogConfig::from_env()) {
+        eprintln!("could not set up logging: {}", err);
+    }
+    let broker = mt_mqtt::start_broker().await?;
+    shutdown_signal().await?;
+    info!("shutdown requested");
+    broker.shutdown().await;
+    Ok(())
+}
+
+/// Waits for SIGINT (Ctrl-C) or, on Unix, SIGTERM.
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await
+    }
+}
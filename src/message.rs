@@ -0,0 +1,108 @@
+use crate::{definitions::*, frame::*};
+use bytes::Bytes;
+use num_traits::FromPrimitive;
+use std::io::{self, Cursor};
+
+/// An application message as it travels between clients and the broker, detached from the
+/// PUBLISH packet it arrived in.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Bytes,
+    pub qos: Qos,
+    pub retain: bool,
+    pub properties: Vec<Option<Property>>,
+}
+
+impl Message {
+    pub(crate) fn from_publish(control_packet: PublishControlPacket, flags: &Flags) -> Message {
+        Message {
+            topic: control_packet.variable_header.topic_name.clone(),
+            properties: control_packet
+                .variable_header
+                .get_properties()
+                .into_iter()
+                // Topic aliases only make sense on the connection that set them, and Subscription
+                // Identifiers on the subscription that matched; the broker adds its own on delivery.
+                .filter(|property| !matches!(property, Some(Property::TopicAlias(_)) | Some(Property::SubscriptionIdentifier(_))))
+                .collect(),
+            payload: control_packet.payload.data,
+            qos: Qos::from_u8(flags.1).unwrap_or_default(),
+            retain: flags.0 == 1,
+        }
+    }
+
+    /// Builds the Will Message announced in a CONNECT packet, if any. Fails when the will has
+    /// properties a message cannot carry.
+    pub(crate) fn from_will(connect_flag: &ConnectFlags, payload: &ConnectPayload) -> Result<Option<Message>, String> {
+        if !connect_flag.will_flag {
+            return Ok(None);
+        }
+        let properties: Vec<Option<Property>> = payload
+            .will_properties
+            .iter()
+            // The Will Delay Interval is about when to publish, not part of the message.
+            .filter(|property| !matches!(property, Some(Property::WillDelayInterval(_))))
+            .cloned()
+            .collect();
+        Message::check_properties(properties.iter().flatten())?;
+        Ok(Some(Message {
+            topic: payload.will_topic.clone().unwrap_or_default(),
+            payload: payload.will_payload.clone().unwrap_or_default(),
+            qos: Qos::from_u8(connect_flag.will_qos).unwrap_or_default(),
+            retain: connect_flag.will_retain,
+            properties,
+        }))
+    }
+
+    /// Checks that `properties` can travel with a message: the ones PUBLISH carries, less the
+    /// Topic Alias and Subscription Identifier, which belong to a connection.
+    pub(crate) fn check_properties<'a, I: IntoIterator<Item = &'a Property>>(properties: I) -> Result<(), String> {
+        let not_allowed = properties.into_iter().find(|property| {
+            !matches!(
+                property,
+                Property::PayloadFormatIndicator(_)
+                    | Property::MessageExpiryInterval(_)
+                    | Property::ContentType(_)
+                    | Property::ResponseTopic(_)
+                    | Property::CorrelationData(_)
+                    | Property::UserProperty(_)
+            )
+        });
+        match not_allowed {
+            Some(property) => Err(format!("{} property not allowed in a message", property)),
+            None => Ok(()),
+        }
+    }
+
+    /// Builds the PUBLISH frame carrying this message, which fails when the message has
+    /// properties PUBLISH does not carry.
+    pub(crate) fn to_frame(&self, packet_identifier: Option<u16>, dup: bool) -> Result<Frame, Error> {
+        Ok(Frame {
+            fix_header: FixHeader::new(ControlPacketType::PUBLISH, Flags(self.retain as u8, self.qos as u8, 0, dup as u8)),
+            control_packet: ControlPacket::Publish(PublishControlPacket {
+                variable_header: PublishVariableHeader::from(self.topic.clone(), packet_identifier, self.properties.clone())?,
+                payload: PublishPayload { data: self.payload.clone() },
+            }),
+        })
+    }
+
+    /// Encodes the message as a PUBLISH packet, the form it is stored on disk in. QoS 1/2
+    /// messages need a packet identifier, stored with them.
+    pub(crate) fn to_bytes(&self, packet_identifier: Option<u16>) -> io::Result<Vec<u8>> {
+        let publish = self
+            .to_frame(packet_identifier, false)
+            .and_then(Frame::serialize)
+            .map_err(|err| io::Error::other(err.to_string()))?;
+        Ok(publish.to_vec())
+    }
+
+    /// Decodes a message encoded by `to_bytes`, `None` if `bytes` is not a PUBLISH packet.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Message> {
+        let frame = Frame::deserialize(&mut Cursor::new(bytes)).ok()?;
+        match frame.control_packet {
+            ControlPacket::Publish(control_packet) => Some(Message::from_publish(control_packet, &frame.fix_header.flags)),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! The `/metrics` endpoint, exposing `Stats` in the Prometheus text format.
+
+use crate::{
+    definitions::ControlPacketType,
+    http::{self, Request, Response},
+    stats::Stats,
+};
+use num_traits::FromPrimitive;
+use std::{
+    fmt::Write,
+    io,
+    sync::{atomic::Ordering, Arc},
+};
+use tokio::net::TcpListener;
+
+/// Serves the metrics of `stats` on `listener` until it fails.
+pub async fn serve(listener: TcpListener, stats: Arc<Stats>) -> io::Result<()> {
+    http::serve(listener, move |request: Request| {
+        let stats = stats.clone();
+        async move {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/metrics") => Response::new(200, "text/plain; version=0.0.4", render(&stats)),
+                _ => Response::not_found(),
+            }
+        }
+    })
+    .await
+}
+
+pub fn render(stats: &Stats) -> String {
+    let mut out = String::new();
+    let load = |counter: &std::sync::atomic::AtomicU64| counter.load(Ordering::Relaxed);
+    let load_gauge = |gauge: &std::sync::atomic::AtomicI64| gauge.load(Ordering::Relaxed);
+
+    metric(&mut out, "mqtt_uptime_seconds", "gauge", "Seconds since the broker started.");
+    let _ = writeln!(out, "mqtt_uptime_seconds {}", stats.started_at.elapsed().as_secs());
+    metric(&mut out, "mqtt_connections_total", "counter", "Connections accepted.");
+    let _ = writeln!(out, "mqtt_connections_total {}", load(&stats.connections_accepted));
+    metric(&mut out, "mqtt_connections_active", "gauge", "Connections currently open.");
+    let _ = writeln!(out, "mqtt_connections_active {}", load_gauge(&stats.connections_active));
+
+    for (name, help, counters) in [
+        (
+            "mqtt_packets_received_total",
+            "Control packets read from clients.",
+            &stats.packets_received,
+        ),
+        ("mqtt_packets_sent_total", "Control packets written to clients.", &stats.packets_sent),
+    ] {
+        metric(&mut out, name, "counter", help);
+        for (index, counter) in counters.iter().enumerate() {
+            if let Some(control_packet_type) = ControlPacketType::from_usize(index) {
+                let _ = writeln!(out, "{}{{type=\"{:?}\"}} {}", name, control_packet_type, load(counter));
+            }
+        }
+    }
+
+    for (name, help, counter) in [
+        (
+            "mqtt_messages_received_total",
+            "Application messages published by clients.",
+            &stats.messages_received,
+        ),
+        (
+            "mqtt_messages_sent_total",
+            "Application messages delivered to clients.",
+            &stats.messages_sent,
+        ),
+        ("mqtt_bytes_received_total", "Bytes read from clients.", &stats.bytes_received),
+        ("mqtt_bytes_sent_total", "Bytes written to clients.", &stats.bytes_sent),
+        (
+            "mqtt_messages_dropped_total",
+            "Messages dropped or refused by full offline queues, or expired in one.",
+            &stats.dropped,
+        ),
+        (
+            "mqtt_slow_consumer_dropped_total",
+            "QoS 0 messages dropped for clients too slow to take them.",
+            &stats.slow_consumer_dropped,
+        ),
+        (
+            "mqtt_rate_limited_total",
+            "Messages refused or dropped because their publisher exceeded its rate limit.",
+            &stats.rate_limited,
+        ),
+        (
+            "mqtt_connections_rate_limited_total",
+            "Connections refused for exceeding the accept rate limits.",
+            &stats.connections_rate_limited,
+        ),
+        (
+            "mqtt_connections_banned_total",
+            "Connections refused because their address, client identifier or user name is banned.",
+            &stats.connections_banned,
+        ),
+    ] {
+        metric(&mut out, name, "counter", help);
+        let _ = writeln!(out, "{} {}", name, load(counter));
+    }
+
+    for (name, help, gauge) in [
+        (
+            "mqtt_messages_in_flight",
+            "QoS 1 and 2 messages waiting for an acknowledgement.",
+            &stats.in_flight,
+        ),
+        ("mqtt_messages_queued", "Messages queued for offline or busy clients.", &stats.queued),
+        ("mqtt_retained_messages", "Retained messages held by the broker.", &stats.retained),
+        (
+            "mqtt_slow_consumers",
+            "Clients whose messages are held back because they do not read them fast enough.",
+            &stats.slow_consumers,
+        ),
+    ] {
+        metric(&mut out, name, "gauge", help);
+        let _ = writeln!(out, "{} {}", name, load_gauge(gauge));
+    }
+
+    metric(
+        &mut out,
+        "mqtt_disconnects_total",
+        "counter",
+        "Closed connections by initiator and reason code.",
+    );
+    for ((initiator, reason), count) in stats.disconnects.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "mqtt_disconnects_total{{initiator=\"{}\",reason=\"{}\"}} {}",
+            initiator, reason, count
+        );
+    }
+    metric(&mut out, "mqtt_auth_failures_total", "counter", "Refused authentications by reason code.");
+    for (reason, count) in stats.auth_failures.lock().unwrap().iter() {
+        let _ = writeln!(out, "mqtt_auth_failures_total{{reason=\"{}\"}} {}", reason, count);
+    }
+
+    let histogram = &stats.routing_latency;
+    metric(
+        &mut out,
+        "mqtt_routing_latency_seconds",
+        "histogram",
+        "Time spent routing a published message to its subscribers.",
+    );
+    let mut cumulative = 0;
+    for (index, bucket) in histogram.buckets.iter().enumerate() {
+        cumulative += load(bucket);
+        let bound = histogram.bounds.get(index).map_or(String::from("+Inf"), |bound| bound.to_string());
+        let _ = writeln!(out, "mqtt_routing_latency_seconds_bucket{{le=\"{}\"}} {}", bound, cumulative);
+    }
+    let sum = load(&histogram.sum_nanos) as f64 / 1e9;
+    let _ = writeln!(out, "mqtt_routing_latency_seconds_sum {}", sum);
+    let _ = writeln!(out, "mqtt_routing_latency_seconds_count {}", cumulative);
+    out
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn endpoint_exposes_counters_and_histograms() {
+        let stats = Arc::new(Stats::new());
+        Stats::count_packet(&stats.packets_received, ControlPacketType::PUBLISH);
+        stats.count_disconnect("server", String::from("KeepAliveTimeout"));
+        stats.routing_latency.observe(Duration::from_micros(30));
+        stats.routing_latency.observe(Duration::from_secs(1));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, stats));
+        let (status, body) = http::request(addr, "GET", "/metrics", "").await;
+        assert_eq!(status, 200);
+        for line in [
+            "mqtt_packets_received_total{type=\"PUBLISH\"} 1",
+            "mqtt_packets_received_total{type=\"CONNECT\"} 0",
+            "mqtt_disconnects_total{initiator=\"server\",reason=\"KeepAliveTimeout\"} 1",
+            "mqtt_routing_latency_seconds_bucket{le=\"0.000025\"} 0",
+            "mqtt_routing_latency_seconds_bucket{le=\"0.00005\"} 1",
+            "mqtt_routing_latency_seconds_bucket{le=\"+Inf\"} 2",
+            "mqtt_routing_latency_seconds_count 2",
+        ] {
+            assert!(body.lines().any(|body_line| body_line == line), "missing {:?} in\n{}", line, body);
+        }
+        assert_eq!(http::request(addr, "GET", "/", "").await.0, 404);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::definitions::*;
+use crate::{definitions::*, frame::Error};
 use bytes::Bytes;
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
@@ -10,15 +10,29 @@ pub struct Properties {
     pub properties: HashMap<String, Option<Property>>,
 }
 impl Properties {
-    pub fn set_properties(&mut self, property: Option<Property>) {
+    /// Sets `property`, failing when the packet does not carry properties of its kind.
+    pub fn set_properties(&mut self, property: Option<Property>) -> Result<(), Error> {
         if let Some(property) = property {
-            *(self.properties.get_mut(&property.to_string()).unwrap()) = Some(property.clone());
+            match self.properties.get_mut(&property.to_string()) {
+                Some(slot) => *slot = Some(property),
+                None => return Err(Error::Other(format!("{} property not allowed in this packet", property))),
+            }
         }
+        Ok(())
     }
 
-    pub fn set_properties_vec(&mut self, properties: Vec<Option<Property>>) {
+    pub fn set_properties_vec(&mut self, properties: Vec<Option<Property>>) -> Result<(), Error> {
         for property in properties {
-            self.set_properties(property);
+            self.set_properties(property)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the properties a packet is built with by this crate, leaving out the ones the
+    /// packet does not carry.
+    fn set_own(&mut self, properties: Vec<Option<Property>>) {
+        for property in properties {
+            let _ = self.set_properties(property);
         }
     }
 }
@@ -42,6 +56,15 @@ impl ConnectFlags {
             user_name_flag: (byte & 0b1000_0000) != 0,
         }
     }
+
+    pub fn to_byte(&self) -> u8 {
+        (self.clean_start as u8) << 1
+            | (self.will_flag as u8) << 2
+            | (self.will_qos & 0b11) << 3
+            | (self.will_retain as u8) << 5
+            | (self.password_flag as u8) << 6
+            | (self.user_name_flag as u8) << 7
+    }
 }
 #[derive(Debug, Default)]
 pub struct ConnectVariableHeader {
@@ -120,16 +143,17 @@ impl PublishVariableHeader {
         }
     }
 
-    pub fn from(topic_name: String, packet_identifier: Option<u16>, _properties: Vec<Option<Property>>) -> Self {
+    /// Fails on properties PUBLISH does not carry, which application messages may come with.
+    pub fn from(topic_name: String, packet_identifier: Option<u16>, _properties: Vec<Option<Property>>) -> Result<Self, Error> {
         let mut publish_variable_header = Self::new();
-        publish_variable_header.set_properties(_properties);
+        publish_variable_header.set_properties(_properties)?;
         publish_variable_header.topic_name = topic_name;
         publish_variable_header.packet_identifier = packet_identifier;
-        publish_variable_header
+        Ok(publish_variable_header)
     }
 
-    pub fn set_properties(&mut self, _properties: Vec<Option<Property>>) {
-        self.properties.set_properties_vec(_properties);
+    pub fn set_properties(&mut self, _properties: Vec<Option<Property>>) -> Result<(), Error> {
+        self.properties.set_properties_vec(_properties)
     }
 
     pub fn get_properties(&self) -> Vec<Option<Property>> {
@@ -166,14 +190,14 @@ impl PubAckVariableHeader {
 
     pub fn from(packet_identifier: u16, reason_code: PubAckReasonCode, _properties: Vec<Option<Property>>) -> Self {
         let mut pub_ack_variable_header = Self::new();
-        pub_ack_variable_header.set_properties(_properties);
+        pub_ack_variable_header.properties.set_own(_properties);
         pub_ack_variable_header.packet_identifier = packet_identifier;
         pub_ack_variable_header.reason_code = reason_code;
         pub_ack_variable_header
     }
 
-    pub fn set_properties(&mut self, _properties: Vec<Option<Property>>) {
-        self.properties.set_properties_vec(_properties);
+    pub fn set_properties(&mut self, _properties: Vec<Option<Property>>) -> Result<(), Error> {
+        self.properties.set_properties_vec(_properties)
     }
 
     pub fn get_properties(&self) -> Vec<Option<Property>> {
@@ -210,14 +234,14 @@ impl PubRecVariableHeader {
 
     pub fn from(packet_identifier: u16, reason_code: PubRecReasonCode, _properties: Vec<Option<Property>>) -> Self {
         let mut pub_ack_variable_header = Self::new();
-        pub_ack_variable_header.set_properties(_properties);
+        pub_ack_variable_header.properties.set_own(_properties);
         pub_ack_variable_header.packet_identifier = packet_identifier;
         pub_ack_variable_header.reason_code = reason_code;
         pub_ack_variable_header
     }
 
-    pub fn set_properties(&mut self, _properties: Vec<Option<Property>>) {
-        self.properties.set_properties_vec(_properties);
+    pub fn set_properties(&mut self, _properties: Vec<Option<Property>>) -> Result<(), Error> {
+        self.properties.set_properties_vec(_properties)
     }
 
     pub fn get_properties(&self) -> Vec<Option<Property>> {
@@ -254,14 +278,14 @@ impl PubRelVariableHeader {
 
     pub fn from(packet_identifier: u16, reason_code: PubRelReasonCode, _properties: Vec<Option<Property>>) -> Self {
         let mut pub_ack_variable_header = Self::new();
-        pub_ack_variable_header.set_properties(_properties);
+        pub_ack_variable_header.properties.set_own(_properties);
         pub_ack_variable_header.packet_identifier = packet_identifier;
         pub_ack_variable_header.reason_code = reason_code;
         pub_ack_variable_header
     }
 
-    pub fn set_properties(&mut self, _properties: Vec<Option<Property>>) {
-        self.properties.set_properties_vec(_properties);
+    pub fn set_properties(&mut self, _properties: Vec<Option<Property>>) -> Result<(), Error> {
+        self.properties.set_properties_vec(_properties)
     }
 
     pub fn get_properties(&self) -> Vec<Option<Property>> {
@@ -298,14 +322,14 @@ impl PubCompVariableHeader {
 
     pub fn from(packet_identifier: u16, reason_code: PubCompReasonCode, _properties: Vec<Option<Property>>) -> Self {
         let mut pub_ack_variable_header = Self::new();
-        pub_ack_variable_header.set_properties(_properties);
+        pub_ack_variable_header.properties.set_own(_properties);
         pub_ack_variable_header.packet_identifier = packet_identifier;
         pub_ack_variable_header.reason_code = reason_code;
         pub_ack_variable_header
     }
 
-    pub fn set_properties(&mut self, _properties: Vec<Option<Property>>) {
-        self.properties.set_properties_vec(_properties);
+    pub fn set_properties(&mut self, _properties: Vec<Option<Property>>) -> Result<(), Error> {
+        self.properties.set_properties_vec(_properties)
     }
 
     pub fn get_properties(&self) -> Vec<Option<Property>> {
@@ -322,18 +346,14 @@ pub struct SubscribeControlPacket {
     pub variable_header: SubscribeVariableHeader,
 }
 #[repr(u8)]
-#[derive(Display, Debug, Clone, FromPrimitive)]
+#[derive(Display, Debug, Default, Clone, FromPrimitive)]
 pub enum RetainHandlingOption {
+    #[default]
     SendRetainedMessageSubTime = 0,
     SendRetainedMessageSubNotExist = 1,
     NotSendRetainedMessage = 2,
 }
-impl Default for RetainHandlingOption {
-    fn default() -> Self {
-        Self::SendRetainedMessageSubTime
-    }
-}
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct SubscriptionOptions {
     pub maximum_qos: Qos,
     pub no_local_option: bool,
@@ -351,6 +371,10 @@ impl SubscriptionOptions {
             reserved: (byte & 0b1100_0000) >> 6,
         }
     }
+
+    pub fn to_byte(&self) -> u8 {
+        self.maximum_qos as u8 | (self.no_local_option as u8) << 2 | (self.retain_as_published as u8) << 3 | (self.retain_handling.clone() as u8) << 4
+    }
 }
 #[derive(Debug, Default)]
 pub struct SubscribePayload {
@@ -378,14 +402,14 @@ impl SubscribeVariableHeader {
 
     pub fn from(packet_identifier: u16, subscribe_payload: Vec<SubscribePayload>, _properties: Vec<Option<Property>>) -> Self {
         let mut pub_ack_variable_header = Self::new();
-        pub_ack_variable_header.set_properties(_properties);
+        pub_ack_variable_header.properties.set_own(_properties);
         pub_ack_variable_header.packet_identifier = packet_identifier;
         pub_ack_variable_header.subscribe_payload = subscribe_payload;
         pub_ack_variable_header
     }
 
-    pub fn set_properties(&mut self, _properties: Vec<Option<Property>>) {
-        self.properties.set_properties_vec(_properties);
+    pub fn set_properties(&mut self, _properties: Vec<Option<Property>>) -> Result<(), Error> {
+        self.properties.set_properties_vec(_properties)
     }
 
     pub fn get_properties(&self) -> Vec<Option<Property>> {
@@ -426,14 +450,14 @@ impl SubAckVariableHeader {
 
     pub fn from(packet_identifier: u16, sub_ack_payload: SubAckPayload, _properties: Vec<Option<Property>>) -> Self {
         let mut pub_ack_variable_header = Self::new();
-        pub_ack_variable_header.set_properties(_properties);
+        pub_ack_variable_header.properties.set_own(_properties);
         pub_ack_variable_header.packet_identifier = packet_identifier;
         pub_ack_variable_header.sub_ack_payload = sub_ack_payload;
         pub_ack_variable_header
     }
 
-    pub fn set_properties(&mut self, _properties: Vec<Option<Property>>) {
-        self.properties.set_properties_vec(_properties);
+    pub fn set_properties(&mut self, _properties: Vec<Option<Property>>) -> Result<(), Error> {
+        self.properties.set_properties_vec(_properties)
     }
 
     pub fn get_properties(&self) -> Vec<Option<Property>> {
@@ -455,6 +479,7 @@ pub struct UnsubscribePayload {
 }
 #[derive(Debug)]
 pub struct UnsubscribeVariableHeader {
+    pub packet_identifier: u16,
     pub unsubscribe_payload: UnsubscribePayload,
     properties: Properties,
 }
@@ -464,20 +489,22 @@ impl UnsubscribeVariableHeader {
         properties_map.insert(Property::UserProperty(String::from("")).to_string(), None);
         let properties = Properties { properties: properties_map };
         Self {
+            packet_identifier: 0,
             unsubscribe_payload: UnsubscribePayload::default(),
             properties,
         }
     }
 
-    pub fn from(unsubscribe_payload: UnsubscribePayload, _properties: Vec<Option<Property>>) -> Self {
+    pub fn from(packet_identifier: u16, unsubscribe_payload: UnsubscribePayload, _properties: Vec<Option<Property>>) -> Self {
         let mut pub_ack_variable_header = Self::new();
-        pub_ack_variable_header.set_properties(_properties);
+        pub_ack_variable_header.properties.set_own(_properties);
+        pub_ack_variable_header.packet_identifier = packet_identifier;
         pub_ack_variable_header.unsubscribe_payload = unsubscribe_payload;
         pub_ack_variable_header
     }
 
-    pub fn set_properties(&mut self, _properties: Vec<Option<Property>>) {
-        self.properties.set_properties_vec(_properties);
+    pub fn set_properties(&mut self, _properties: Vec<Option<Property>>) -> Result<(), Error> {
+        self.properties.set_properties_vec(_properties)
     }
 
     pub fn get_properties(&self) -> Vec<Option<Property>> {
@@ -499,6 +526,7 @@ pub struct UnsubAckPayload {
 }
 #[derive(Debug)]
 pub struct UnsubAckVariableHeader {
+    pub packet_identifier: u16,
     pub unsub_ack_payload: UnsubAckPayload,
     properties: Properties,
 }
@@ -509,20 +537,22 @@ impl UnsubAckVariableHeader {
         properties_map.insert(Property::UserProperty(String::from("")).to_string(), None);
         let properties = Properties { properties: properties_map };
         Self {
+            packet_identifier: 0,
             unsub_ack_payload: UnsubAckPayload::default(),
             properties,
         }
     }
 
-    pub fn from(unsub_ack_payload: UnsubAckPayload, _properties: Vec<Option<Property>>) -> Self {
+    pub fn from(packet_identifier: u16, unsub_ack_payload: UnsubAckPayload, _properties: Vec<Option<Property>>) -> Self {
         let mut pub_ack_variable_header = Self::new();
-        pub_ack_variable_header.set_properties(_properties);
+        pub_ack_variable_header.properties.set_own(_properties);
+        pub_ack_variable_header.packet_identifier = packet_identifier;
         pub_ack_variable_header.unsub_ack_payload = unsub_ack_payload;
         pub_ack_variable_header
     }
 
-    pub fn set_properties(&mut self, _properties: Vec<Option<Property>>) {
-        self.properties.set_properties_vec(_properties);
+    pub fn set_properties(&mut self, _properties: Vec<Option<Property>>) -> Result<(), Error> {
+        self.properties.set_properties_vec(_properties)
     }
 
     pub fn get_properties(&self) -> Vec<Option<Property>> {
@@ -559,13 +589,13 @@ impl DisconnectVariableHeader {
 
     pub fn from(disconnect_reason_code: DisconnectReasonCode, _properties: Vec<Option<Property>>) -> Self {
         let mut pub_ack_variable_header = Self::new();
-        pub_ack_variable_header.set_properties(_properties);
+        pub_ack_variable_header.properties.set_own(_properties);
         pub_ack_variable_header.disconnect_reason_code = disconnect_reason_code;
         pub_ack_variable_header
     }
 
-    pub fn set_properties(&mut self, _properties: Vec<Option<Property>>) {
-        self.properties.set_properties_vec(_properties);
+    pub fn set_properties(&mut self, _properties: Vec<Option<Property>>) -> Result<(), Error> {
+        self.properties.set_properties_vec(_properties)
     }
 
     pub fn get_properties(&self) -> Vec<Option<Property>> {
@@ -591,7 +621,7 @@ impl AuthVariableHeader {
         let mut properties_map = HashMap::new();
         properties_map.insert(Property::AuthenticationMethod(String::from("")).to_string(), None);
         properties_map.insert(Property::AuthenticationData(Bytes::new()).to_string(), None);
-        properties_map.insert(Property::ResponseInformation(String::from("")).to_string(), None);
+        properties_map.insert(Property::ReasonString(String::from("")).to_string(), None);
         properties_map.insert(Property::UserProperty(String::from("")).to_string(), None);
         let properties = Properties { properties: properties_map };
         Self {
@@ -602,13 +632,13 @@ impl AuthVariableHeader {
 
     pub fn from(auth_reason_code: AuthReasonCode, _properties: Vec<Option<Property>>) -> Self {
         let mut pub_ack_variable_header = Self::new();
-        pub_ack_variable_header.set_properties(_properties);
+        pub_ack_variable_header.properties.set_own(_properties);
         pub_ack_variable_header.auth_reason_code = auth_reason_code;
         pub_ack_variable_header
     }
 
-    pub fn set_properties(&mut self, _properties: Vec<Option<Property>>) {
-        self.properties.set_properties_vec(_properties);
+    pub fn set_properties(&mut self, _properties: Vec<Option<Property>>) -> Result<(), Error> {
+        self.properties.set_properties_vec(_properties)
     }
 
     pub fn get_properties(&self) -> Vec<Option<Property>> {
@@ -0,0 +1,748 @@
+//! Durable broker state: every change to the retained messages and the sessions is appended to
+//! a write-ahead log, which a snapshot of the whole state replaces once it grows long. On
+//! startup the snapshot is loaded and the log replayed on top of it.
+//!
+//! The directory holds `snapshot`, the state at some point headed by its generation, and
+//! `wal.<generation>`, the changes made since. Taking a snapshot starts a new generation: its
+//! log is created empty, the snapshot written aside and renamed over the previous one, and only
+//! then the previous log removed, so whenever the broker dies the snapshot on disk comes with
+//! the log that follows it.
+//!
+//! A thread of its own writes and syncs the records. Each is framed by its length and checksum,
+//! so that a last record cut short by a crash, which is dropped, is told apart from a damaged
+//! one, which stops the broker from starting.
+
+use crate::{
+    config::{FsyncPolicy, PersistenceConfig},
+    message::Message,
+    packet::SubscriptionOptions,
+    queue::{OfflineQueue, Queued},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryInto,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+const SNAPSHOT: &str = "snapshot";
+
+/// A change to the broker state.
+#[derive(Debug)]
+pub(crate) enum Record {
+    Retain(Message),
+    ClearRetained(String),
+    /// Creates the session of a client or updates its Session Expiry Interval.
+    Session {
+        client_id: String,
+        session_expiry_interval: u32,
+    },
+    RemoveSession(String),
+    Subscribe {
+        client_id: String,
+        topic_filter: String,
+        subscription_options: SubscriptionOptions,
+    },
+    Unsubscribe {
+        client_id: String,
+        topic_filter: String,
+    },
+    /// A message waits in the session of a client that is away.
+    Enqueue {
+        client_id: String,
+        queued: Queued,
+    },
+    /// The `count` oldest messages waiting in a session left it, delivered or dropped.
+    Dequeue {
+        client_id: String,
+        count: u32,
+    },
+    /// The messages of a session expired at `now`, in seconds since the Unix epoch, are gone.
+    PurgeExpired {
+        client_id: String,
+        now: u64,
+    },
+    /// A QoS 1/2 message went to the connection of a client, it stays until `delivery` is
+    /// acknowledged.
+    Deliver {
+        client_id: String,
+        delivery: u64,
+        queued: Queued,
+    },
+    /// The client acknowledged `delivery`, or its connection ended and handed it back.
+    Acknowledge {
+        client_id: String,
+        delivery: u64,
+    },
+}
+
+/// A session as the log has it.
+#[derive(Debug, Default)]
+pub(crate) struct StoredSession {
+    pub(crate) session_expiry_interval: u32,
+    pub(crate) subscriptions: HashMap<String, SubscriptionOptions>,
+    pub(crate) queue: OfflineQueue,
+    // The messages sent to the client and not acknowledged yet, in the order they went out.
+    pub(crate) in_flight: BTreeMap<u64, Queued>,
+}
+
+/// The broker state the snapshot and the log add up to.
+#[derive(Debug, Default)]
+pub(crate) struct State {
+    pub(crate) retained: HashMap<String, Message>,
+    pub(crate) sessions: HashMap<String, StoredSession>,
+}
+
+impl State {
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Retain(message) => {
+                self.retained.insert(message.topic.clone(), message);
+            }
+            Record::ClearRetained(topic) => {
+                self.retained.remove(&topic);
+            }
+            Record::Session {
+                client_id,
+                session_expiry_interval,
+            } => self.sessions.entry(client_id).or_default().session_expiry_interval = session_expiry_interval,
+            Record::RemoveSession(client_id) => {
+                self.sessions.remove(&client_id);
+            }
+            Record::Subscribe {
+                client_id,
+                topic_filter,
+                subscription_options,
+            } => {
+                if let Some(session) = self.sessions.get_mut(&client_id) {
+                    session.subscriptions.insert(topic_filter, subscription_options);
+                }
+            }
+            Record::Unsubscribe { client_id, topic_filter } => {
+                if let Some(session) = self.sessions.get_mut(&client_id) {
+                    session.subscriptions.remove(&topic_filter);
+                }
+            }
+            Record::Enqueue { client_id, queued } => {
+                if let Some(session) = self.sessions.get_mut(&client_id) {
+                    session.queue.push_back(queued);
+                }
+            }
+            Record::Dequeue { client_id, count } => {
+                if let Some(session) = self.sessions.get_mut(&client_id) {
+                    for _ in 0..count {
+                        session.queue.pop_front();
+                    }
+                }
+            }
+            Record::PurgeExpired { client_id, now } => {
+                if let Some(session) = self.sessions.get_mut(&client_id) {
+                    session.queue.purge_expired(now);
+                }
+            }
+            Record::Deliver { client_id, delivery, queued } => {
+                if let Some(session) = self.sessions.get_mut(&client_id) {
+                    session.in_flight.insert(delivery, queued);
+                }
+            }
+            Record::Acknowledge { client_id, delivery } => {
+                if let Some(session) = self.sessions.get_mut(&client_id) {
+                    session.in_flight.remove(&delivery);
+                }
+            }
+        }
+    }
+}
+
+/// The write-ahead log as the broker task sees it. Records go to a thread of its own, which
+/// writes and syncs them without holding up the broker.
+#[derive(Debug)]
+pub(crate) struct Wal {
+    commands: mpsc::Sender<Command>,
+    thread: Option<thread::JoinHandle<()>>,
+    snapshot_after: usize,
+    // Records in the log since the last snapshot.
+    records: usize,
+    // An append failed, what follows it is not written until a snapshot starts over.
+    broken: Arc<AtomicBool>,
+}
+
+enum Command {
+    Append(Record),
+    Snapshot(Vec<Record>),
+    // Told whether the records sent before are written.
+    Confirm(Box<dyn FnOnce(bool) + Send>),
+}
+
+impl Wal {
+    /// Opens the persistence directory and recovers the state it holds. A record only partly
+    /// written when the broker died ends the log and is dropped, a damaged one fails.
+    pub(crate) fn open(config: &PersistenceConfig) -> io::Result<(Wal, State)> {
+        let (mut log, state, records) = Log::open(config)?;
+        let (commands, received) = mpsc::channel();
+        let broken = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let broken = broken.clone();
+            thread::Builder::new()
+                .name(String::from("wal-writer"))
+                .spawn(move || log.run(received, &broken))?
+        };
+        let wal = Wal {
+            commands,
+            thread: Some(thread),
+            snapshot_after: config.snapshot_after,
+            records,
+            broken,
+        };
+        Ok((wal, state))
+    }
+
+    pub(crate) fn append(&mut self, record: Record) {
+        let _ = self.commands.send(Command::Append(record));
+        self.records += 1;
+    }
+
+    /// Whether the log grew long enough to be replaced by a snapshot.
+    pub(crate) fn wants_snapshot(&self) -> bool {
+        self.records >= self.snapshot_after
+    }
+
+    /// Whether an append failed since the last snapshot.
+    pub(crate) fn is_broken(&self) -> bool {
+        self.broken.load(Ordering::Relaxed)
+    }
+
+    /// Replaces the log with a snapshot made of `records`, which describe the whole state.
+    pub(crate) fn snapshot(&mut self, records: Vec<Record>) {
+        let _ = self.commands.send(Command::Snapshot(records));
+        self.records = 0;
+    }
+
+    /// Calls `written` with whether the records appended so far are written, once they are.
+    pub(crate) fn confirm<F: FnOnce(bool) + Send + 'static>(&self, written: F) {
+        if let Err(mpsc::SendError(Command::Confirm(written))) = self.commands.send(Command::Confirm(Box::new(written))) {
+            written(false);
+        }
+    }
+
+    /// Waits for everything appended to be written and synced.
+    pub(crate) async fn close(mut self) {
+        drop(self.commands);
+        if let Some(thread) = self.thread.take() {
+            let _ = tokio::task::spawn_blocking(move || thread.join()).await;
+        }
+    }
+}
+
+/// The log of the current generation, written by the thread of the `Wal`.
+#[derive(Debug)]
+struct Log {
+    directory: PathBuf,
+    fsync: FsyncPolicy,
+    generation: u64,
+    file: File,
+    // Where the last record written in full ends.
+    len: u64,
+    synced: bool,
+}
+
+impl Log {
+    /// Opens the log and recovers the state, with how many records the log has.
+    fn open(config: &PersistenceConfig) -> io::Result<(Log, State, usize)> {
+        let directory = config.directory.clone();
+        fs::create_dir_all(&directory)?;
+        let mut state = State::default();
+        let generation = match read_if_exists(&directory.join(SNAPSHOT))? {
+            Some(snapshot) => {
+                let generation = snapshot.get(..8).and_then(|header| header.try_into().ok()).map(u64::from_be_bytes);
+                let generation = generation.ok_or_else(|| invalid_data("snapshot without a header"))?;
+                let (_, len) = replay(&snapshot[8..], &mut state)?;
+                if len != snapshot.len() - 8 {
+                    return Err(invalid_data("truncated snapshot"));
+                }
+                generation
+            }
+            None => 0,
+        };
+        let log_path = log_path(&directory, generation);
+        let log = read_if_exists(&log_path)?.unwrap_or_default();
+        let (records, len) = replay(&log, &mut state)?;
+        let file = OpenOptions::new().create(true).append(true).open(&log_path)?;
+        file.set_len(len as u64)?;
+        // Logs of other generations are left over from a snapshot the broker died taking.
+        for entry in fs::read_dir(&directory)? {
+            let path = entry?.path();
+            let is_log = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("wal."));
+            if is_log && path != log_path {
+                fs::remove_file(path)?;
+            }
+        }
+        let log = Log {
+            directory,
+            fsync: config.fsync,
+            generation,
+            file,
+            len: len as u64,
+            synced: true,
+        };
+        Ok((log, state, records))
+    }
+
+    /// Writes what the broker sends until it closes the `Wal`, then syncs.
+    fn run(&mut self, commands: mpsc::Receiver<Command>, broken: &AtomicBool) {
+        let sync_interval = match self.fsync {
+            FsyncPolicy::Interval(interval) => interval,
+            _ => Duration::MAX,
+        };
+        let mut last_sync = Instant::now();
+        loop {
+            let timeout = sync_interval.saturating_sub(last_sync.elapsed());
+            let result = match commands.recv_timeout(timeout) {
+                Ok(Command::Append(_)) if broken.load(Ordering::Relaxed) => Ok(()),
+                Ok(Command::Append(record)) => self.append(&record),
+                Ok(Command::Snapshot(records)) => self.snapshot(&records).map(|()| broken.store(false, Ordering::Relaxed)),
+                Ok(Command::Confirm(written)) => {
+                    written(!broken.load(Ordering::Relaxed));
+                    Ok(())
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    last_sync = Instant::now();
+                    self.sync()
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            };
+            if let Err(err) = result {
+                warn!(error = %err, "could not write the write-ahead log");
+                broken.store(true, Ordering::Relaxed);
+            }
+        }
+        if let Err(err) = self.sync() {
+            warn!(error = %err, "could not sync the write-ahead log");
+        }
+    }
+
+    fn append(&mut self, record: &Record) -> io::Result<()> {
+        let framed = frame(&encode(record)?);
+        if let Err(err) = self.file.write_all(&framed) {
+            // Leave no partial record for the next ones to follow.
+            let _ = self.file.set_len(self.len);
+            return Err(err);
+        }
+        self.len += framed.len() as u64;
+        self.synced = false;
+        if self.fsync == FsyncPolicy::Always {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the log with a snapshot made of `records`, which describe the whole state.
+    fn snapshot(&mut self, records: &[Record]) -> io::Result<()> {
+        let generation = self.generation + 1;
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(log_path(&self.directory, generation))?;
+        let mut snapshot = generation.to_be_bytes().to_vec();
+        for record in records {
+            snapshot.extend_from_slice(&frame(&encode(record)?));
+        }
+        let written = self.directory.join("snapshot.tmp");
+        let mut written_file = File::create(&written)?;
+        written_file.write_all(&snapshot)?;
+        written_file.sync_all()?;
+        fs::rename(&written, self.directory.join(SNAPSHOT))?;
+        // Make the rename itself durable, where directories can be synced.
+        if let Ok(directory) = File::open(&self.directory) {
+            let _ = directory.sync_all();
+        }
+        fs::remove_file(log_path(&self.directory, self.generation))?;
+        self.file = file;
+        self.generation = generation;
+        self.len = 0;
+        self.synced = true;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if !self.synced {
+            self.file.sync_data()?;
+            self.synced = true;
+        }
+        Ok(())
+    }
+}
+
+fn log_path(directory: &Path, generation: u64) -> PathBuf {
+    directory.join(format!("wal.{}", generation))
+}
+
+fn read_if_exists(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn invalid_data(error: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Applies the records of `buf` to `state`, returns how many there were and the length they
+/// took up to a last record cut short. A record that does not match its checksum, or does not
+/// decode, fails.
+fn replay(mut buf: &[u8], state: &mut State) -> io::Result<(usize, usize)> {
+    let (mut records, mut len) = (0, 0);
+    while let Some((body, framed_len)) = unframe(buf) {
+        let body = body.map_err(|()| invalid_data(&format!("record at byte {} does not match its checksum", len)))?;
+        let record = decode(body).ok_or_else(|| invalid_data(&format!("record at byte {} does not decode", len)))?;
+        state.apply(record);
+        buf = &buf[framed_len..];
+        records += 1;
+        len += framed_len;
+    }
+    Ok((records, len))
+}
+
+// Each record goes on disk after its length and its CRC-32, both big endian u32.
+fn frame(body: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(body.len() + 8);
+    framed.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    framed.extend_from_slice(&crc32(body).to_be_bytes());
+    framed.extend_from_slice(body);
+    framed
+}
+
+/// The body of the record at the start of `buf`, `Err` when it does not match its checksum,
+/// with the length it takes. `None` if the record is incomplete.
+fn unframe(buf: &[u8]) -> Option<(Result<&[u8], ()>, usize)> {
+    let len = u32::from_be_bytes(buf.get(..4)?.try_into().ok()?) as usize;
+    let checksum = u32::from_be_bytes(buf.get(4..8)?.try_into().ok()?);
+    let body = buf.get(8..8 + len)?;
+    Some((Some(body).filter(|body| crc32(body) == checksum).ok_or(()), 8 + len))
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut crc = n as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[n] = crc;
+        n += 1;
+    }
+    table
+}
+
+/// The CRC-32 of zlib and Ethernet.
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes
+        .iter()
+        .fold(!0, |crc, byte| CRC32_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+// A record is its kind followed by its fields: strings and messages are prefixed by their
+// length as a big endian u32, messages are PUBLISH packets, times are big endian u64 and 0
+// when there is none.
+const RETAIN: u8 = 1;
+const CLEAR_RETAINED: u8 = 2;
+const SESSION: u8 = 3;
+const REMOVE_SESSION: u8 = 4;
+const SUBSCRIBE: u8 = 5;
+const UNSUBSCRIBE: u8 = 6;
+const ENQUEUE: u8 = 7;
+const DEQUEUE: u8 = 8;
+const PURGE_EXPIRED: u8 = 9;
+const DELIVER: u8 = 10;
+const ACKNOWLEDGE: u8 = 11;
+
+fn encode(record: &Record) -> io::Result<Vec<u8>> {
+    let mut encoded = Vec::new();
+    match record {
+        Record::Retain(message) => {
+            encoded.push(RETAIN);
+            put_message(&mut encoded, message)?;
+        }
+        Record::ClearRetained(topic) => {
+            encoded.push(CLEAR_RETAINED);
+            put_bytes(&mut encoded, topic.as_bytes());
+        }
+        Record::Session {
+            client_id,
+            session_expiry_interval,
+        } => {
+            encoded.push(SESSION);
+            put_bytes(&mut encoded, client_id.as_bytes());
+            encoded.extend_from_slice(&session_expiry_interval.to_be_bytes());
+        }
+        Record::RemoveSession(client_id) => {
+            encoded.push(REMOVE_SESSION);
+            put_bytes(&mut encoded, client_id.as_bytes());
+        }
+        Record::Subscribe {
+            client_id,
+            topic_filter,
+            subscription_options,
+        } => {
+            encoded.push(SUBSCRIBE);
+            put_bytes(&mut encoded, client_id.as_bytes());
+            put_bytes(&mut encoded, topic_filter.as_bytes());
+            encoded.push(subscription_options.to_byte());
+        }
+        Record::Unsubscribe { client_id, topic_filter } => {
+            encoded.push(UNSUBSCRIBE);
+            put_bytes(&mut encoded, client_id.as_bytes());
+            put_bytes(&mut encoded, topic_filter.as_bytes());
+        }
+        Record::Enqueue { client_id, queued } => {
+            encoded.push(ENQUEUE);
+            put_bytes(&mut encoded, client_id.as_bytes());
+            put_message(&mut encoded, &queued.message)?;
+            encoded.extend_from_slice(&queued.expires_at.unwrap_or(0).to_be_bytes());
+        }
+        Record::Dequeue { client_id, count } => {
+            encoded.push(DEQUEUE);
+            put_bytes(&mut encoded, client_id.as_bytes());
+            encoded.extend_from_slice(&count.to_be_bytes());
+        }
+        Record::PurgeExpired { client_id, now } => {
+            encoded.push(PURGE_EXPIRED);
+            put_bytes(&mut encoded, client_id.as_bytes());
+            encoded.extend_from_slice(&now.to_be_bytes());
+        }
+        Record::Deliver { client_id, delivery, queued } => {
+            encoded.push(DELIVER);
+            put_bytes(&mut encoded, client_id.as_bytes());
+            encoded.extend_from_slice(&delivery.to_be_bytes());
+            put_message(&mut encoded, &queued.message)?;
+            encoded.extend_from_slice(&queued.expires_at.unwrap_or(0).to_be_bytes());
+        }
+        Record::Acknowledge { client_id, delivery } => {
+            encoded.push(ACKNOWLEDGE);
+            put_bytes(&mut encoded, client_id.as_bytes());
+            encoded.extend_from_slice(&delivery.to_be_bytes());
+        }
+    }
+    Ok(encoded)
+}
+
+fn put_bytes(encoded: &mut Vec<u8>, bytes: &[u8]) {
+    encoded.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    encoded.extend_from_slice(bytes);
+}
+
+fn put_message(encoded: &mut Vec<u8>, message: &Message) -> io::Result<()> {
+    // The packet identifier only makes the packet valid, it is not read back.
+    let packet_identifier = Some(1).filter(|_| message.qos as u8 > 0);
+    put_bytes(encoded, &message.to_bytes(packet_identifier)?);
+    Ok(())
+}
+
+/// Reads the fields of a record, each `None` past the end of the buffer.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let taken = self.buf.get(..len)?;
+        self.buf = &self.buf[len..];
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.bytes()?.to_vec()).ok()
+    }
+
+    fn message(&mut self) -> Option<Message> {
+        Message::from_bytes(self.bytes()?)
+    }
+
+    fn queued(&mut self) -> Option<Queued> {
+        Some(Queued {
+            message: self.message()?,
+            expires_at: Some(self.u64()?).filter(|expires_at| *expires_at != 0),
+        })
+    }
+}
+
+/// Decodes the body of a record, `None` if it is not one.
+fn decode(body: &[u8]) -> Option<Record> {
+    let mut reader = Reader { buf: body };
+    let record = match reader.u8()? {
+        RETAIN => Record::Retain(reader.message()?),
+        CLEAR_RETAINED => Record::ClearRetained(reader.string()?),
+        SESSION => Record::Session {
+            client_id: reader.string()?,
+            session_expiry_interval: reader.u32()?,
+        },
+        REMOVE_SESSION => Record::RemoveSession(reader.string()?),
+        SUBSCRIBE => Record::Subscribe {
+            client_id: reader.string()?,
+            topic_filter: reader.string()?,
+            subscription_options: SubscriptionOptions::new(reader.u8().filter(|byte| byte & 0b11 != 0b11 && byte & 0b11_0000 != 0b11_0000)?),
+        },
+        UNSUBSCRIBE => Record::Unsubscribe {
+            client_id: reader.string()?,
+            topic_filter: reader.string()?,
+        },
+        ENQUEUE => Record::Enqueue {
+            client_id: reader.string()?,
+            queued: reader.queued()?,
+        },
+        DEQUEUE => Record::Dequeue {
+            client_id: reader.string()?,
+            count: reader.u32()?,
+        },
+        PURGE_EXPIRED => Record::PurgeExpired {
+            client_id: reader.string()?,
+            now: reader.u64()?,
+        },
+        DELIVER => Record::Deliver {
+            client_id: reader.string()?,
+            delivery: reader.u64()?,
+            queued: reader.queued()?,
+        },
+        ACKNOWLEDGE => Record::Acknowledge {
+            client_id: reader.string()?,
+            delivery: reader.u64()?,
+        },
+        _ => return None,
+    };
+    Some(record).filter(|_| reader.buf.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::definitions::Qos;
+    use bytes::Bytes;
+
+    #[test]
+    fn the_log_replays_on_the_snapshot_and_loses_only_a_torn_record() {
+        let directory = std::env::temp_dir().join(format!("mt-mqtt-wal-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let config = PersistenceConfig {
+            fsync: FsyncPolicy::Never,
+            ..PersistenceConfig::new(&directory)
+        };
+        let message = |topic: &str| Message {
+            topic: String::from(topic),
+            payload: Bytes::from_static(b"payload"),
+            qos: Qos::AtleastOnce,
+            retain: false,
+            properties: Vec::new(),
+        };
+        let session = |client_id: &str| Record::Session {
+            client_id: String::from(client_id),
+            session_expiry_interval: 60,
+        };
+
+        let (mut wal, _, _) = Log::open(&config).unwrap();
+        wal.snapshot(&[Record::Retain(message("status/door")), session("a")]).unwrap();
+        wal.append(&Record::Subscribe {
+            client_id: String::from("a"),
+            topic_filter: String::from("status/#"),
+            subscription_options: SubscriptionOptions::default(),
+        })
+        .unwrap();
+        for topic in ["status/window", "status/roof"] {
+            wal.append(&Record::Enqueue {
+                client_id: String::from("a"),
+                queued: Queued {
+                    message: message(topic),
+                    expires_at: Some(100),
+                },
+            })
+            .unwrap();
+        }
+        wal.append(&Record::Dequeue {
+            client_id: String::from("a"),
+            count: 1,
+        })
+        .unwrap();
+        for delivery in 0..2 {
+            wal.append(&Record::Deliver {
+                client_id: String::from("a"),
+                delivery,
+                queued: Queued {
+                    message: message("status/gate"),
+                    expires_at: None,
+                },
+            })
+            .unwrap();
+        }
+        wal.append(&Record::Acknowledge {
+            client_id: String::from("a"),
+            delivery: 0,
+        })
+        .unwrap();
+        wal.append(&Record::ClearRetained(String::from("status/door"))).unwrap();
+        wal.append(&session("b")).unwrap();
+        drop(wal);
+        // The broker died in the middle of the last record.
+        let log = log_path(&directory, 1);
+        let len = fs::metadata(&log).unwrap().len();
+        OpenOptions::new().write(true).open(&log).unwrap().set_len(len - 2).unwrap();
+
+        let (mut wal, state, _) = Log::open(&config).unwrap();
+        assert!(state.retained.is_empty());
+        assert_eq!(state.sessions.len(), 1);
+        let a = &state.sessions["a"];
+        assert_eq!(a.session_expiry_interval, 60);
+        assert!(a.subscriptions.contains_key("status/#"));
+        let queued: Vec<&Queued> = a.queue.iter().collect();
+        assert_eq!(queued[0].message.topic, "status/roof");
+        assert_eq!(queued[0].expires_at, Some(100));
+        assert_eq!(a.in_flight.keys().collect::<Vec<_>>(), [&1]);
+        // Records go on from where the torn one started.
+        wal.append(&session("c")).unwrap();
+        drop(wal);
+        let (_, state, _) = Log::open(&config).unwrap();
+        assert!(state.sessions.contains_key("c"));
+
+        // A damaged record is not mistaken for one cut short.
+        let mut damaged = fs::read(&log).unwrap();
+        let last = damaged.len() - 1;
+        damaged[last] ^= 1;
+        fs::write(&log, damaged).unwrap();
+        assert_eq!(Log::open(&config).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
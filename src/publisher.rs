@@ -1,4 +1,1244 @@
-pub struct Publisher {
-    connected: bool,
+//! The client side: connecting to a broker, the event loop driving the connection and
+//! publishing. Subscriptions are in `subscriber`.
+//!
+//! [`MqttClient::connect`] returns the client, a cheap handle that can be cloned and shared,
+//! and the [`EventLoop`] owning the connection, which must be run for anything to happen:
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), mt_mqtt::ClientError> {
+//! use mt_mqtt::{ConnectOptions, MqttClient, Qos};
+//!
+//! let (client, event_loop) = MqttClient::connect("127.0.0.1:1883", ConnectOptions::new("sensor-1")).await?;
+//! tokio::spawn(event_loop.run());
+//! client.publish("sensors/1/temperature", "21.5", Qos::AtleastOnce, false, Vec::new()).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{definitions::*, frame::*, message::Message, request::Responses, store::ClientStore, subscriber::Subscriptions};
+use bytes::{Buf, Bytes, BytesMut};
+use num_traits::ToPrimitive;
+use rand::Rng;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    io::{self, Cursor},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{self, TcpStream, ToSocketAddrs},
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    time::{self, Instant},
+};
+use tracing::{debug, info, warn};
+
+/// How long the broker has to answer CONNECT.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The message the broker publishes for the client when it goes away without a DISCONNECT.
+#[derive(Debug, Clone)]
+pub struct Will {
+    pub topic: String,
+    pub payload: Bytes,
+    pub qos: Qos,
+    pub retain: bool,
+    /// Will properties, e.g. `WillDelayInterval` or `MessageExpiryInterval`.
+    pub properties: Vec<Property>,
+}
+
+/// What goes in CONNECT.
+#[derive(Clone)]
+pub struct ConnectOptions {
+    /// Client Identifier, empty to have the broker assign one.
+    pub client_id: String,
+    /// Start over instead of resuming the session of `client_id`.
+    pub clean_start: bool,
+    /// Keep Alive in seconds, 0 to disable it. The broker may impose its own.
+    pub keep_alive: u16,
+    pub will: Option<Will>,
+    pub user_name: Option<String>,
+    pub password: Option<Bytes>,
+    /// CONNECT properties, e.g. `SessionExpiryInterval` or `ReceiveMaximum`.
+    pub properties: Vec<Property>,
+    /// How to get the connection back when it is lost, `None` to have the event loop end
+    /// instead.
+    pub reconnect: Option<ReconnectOptions>,
+    /// Where to keep the exchanges in progress so that they survive the process. Without
+    /// `clean_start`, the ones stored by a previous run are taken up again on connecting.
+    pub store: Option<Arc<dyn ClientStore>>,
+}
+
+/// Everything but the password.
+impl fmt::Debug for ConnectOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectOptions")
+            .field("client_id", &self.client_id)
+            .field("clean_start", &self.clean_start)
+            .field("keep_alive", &self.keep_alive)
+            .field("will", &self.will)
+            .field("user_name", &self.user_name)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("properties", &self.properties)
+            .field("reconnect", &self.reconnect)
+            .field("store", &self.store)
+            .finish()
+    }
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        ConnectOptions {
+            client_id: String::new(),
+            clean_start: true,
+            keep_alive: 60,
+            will: None,
+            user_name: None,
+            password: None,
+            properties: Vec::new(),
+            reconnect: None,
+            store: None,
+        }
+    }
+}
+
+/// What happens to a publish when the offline buffer is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// The oldest buffered message is dropped to make room.
+    DropOldest,
+    /// The new message is dropped.
+    DropNewest,
+}
+
+/// Reconnection after a lost connection. Attempts are spaced by an exponential backoff, each
+/// delay picked at random between half and all of `initial_delay * 2^attempt`, capped at
+/// `max_delay`. The session is resumed, so the broker should be given a Session Expiry Interval
+/// for it to outlive the connection; when it did not, the subscriptions are made again.
+///
+/// Publishes made while disconnected wait in a buffer of `offline_buffer` messages, a dropped
+/// one fails with [`ClientError::OfflineBufferFull`]. QoS 1 and 2 messages that were not
+/// acknowledged are sent again with DUP set.
+#[derive(Debug, Clone)]
+pub struct ReconnectOptions {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub offline_buffer: usize,
+    pub overflow_policy: OverflowPolicy,
+}
+
+impl Default for ReconnectOptions {
+    fn default() -> Self {
+        ReconnectOptions {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            offline_buffer: 1000,
+            overflow_policy: OverflowPolicy::DropOldest,
+        }
+    }
+}
+
+impl ReconnectOptions {
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.initial_delay.saturating_mul(2u32.saturating_pow(attempt)).min(self.max_delay);
+        rand::rng().random_range(delay / 2..=delay)
+    }
+}
+
+impl ConnectOptions {
+    pub fn new<T: Into<String>>(client_id: T) -> ConnectOptions {
+        ConnectOptions {
+            client_id: client_id.into(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    /// The broker sent something that is not valid MQTT 5, or not at that point.
+    Protocol(String),
+    /// The broker answered CONNECT with this reason code.
+    ConnectionRefused(ConnAckReasonCode),
+    /// The broker closed the connection with a DISCONNECT carrying this reason code.
+    Disconnected(DisconnectReasonCode),
+    /// The broker did not answer CONNECT or PINGREQ in time, or a request got no response in
+    /// time.
+    Timeout,
+    /// The broker refused a publish, subscribe or unsubscribe with this reason code.
+    Rejected(u8),
+    /// The event loop has stopped, the request could not be completed.
+    ConnectionLost,
+    /// The message was dropped from the offline buffer, see [`OverflowPolicy`].
+    OfflineBufferFull,
+    /// The message answered has no Response Topic.
+    NoResponseTopic,
+    /// Every packet identifier is taken by an exchange in progress.
+    NoPacketIdentifier,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(err) => err.fmt(f),
+            ClientError::Protocol(err) => write!(f, "protocol error: {}", err),
+            ClientError::ConnectionRefused(reason_code) => write!(f, "connection refused: {:?}", reason_code),
+            ClientError::Disconnected(reason_code) => write!(f, "disconnected by the broker: {:?}", reason_code),
+            ClientError::Timeout => write!(f, "the broker did not answer in time"),
+            ClientError::Rejected(reason_code) => write!(f, "rejected by the broker with reason code 0x{:02x}", reason_code),
+            ClientError::ConnectionLost => write!(f, "connection lost"),
+            ClientError::OfflineBufferFull => write!(f, "offline buffer full"),
+            ClientError::NoResponseTopic => write!(f, "no response topic"),
+            ClientError::NoPacketIdentifier => write!(f, "no packet identifier free"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(err: io::Error) -> ClientError {
+        ClientError::Io(err)
+    }
+}
+
+impl From<Error> for ClientError {
+    fn from(err: Error) -> ClientError {
+        ClientError::Protocol(err.to_string())
+    }
+}
+
+pub(crate) type Reply<T> = oneshot::Sender<Result<T, ClientError>>;
+
+/// What the client handles ask of the event loop.
+#[derive(Debug)]
+pub(crate) enum Request {
+    Publish {
+        message: Message,
+        done: Reply<()>,
+    },
+    Subscribe {
+        topic_filter: String,
+        subscription_options: SubscriptionOptions,
+        sender: UnboundedSender<Message>,
+        done: Reply<()>,
+    },
+    Unsubscribe {
+        topic_filter: String,
+        done: Reply<()>,
+    },
+    Disconnect {
+        done: Reply<()>,
+    },
+}
+
+/// Publishes, subscribes and unsubscribes through the [`EventLoop`] it was connected with.
+#[derive(Debug, Clone)]
+pub struct MqttClient {
+    requests: UnboundedSender<Request>,
+    pub(crate) responses: Arc<Responses>,
+}
+
+impl MqttClient {
+    /// Connects to the broker at `addr` and waits for CONNACK. Reconnections go to the
+    /// addresses `addr` resolved to now. Fails with [`ClientError::Protocol`] when the will has
+    /// properties a message cannot carry.
+    pub async fn connect<A: ToSocketAddrs>(addr: A, options: ConnectOptions) -> Result<(MqttClient, EventLoop), ClientError> {
+        if let Some(will) = &options.will {
+            let properties = will
+                .properties
+                .iter()
+                .filter(|property| !matches!(property, Property::WillDelayInterval(_)));
+            Message::check_properties(properties).map_err(ClientError::Protocol)?;
+        }
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let addrs: Vec<SocketAddr> = net::lookup_host(addr).await?.collect();
+        let mut event_loop = EventLoop {
+            connection: Connection::new(TcpStream::connect(&addrs[..]).await?),
+            addrs,
+            options,
+            requests: receiver,
+            client_id: String::new(),
+            session_present: false,
+            response_information: None,
+            keep_alive: 0,
+            receive_maximum: MAX_IN_FLIGHT,
+            next_packet_identifier: 0,
+            outbound_in_flight: HashMap::new(),
+            outbound_queue: VecDeque::new(),
+            offline: VecDeque::new(),
+            held: Vec::new(),
+            inbound_in_flight: HashSet::new(),
+            subscriptions: Subscriptions::default(),
+            ping_sent: None,
+            sequence: 0,
+        };
+        event_loop.restore().await?;
+        event_loop.handshake(event_loop.options.clean_start).await?;
+        event_loop.resend_in_flight().await?;
+        let response_topic = match &event_loop.response_information {
+            Some(response_information) => response_information.clone(),
+            None => format!("responses/{}", event_loop.client_id),
+        };
+        let client = MqttClient {
+            requests: sender,
+            responses: Arc::new(Responses::new(response_topic)),
+        };
+        Ok((client, event_loop))
+    }
+
+    /// Publishes a message. Resolves once it is written for QoS 0, on PUBACK for QoS 1 and on
+    /// PUBCOMP for QoS 2. Properties PUBLISH does not carry, and the Topic Alias and
+    /// Subscription Identifier, fail with [`ClientError::Protocol`].
+    pub async fn publish<T: Into<String>, P: Into<Bytes>>(
+        &self,
+        topic: T,
+        payload: P,
+        qos: Qos,
+        retain: bool,
+        properties: Vec<Property>,
+    ) -> Result<(), ClientError> {
+        Message::check_properties(&properties).map_err(ClientError::Protocol)?;
+        let message = Message {
+            topic: topic.into(),
+            payload: payload.into(),
+            qos,
+            retain,
+            properties: properties.into_iter().map(Some).collect(),
+        };
+        self.ask(|done| Request::Publish { message, done }).await
+    }
+
+    /// Sends DISCONNECT with Normal Disconnection, the broker discards the will. The event loop
+    /// returns once it is written.
+    pub async fn disconnect(&self) -> Result<(), ClientError> {
+        self.ask(|done| Request::Disconnect { done }).await
+    }
+
+    pub(crate) async fn ask<T, F: FnOnce(Reply<T>) -> Request>(&self, request: F) -> Result<T, ClientError> {
+        let (done, result) = oneshot::channel();
+        self.requests.send(request(done)).map_err(|_| ClientError::ConnectionLost)?;
+        result.await.map_err(|_| ClientError::ConnectionLost)?
+    }
+}
+
+/// An outbound QoS 1/2 message waiting for its acknowledgement.
+#[derive(Debug)]
+struct InFlight {
+    message: Message,
+    done: Reply<()>,
+    // PUBREC came, PUBCOMP is next.
+    released: bool,
+    // Order in which the messages were first sent, to send them again in that order.
+    sequence: u64,
+}
+
+/// The most QoS 1/2 messages in flight at once, whatever the broker's Receive Maximum, which
+/// leaves packet identifiers for SUBSCRIBE and UNSUBSCRIBE.
+const MAX_IN_FLIGHT: u16 = u16::MAX / 2;
+
+/// Owns the connection: writes what the client asks for, reads what the broker sends and keeps
+/// the connection alive. It does nothing unless [`EventLoop::run`] is polled.
+#[derive(Debug)]
+pub struct EventLoop {
+    connection: Connection,
+    addrs: Vec<SocketAddr>,
+    options: ConnectOptions,
+    requests: UnboundedReceiver<Request>,
+    client_id: String,
+    session_present: bool,
+    response_information: Option<String>,
+    // Keep Alive in seconds in effect, and how many QoS 1/2 messages the broker accepts at once.
+    keep_alive: u16,
+    receive_maximum: u16,
+    next_packet_identifier: u16,
+    // Outbound QoS 1/2 messages not yet acknowledged, and the ones waiting for room under the
+    // broker's Receive Maximum.
+    outbound_in_flight: HashMap<u16, InFlight>,
+    outbound_queue: VecDeque<(Message, Reply<()>)>,
+    // Publishes, and other requests, made while disconnected.
+    offline: VecDeque<(Message, Reply<()>)>,
+    held: Vec<Request>,
+    // Inbound QoS 2 packet identifiers waiting for PUBREL.
+    inbound_in_flight: HashSet<u16>,
+    subscriptions: Subscriptions,
+    ping_sent: Option<Instant>,
+    sequence: u64,
+}
+
+impl EventLoop {
+    /// Client Identifier of the connection, the one the broker assigned if none was given.
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    /// Whether the broker resumed an existing session.
+    pub fn session_present(&self) -> bool {
+        self.session_present
+    }
+
+    /// Response Information the broker sent, when CONNECT had `RequestResponseInformation`.
+    pub fn response_information(&self) -> Option<&str> {
+        self.response_information.as_deref()
+    }
+
+    /// Sends CONNECT on the new connection and waits for a successful CONNACK.
+    async fn handshake(&mut self, clean_start: bool) -> Result<(), ClientError> {
+        time::timeout(CONNECT_TIMEOUT, self.exchange_connect(clean_start))
+            .await
+            .map_err(|_| ClientError::Timeout)?
+    }
+
+    async fn exchange_connect(&mut self, clean_start: bool) -> Result<(), ClientError> {
+        self.connection.write_frame(connect_frame(&self.options, clean_start)).await?;
+        let conn_ack = match self.connection.read_frame().await?.control_packet {
+            ControlPacket::ConnAck(conn_ack) => conn_ack,
+            other => return Err(ClientError::Protocol(format!("expected CONNACK, got {:?}", other))),
+        };
+        let variable_header = conn_ack.variable_header;
+        if variable_header.reason_code.to_u8().unwrap_or_default() >= 0x80 {
+            return Err(ClientError::ConnectionRefused(variable_header.reason_code));
+        }
+        self.session_present = variable_header.conn_ack_flag.session_present_flag;
+        self.client_id = self.options.client_id.clone();
+        self.response_information = None;
+        self.keep_alive = self.options.keep_alive;
+        self.receive_maximum = MAX_IN_FLIGHT;
+        self.ping_sent = None;
+        for property in variable_header.properties.into_iter().flatten() {
+            match property {
+                Property::ServerKeepAlive(keep_alive) => self.keep_alive = keep_alive,
+                Property::ReceiveMaximum(receive_maximum) => self.receive_maximum = receive_maximum.clamp(1, MAX_IN_FLIGHT),
+                Property::AssignedClientIdentifier(client_id) => self.client_id = client_id,
+                Property::ResponseInformation(response_information) => self.response_information = Some(response_information),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Drives the connection until it closes: `Ok` after [`MqttClient::disconnect`] or once
+    /// every client handle is dropped, an error when the connection is lost or the broker
+    /// disconnects and reconnecting is not enabled. Requests still waiting then fail with
+    /// [`ClientError::ConnectionLost`].
+    ///
+    /// With [`ConnectOptions::reconnect`] it keeps reconnecting, except when another connection
+    /// took the session over.
+    pub async fn run(mut self) -> Result<(), ClientError> {
+        loop {
+            let err = match self.serve().await {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            let reconnect = match &self.options.reconnect {
+                Some(_) if matches!(err, ClientError::Disconnected(DisconnectReasonCode::SessionTakenOver)) => return Err(err),
+                Some(reconnect) => reconnect.clone(),
+                None => return Err(err),
+            };
+            warn!(error = %err, "connection lost, reconnecting");
+            if !self.reconnect(&reconnect).await {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Runs the current connection until it closes, `Ok` when the client disconnected.
+    async fn serve(&mut self) -> Result<(), ClientError> {
+        loop {
+            let keep_alive = Duration::from_secs(self.keep_alive as u64);
+            // Ping once nothing was sent for Keep Alive, give up if the answer takes as long.
+            let ping_deadline = match self.ping_sent {
+                Some(ping_sent) => ping_sent + keep_alive,
+                None => self.connection.last_sent + keep_alive,
+            };
+            tokio::select! {
+                frame = self.connection.read_frame() => self.handle_frame(frame?).await?,
+                request = self.requests.recv() => match request {
+                    Some(request) => {
+                        if let Some(done) = self.handle_request(request).await? {
+                            let _ = done.send(Ok(()));
+                            return Ok(());
+                        }
+                    }
+                    None => {
+                        let _ = self.connection.write_frame(disconnect_frame()).await;
+                        return Ok(());
+                    }
+                },
+                _ = time::sleep_until(ping_deadline), if self.keep_alive > 0 => {
+                    if self.ping_sent.is_some() {
+                        return Err(ClientError::Timeout);
+                    }
+                    self.connection.write_frame(Frame::new(ControlPacketType::PINGREQ)).await?;
+                    self.ping_sent = Some(Instant::now());
+                }
+            }
+        }
+    }
+
+    /// Acts on a request. Returns where to confirm the disconnection when the client asked for
+    /// one.
+    async fn handle_request(&mut self, request: Request) -> Result<Option<Reply<()>>, ClientError> {
+        match request {
+            Request::Publish { message, done } => self.publish(message, done).await?,
+            Request::Subscribe {
+                topic_filter,
+                subscription_options,
+                sender,
+                done,
+            } => {
+                let packet_identifier = match self.packet_identifier() {
+                    Ok(packet_identifier) => packet_identifier,
+                    Err(err) => {
+                        let _ = done.send(Err(err));
+                        return Ok(None);
+                    }
+                };
+                let subscribe = self
+                    .subscriptions
+                    .subscribe(packet_identifier, topic_filter, subscription_options, sender, done);
+                self.connection.write_frame(subscribe).await?;
+            }
+            Request::Unsubscribe { topic_filter, done } => {
+                let packet_identifier = match self.packet_identifier() {
+                    Ok(packet_identifier) => packet_identifier,
+                    Err(err) => {
+                        let _ = done.send(Err(err));
+                        return Ok(None);
+                    }
+                };
+                let unsubscribe = self.subscriptions.unsubscribe(packet_identifier, topic_filter, done);
+                self.connection.write_frame(unsubscribe).await?;
+            }
+            Request::Disconnect { done } => {
+                // Even if DISCONNECT could not be written, the connection is over.
+                let _ = self.connection.write_frame(disconnect_frame()).await;
+                return Ok(Some(done));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn publish(&mut self, message: Message, done: Reply<()>) -> Result<(), ClientError> {
+        if message.qos == Qos::AtMostOnce {
+            match message.to_frame(None, false) {
+                Ok(frame) => {
+                    self.connection.write_frame(frame).await?;
+                    let _ = done.send(Ok(()));
+                }
+                Err(err) => {
+                    let _ = done.send(Err(err.into()));
+                }
+            }
+        } else if self.outbound_in_flight.len() >= self.receive_maximum as usize {
+            self.outbound_queue.push_back((message, done));
+        } else {
+            let sent = self
+                .packet_identifier()
+                .and_then(|packet_identifier| Ok((packet_identifier, message.to_frame(Some(packet_identifier), false)?)));
+            let (packet_identifier, frame) = match sent {
+                Ok(sent) => sent,
+                Err(err) => {
+                    let _ = done.send(Err(err));
+                    return Ok(());
+                }
+            };
+            let stored = message.clone();
+            if let Err(err) = self.record(move |store| store.outbound_sent(packet_identifier, &stored)).await {
+                let _ = done.send(Err(err.into()));
+                return Ok(());
+            }
+            self.sequence += 1;
+            let in_flight = InFlight {
+                message,
+                done,
+                released: false,
+                sequence: self.sequence,
+            };
+            self.outbound_in_flight.insert(packet_identifier, in_flight);
+            self.connection.write_frame(frame).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends the queued messages the broker has room for.
+    async fn publish_queued(&mut self) -> Result<(), ClientError> {
+        while self.outbound_in_flight.len() < self.receive_maximum as usize {
+            match self.outbound_queue.pop_front() {
+                Some((message, done)) => self.publish(message, done).await?,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_frame(&mut self, frame: Frame) -> Result<(), ClientError> {
+        match frame.control_packet {
+            ControlPacket::Publish(control_packet) => {
+                let packet_identifier = control_packet.variable_header.packet_identifier;
+                let message = Message::from_publish(control_packet, &frame.fix_header.flags);
+                match (message.qos, packet_identifier) {
+                    (Qos::AtMostOnce, _) => self.subscriptions.deliver(message),
+                    (Qos::AtleastOnce, Some(packet_identifier)) => {
+                        self.subscriptions.deliver(message);
+                        let mut pub_ack = Frame::new(ControlPacketType::PUBACK);
+                        pub_ack.control_packet = ControlPacket::PubAck(PubAckControlPacket {
+                            variable_header: PubAckVariableHeader::from(packet_identifier, PubAckReasonCode::Success, Vec::new()),
+                        });
+                        self.connection.write_frame(pub_ack).await?;
+                    }
+                    (Qos::ExactlyOnce, Some(packet_identifier)) => {
+                        // A redelivery of a message not released yet is not passed on again.
+                        if self.inbound_in_flight.insert(packet_identifier) {
+                            self.record(move |store| store.inbound_received(packet_identifier)).await?;
+                            self.subscriptions.deliver(message);
+                        }
+                        let mut pub_rec = Frame::new(ControlPacketType::PUBREC);
+                        pub_rec.control_packet = ControlPacket::PubRec(PubRecControlPacket {
+                            variable_header: PubRecVariableHeader::from(packet_identifier, PubRecReasonCode::Success, Vec::new()),
+                        });
+                        self.connection.write_frame(pub_rec).await?;
+                    }
+                    _ => return Err(ClientError::Protocol(String::from("PUBLISH without a packet identifier"))),
+                }
+            }
+            ControlPacket::PubAck(control_packet) => {
+                let variable_header = control_packet.variable_header;
+                if let Some(in_flight) = self.outbound_in_flight.remove(&variable_header.packet_identifier) {
+                    let packet_identifier = variable_header.packet_identifier;
+                    self.record(move |store| store.outbound_completed(packet_identifier)).await?;
+                    let _ = in_flight.done.send(acknowledged(variable_header.reason_code.to_u8().unwrap_or_default()));
+                }
+                self.publish_queued().await?;
+            }
+            ControlPacket::PubRec(control_packet) => {
+                let packet_identifier = control_packet.variable_header.packet_identifier;
+                let reason_code = control_packet.variable_header.reason_code.to_u8().unwrap_or_default();
+                if reason_code >= 0x80 {
+                    // A failed PUBREC ends the exchange.
+                    if let Some(in_flight) = self.outbound_in_flight.remove(&packet_identifier) {
+                        self.record(move |store| store.outbound_completed(packet_identifier)).await?;
+                        let _ = in_flight.done.send(acknowledged(reason_code));
+                    }
+                    return self.publish_queued().await;
+                }
+                let reason_code = match self.outbound_in_flight.get_mut(&packet_identifier) {
+                    Some(in_flight) => {
+                        in_flight.released = true;
+                        self.record(move |store| store.outbound_released(packet_identifier)).await?;
+                        PubRelReasonCode::Success
+                    }
+                    None => PubRelReasonCode::PacketIdentifierNotFound,
+                };
+                self.connection.write_frame(pub_rel_frame(packet_identifier, reason_code)).await?;
+            }
+            ControlPacket::PubComp(control_packet) => {
+                let packet_identifier = control_packet.variable_header.packet_identifier;
+                if let Some(in_flight) = self.outbound_in_flight.remove(&packet_identifier) {
+                    self.record(move |store| store.outbound_completed(packet_identifier)).await?;
+                    let _ = in_flight.done.send(Ok(()));
+                }
+                self.publish_queued().await?;
+            }
+            ControlPacket::PubRel(control_packet) => {
+                let packet_identifier = control_packet.variable_header.packet_identifier;
+                let reason_code = match self.inbound_in_flight.remove(&packet_identifier) {
+                    true => {
+                        self.record(move |store| store.inbound_released(packet_identifier)).await?;
+                        PubCompReasonCode::Success
+                    }
+                    false => PubCompReasonCode::PacketIdentifierNotFound,
+                };
+                let mut pub_comp = Frame::new(ControlPacketType::PUBCOMP);
+                pub_comp.control_packet = ControlPacket::PubComp(PubCompControlPacket {
+                    variable_header: PubCompVariableHeader::from(packet_identifier, reason_code, Vec::new()),
+                });
+                self.connection.write_frame(pub_comp).await?;
+            }
+            ControlPacket::SubAck(control_packet) => self.subscriptions.sub_acked(control_packet),
+            ControlPacket::UnsubAck(control_packet) => self.subscriptions.unsub_acked(control_packet),
+            ControlPacket::PingResp => self.ping_sent = None,
+            ControlPacket::Disconnect(control_packet) => {
+                return Err(ClientError::Disconnected(control_packet.variable_header.disconnect_reason_code));
+            }
+            other => return Err(ClientError::Protocol(format!("unexpected {:?}", other))),
+        }
+        Ok(())
+    }
+
+    /// Waits out the backoff and reconnects, buffering the requests made in the meantime.
+    /// Returns `false` if the client disconnected or went away before it succeeded.
+    async fn reconnect(&mut self, reconnect: &ReconnectOptions) -> bool {
+        for attempt in 0.. {
+            let retry_at = Instant::now() + reconnect.delay(attempt);
+            loop {
+                tokio::select! {
+                    _ = time::sleep_until(retry_at) => break,
+                    request = self.requests.recv() => match request {
+                        Some(Request::Disconnect { done }) => {
+                            let _ = done.send(Ok(()));
+                            return false;
+                        }
+                        Some(Request::Publish { message, done }) => self.buffer(message, done, reconnect),
+                        Some(request) => self.held.push(request),
+                        None => return false,
+                    },
+                }
+            }
+            match self.resume().await {
+                Ok(()) => {
+                    info!(attempt, session_present = self.session_present, "reconnected");
+                    return true;
+                }
+                Err(err) => debug!(attempt, error = %err, "could not reconnect"),
+            }
+        }
+        false
+    }
+
+    /// Keeps a publish made while disconnected, making room as `reconnect` says.
+    fn buffer(&mut self, message: Message, done: Reply<()>, reconnect: &ReconnectOptions) {
+        if self.offline.len() >= reconnect.offline_buffer {
+            let oldest = match reconnect.overflow_policy {
+                OverflowPolicy::DropOldest => self.offline.pop_front(),
+                OverflowPolicy::DropNewest => None,
+            };
+            match oldest {
+                Some((_, dropped)) => {
+                    let _ = dropped.send(Err(ClientError::OfflineBufferFull));
+                }
+                None => {
+                    let _ = done.send(Err(ClientError::OfflineBufferFull));
+                    return;
+                }
+            }
+        }
+        self.offline.push_back((message, done));
+    }
+
+    /// Connects again, resuming the session, and sends what could not be sent while away.
+    async fn resume(&mut self) -> Result<(), ClientError> {
+        self.connection = Connection::new(TcpStream::connect(&self.addrs[..]).await?);
+        self.handshake(false).await?;
+        self.resend_in_flight().await?;
+        for frame in self.subscriptions.pending_frames() {
+            self.connection.write_frame(frame).await?;
+        }
+        if !self.session_present {
+            for (topic_filter, subscription_options) in self.subscriptions.granted() {
+                let packet_identifier = self.packet_identifier()?;
+                let subscribe = self.subscriptions.resubscribe(packet_identifier, topic_filter, subscription_options);
+                self.connection.write_frame(subscribe).await?;
+            }
+        }
+        for request in std::mem::take(&mut self.held) {
+            if let Some(done) = self.handle_request(request).await? {
+                let _ = done.send(Ok(()));
+            }
+        }
+        while let Some((message, done)) = self.offline.pop_front() {
+            self.publish(message, done).await?;
+        }
+        self.publish_queued().await
+    }
+
+    /// Takes the exchanges in progress of a previous run back from the store, or forgets them
+    /// for a clean start.
+    async fn restore(&mut self) -> Result<(), ClientError> {
+        let store = match &self.options.store {
+            Some(store) => store.clone(),
+            None => return Ok(()),
+        };
+        if self.options.clean_start {
+            return Ok(self.record(|store| store.clear()).await?);
+        }
+        let state = blocking(move || store.load()).await?;
+        for stored in state.outbound {
+            // Nobody waits for the messages of a previous run.
+            let (done, _) = oneshot::channel();
+            self.sequence += 1;
+            let in_flight = InFlight {
+                message: stored.message,
+                done,
+                released: stored.released,
+                sequence: self.sequence,
+            };
+            self.outbound_in_flight.insert(stored.packet_identifier, in_flight);
+            self.next_packet_identifier = self.next_packet_identifier.max(stored.packet_identifier);
+        }
+        self.inbound_in_flight.extend(state.inbound);
+        Ok(())
+    }
+
+    /// Sends again, in their order, the messages not acknowledged on the previous connection,
+    /// after dropping the exchanges a new session does not have.
+    async fn resend_in_flight(&mut self) -> Result<(), ClientError> {
+        if !self.session_present {
+            // The broker forgot the exchanges in progress along with the session.
+            for packet_identifier in std::mem::take(&mut self.inbound_in_flight) {
+                self.record(move |store| store.inbound_released(packet_identifier)).await?;
+            }
+            let released: Vec<u16> = self
+                .outbound_in_flight
+                .iter()
+                .filter(|(_, in_flight)| in_flight.released)
+                .map(|(packet_identifier, _)| *packet_identifier)
+                .collect();
+            // The broker took those over before it lost them, sending them again would duplicate them.
+            for packet_identifier in released {
+                if let Some(in_flight) = self.outbound_in_flight.remove(&packet_identifier) {
+                    self.record(move |store| store.outbound_completed(packet_identifier)).await?;
+                    let _ = in_flight.done.send(Ok(()));
+                }
+            }
+        }
+        let mut in_flight: Vec<(u64, u16)> = self
+            .outbound_in_flight
+            .iter()
+            .map(|(packet_identifier, in_flight)| (in_flight.sequence, *packet_identifier))
+            .collect();
+        in_flight.sort_unstable();
+        for (_, packet_identifier) in in_flight {
+            let frame = match &self.outbound_in_flight[&packet_identifier] {
+                in_flight if in_flight.released => pub_rel_frame(packet_identifier, PubRelReasonCode::Success),
+                in_flight => in_flight.message.to_frame(Some(packet_identifier), true)?,
+            };
+            self.connection.write_frame(frame).await?;
+        }
+        Ok(())
+    }
+
+    /// Records a step of an exchange in the store, if there is one. Stores write to disk, which
+    /// happens off the event loop.
+    async fn record<F: FnOnce(&dyn ClientStore) -> io::Result<()> + Send + 'static>(&self, step: F) -> io::Result<()> {
+        match &self.options.store {
+            Some(store) => {
+                let store = store.clone();
+                blocking(move || step(store.as_ref())).await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// The next packet identifier not used by an exchange in progress.
+    fn packet_identifier(&mut self) -> Result<u16, ClientError> {
+        for _ in 0..u16::MAX {
+            self.next_packet_identifier = self.next_packet_identifier.checked_add(1).unwrap_or(1);
+            let packet_identifier = self.next_packet_identifier;
+            if !self.outbound_in_flight.contains_key(&packet_identifier) && !self.subscriptions.is_pending(packet_identifier) {
+                return Ok(packet_identifier);
+            }
+        }
+        Err(ClientError::NoPacketIdentifier)
+    }
+}
+
+/// Runs `step` where it may block.
+async fn blocking<T: Send + 'static, F: FnOnce() -> io::Result<T> + Send + 'static>(step: F) -> io::Result<T> {
+    tokio::task::spawn_blocking(step).await.map_err(io::Error::other)?
 }
 
+/// The socket of an event loop, with what was read of the next packet.
+#[derive(Debug)]
+struct Connection {
+    stream: TcpStream,
+    buffer: BytesMut,
+    // When a packet was last written, for Keep Alive.
+    last_sent: Instant,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Connection {
+        Connection {
+            stream,
+            buffer: BytesMut::with_capacity(4096),
+            last_sent: Instant::now(),
+        }
+    }
+
+    async fn read_frame(&mut self) -> Result<Frame, ClientError> {
+        loop {
+            let mut buf = Cursor::new(&self.buffer[..]);
+            match Frame::deserialize(&mut buf) {
+                Ok(frame) => {
+                    let len = buf.position() as usize;
+                    self.buffer.advance(len);
+                    return Ok(frame);
+                }
+                Err(Error::Incomplete(_)) => (),
+                Err(err) => return Err(err.into()),
+            }
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                return Err(ClientError::Io(io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+    }
+
+    async fn write_frame(&mut self, frame: Frame) -> Result<(), ClientError> {
+        self.stream.write_all(&Frame::serialize(frame)?).await?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+}
+
+/// The result of a PUBACK or PUBREC carrying `reason_code`.
+fn acknowledged(reason_code: u8) -> Result<(), ClientError> {
+    match reason_code {
+        0x00..=0x7f => Ok(()),
+        _ => Err(ClientError::Rejected(reason_code)),
+    }
+}
+
+fn pub_rel_frame(packet_identifier: u16, reason_code: PubRelReasonCode) -> Frame {
+    let mut pub_rel = Frame::new(ControlPacketType::PUBREL);
+    pub_rel.control_packet = ControlPacket::PubRel(PubRelControlPacket {
+        variable_header: PubRelVariableHeader::from(packet_identifier, reason_code, Vec::new()),
+    });
+    pub_rel
+}
+
+fn connect_frame(options: &ConnectOptions, clean_start: bool) -> Frame {
+    let mut connect = Frame::new(ControlPacketType::CONNECT);
+    if let ControlPacket::Connect(control_packet) = &mut connect.control_packet {
+        let variable_header = &mut control_packet.variable_header;
+        variable_header.protocol_name = String::from("MQTT");
+        variable_header.protocol_version = 5;
+        variable_header.keep_alive = options.keep_alive;
+        variable_header.properties = options.properties.iter().cloned().map(Some).collect();
+        variable_header.connect_flag.clean_start = clean_start;
+        variable_header.connect_flag.user_name_flag = options.user_name.is_some();
+        variable_header.connect_flag.password_flag = options.password.is_some();
+        let payload = &mut control_packet.payload;
+        payload.client_identifier = options.client_id.clone();
+        payload.user_name = options.user_name.clone();
+        payload.password = options.password.clone();
+        if let Some(will) = &options.will {
+            variable_header.connect_flag.will_flag = true;
+            variable_header.connect_flag.will_qos = will.qos as u8;
+            variable_header.connect_flag.will_retain = will.retain;
+            payload.will_topic = Some(will.topic.clone());
+            payload.will_payload = Some(will.payload.clone());
+            payload.will_properties = will.properties.iter().cloned().map(Some).collect();
+        }
+    }
+    connect
+}
+
+fn disconnect_frame() -> Frame {
+    let mut disconnect = Frame::new(ControlPacketType::DISCONNECT);
+    disconnect.control_packet = ControlPacket::Disconnect(DisconnectControlPacket {
+        variable_header: DisconnectVariableHeader::from(DisconnectReasonCode::NormalDisconnection, Vec::new()),
+    });
+    disconnect
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::BrokerConfig, server::MqttServer, store::FileClientStore, MessageStream};
+    use std::net::SocketAddr;
+    use tokio::{net::TcpListener, task::JoinHandle};
+
+    async fn start_broker() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = BrokerConfig {
+            sys_interval: None,
+            client_id_strict_charset: true,
+            ..Default::default()
+        };
+        MqttServer::serve(listener, config).await.unwrap().local_addr()
+    }
+
+    async fn connect(addr: SocketAddr, options: ConnectOptions) -> (MqttClient, JoinHandle<Result<(), ClientError>>) {
+        let (client, event_loop) = MqttClient::connect(addr, options).await.unwrap();
+        (client, tokio::spawn(event_loop.run()))
+    }
+
+    async fn next(stream: &mut MessageStream) -> Message {
+        time::timeout(Duration::from_secs(2), stream.recv()).await.unwrap().unwrap()
+    }
+
+    #[test]
+    fn connect_options_hide_the_password() {
+        let options = ConnectOptions {
+            user_name: Some(String::from("alice")),
+            password: Some(Bytes::from_static(b"secret")),
+            ..Default::default()
+        };
+        let formatted = format!("{:?}", options);
+        assert!(formatted.contains("alice") && !formatted.contains("secret"), "{}", formatted);
+    }
+
+    #[tokio::test]
+    async fn messages_round_trip_at_every_qos() {
+        let addr = start_broker().await;
+        let (subscriber, _) = connect(addr, ConnectOptions::new("subscriber")).await;
+        let (publisher, _) = connect(addr, ConnectOptions::new("publisher")).await;
+        publisher.publish("lamp/state", "on", Qos::AtleastOnce, true, Vec::new()).await.unwrap();
+
+        let mut lamp = subscriber.subscribe("lamp/#", Qos::ExactlyOnce).await.unwrap();
+        let retained = next(&mut lamp).await;
+        assert_eq!(
+            (retained.topic.as_str(), retained.payload.as_ref(), retained.retain),
+            ("lamp/state", &b"on"[..], true)
+        );
+
+        for qos in [Qos::AtMostOnce, Qos::AtleastOnce, Qos::ExactlyOnce] {
+            let properties = vec![Property::ContentType(String::from("text/plain"))];
+            publisher
+                .publish("lamp/level", format!("{}", qos as u8), qos, false, properties)
+                .await
+                .unwrap();
+            let message = next(&mut lamp).await;
+            assert_eq!((message.qos, message.payload), (qos, Bytes::from(format!("{}", qos as u8))));
+            assert!(message
+                .properties
+                .iter()
+                .flatten()
+                .any(|property| matches!(property, Property::ContentType(_))));
+        }
+
+        subscriber.unsubscribe("lamp/#").await.unwrap();
+        assert!(lamp.recv().await.is_none(), "unsubscribing ends the stream");
+        publisher.publish("$SYS/x", "x", Qos::AtleastOnce, false, Vec::new()).await.unwrap_err();
+        assert!(matches!(
+            publisher
+                .publish("lamp/state", "on", Qos::AtleastOnce, false, vec![Property::TopicAlias(1)])
+                .await,
+            Err(ClientError::Protocol(_))
+        ));
+
+        let options = ConnectOptions {
+            will: Some(Will {
+                topic: String::from("lamp/state"),
+                payload: Bytes::from_static(b"off"),
+                qos: Qos::AtMostOnce,
+                retain: false,
+                properties: vec![Property::SessionExpiryInterval(60)],
+            }),
+            ..ConnectOptions::new("lamp")
+        };
+        assert!(matches!(MqttClient::connect(addr, options).await, Err(ClientError::Protocol(_))));
+    }
+
+    #[tokio::test]
+    async fn event_loop_keeps_the_connection_alive_and_reports_how_it_ends() {
+        let addr = start_broker().await;
+        let options = ConnectOptions {
+            keep_alive: 1,
+            will: Some(Will {
+                topic: String::from("status/pinger"),
+                payload: Bytes::from_static(b"gone"),
+                qos: Qos::AtleastOnce,
+                retain: false,
+                properties: Vec::new(),
+            }),
+            ..ConnectOptions::new("pinger")
+        };
+        let (pinger, pinger_loop) = connect(addr, options).await;
+        let (watcher, watcher_loop) = connect(addr, ConnectOptions::new("watcher")).await;
+        let mut status = watcher.subscribe("status/#", Qos::AtleastOnce).await.unwrap();
+
+        // The broker would drop the connection after 1.5 seconds without the pings.
+        time::sleep(Duration::from_millis(2500)).await;
+        pinger
+            .publish("status/pinger", "alive", Qos::AtleastOnce, false, Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(next(&mut status).await.payload.as_ref(), b"alive");
+
+        // Dropping every handle disconnects normally, the will is not sent.
+        drop(pinger);
+        assert!(pinger_loop.await.unwrap().is_ok());
+        assert!(time::timeout(Duration::from_millis(300), status.recv()).await.is_err());
+
+        let _taking_over = connect(addr, ConnectOptions::new("watcher")).await;
+        assert!(matches!(
+            watcher_loop.await.unwrap(),
+            Err(ClientError::Disconnected(DisconnectReasonCode::SessionTakenOver))
+        ));
+        assert!(matches!(
+            watcher.publish("status/watcher", "x", Qos::AtMostOnce, false, Vec::new()).await,
+            Err(ClientError::ConnectionLost)
+        ));
+        assert!(matches!(
+            MqttClient::connect(addr, ConnectOptions::new("bad/id")).await,
+            Err(ClientError::ConnectionRefused(ConnAckReasonCode::ClientIdentifierNotValid))
+        ));
+    }
+
+    fn conn_ack_frame(session_present: bool) -> Frame {
+        let mut conn_ack = Frame::new(ControlPacketType::CONNACK);
+        if let ControlPacket::ConnAck(control_packet) = &mut conn_ack.control_packet {
+            control_packet.variable_header.conn_ack_flag.session_present_flag = session_present;
+        }
+        conn_ack
+    }
+
+    fn pub_ack_frame(packet_identifier: u16) -> Frame {
+        let mut pub_ack = Frame::new(ControlPacketType::PUBACK);
+        pub_ack.control_packet = ControlPacket::PubAck(PubAckControlPacket {
+            variable_header: PubAckVariableHeader::from(packet_identifier, PubAckReasonCode::Success, Vec::new()),
+        });
+        pub_ack
+    }
+
+    /// DUP flag, packet identifier and payload of a PUBLISH.
+    fn published(frame: Frame) -> (u8, u16, Bytes) {
+        let dup = frame.fix_header.flags.3;
+        match frame.control_packet {
+            ControlPacket::Publish(control_packet) => (
+                dup,
+                control_packet.variable_header.packet_identifier.unwrap(),
+                control_packet.payload.data,
+            ),
+            other => panic!("expected PUBLISH, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnecting_resumes_the_session_and_sends_again_what_was_not_acknowledged() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (dropped, connection_dropped) = oneshot::channel();
+        let broker = tokio::spawn(async move {
+            // The first connection goes away with the QoS 1 message unacknowledged.
+            let mut connection = Connection::new(listener.accept().await.unwrap().0);
+            connection.read_frame().await.unwrap();
+            connection.write_frame(conn_ack_frame(false)).await.unwrap();
+            let (dup, first_identifier, payload) = published(connection.read_frame().await.unwrap());
+            assert_eq!((dup, payload.as_ref()), (0, &b"1"[..]));
+            drop(connection);
+            dropped.send(()).unwrap();
+
+            let mut connection = Connection::new(listener.accept().await.unwrap().0);
+            match connection.read_frame().await.unwrap().control_packet {
+                ControlPacket::Connect(control_packet) => assert!(!control_packet.variable_header.connect_flag.clean_start),
+                other => panic!("expected CONNECT, got {:?}", other),
+            }
+            connection.write_frame(conn_ack_frame(true)).await.unwrap();
+            let (dup, packet_identifier, payload) = published(connection.read_frame().await.unwrap());
+            assert_eq!((dup, packet_identifier, payload.as_ref()), (1, first_identifier, &b"1"[..]));
+            connection.write_frame(pub_ack_frame(packet_identifier)).await.unwrap();
+            let (dup, packet_identifier, payload) = published(connection.read_frame().await.unwrap());
+            assert_eq!((dup, payload.as_ref()), (0, &b"3"[..]));
+            connection.write_frame(pub_ack_frame(packet_identifier)).await.unwrap();
+            connection
+        });
+
+        let reconnect = ReconnectOptions {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(1),
+            offline_buffer: 1,
+            overflow_policy: OverflowPolicy::DropOldest,
+        };
+        let options = ConnectOptions {
+            reconnect: Some(reconnect),
+            ..ConnectOptions::new("sensor")
+        };
+        let (client, _) = connect(addr, options).await;
+        let publish = |payload: &'static str| {
+            let client = client.clone();
+            tokio::spawn(async move { client.publish("sensor/level", payload, Qos::AtleastOnce, false, Vec::new()).await })
+        };
+        let first = publish("1");
+        connection_dropped.await.unwrap();
+        // Give the event loop time to notice, the messages then wait for the next connection.
+        time::sleep(Duration::from_millis(100)).await;
+        let second = publish("2");
+        time::sleep(Duration::from_millis(50)).await;
+        let third = publish("3");
+
+        broker.await.unwrap();
+        first.await.unwrap().unwrap();
+        assert!(matches!(second.await.unwrap(), Err(ClientError::OfflineBufferFull)));
+        third.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn subscriptions_are_made_again_when_the_broker_lost_the_session() {
+        let config = BrokerConfig {
+            sys_interval: None,
+            ..Default::default()
+        };
+        let broker = MqttServer::serve(TcpListener::bind("127.0.0.1:0").await.unwrap(), config.clone())
+            .await
+            .unwrap();
+        let addr = broker.local_addr();
+        let options = ConnectOptions {
+            reconnect: Some(ReconnectOptions {
+                initial_delay: Duration::from_millis(100),
+                ..Default::default()
+            }),
+            ..ConnectOptions::new("lamp")
+        };
+        let (client, event_loop) = connect(addr, options).await;
+        let mut lamp = client.subscribe("lamp/#", Qos::AtleastOnce).await.unwrap();
+
+        broker.shutdown().await;
+        let offline = {
+            let client = client.clone();
+            tokio::spawn(async move { client.publish("lamp/state", "off", Qos::AtleastOnce, false, Vec::new()).await })
+        };
+        let broker = MqttServer::serve(TcpListener::bind(addr).await.unwrap(), config).await.unwrap();
+
+        // The message published while away goes out after the subscription is restored.
+        offline.await.unwrap().unwrap();
+        assert_eq!(next(&mut lamp).await.payload.as_ref(), b"off");
+        broker.publish("lamp/state", "on", Qos::AtleastOnce, false, Vec::new()).await.unwrap();
+        assert_eq!(next(&mut lamp).await.payload.as_ref(), b"on");
+
+        client.disconnect().await.unwrap();
+        assert!(event_loop.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn a_client_started_again_sends_the_messages_a_crash_left_unacknowledged() {
+        let path = std::env::temp_dir().join(format!("mt-mqtt-publisher-store-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let options = |store: FileClientStore| ConnectOptions {
+            clean_start: false,
+            store: Some(Arc::new(store)),
+            ..ConnectOptions::new("meter")
+        };
+
+        let broker = tokio::spawn(async move {
+            let mut connection = Connection::new(listener.accept().await.unwrap().0);
+            connection.read_frame().await.unwrap();
+            connection.write_frame(conn_ack_frame(false)).await.unwrap();
+            let (_, packet_identifier, _) = published(connection.read_frame().await.unwrap());
+            (listener, connection, packet_identifier)
+        });
+        let (client, event_loop) = connect(addr, options(FileClientStore::open(&path).unwrap())).await;
+        let publish = tokio::spawn(async move { client.publish("meter/1", "42", Qos::AtleastOnce, false, Vec::new()).await });
+        let (listener, _connection, first_identifier) = broker.await.unwrap();
+        // The process dies before PUBACK.
+        event_loop.abort();
+        assert!(publish.await.unwrap().is_err());
+
+        let broker = tokio::spawn(async move {
+            let mut connection = Connection::new(listener.accept().await.unwrap().0);
+            connection.read_frame().await.unwrap();
+            connection.write_frame(conn_ack_frame(true)).await.unwrap();
+            let (dup, packet_identifier, payload) = published(connection.read_frame().await.unwrap());
+            assert_eq!((dup, packet_identifier, payload.as_ref()), (1, first_identifier, &b"42"[..]));
+            connection.write_frame(pub_ack_frame(packet_identifier)).await.unwrap();
+            connection
+        });
+        let (_client, event_loop) = connect(addr, options(FileClientStore::open(&path).unwrap())).await;
+        let _connection = broker.await.unwrap();
+        // Once acknowledged, the message is not sent again on the next start.
+        time::sleep(Duration::from_millis(100)).await;
+        event_loop.abort();
+        assert!(FileClientStore::open(&path).unwrap().load().unwrap().outbound.is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,171 @@
+//! The queue of messages waiting in a session, for its client to come back or for its
+//! connection to take them, bounded by the offline queue settings of the broker.
+//!
+//! Messages keep their Message Expiry Interval as the time they expire at, in seconds since the
+//! Unix epoch, which stays right across restarts of the broker. A message expired in the queue
+//! is never delivered, and the others go out with the interval they have left.
+
+use crate::{
+    config::OfflineQueueConfig,
+    definitions::{Property, Qos},
+    message::Message,
+};
+use std::{
+    collections::VecDeque,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A message waiting in a session.
+#[derive(Debug, Clone)]
+pub(crate) struct Queued {
+    pub(crate) message: Message,
+    /// When the message expires, in seconds since the Unix epoch.
+    pub(crate) expires_at: Option<u64>,
+}
+
+impl Queued {
+    /// Queues `message` at `now`, the time its Message Expiry Interval counts from.
+    pub(crate) fn new(message: Message, now: u64) -> Queued {
+        let expires_at = message.properties.iter().flatten().find_map(|property| match property {
+            Property::MessageExpiryInterval(interval) => Some(now + *interval as u64),
+            _ => None,
+        });
+        Queued { message, expires_at }
+    }
+
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Bytes the message takes in the queue, its topic and payload.
+    fn size(&self) -> usize {
+        self.message.topic.len() + self.message.payload.len()
+    }
+
+    /// The message as it goes out at `now`, with the Message Expiry Interval it has left.
+    pub(crate) fn message_at(&self, now: u64) -> Message {
+        let mut message = self.message.clone();
+        if let Some(expires_at) = self.expires_at {
+            for property in message.properties.iter_mut().flatten() {
+                if let Property::MessageExpiryInterval(interval) = property {
+                    *interval = expires_at.saturating_sub(now) as u32;
+                }
+            }
+        }
+        message
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct OfflineQueue {
+    messages: VecDeque<Queued>,
+    bytes: usize,
+}
+
+impl OfflineQueue {
+    pub(crate) fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Queued> {
+        self.messages.iter()
+    }
+
+    /// Whether `message` goes in a queue with these settings at all.
+    pub(crate) fn accepts(config: &OfflineQueueConfig, message: &Message) -> bool {
+        message.qos != Qos::AtMostOnce || config.queue_qos0
+    }
+
+    /// Whether `queued` fits in the queue as it is.
+    pub(crate) fn fits(&self, config: &OfflineQueueConfig, queued: &Queued) -> bool {
+        self.messages.len() < config.max_messages && self.bytes + queued.size() <= config.max_bytes
+    }
+
+    /// Whether `queued` would fit in the queue once the messages expired at `now` are gone.
+    pub(crate) fn has_room(&self, config: &OfflineQueueConfig, queued: &Queued, now: u64) -> bool {
+        let (count, bytes) = self
+            .messages
+            .iter()
+            .filter(|queued| !queued.is_expired(now))
+            .fold((0, 0), |(count, bytes), queued| (count + 1, bytes + queued.size()));
+        count < config.max_messages && bytes + queued.size() <= config.max_bytes
+    }
+
+    pub(crate) fn push_back(&mut self, queued: Queued) {
+        self.bytes += queued.size();
+        self.messages.push_back(queued);
+    }
+
+    pub(crate) fn pop_front(&mut self) -> Option<Queued> {
+        let queued = self.messages.pop_front()?;
+        self.bytes -= queued.size();
+        Some(queued)
+    }
+
+    /// Puts back a message `pop_front` took.
+    pub(crate) fn push_front(&mut self, queued: Queued) {
+        self.bytes += queued.size();
+        self.messages.push_front(queued);
+    }
+
+    /// Removes the messages expired at `now`, returns how many there were.
+    pub(crate) fn purge_expired(&mut self, now: u64) -> usize {
+        let len = self.messages.len();
+        self.messages.retain(|queued| !queued.is_expired(now));
+        self.bytes = self.messages.iter().map(Queued::size).sum();
+        len - self.messages.len()
+    }
+}
+
+/// Seconds since the Unix epoch.
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::QueueFullPolicy;
+    use bytes::Bytes;
+
+    fn message(payload: &'static [u8], expiry_interval: Option<u32>) -> Message {
+        Message {
+            topic: String::from("t"),
+            payload: Bytes::from_static(payload),
+            qos: Qos::AtleastOnce,
+            retain: false,
+            properties: expiry_interval
+                .map(|interval| Some(Property::MessageExpiryInterval(interval)))
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn queue_bounds_count_unexpired_messages_and_their_bytes() {
+        let config = OfflineQueueConfig {
+            max_messages: 2,
+            max_bytes: 10,
+            policy: QueueFullPolicy::DropNewest,
+            queue_qos0: false,
+        };
+        let mut queue = OfflineQueue::default();
+        queue.push_back(Queued::new(message(b"1234", Some(10)), 100));
+        let big = Queued::new(message(b"123456", None), 100);
+        assert!(!queue.fits(&config, &big), "11 bytes with the first one");
+        assert!(!queue.has_room(&config, &big, 105));
+        assert!(queue.has_room(&config, &big, 110), "the first one expired");
+
+        queue.push_back(Queued::new(message(b"12", None), 100));
+        let first = queue.pop_front().unwrap();
+        assert!(matches!(first.message_at(104).properties[..], [Some(Property::MessageExpiryInterval(6))]));
+        queue.push_front(first);
+        assert!(!queue.fits(&config, &big));
+        assert_eq!(queue.purge_expired(110), 1);
+        assert!(queue.fits(&config, &big));
+    }
+}
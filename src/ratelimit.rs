@@ -0,0 +1,214 @@
+//! Token buckets limiting how fast a client publishes and how fast connections come in. Each
+//! bucket holds a few seconds worth of its rate and refills continuously; a message takes one
+//! token from the message bucket and its payload size from the byte bucket, a connection one
+//! token from the bucket of its address and one from the bucket of the listener.
+
+use crate::config::{ConnectionLimits, RateLimit};
+use std::{collections::HashMap, net::IpAddr, time::Duration};
+use tokio::time::Instant;
+
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, burst_seconds: u32, now: Instant) -> TokenBucket {
+        let capacity = rate as f64 * burst_seconds.max(1) as f64;
+        TokenBucket {
+            rate: rate as f64,
+            capacity,
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Whether the bucket can pay `cost`. A cost larger than the bucket is paid by a full
+    /// bucket, which goes into debt. A bucket with a rate of 0 pays for nothing.
+    fn has(&self, cost: f64) -> bool {
+        self.capacity > 0.0 && self.tokens >= cost.min(self.capacity)
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.capacity
+    }
+}
+
+/// What to do with a message a client publishes.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Allow,
+    Refuse,
+    /// The client has kept exceeding its limits for too long.
+    Disconnect,
+}
+
+/// The limits of one client.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    disconnect_after: Duration,
+    // Since when every message of the client has been refused.
+    exceeding_since: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: RateLimit, disconnect_after: Duration, now: Instant) -> RateLimiter {
+        RateLimiter {
+            messages: limit.messages_per_second.map(|rate| TokenBucket::new(rate, limit.burst_seconds, now)),
+            bytes: limit.bytes_per_second.map(|rate| TokenBucket::new(rate, limit.burst_seconds, now)),
+            disconnect_after,
+            exceeding_since: None,
+        }
+    }
+
+    /// Takes a message of `size` bytes from the buckets, if they hold enough.
+    pub(crate) fn check(&mut self, size: usize, now: Instant) -> Verdict {
+        let mut buckets: Vec<(&mut TokenBucket, f64)> = vec![(self.messages.as_mut(), 1.0), (self.bytes.as_mut(), size as f64)]
+            .into_iter()
+            .filter_map(|(bucket, cost)| Some((bucket?, cost)))
+            .collect();
+        for (bucket, _) in buckets.iter_mut() {
+            bucket.refill(now);
+        }
+        if buckets.iter().all(|(bucket, cost)| bucket.has(*cost)) {
+            for (bucket, cost) in buckets {
+                bucket.tokens -= cost;
+            }
+            self.exceeding_since = None;
+            return Verdict::Allow;
+        }
+        let exceeding_since = *self.exceeding_since.get_or_insert(now);
+        match now.duration_since(exceeding_since) >= self.disconnect_after {
+            true => Verdict::Disconnect,
+            false => Verdict::Refuse,
+        }
+    }
+}
+
+/// The accept rates of a listener.
+#[derive(Debug)]
+pub(crate) struct ConnectionRateLimiter {
+    per_address_per_second: Option<u32>,
+    burst_seconds: u32,
+    global: Option<TokenBucket>,
+    per_address: HashMap<IpAddr, TokenBucket>,
+}
+
+impl ConnectionRateLimiter {
+    pub(crate) fn new(limits: &ConnectionLimits, now: Instant) -> ConnectionRateLimiter {
+        ConnectionRateLimiter {
+            per_address_per_second: limits.per_address_per_second,
+            burst_seconds: limits.burst_seconds,
+            global: limits.per_second.map(|rate| TokenBucket::new(rate, limits.burst_seconds, now)),
+            per_address: HashMap::new(),
+        }
+    }
+
+    /// Takes a connection from `address`, returns false when it is one too many.
+    pub(crate) fn allows(&mut self, address: IpAddr, now: Instant) -> bool {
+        let (per_address, burst_seconds) = (&mut self.per_address, self.burst_seconds);
+        let per_address = self
+            .per_address_per_second
+            .map(|rate| per_address.entry(address).or_insert_with(|| TokenBucket::new(rate, burst_seconds, now)));
+        let mut buckets: Vec<&mut TokenBucket> = vec![self.global.as_mut(), per_address].into_iter().flatten().collect();
+        for bucket in buckets.iter_mut() {
+            bucket.refill(now);
+        }
+        if !buckets.iter().all(|bucket| bucket.has(1.0)) {
+            return false;
+        }
+        for bucket in buckets {
+            bucket.tokens -= 1.0;
+        }
+        true
+    }
+
+    /// Forgets the addresses quiet for long enough to be back to a full bucket.
+    pub(crate) fn prune(&mut self, now: Instant) {
+        self.per_address.retain(|_, bucket| {
+            bucket.refill(now);
+            !bucket.is_full()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ClientPattern, RateLimits};
+
+    #[test]
+    fn overrides_pick_clients_by_client_id_or_user_name() {
+        let limit = |messages_per_second| RateLimit {
+            messages_per_second: Some(messages_per_second),
+            bytes_per_second: None,
+            burst_seconds: 1,
+        };
+        let limits = RateLimits {
+            default: Some(limit(10)),
+            overrides: vec![
+                (ClientPattern::ClientId(String::from("sensor-*-eu")), Some(limit(1))),
+                (ClientPattern::UserName(String::from("admin")), None),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(limits.limit_for("sensor-12-eu", None), Some(limit(1)));
+        assert_eq!(limits.limit_for("sensor-12-us", None), Some(limit(10)));
+        assert_eq!(limits.limit_for("sensor--eu", Some("admin")), Some(limit(1)), "the first match applies");
+        assert_eq!(limits.limit_for("console", Some("admin")), None);
+    }
+
+    #[test]
+    fn buckets_refill_with_time_and_sustained_excess_disconnects() {
+        let limit = RateLimit {
+            messages_per_second: Some(2),
+            bytes_per_second: Some(100),
+            burst_seconds: 1,
+        };
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(limit, Duration::from_millis(300), start);
+        assert_eq!(limiter.check(10, start), Verdict::Allow);
+        assert_eq!(limiter.check(10, start), Verdict::Allow);
+        assert_eq!(limiter.check(10, start), Verdict::Refuse, "two messages a second");
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.check(10, later), Verdict::Allow);
+
+        // A message bigger than the byte bucket passes when the bucket is full, and leaves it
+        // in debt.
+        let later = later + Duration::from_secs(5);
+        assert_eq!(limiter.check(150, later), Verdict::Allow);
+        assert_eq!(limiter.check(1, later + Duration::from_millis(100)), Verdict::Refuse);
+        assert_eq!(limiter.check(1, later + Duration::from_millis(450)), Verdict::Disconnect);
+    }
+
+    #[test]
+    fn a_zero_rate_refuses_everything() {
+        let limit = RateLimit {
+            messages_per_second: Some(0),
+            bytes_per_second: None,
+            burst_seconds: 1,
+        };
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(limit, Duration::from_secs(10), start);
+        assert_eq!(limiter.check(0, start), Verdict::Refuse);
+        assert_eq!(limiter.check(0, start + Duration::from_secs(5)), Verdict::Refuse);
+
+        let limits = ConnectionLimits {
+            per_address_per_second: Some(0),
+            ..Default::default()
+        };
+        let mut accept_rate = ConnectionRateLimiter::new(&limits, start);
+        assert!(!accept_rate.allows("127.0.0.1".parse().unwrap(), start));
+    }
+}
@@ -0,0 +1,106 @@
+//! Redirect mode: sending clients to another server, for instance for a maintenance window.
+//! Clients a rule picks are refused in CONNACK, and connected ones disconnected, with
+//! `UseAnotherServer` or `ServerMoved` and the other server as the Server Reference.
+
+use crate::{
+    config::ClientPattern,
+    definitions::{ConnAckReasonCode, DisconnectReasonCode},
+};
+use tokio::sync::watch;
+
+/// The server a client is sent to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    /// Sent as the Server Reference, such as `mqtt2.example.com:1883`.
+    pub server_reference: String,
+    /// `ServerMoved` when the client should use the other server from now on,
+    /// `UseAnotherServer` when it is only for a while.
+    pub permanent: bool,
+}
+
+impl Redirect {
+    pub fn temporary<S: Into<String>>(server_reference: S) -> Redirect {
+        Redirect {
+            server_reference: server_reference.into(),
+            permanent: false,
+        }
+    }
+
+    pub fn permanent<S: Into<String>>(server_reference: S) -> Redirect {
+        Redirect {
+            server_reference: server_reference.into(),
+            permanent: true,
+        }
+    }
+
+    pub(crate) fn conn_ack_reason_code(&self) -> ConnAckReasonCode {
+        match self.permanent {
+            true => ConnAckReasonCode::ServerMoved,
+            false => ConnAckReasonCode::UseAnotherServer,
+        }
+    }
+
+    pub(crate) fn disconnect_reason_code(&self) -> DisconnectReasonCode {
+        match self.permanent {
+            true => DisconnectReasonCode::ServerMoved,
+            false => DisconnectReasonCode::UseAnotherServer,
+        }
+    }
+}
+
+/// Which clients are sent where.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RedirectRules {
+    /// Where the clients no rule picks go, `None` to serve them.
+    pub default: Option<Redirect>,
+    /// Redirects of the clients matching a pattern, the first matching one applies. `None`
+    /// serves the clients it picks.
+    pub rules: Vec<(ClientPattern, Option<Redirect>)>,
+}
+
+impl RedirectRules {
+    pub fn redirect_for(&self, client_id: &str, user_name: Option<&str>) -> Option<&Redirect> {
+        self.rules
+            .iter()
+            .find(|(pattern, _)| pattern.matches(client_id, user_name))
+            .map_or(self.default.as_ref(), |(_, redirect)| redirect.as_ref())
+    }
+}
+
+/// The redirect rules of a broker, which can be changed while it runs. The connected clients a
+/// change redirects are disconnected right away.
+#[derive(Debug)]
+pub struct Redirects {
+    rules: watch::Sender<RedirectRules>,
+}
+
+impl Default for Redirects {
+    fn default() -> Self {
+        Redirects::new(RedirectRules::default())
+    }
+}
+
+impl Redirects {
+    pub fn new(rules: RedirectRules) -> Redirects {
+        Redirects {
+            rules: watch::Sender::new(rules),
+        }
+    }
+
+    pub fn rules(&self) -> RedirectRules {
+        self.rules.borrow().clone()
+    }
+
+    pub fn set(&self, rules: RedirectRules) {
+        self.rules.send_replace(rules);
+    }
+
+    pub fn redirect_for(&self, client_id: &str, user_name: Option<&str>) -> Option<Redirect> {
+        self.rules.borrow().redirect_for(client_id, user_name).cloned()
+    }
+
+    /// Follows the changes of the rules.
+    pub(crate) fn subscribe(&self) -> watch::Receiver<RedirectRules> {
+        self.rules.subscribe()
+    }
+}
@@ -0,0 +1,180 @@
+//! Request/response: a request is published with a Response Topic and Correlation Data, the
+//! responder publishes its answer to that topic with the same Correlation Data.
+//!
+//! Each client gets its response topic from the Response Information of CONNACK, which the
+//! broker sends when CONNECT has `RequestResponseInformation` set to 1, and falls back to
+//! `responses/<client id>` otherwise.
+
+use crate::{
+    definitions::{Property, Qos},
+    message::Message,
+    publisher::{ClientError, MqttClient},
+    subscriber::MessageStream,
+};
+use bytes::Bytes;
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{oneshot, OnceCell},
+    time,
+};
+
+/// The answer to [`MqttClient::request`].
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub payload: Bytes,
+    /// Properties of the response, its Correlation Data included.
+    pub properties: Vec<Property>,
+}
+
+/// Requests of a client waiting for their response, by Correlation Data.
+#[derive(Debug)]
+pub(crate) struct Responses {
+    topic: String,
+    waiting: Arc<Mutex<HashMap<Bytes, oneshot::Sender<Message>>>>,
+    // Set once the response topic is subscribed to, on the first request.
+    subscribed: OnceCell<()>,
+}
+
+impl Responses {
+    pub(crate) fn new(topic: String) -> Responses {
+        Responses {
+            topic,
+            waiting: Arc::new(Mutex::new(HashMap::new())),
+            subscribed: OnceCell::new(),
+        }
+    }
+}
+
+impl MqttClient {
+    /// Publishes `payload` to `topic` as a QoS 1 request and waits for the response, at most
+    /// `timeout` altogether.
+    pub async fn request<T: Into<String>, P: Into<Bytes>>(&self, topic: T, payload: P, timeout: Duration) -> Result<Response, ClientError> {
+        let responses = &self.responses;
+        let correlation_data = Bytes::from(rand::rng().random::<[u8; 16]>().to_vec());
+        let (sender, receiver) = oneshot::channel();
+        responses.waiting.lock().unwrap().insert(correlation_data.clone(), sender);
+        let properties = vec![
+            Property::ResponseTopic(responses.topic.clone()),
+            Property::CorrelationData(correlation_data.clone()),
+        ];
+        let exchange = async {
+            responses
+                .subscribed
+                .get_or_try_init(|| async {
+                    let stream = self.subscribe(responses.topic.clone(), Qos::AtleastOnce).await?;
+                    tokio::spawn(dispatch(stream, responses.waiting.clone()));
+                    Ok::<_, ClientError>(())
+                })
+                .await?;
+            self.publish(topic, payload, Qos::AtleastOnce, false, properties).await?;
+            receiver.await.map_err(|_| ClientError::ConnectionLost)
+        };
+        let result = time::timeout(timeout, exchange).await;
+        responses.waiting.lock().unwrap().remove(&correlation_data);
+        let message = result.map_err(|_| ClientError::Timeout)??;
+        Ok(Response {
+            payload: message.payload,
+            properties: message.properties.into_iter().flatten().collect(),
+        })
+    }
+
+    /// Answers `request`, a message received with a Response Topic, by publishing `payload` to
+    /// that topic with the Correlation Data of the request, at the QoS of the request.
+    pub async fn respond<P: Into<Bytes>>(&self, request: &Message, payload: P) -> Result<(), ClientError> {
+        let mut response_topic = None;
+        let mut properties = Vec::new();
+        for property in request.properties.iter().flatten() {
+            match property {
+                Property::ResponseTopic(topic) => response_topic = Some(topic.clone()),
+                Property::CorrelationData(correlation_data) => properties.push(Property::CorrelationData(correlation_data.clone())),
+                _ => (),
+            }
+        }
+        let response_topic = response_topic.ok_or(ClientError::NoResponseTopic)?;
+        self.publish(response_topic, payload, request.qos, false, properties).await
+    }
+}
+
+/// Hands the messages of the response topic to the requests waiting for them.
+async fn dispatch(mut stream: MessageStream, waiting: Arc<Mutex<HashMap<Bytes, oneshot::Sender<Message>>>>) {
+    while let Some(message) = stream.recv().await {
+        let correlation_data = message.properties.iter().flatten().find_map(|property| match property {
+            Property::CorrelationData(correlation_data) => Some(correlation_data.clone()),
+            _ => None,
+        });
+        // Late responses, and stray messages, have nobody waiting for them.
+        let sender = correlation_data.and_then(|correlation_data| waiting.lock().unwrap().remove(&correlation_data));
+        if let Some(sender) = sender {
+            let _ = sender.send(message);
+        }
+    }
+    // The event loop stopped, the requests still waiting fail.
+    waiting.lock().unwrap().clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::BrokerConfig, publisher::ConnectOptions, server::MqttServer};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn requests_get_the_response_with_their_correlation_data() {
+        let config = BrokerConfig {
+            sys_interval: None,
+            ..Default::default()
+        };
+        let broker = MqttServer::serve(TcpListener::bind("127.0.0.1:0").await.unwrap(), config).await.unwrap();
+        let addr = broker.local_addr();
+
+        let (responder, event_loop) = MqttClient::connect(addr, ConnectOptions::new("clock")).await.unwrap();
+        assert_eq!(event_loop.response_information(), None);
+        tokio::spawn(event_loop.run());
+        let mut requests = responder.subscribe("services/time", Qos::AtleastOnce).await.unwrap();
+        tokio::spawn(async move {
+            while let Some(request) = requests.recv().await {
+                let answer = format!("noon for {}", String::from_utf8_lossy(&request.payload));
+                responder.respond(&request, answer).await.unwrap();
+            }
+        });
+
+        let options = ConnectOptions {
+            properties: vec![Property::RequestResponseInformation(1)],
+            ..ConnectOptions::new("asker")
+        };
+        let (requester, event_loop) = MqttClient::connect(addr, options).await.unwrap();
+        assert_eq!(event_loop.response_information(), Some("responses/asker"));
+        tokio::spawn(event_loop.run());
+
+        let timeout = Duration::from_secs(2);
+        let (first, second) = tokio::join!(
+            requester.request("services/time", "paris", timeout),
+            requester.request("services/time", "tokyo", timeout)
+        );
+        assert_eq!(first.unwrap().payload.as_ref(), b"noon for paris");
+        let second = second.unwrap();
+        assert_eq!(second.payload.as_ref(), b"noon for tokyo");
+        assert!(second
+            .properties
+            .iter()
+            .any(|property| matches!(property, Property::CorrelationData(data) if data.len() == 16)));
+
+        assert!(matches!(
+            requester.request("services/nobody", "x", Duration::from_millis(200)).await,
+            Err(ClientError::Timeout)
+        ));
+        let plain = Message {
+            topic: String::from("services/time"),
+            payload: Bytes::new(),
+            qos: Qos::AtMostOnce,
+            retain: false,
+            properties: Vec::new(),
+        };
+        assert!(matches!(requester.respond(&plain, "x").await, Err(ClientError::NoResponseTopic)));
+    }
+}
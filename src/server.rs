@@ -1,35 +1,195 @@
-use crate::client::*;
-use tokio::net::{TcpListener, TcpStream};
+use crate::{
+    admin,
+    broker::*,
+    client::*,
+    config::BrokerConfig,
+    definitions::ConnAckReasonCode,
+    handle::{self, BrokerHandle},
+    metrics,
+    ratelimit::ConnectionRateLimiter,
+    stats::Stats,
+};
+use std::{net::IpAddr, sync::Arc, time::Duration};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc::Sender, oneshot, watch},
+    task::{JoinHandle, JoinSet},
+    time::{self, Instant},
+};
+use tracing::{debug, info, warn};
 
-const SECURE_TCP_PORT: u32 = 8883;
-const UNSECURE_TCP_PORT: u32 = 1883;
+#[allow(dead_code)]
+pub const SECURE_TCP_PORT: u32 = 8883;
+pub const UNSECURE_TCP_PORT: u32 = 1883;
+#[allow(dead_code)]
 const NUM_THREADS: u32 = 4;
+const ACL_RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+const ACCEPT_RATE_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
 
-pub struct MqttServer {}
+pub struct MqttServer {
+    config: Arc<BrokerConfig>,
+    stats: Arc<Stats>,
+    broker: Sender<BrokerMessage>,
+    _shutdown_sender: Arc<watch::Sender<bool>>,
+    shutdown: watch::Receiver<bool>,
+}
 
 impl MqttServer {
-    async fn client_spawner(stream: TcpStream) -> Client {
-        println!("Spawning a client");
-        Client::new(stream)
+    async fn client_spawner(
+        stream: TcpStream,
+        connection_id: u64,
+        config: Arc<BrokerConfig>,
+        stats: Arc<Stats>,
+        broker: Sender<BrokerMessage>,
+        shutdown: watch::Receiver<bool>,
+        refusal: Option<ConnAckReasonCode>,
+    ) -> Client {
+        Client::new(stream, connection_id, config, stats, broker, shutdown, refusal)
+    }
+
+    pub async fn start(config: BrokerConfig) -> Result<BrokerHandle, Box<dyn std::error::Error>> {
+        let unsecure_listener = TcpListener::bind(&config.bind_address).await?;
+        info!(address = %config.bind_address, "listening");
+        MqttServer::serve(unsecure_listener, config).await
+    }
+
+    /// Starts the broker and its endpoints and accepts MQTT connections on `unsecure_listener`
+    /// in the background.
+    pub async fn serve(unsecure_listener: TcpListener, config: BrokerConfig) -> Result<BrokerHandle, Box<dyn std::error::Error>> {
+        let config = Arc::new(config);
+        let stats = Arc::new(Stats::new());
+        let broker = Broker::new(config.clone(), stats.clone())?;
+        let broker_sender = broker.sender();
+        // Side tasks run until the server stops them.
+        let mut tasks = Vec::new();
+        if let Some(metrics_bind_address) = &config.metrics_bind_address {
+            let metrics_listener = TcpListener::bind(metrics_bind_address).await?;
+            info!(address = %metrics_bind_address, "serving metrics");
+            let stats = stats.clone();
+            tasks.push(tokio::spawn(async move {
+                if let Err(err) = metrics::serve(metrics_listener, stats).await {
+                    warn!(error = %err, "metrics endpoint stopped");
+                }
+            }));
+        }
+        if let Some(admin_bind_address) = &config.admin_bind_address {
+            let admin_listener = TcpListener::bind(admin_bind_address).await?;
+            info!(address = %admin_bind_address, "serving the admin API");
+            let broker_sender = broker_sender.clone();
+            let bans = config.bans.clone();
+            tasks.push(tokio::spawn(async move {
+                if let Err(err) = admin::serve(admin_listener, broker_sender, bans).await {
+                    warn!(error = %err, "admin API stopped");
+                }
+            }));
+        }
+        if let Some(acl) = config.acl.clone() {
+            tasks.push(tokio::spawn(async move {
+                let mut reload_check = time::interval(ACL_RELOAD_INTERVAL);
+                loop {
+                    reload_check.tick().await;
+                    match acl.reload_if_changed() {
+                        Ok(true) => info!("access rules reloaded"),
+                        Ok(false) => (),
+                        Err(err) => warn!(error = %err, "could not reload access rules"),
+                    }
+                }
+            }));
+        }
+        let local_addr = unsecure_listener.local_addr()?;
+        let (shutdown_sender, shutdown) = watch::channel(false);
+        let shutdown_sender = Arc::new(shutdown_sender);
+        let broker_task = tokio::spawn(broker.run());
+        let server = MqttServer {
+            config: config.clone(),
+            stats,
+            broker: broker_sender.clone(),
+            // Keeps the signal alive for the clients even when every handle is dropped.
+            _shutdown_sender: shutdown_sender.clone(),
+            shutdown,
+        };
+        let server_task = tokio::spawn(server.run(unsecure_listener, broker_task, tasks));
+        Ok(BrokerHandle::new(local_addr, config, broker_sender, shutdown_sender, server_task))
     }
 
-    pub async fn start() -> Result<(), Box<dyn std::error::Error>> {
-        let bind_addr = String::from("0.0.0.0:") + &UNSECURE_TCP_PORT.to_string();
-        let mut unsecure_listener = TcpListener::bind(bind_addr.clone()).await?;
-        println!("Listening on {}", bind_addr);
+    async fn run(self, unsecure_listener: TcpListener, broker_task: JoinHandle<()>, tasks: Vec<JoinHandle<()>>) {
+        let mut clients = JoinSet::new();
+        let mut connection_id: u64 = 0;
+        let mut shutdown = self.shutdown.clone();
+        let mut accept_rate = ConnectionRateLimiter::new(&self.config.connection_limits, Instant::now());
+        let mut accept_rate_prune = time::interval(ACCEPT_RATE_PRUNE_INTERVAL);
         loop {
-            // Asynchronously wait for an inbound socket.
-            let (socket, addr) = unsecure_listener.accept().await?;
-            println!("Got a new socket from addr: {:?}", addr);
-            // And this is where much of the magic of this server happens. We
-            // crucially want all clients to make progress concurrently, rather than
-            // blocking one on completion of another. To achieve this we use the
-            // `tokio::spawn` function to execute the work in the background.
-            //
-            // Essentially here we're executing a new task to run concurrently,
-            // which will allow all of our clients to be processed concurrently.
-            let client = MqttServer::client_spawner(socket).await;
-            tokio::spawn(client.run());
+            tokio::select! {
+                // Asynchronously wait for an inbound socket.
+                accepted = unsecure_listener.accept() => match accepted {
+                    Ok((socket, addr)) => {
+                        debug!(peer = %addr, "accepted a connection");
+                        connection_id += 1;
+                        Stats::add(&self.stats.connections_accepted, 1);
+                        // Refused connections still get their CONNECT answered, with the reason,
+                        // if it comes quickly.
+                        let refusal = self.refusal(addr.ip(), &mut accept_rate);
+                        // And this is where much of the magic of this server happens. We
+                        // crucially want all clients to make progress concurrently, rather than
+                        // blocking one on completion of another. To achieve this we spawn a task
+                        // per client, which will allow all of our clients to be processed
+                        // concurrently.
+                        let client = MqttServer::client_spawner(
+                            socket,
+                            connection_id,
+                            self.config.clone(),
+                            self.stats.clone(),
+                            self.broker.clone(),
+                            self.shutdown.clone(),
+                            refusal,
+                        )
+                        .await;
+                        clients.spawn(client.run());
+                    }
+                    // Running out of file descriptors and the like passes, keep accepting.
+                    Err(err) => {
+                        warn!(error = %err, "could not accept a connection");
+                        time::sleep(ACCEPT_RETRY_DELAY).await;
+                    }
+                },
+                _ = accept_rate_prune.tick() => accept_rate.prune(Instant::now()),
+                // Forget about the clients that are done.
+                Some(_) = clients.join_next(), if !clients.is_empty() => (),
+                _ = handle::stopping(&mut shutdown) => break,
+            }
+        }
+        drop(unsecure_listener);
+
+        // The clients saw the signal too and are closing their connections.
+        info!(connections = clients.len(), "shutting down");
+        let drained = time::timeout(self.config.shutdown_timeout, async { while clients.join_next().await.is_some() {} }).await;
+        if drained.is_err() {
+            warn!(connections = clients.len(), "closing the connections that did not finish in time");
+            clients.shutdown().await;
+        }
+        // The broker takes in what the clients left before stopping.
+        let (stopped_sender, stopped) = oneshot::channel();
+        if self.broker.send(BrokerMessage::Stop { stopped: stopped_sender }).await.is_ok() {
+            let _ = stopped.await;
+        }
+        let _ = broker_task.await;
+        for task in tasks {
+            task.abort();
+        }
+        info!("shut down");
+    }
+
+    /// Why a connection from `address` is refused, if it is.
+    fn refusal(&self, address: IpAddr, accept_rate: &mut ConnectionRateLimiter) -> Option<ConnAckReasonCode> {
+        if self.config.bans.is_address_banned(address) {
+            Stats::add(&self.stats.connections_banned, 1);
+            return Some(ConnAckReasonCode::Banned);
+        }
+        if !accept_rate.allows(address, Instant::now()) {
+            Stats::add(&self.stats.connections_rate_limited, 1);
+            return Some(ConnAckReasonCode::ConnectionRateExceeded);
         }
+        None
     }
 }
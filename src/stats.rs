@@ -0,0 +1,157 @@
+use crate::definitions::ControlPacketType;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// Upper bounds, in seconds, of the routing latency histogram buckets.
+pub const LATENCY_BUCKETS: [f64; 12] = [0.00001, 0.000025, 0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.05, 0.1];
+
+/// Counters and gauges shared by the server, the client tasks and the broker. The broker
+/// publishes them under `$SYS/broker` and the metrics endpoint exposes them to Prometheus.
+#[derive(Debug)]
+pub struct Stats {
+    pub started_at: Instant,
+    pub connections_accepted: AtomicU64,
+    pub connections_active: AtomicI64,
+    /// Packets read and written, indexed by `ControlPacketType`.
+    pub packets_received: [AtomicU64; 16],
+    pub packets_sent: [AtomicU64; 16],
+    pub messages_received: AtomicU64,
+    pub messages_sent: AtomicU64,
+    pub bytes_received: AtomicU64,
+    pub bytes_sent: AtomicU64,
+    /// QoS 1/2 messages waiting for an acknowledgement, in either direction.
+    pub in_flight: AtomicI64,
+    /// Messages waiting for a client to come back or for room in its Receive Maximum.
+    pub queued: AtomicI64,
+    /// Messages full offline queues dropped or refused, and messages that expired in one.
+    pub dropped: AtomicU64,
+    /// QoS 0 messages dropped for connections too slow to take them.
+    pub slow_consumer_dropped: AtomicU64,
+    /// Connections whose messages are held back because they do not read them fast enough.
+    pub slow_consumers: AtomicI64,
+    /// Messages refused, or dropped for QoS 0, because their publisher exceeded its rate limit.
+    pub rate_limited: AtomicU64,
+    /// Connections refused for coming in faster than the accept rates allow.
+    pub connections_rate_limited: AtomicU64,
+    /// Connections refused for a ban.
+    pub connections_banned: AtomicU64,
+    pub retained: AtomicI64,
+    /// Closed connections by who closed them and the reason code.
+    pub disconnects: Mutex<BTreeMap<(&'static str, String), u64>>,
+    /// Refused authentications by reason code.
+    pub auth_failures: Mutex<BTreeMap<String, u64>>,
+    /// Time the broker takes to route a published message to its subscribers.
+    pub routing_latency: Histogram,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            started_at: Instant::now(),
+            connections_accepted: AtomicU64::new(0),
+            connections_active: AtomicI64::new(0),
+            packets_received: Default::default(),
+            packets_sent: Default::default(),
+            messages_received: AtomicU64::new(0),
+            messages_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            in_flight: AtomicI64::new(0),
+            queued: AtomicI64::new(0),
+            dropped: AtomicU64::new(0),
+            slow_consumer_dropped: AtomicU64::new(0),
+            slow_consumers: AtomicI64::new(0),
+            rate_limited: AtomicU64::new(0),
+            connections_rate_limited: AtomicU64::new(0),
+            connections_banned: AtomicU64::new(0),
+            retained: AtomicI64::new(0),
+            disconnects: Mutex::new(BTreeMap::new()),
+            auth_failures: Mutex::new(BTreeMap::new()),
+            routing_latency: Histogram::new(&LATENCY_BUCKETS),
+        }
+    }
+
+    pub fn add(counter: &AtomicU64, value: usize) {
+        counter.fetch_add(value as u64, Ordering::Relaxed);
+    }
+
+    pub fn adjust(gauge: &AtomicI64, delta: i64) {
+        gauge.fetch_add(delta, Ordering::Relaxed);
+    }
+
+    pub fn count_packet(counters: &[AtomicU64; 16], control_packet_type: ControlPacketType) {
+        Stats::add(&counters[control_packet_type as usize], 1);
+    }
+
+    pub fn count_disconnect(&self, initiator: &'static str, reason: String) {
+        *self.disconnects.lock().unwrap().entry((initiator, reason)).or_default() += 1;
+    }
+
+    pub fn count_auth_failure(&self, reason: String) {
+        *self.auth_failures.lock().unwrap().entry(reason).or_default() += 1;
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            at: Instant::now(),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The counters at one point in time, to turn them into rates.
+#[derive(Debug, Clone, Copy)]
+pub struct Snapshot {
+    pub at: Instant,
+    pub messages_received: u64,
+    pub messages_sent: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+impl Snapshot {
+    /// Per second rate of the counter picked by `counter` between `earlier` and `self`.
+    pub fn rate<F: Fn(&Snapshot) -> u64>(&self, earlier: &Snapshot, counter: F) -> f64 {
+        let elapsed = self.at.duration_since(earlier.at).as_secs_f64();
+        match elapsed > 0.0 {
+            true => (counter(self) - counter(earlier)) as f64 / elapsed,
+            false => 0.0,
+        }
+    }
+}
+
+/// Durations counted in cumulative buckets, the way Prometheus histograms are.
+#[derive(Debug)]
+pub struct Histogram {
+    pub bounds: &'static [f64],
+    /// One count per bound, plus the `+Inf` one.
+    pub buckets: Vec<AtomicU64>,
+    pub sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = self.bounds.iter().position(|bound| seconds <= *bound).unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
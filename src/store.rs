@@ -0,0 +1,432 @@
+//! Client session state kept outside the process: the outbound QoS 1/2 messages not yet
+//! acknowledged and the inbound QoS 2 packet identifiers not yet released. With a store in
+//! [`ConnectOptions::store`](crate::ConnectOptions::store), a client started again after a crash
+//! picks its exchanges up where they were and sends the unacknowledged messages again.
+
+use crate::message::Message;
+use std::{
+    convert::TryInto,
+    fmt::Debug,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Where a client keeps the state of its exchanges in progress. Each client needs its own.
+///
+/// The event loop records each step before acting on it. An error recording a new message fails
+/// its publish, any other ends the connection.
+pub trait ClientStore: Debug + Send + Sync {
+    /// A QoS 1/2 message is about to be sent with `packet_identifier`.
+    fn outbound_sent(&self, packet_identifier: u16, message: &Message) -> io::Result<()>;
+    /// PUBREC came for the QoS 2 message sent with `packet_identifier`, PUBREL is next.
+    fn outbound_released(&self, packet_identifier: u16) -> io::Result<()>;
+    /// The exchange of the message sent with `packet_identifier` is over.
+    fn outbound_completed(&self, packet_identifier: u16) -> io::Result<()>;
+    /// A QoS 2 message came with `packet_identifier`, PUBREL is next.
+    fn inbound_received(&self, packet_identifier: u16) -> io::Result<()>;
+    /// PUBREL came for the QoS 2 message received with `packet_identifier`.
+    fn inbound_released(&self, packet_identifier: u16) -> io::Result<()>;
+    /// Everything stored, outbound messages in the order they were first sent.
+    fn load(&self) -> io::Result<StoredState>;
+    /// Forgets everything, when the client starts a new session.
+    fn clear(&self) -> io::Result<()>;
+}
+
+/// The exchanges in progress of a client, as a store has them.
+#[derive(Debug, Clone, Default)]
+pub struct StoredState {
+    pub outbound: Vec<StoredMessage>,
+    pub inbound: Vec<u16>,
+}
+
+/// An outbound message waiting for its acknowledgement.
+#[derive(Debug, Clone)]
+pub struct StoredMessage {
+    pub packet_identifier: u16,
+    pub message: Message,
+    /// PUBREC came, only PUBREL is sent again.
+    pub released: bool,
+}
+
+/// A step of an exchange, as written to a `FileClientStore`.
+#[derive(Debug)]
+enum Record {
+    OutboundSent(u16, Message),
+    OutboundReleased(u16),
+    OutboundCompleted(u16),
+    InboundReceived(u16),
+    InboundReleased(u16),
+}
+
+impl StoredState {
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::OutboundSent(packet_identifier, message) => {
+                self.outbound.retain(|stored| stored.packet_identifier != packet_identifier);
+                self.outbound.push(StoredMessage {
+                    packet_identifier,
+                    message,
+                    released: false,
+                });
+            }
+            Record::OutboundReleased(packet_identifier) => {
+                if let Some(stored) = self.outbound.iter_mut().find(|stored| stored.packet_identifier == packet_identifier) {
+                    stored.released = true;
+                }
+            }
+            Record::OutboundCompleted(packet_identifier) => self.outbound.retain(|stored| stored.packet_identifier != packet_identifier),
+            Record::InboundReceived(packet_identifier) => {
+                if !self.inbound.contains(&packet_identifier) {
+                    self.inbound.push(packet_identifier);
+                }
+            }
+            Record::InboundReleased(packet_identifier) => self.inbound.retain(|inbound| *inbound != packet_identifier),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.outbound.is_empty() && self.inbound.is_empty()
+    }
+
+    /// How many records `records` returns.
+    fn record_count(&self) -> usize {
+        self.outbound.iter().map(|stored| 1 + stored.released as usize).sum::<usize>() + self.inbound.len()
+    }
+
+    /// The records bringing an empty state to this one.
+    fn records(&self) -> Vec<Record> {
+        let mut records = Vec::new();
+        for stored in &self.outbound {
+            records.push(Record::OutboundSent(stored.packet_identifier, stored.message.clone()));
+            if stored.released {
+                records.push(Record::OutboundReleased(stored.packet_identifier));
+            }
+        }
+        records.extend(self.inbound.iter().map(|packet_identifier| Record::InboundReceived(*packet_identifier)));
+        records
+    }
+}
+
+/// Keeps the state in memory: it survives the connection and the event loop, for a new
+/// client in the same process, but not the process.
+#[derive(Debug, Default)]
+pub struct InMemoryClientStore {
+    state: Mutex<StoredState>,
+}
+
+impl InMemoryClientStore {
+    pub fn new() -> InMemoryClientStore {
+        Default::default()
+    }
+
+    fn apply(&self, record: Record) -> io::Result<()> {
+        self.state.lock().unwrap().apply(record);
+        Ok(())
+    }
+}
+
+impl ClientStore for InMemoryClientStore {
+    fn outbound_sent(&self, packet_identifier: u16, message: &Message) -> io::Result<()> {
+        self.apply(Record::OutboundSent(packet_identifier, message.clone()))
+    }
+
+    fn outbound_released(&self, packet_identifier: u16) -> io::Result<()> {
+        self.apply(Record::OutboundReleased(packet_identifier))
+    }
+
+    fn outbound_completed(&self, packet_identifier: u16) -> io::Result<()> {
+        self.apply(Record::OutboundCompleted(packet_identifier))
+    }
+
+    fn inbound_received(&self, packet_identifier: u16) -> io::Result<()> {
+        self.apply(Record::InboundReceived(packet_identifier))
+    }
+
+    fn inbound_released(&self, packet_identifier: u16) -> io::Result<()> {
+        self.apply(Record::InboundReleased(packet_identifier))
+    }
+
+    fn load(&self) -> io::Result<StoredState> {
+        Ok(self.state.lock().unwrap().clone())
+    }
+
+    fn clear(&self) -> io::Result<()> {
+        *self.state.lock().unwrap() = StoredState::default();
+        Ok(())
+    }
+}
+
+/// Keeps the state in a file, each step appended and synced to disk before the event loop goes
+/// on. The file is compacted when it is opened and when it has grown to several times what the
+/// state takes, and emptied whenever no exchange is left. A step only partly written when the
+/// process died is ignored.
+#[derive(Debug)]
+pub struct FileClientStore {
+    log: Mutex<Log>,
+}
+
+#[derive(Debug)]
+struct Log {
+    path: PathBuf,
+    file: File,
+    state: StoredState,
+    // Records in the file.
+    records: usize,
+}
+
+// The log is compacted once it holds this many times the records of the state, and at least
+// `COMPACT_MIN_RECORDS`.
+const COMPACT_RATIO: usize = 4;
+const COMPACT_MIN_RECORDS: usize = 1000;
+
+impl FileClientStore {
+    /// Opens the store in the file at `path`, created if it does not exist.
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<FileClientStore> {
+        let path = path.into();
+        let content = match fs::read(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        let mut state = StoredState::default();
+        let mut buf = &content[..];
+        while let Some((record, len)) = decode(buf) {
+            state.apply(record);
+            buf = &buf[len..];
+        }
+        let file = compact(&path, &state)?;
+        let records = state.record_count();
+        Ok(FileClientStore {
+            log: Mutex::new(Log { path, file, state, records }),
+        })
+    }
+
+    fn append(&self, record: Record) -> io::Result<()> {
+        let encoded = encode(&record)?;
+        let mut log = self.log.lock().unwrap();
+        log.state.apply(record);
+        if log.state.is_empty() {
+            log.file.set_len(0)?;
+            log.records = 0;
+        } else if log.records + 1 >= COMPACT_MIN_RECORDS.max(COMPACT_RATIO * log.state.record_count()) {
+            log.file = compact(&log.path, &log.state)?;
+            log.records = log.state.record_count();
+            return Ok(());
+        } else {
+            log.file.write_all(&encoded)?;
+            log.records += 1;
+        }
+        log.file.sync_data()
+    }
+}
+
+impl ClientStore for FileClientStore {
+    fn outbound_sent(&self, packet_identifier: u16, message: &Message) -> io::Result<()> {
+        self.append(Record::OutboundSent(packet_identifier, message.clone()))
+    }
+
+    fn outbound_released(&self, packet_identifier: u16) -> io::Result<()> {
+        self.append(Record::OutboundReleased(packet_identifier))
+    }
+
+    fn outbound_completed(&self, packet_identifier: u16) -> io::Result<()> {
+        self.append(Record::OutboundCompleted(packet_identifier))
+    }
+
+    fn inbound_received(&self, packet_identifier: u16) -> io::Result<()> {
+        self.append(Record::InboundReceived(packet_identifier))
+    }
+
+    fn inbound_released(&self, packet_identifier: u16) -> io::Result<()> {
+        self.append(Record::InboundReleased(packet_identifier))
+    }
+
+    fn load(&self) -> io::Result<StoredState> {
+        Ok(self.log.lock().unwrap().state.clone())
+    }
+
+    fn clear(&self) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        log.state = StoredState::default();
+        log.file.set_len(0)?;
+        log.records = 0;
+        log.file.sync_data()
+    }
+}
+
+/// Writes the records of `state` aside and swaps them in for the log at `path`, which stays
+/// whole until then. Returns the new log, open for appending.
+fn compact(path: &Path, state: &StoredState) -> io::Result<File> {
+    let compacted = path.with_extension("compacting");
+    let mut file = File::create(&compacted)?;
+    for record in state.records() {
+        file.write_all(&encode(&record)?)?;
+    }
+    file.sync_all()?;
+    fs::rename(&compacted, path)?;
+    OpenOptions::new().append(true).open(path)
+}
+
+// A record is its kind, the packet identifier and, for a sent message, the length and bytes of
+// its PUBLISH packet, integers in big endian like in MQTT.
+const OUTBOUND_SENT: u8 = 1;
+const OUTBOUND_RELEASED: u8 = 2;
+const OUTBOUND_COMPLETED: u8 = 3;
+const INBOUND_RECEIVED: u8 = 4;
+const INBOUND_RELEASED: u8 = 5;
+
+fn encode(record: &Record) -> io::Result<Vec<u8>> {
+    let (kind, packet_identifier) = match record {
+        Record::OutboundSent(packet_identifier, _) => (OUTBOUND_SENT, packet_identifier),
+        Record::OutboundReleased(packet_identifier) => (OUTBOUND_RELEASED, packet_identifier),
+        Record::OutboundCompleted(packet_identifier) => (OUTBOUND_COMPLETED, packet_identifier),
+        Record::InboundReceived(packet_identifier) => (INBOUND_RECEIVED, packet_identifier),
+        Record::InboundReleased(packet_identifier) => (INBOUND_RELEASED, packet_identifier),
+    };
+    let mut encoded = vec![kind];
+    encoded.extend_from_slice(&packet_identifier.to_be_bytes());
+    if let Record::OutboundSent(packet_identifier, message) = record {
+        let publish = message.to_bytes(Some(*packet_identifier))?;
+        encoded.extend_from_slice(&(publish.len() as u32).to_be_bytes());
+        encoded.extend_from_slice(&publish);
+    }
+    Ok(encoded)
+}
+
+/// Decodes the record at the start of `buf` and returns it with its length, `None` if it is
+/// incomplete or not a record.
+fn decode(buf: &[u8]) -> Option<(Record, usize)> {
+    let kind = *buf.first()?;
+    let packet_identifier = u16::from_be_bytes(buf.get(1..3)?.try_into().ok()?);
+    let record = match kind {
+        OUTBOUND_SENT => {
+            let len = u32::from_be_bytes(buf.get(3..7)?.try_into().ok()?) as usize;
+            let message = Message::from_bytes(buf.get(7..7 + len)?)?;
+            return Some((Record::OutboundSent(packet_identifier, message), 7 + len));
+        }
+        OUTBOUND_RELEASED => Record::OutboundReleased(packet_identifier),
+        OUTBOUND_COMPLETED => Record::OutboundCompleted(packet_identifier),
+        INBOUND_RECEIVED => Record::InboundReceived(packet_identifier),
+        INBOUND_RELEASED => Record::InboundReleased(packet_identifier),
+        _ => return None,
+    };
+    Some((record, 3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::definitions::Qos;
+    use bytes::Bytes;
+
+    fn message(payload: &'static str, qos: Qos) -> Message {
+        Message {
+            topic: String::from("meter/1"),
+            payload: Bytes::from_static(payload.as_bytes()),
+            qos,
+            retain: false,
+            properties: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn file_store_survives_reopening_and_a_torn_write() {
+        let path = std::env::temp_dir().join(format!("mt-mqtt-client-store-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let store = FileClientStore::open(&path).unwrap();
+        store.outbound_sent(1, &message("1", Qos::AtleastOnce)).unwrap();
+        store.outbound_sent(2, &message("2", Qos::ExactlyOnce)).unwrap();
+        store.outbound_sent(3, &message("3", Qos::AtleastOnce)).unwrap();
+        store.outbound_released(2).unwrap();
+        store.outbound_completed(1).unwrap();
+        store.inbound_received(7).unwrap();
+        drop(store);
+        // The process died while writing the next step.
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&[OUTBOUND_SENT, 0, 4, 0])
+            .unwrap();
+
+        let store = FileClientStore::open(&path).unwrap();
+        let state = store.load().unwrap();
+        let outbound: Vec<(u16, &[u8], Qos, bool)> = state
+            .outbound
+            .iter()
+            .map(|stored| {
+                (
+                    stored.packet_identifier,
+                    stored.message.payload.as_ref(),
+                    stored.message.qos,
+                    stored.released,
+                )
+            })
+            .collect();
+        assert_eq!(
+            outbound,
+            [(2, &b"2"[..], Qos::ExactlyOnce, true), (3, &b"3"[..], Qos::AtleastOnce, false)]
+        );
+        assert_eq!(state.inbound, [7]);
+
+        store.outbound_completed(2).unwrap();
+        store.outbound_completed(3).unwrap();
+        store.inbound_released(7).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0, "nothing left to keep");
+        store.outbound_sent(9, &message("9", Qos::AtleastOnce)).unwrap();
+        store.clear().unwrap();
+        assert!(FileClientStore::open(&path).unwrap().load().unwrap().outbound.is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_store_compacts_while_exchanges_stay_in_progress() {
+        let path = std::env::temp_dir().join(format!("mt-mqtt-client-store-compact-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let store = FileClientStore::open(&path).unwrap();
+        // One message stays in flight throughout, the file is never emptied.
+        store.outbound_sent(1, &message("1", Qos::AtleastOnce)).unwrap();
+        for _ in 0..COMPACT_MIN_RECORDS * 3 {
+            store.outbound_sent(2, &message("2", Qos::AtleastOnce)).unwrap();
+            store.outbound_completed(2).unwrap();
+        }
+        let record_size = encode(&Record::OutboundSent(2, message("2", Qos::AtleastOnce))).unwrap().len();
+        assert!(fs::metadata(&path).unwrap().len() <= (COMPACT_MIN_RECORDS * record_size) as u64);
+        drop(store);
+
+        let state = FileClientStore::open(&path).unwrap().load().unwrap();
+        let outbound: Vec<u16> = state.outbound.iter().map(|stored| stored.packet_identifier).collect();
+        assert_eq!(outbound, [1]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_store_opens_with_a_damaged_message() {
+        let path = std::env::temp_dir().join(format!("mt-mqtt-client-store-damaged-{}", std::process::id()));
+        let damaged: [&[u8]; 3] = [
+            // The topic name runs past the end of the packet.
+            &[0x32, 0x02, 0x00, 0x05],
+            // QoS 3.
+            &[0x36, 0x05, 0x00, 0x01, b't', 0x00, 0x01],
+            // A remaining length longer than four bytes.
+            &[0x32, 0xff, 0xff, 0xff, 0xff, 0x01],
+        ];
+        for publish in damaged {
+            let _ = fs::remove_file(&path);
+            let store = FileClientStore::open(&path).unwrap();
+            store.inbound_received(7).unwrap();
+            drop(store);
+            let mut record = vec![OUTBOUND_SENT, 0, 1];
+            record.extend_from_slice(&(publish.len() as u32).to_be_bytes());
+            record.extend_from_slice(publish);
+            OpenOptions::new().append(true).open(&path).unwrap().write_all(&record).unwrap();
+
+            let state = FileClientStore::open(&path).unwrap().load().unwrap();
+            assert!(state.outbound.is_empty());
+            assert_eq!(state.inbound, [7]);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,242 @@
+//! Client subscriptions: subscribing returns a [`MessageStream`] of the messages matching the
+//! topic filter, which the event loop feeds as PUBLISH packets come in.
+
+use crate::{
+    definitions::*,
+    frame::*,
+    message::Message,
+    publisher::{ClientError, MqttClient, Reply, Request},
+    topic::matches,
+};
+use futures_core::Stream;
+use num_traits::ToPrimitive;
+use std::{
+    collections::{HashMap, HashSet},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+/// Messages received for a subscription, retained ones included. Dropping it stops the
+/// delivery but leaves the subscription with the broker, see [`MqttClient::unsubscribe`].
+#[derive(Debug)]
+pub struct MessageStream {
+    receiver: UnboundedReceiver<Message>,
+}
+
+impl MessageStream {
+    /// Waits for the next message, `None` once the event loop has stopped.
+    pub async fn recv(&mut self) -> Option<Message> {
+        self.receiver.recv().await
+    }
+}
+
+impl Stream for MessageStream {
+    type Item = Message;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+impl MqttClient {
+    /// Subscribes to `topic_filter` with `maximum_qos` and resolves on a successful SUBACK.
+    /// A message matching several subscriptions of the client goes to each of their streams.
+    pub async fn subscribe<T: Into<String>>(&self, topic_filter: T, maximum_qos: Qos) -> Result<MessageStream, ClientError> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let subscription_options = SubscriptionOptions {
+            maximum_qos,
+            ..Default::default()
+        };
+        let topic_filter = topic_filter.into();
+        self.ask(|done| Request::Subscribe {
+            topic_filter,
+            subscription_options,
+            sender,
+            done,
+        })
+        .await?;
+        Ok(MessageStream { receiver })
+    }
+
+    /// Unsubscribes from `topic_filter`, whose streams end.
+    pub async fn unsubscribe<T: Into<String>>(&self, topic_filter: T) -> Result<(), ClientError> {
+        let topic_filter = topic_filter.into();
+        self.ask(|done| Request::Unsubscribe { topic_filter, done }).await
+    }
+}
+
+/// A subscribe call, granted or waiting for its SUBACK.
+#[derive(Debug)]
+struct Subscription {
+    topic_filter: String,
+    subscription_options: SubscriptionOptions,
+    sender: UnboundedSender<Message>,
+}
+
+/// The subscriptions of a client, kept by the event loop across reconnections.
+#[derive(Debug, Default)]
+pub(crate) struct Subscriptions {
+    // Subscriptions the broker granted, one per subscribe call.
+    active: Vec<Subscription>,
+    pending: HashMap<u16, (Subscription, Reply<()>)>,
+    pending_unsubscribes: HashMap<u16, (String, Reply<()>)>,
+    // SUBSCRIBE packets restoring the granted subscriptions in a new session.
+    resubscribing: HashMap<u16, String>,
+}
+
+impl Subscriptions {
+    /// Records a subscribe request and returns its SUBSCRIBE packet.
+    pub(crate) fn subscribe(
+        &mut self,
+        packet_identifier: u16,
+        topic_filter: String,
+        subscription_options: SubscriptionOptions,
+        sender: UnboundedSender<Message>,
+        done: Reply<()>,
+    ) -> Frame {
+        let subscribe = subscribe_frame(packet_identifier, &topic_filter, &subscription_options);
+        let subscription = Subscription {
+            topic_filter,
+            subscription_options,
+            sender,
+        };
+        self.pending.insert(packet_identifier, (subscription, done));
+        subscribe
+    }
+
+    /// Returns the SUBSCRIBE packet restoring a granted subscription.
+    pub(crate) fn resubscribe(&mut self, packet_identifier: u16, topic_filter: String, subscription_options: SubscriptionOptions) -> Frame {
+        let subscribe = subscribe_frame(packet_identifier, &topic_filter, &subscription_options);
+        self.resubscribing.insert(packet_identifier, topic_filter);
+        subscribe
+    }
+
+    /// Each granted topic filter once, with its options.
+    pub(crate) fn granted(&self) -> Vec<(String, SubscriptionOptions)> {
+        let mut seen = HashSet::new();
+        self.active
+            .iter()
+            .filter(|subscription| seen.insert(&subscription.topic_filter))
+            .map(|subscription| (subscription.topic_filter.clone(), subscription.subscription_options.clone()))
+            .collect()
+    }
+
+    /// The SUBSCRIBE and UNSUBSCRIBE packets still waiting for an answer, to send them again on
+    /// a new connection.
+    pub(crate) fn pending_frames(&self) -> Vec<Frame> {
+        let subscribes = self.pending.iter().map(|(packet_identifier, (subscription, _))| {
+            subscribe_frame(*packet_identifier, &subscription.topic_filter, &subscription.subscription_options)
+        });
+        let unsubscribes = self
+            .pending_unsubscribes
+            .iter()
+            .map(|(packet_identifier, (topic_filter, _))| unsubscribe_frame(*packet_identifier, topic_filter));
+        subscribes.chain(unsubscribes).collect()
+    }
+
+    /// Records an unsubscribe request and returns its UNSUBSCRIBE packet.
+    pub(crate) fn unsubscribe(&mut self, packet_identifier: u16, topic_filter: String, done: Reply<()>) -> Frame {
+        let unsubscribe = unsubscribe_frame(packet_identifier, &topic_filter);
+        self.pending_unsubscribes.insert(packet_identifier, (topic_filter, done));
+        unsubscribe
+    }
+
+    pub(crate) fn is_pending(&self, packet_identifier: u16) -> bool {
+        self.pending.contains_key(&packet_identifier)
+            || self.pending_unsubscribes.contains_key(&packet_identifier)
+            || self.resubscribing.contains_key(&packet_identifier)
+    }
+
+    pub(crate) fn sub_acked(&mut self, control_packet: SubAckControlPacket) {
+        let variable_header = control_packet.variable_header;
+        let packet_identifier = variable_header.packet_identifier;
+        let reason_code = variable_header
+            .sub_ack_payload
+            .sub_ack_reason_codes
+            .first()
+            .and_then(|reason_code| reason_code.to_u8())
+            .unwrap_or(0x80);
+        if let Some(topic_filter) = self.resubscribing.remove(&packet_identifier) {
+            // A subscription the new session refuses is over, its streams end.
+            if reason_code >= 0x80 {
+                self.active.retain(|subscription| subscription.topic_filter != topic_filter);
+            }
+            return;
+        }
+        let (subscription, done) = match self.pending.remove(&packet_identifier) {
+            Some(pending) => pending,
+            None => return,
+        };
+        if reason_code >= 0x80 {
+            let _ = done.send(Err(ClientError::Rejected(reason_code)));
+            return;
+        }
+        self.active.push(subscription);
+        let _ = done.send(Ok(()));
+    }
+
+    pub(crate) fn unsub_acked(&mut self, control_packet: UnsubAckControlPacket) {
+        let variable_header = control_packet.variable_header;
+        let (topic_filter, done) = match self.pending_unsubscribes.remove(&variable_header.packet_identifier) {
+            Some(pending) => pending,
+            None => return,
+        };
+        let reason_code = variable_header
+            .unsub_ack_payload
+            .un_sub_ack_reason_code
+            .first()
+            .and_then(|reason_code| reason_code.to_u8())
+            .unwrap_or(0x80);
+        if reason_code >= 0x80 {
+            let _ = done.send(Err(ClientError::Rejected(reason_code)));
+            return;
+        }
+        self.active.retain(|subscription| subscription.topic_filter != topic_filter);
+        let _ = done.send(Ok(()));
+    }
+
+    /// Passes `message` to the streams of every subscription it matches, including the ones
+    /// still waiting for SUBACK since retained messages may come first.
+    pub(crate) fn deliver(&mut self, message: Message) {
+        let pending = self.pending.values().map(|(subscription, _)| subscription);
+        for subscription in self.active.iter().chain(pending) {
+            if matches(&subscription.topic_filter, &message.topic) {
+                let _ = subscription.sender.send(message.clone());
+            }
+        }
+        // Streams that were dropped are not fed anymore.
+        self.active.retain(|subscription| !subscription.sender.is_closed());
+    }
+}
+
+fn subscribe_frame(packet_identifier: u16, topic_filter: &str, subscription_options: &SubscriptionOptions) -> Frame {
+    Frame {
+        fix_header: FixHeader::new(ControlPacketType::SUBSCRIBE, Flags(0, 1, 0, 0)),
+        control_packet: ControlPacket::Subscribe(SubscribeControlPacket {
+            variable_header: SubscribeVariableHeader::from(
+                packet_identifier,
+                vec![SubscribePayload {
+                    topic_filter: String::from(topic_filter),
+                    subscription_options: subscription_options.clone(),
+                }],
+                Vec::new(),
+            ),
+        }),
+    }
+}
+
+fn unsubscribe_frame(packet_identifier: u16, topic_filter: &str) -> Frame {
+    Frame {
+        fix_header: FixHeader::new(ControlPacketType::UNSUBSCRIBE, Flags(0, 1, 0, 0)),
+        control_packet: ControlPacket::Unsubscribe(UnsubscribeControlPacket {
+            variable_header: UnsubscribeVariableHeader::from(
+                packet_identifier,
+                UnsubscribePayload {
+                    topic_filters: vec![String::from(topic_filter)],
+                },
+                Vec::new(),
+            ),
+        }),
+    }
+}
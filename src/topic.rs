@@ -1,7 +1,6 @@
 use std::{
-    collections::{hash_map::Entry, HashMap, HashSet},
+    collections::{HashMap, HashSet},
     fmt::Debug,
-    string::*,
 };
 
 #[derive(Debug)]
@@ -9,7 +8,6 @@ pub struct TopicTree {
     sub_topics: HashMap<String, Box<TopicTree>>,
     topic_subscribers_id: HashSet<String>,
     multi_level_topic_subscribers_id: HashSet<String>,
-    single_level_topic_subscribers_id: HashSet<String>,
 }
 
 impl TopicTree {
@@ -18,102 +16,154 @@ impl TopicTree {
             sub_topics: HashMap::new(),
             topic_subscribers_id: HashSet::new(),
             multi_level_topic_subscribers_id: HashSet::new(),
-            single_level_topic_subscribers_id: HashSet::new(),
         }
     }
 
-    fn new<S1: AsRef<str>, S2: AsRef<str>>(topic_str: S1, topic_subscriber_id: S2) -> TopicTree {
-        let mut topic = TopicTree {
-            sub_topics: HashMap::new(),
-            topic_subscribers_id: HashSet::new(),
-            multi_level_topic_subscribers_id: HashSet::new(),
-            single_level_topic_subscribers_id: HashSet::new(),
-        };
-        let splitted_topic: Vec<&str> = topic_str.as_ref().splitn(2, '/').collect();
-        let topic_str = splitted_topic[0].to_string();
-        if !topic_str.is_empty() {
-            if splitted_topic.len() > 1 {
-                topic
-                    .sub_topics
-                    .insert(topic_str, Box::new(TopicTree::new(splitted_topic[1].to_string(), topic_subscriber_id)));
-            } else {
-                topic.sub_topics.insert(topic_str, Box::new(TopicTree::new("", topic_subscriber_id)));
-            }
-        } else {
-            topic.topic_subscribers_id.insert(String::from(topic_subscriber_id.as_ref()));
-        }
-        topic
-    }
-
-    pub fn subscribe<S1: AsRef<str>, S2: AsRef<str>>(&mut self, topic_str: S1, topic_subscriber_id: S2) {
-        let splitted_topic: Vec<&str> = topic_str.as_ref().splitn(2, '/').collect();
-        let topic_str = splitted_topic[0].to_string();
-        if !topic_str.is_empty() {
-            if topic_str == "#" {
-                self.multi_level_topic_subscribers_id.insert(String::from(topic_subscriber_id.as_ref()));
-            } else if topic_str == "+" {
-                self.single_level_topic_subscribers_id.insert(String::from(topic_subscriber_id.as_ref()));
-            } else if splitted_topic.len() > 1 {
-                match self.sub_topics.entry(topic_str) {
-                    Entry::Occupied(o) => o.into_mut().subscribe(splitted_topic[1].to_string(), topic_subscriber_id),
-                    Entry::Vacant(v) => {
-                        v.insert(Box::new(TopicTree::new(splitted_topic[1].to_string(), topic_subscriber_id)));
-                    }
-                };
-            } else {
-                match self.sub_topics.entry(topic_str) {
-                    Entry::Occupied(o) => o.into_mut().subscribe("", topic_subscriber_id),
-                    Entry::Vacant(v) => {
-                        v.insert(Box::new(TopicTree::new("", topic_subscriber_id)));
-                    }
-                }
+    /// Registers `topic_subscriber_id` for `topic_filter`. `+` levels are stored as a regular
+    /// child named `+`, a trailing `#` is stored on the node it hangs from.
+    pub fn subscribe<S1: AsRef<str>, S2: AsRef<str>>(&mut self, topic_filter: S1, topic_subscriber_id: S2) {
+        let levels: Vec<&str> = topic_filter.as_ref().split('/').collect();
+        self.subscribe_levels(&levels, topic_subscriber_id.as_ref());
+    }
+
+    fn subscribe_levels(&mut self, levels: &[&str], topic_subscriber_id: &str) {
+        match levels.split_first() {
+            None => {
+                self.topic_subscribers_id.insert(String::from(topic_subscriber_id));
             }
-            if !self.multi_level_topic_subscribers_id.is_empty() {
-                for (_, elem) in self.sub_topics.iter_mut() {
-                    for multi_ids in self.multi_level_topic_subscribers_id.iter() {
-                        elem.subscribe("#", multi_ids);
-                    }
-                }
+            Some((&"#", _)) => {
+                self.multi_level_topic_subscribers_id.insert(String::from(topic_subscriber_id));
             }
-            if !self.single_level_topic_subscribers_id.is_empty() {
-                for (_, elem) in self.sub_topics.iter_mut() {
-                    for multi_ids in self.single_level_topic_subscribers_id.iter() {
-                        if splitted_topic.len() > 1 {
-                            elem.subscribe(splitted_topic[1].to_string(), multi_ids);
-                        } else {
-                            elem.subscribe("", multi_ids);
-                        }
+            Some((level, rest)) => self
+                .sub_topics
+                .entry(String::from(*level))
+                .or_insert_with(|| Box::new(TopicTree::new_root()))
+                .subscribe_levels(rest, topic_subscriber_id),
+        }
+    }
+
+    /// Removes `topic_subscriber_id` from `topic_filter`, pruning nodes that became empty.
+    pub fn unsubscribe<S1: AsRef<str>, S2: AsRef<str>>(&mut self, topic_filter: S1, topic_subscriber_id: S2) -> bool {
+        let levels: Vec<&str> = topic_filter.as_ref().split('/').collect();
+        self.unsubscribe_levels(&levels, topic_subscriber_id.as_ref())
+    }
+
+    fn unsubscribe_levels(&mut self, levels: &[&str], topic_subscriber_id: &str) -> bool {
+        match levels.split_first() {
+            None => self.topic_subscribers_id.remove(topic_subscriber_id),
+            Some((&"#", _)) => self.multi_level_topic_subscribers_id.remove(topic_subscriber_id),
+            Some((level, rest)) => match self.sub_topics.get_mut(*level) {
+                Some(sub_topic) => {
+                    let removed = sub_topic.unsubscribe_levels(rest, topic_subscriber_id);
+                    if sub_topic.is_empty() {
+                        self.sub_topics.remove(*level);
                     }
+                    removed
                 }
+                None => false,
+            },
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sub_topics.is_empty() && self.topic_subscribers_id.is_empty() && self.multi_level_topic_subscribers_id.is_empty()
+    }
+
+    /// Returns the ids subscribed to a filter matching the topic name `topic_str`.
+    pub fn get_subscribers_id<S: AsRef<str>>(&self, topic_str: S) -> Option<Vec<String>> {
+        let levels: Vec<&str> = topic_str.as_ref().split('/').collect();
+        let mut all_subscriber = HashSet::new();
+        if is_system_topic(topic_str.as_ref()) {
+            // Topics starting with `$` are only matched by filters that spell out their first level.
+            if let Some(sub_topic) = self.sub_topics.get(levels[0]) {
+                sub_topic.collect_subscribers_id(&levels[1..], &mut all_subscriber);
             }
         } else {
-            self.topic_subscribers_id.insert(String::from(topic_subscriber_id.as_ref()));
+            self.collect_subscribers_id(&levels, &mut all_subscriber);
+        }
+        match all_subscriber.len() {
+            0 => None,
+            _ => Some(all_subscriber.into_iter().collect()),
         }
     }
 
-    fn get_subscribers_id<S: AsRef<str>>(&mut self, topic_str: S) -> Option<Vec<String>> {
-        if topic_str.as_ref().is_empty() {
-            match self.topic_subscribers_id.len() + self.multi_level_topic_subscribers_id.len() {
-                0 => None,
-                _ => {
-                    let mut all_subscriber = self.topic_subscribers_id.clone();
-                    all_subscriber.extend(self.multi_level_topic_subscribers_id.clone());
-                    Some(all_subscriber.into_iter().collect())
+    fn collect_subscribers_id(&self, levels: &[&str], all_subscriber: &mut HashSet<String>) {
+        // `#` also matches the parent level, so it applies whether or not levels remain.
+        all_subscriber.extend(self.multi_level_topic_subscribers_id.iter().cloned());
+        match levels.split_first() {
+            None => all_subscriber.extend(self.topic_subscribers_id.iter().cloned()),
+            Some((level, rest)) => {
+                if let Some(sub_topic) = self.sub_topics.get(*level) {
+                    sub_topic.collect_subscribers_id(rest, all_subscriber);
+                }
+                if let Some(sub_topic) = self.sub_topics.get("+") {
+                    sub_topic.collect_subscribers_id(rest, all_subscriber);
                 }
             }
-        } else {
-            let splitted_topic: Vec<&str> = topic_str.as_ref().splitn(2, '/').collect();
-            if splitted_topic.len() > 1 {
-                return self.sub_topics.get_mut(splitted_topic[0]).unwrap().get_subscribers_id(splitted_topic[1]);
-            } else {
-                return match self.sub_topics.entry(String::from(splitted_topic[0])) {
-                    Entry::Occupied(o) => o.into_mut().get_subscribers_id(""),
-                    Entry::Vacant(v) => match self.multi_level_topic_subscribers_id.len() {
-                        0 => None,
-                        _ => Some(self.multi_level_topic_subscribers_id.clone().into_iter().collect()),
-                    },
-                };
-            }
+        }
+    }
+}
+
+/// Returns whether `topic_str` starts with `$`, like the `$SYS` topics of the broker, which
+/// wildcards at the first level do not match.
+pub fn is_system_topic<S: AsRef<str>>(topic_str: S) -> bool {
+    topic_str.as_ref().starts_with('$')
+}
+
+/// Returns whether `topic_filter` is a valid topic filter: not empty, with `+` only as a whole
+/// level and `#` only as the whole last level.
+pub fn is_valid_topic_filter(topic_filter: &str) -> bool {
+    let mut levels = topic_filter.split('/').peekable();
+    while let Some(level) = levels.next() {
+        let valid = match level {
+            "+" => true,
+            "#" => levels.peek().is_none(),
+            level => !level.contains(['+', '#']),
+        };
+        if !valid {
+            return false;
+        }
+    }
+    !topic_filter.is_empty()
+}
+
+fn starts_with_wildcard(topic_filter: &str) -> bool {
+    topic_filter.starts_with('+') || topic_filter.starts_with('#')
+}
+
+/// Returns whether the topic name `topic_str` matches `topic_filter`, following the same
+/// rules as `TopicTree`.
+pub fn matches<S1: AsRef<str>, S2: AsRef<str>>(topic_filter: S1, topic_str: S2) -> bool {
+    if is_system_topic(&topic_str) && starts_with_wildcard(topic_filter.as_ref()) {
+        return false;
+    }
+    let mut filter_levels = topic_filter.as_ref().split('/');
+    let mut topic_levels = topic_str.as_ref().split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => (),
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => (),
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Returns whether every topic name matched by `topic_filter` is also matched by `outer_filter`.
+pub fn covers<S1: AsRef<str>, S2: AsRef<str>>(outer_filter: S1, topic_filter: S2) -> bool {
+    if is_system_topic(&topic_filter) && starts_with_wildcard(outer_filter.as_ref()) {
+        return false;
+    }
+    let mut outer_levels = outer_filter.as_ref().split('/');
+    let mut filter_levels = topic_filter.as_ref().split('/');
+    loop {
+        match (outer_levels.next(), filter_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(filter_level)) if filter_level != "#" => (),
+            (Some(outer_level), Some(filter_level)) if outer_level == filter_level && filter_level != "+" && filter_level != "#" => (),
+            (None, None) => return true,
+            _ => return false,
         }
     }
 }
@@ -122,6 +172,13 @@ impl TopicTree {
 mod tests {
     use super::*;
     use std::time::Instant;
+
+    fn sorted(ids: Option<Vec<String>>) -> Vec<String> {
+        let mut ids = ids.unwrap_or_default();
+        ids.sort();
+        ids
+    }
+
     #[test]
     fn topic_test() {
         let start = Instant::now();
@@ -137,17 +194,92 @@ mod tests {
         root_topic.subscribe("a/#", "observer root");
         root_topic.subscribe("a/+/z", "observer +");
         root_topic.subscribe("+/+/#", "observer +#");
-        //println!("{:#?}", root_topic);
-        println!("{:#?}", root_topic.get_subscribers_id("a/b/c"));
-        println!("{:#?}", root_topic.get_subscribers_id("a/g"));
-        println!("{:#?}", root_topic.get_subscribers_id("a"));
-        println!("{:#?}", root_topic.get_subscribers_id("a/g/d"));
-        println!("{:#?}", root_topic.get_subscribers_id("a/g/e"));
-        println!("{:#?}", root_topic.get_subscribers_id("a/g/m"));
-        println!("{:#?}", root_topic.get_subscribers_id("hello/beto"));
-        println!("{:#?}", root_topic.get_subscribers_id("a/g/z"));
-        println!("{:#?}", root_topic.get_subscribers_id("a/b/z"));
+        assert_eq!(
+            sorted(root_topic.get_subscribers_id("a/b/c")),
+            vec!["observer +#", "observer root", "observer2"]
+        );
+        assert_eq!(
+            sorted(root_topic.get_subscribers_id("a/g")),
+            vec!["observer +#", "observer root", "observer#"]
+        );
+        assert_eq!(sorted(root_topic.get_subscribers_id("a")), vec!["observer root"]);
+        assert_eq!(
+            sorted(root_topic.get_subscribers_id("a/g/d")),
+            vec!["observer +#", "observer root", "observer#", "observer3"]
+        );
+        assert_eq!(
+            sorted(root_topic.get_subscribers_id("a/g/m")),
+            vec!["observer +#", "observer root", "observer#"]
+        );
+        assert_eq!(sorted(root_topic.get_subscribers_id("hello/beto")), vec!["observer +#", "observer1"]);
+        assert_eq!(
+            sorted(root_topic.get_subscribers_id("a/g/z")),
+            vec!["observer +", "observer +#", "observer root", "observer#", "observer3"]
+        );
+        assert_eq!(
+            sorted(root_topic.get_subscribers_id("a/b/z")),
+            vec!["observer +", "observer +#", "observer root"]
+        );
+        assert_eq!(root_topic.get_subscribers_id("x"), None);
         let duration = start.elapsed();
         println!("Time elapsed in topic_test() is: {:?}", duration);
     }
+
+    #[test]
+    fn unsubscribe_prunes_empty_levels() {
+        let mut root_topic = TopicTree::new_root();
+        root_topic.subscribe("a/+/c", "observer1");
+        root_topic.subscribe("a/#", "observer2");
+        assert!(root_topic.unsubscribe("a/+/c", "observer1"));
+        assert!(!root_topic.unsubscribe("a/+/c", "observer1"));
+        assert_eq!(sorted(root_topic.get_subscribers_id("a/b/c")), vec!["observer2"]);
+        assert!(root_topic.unsubscribe("a/#", "observer2"));
+        assert!(root_topic.is_empty());
+    }
+
+    #[test]
+    fn matches_test() {
+        assert!(matches("a/+/c", "a/b/c"));
+        assert!(matches("a/#", "a"));
+        assert!(matches("#", "a/b"));
+        assert!(!matches("a/+", "a/b/c"));
+        assert!(!matches("a/b", "a"));
+        assert!(!matches("#", "$SYS/broker/uptime"));
+        assert!(!matches("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(matches("$SYS/#", "$SYS/broker/uptime"));
+    }
+
+    #[test]
+    fn wildcards_skip_system_topics() {
+        let mut root_topic = TopicTree::new_root();
+        root_topic.subscribe("#", "everything");
+        root_topic.subscribe("+/broker/#", "plus");
+        root_topic.subscribe("$SYS/#", "monitor");
+        root_topic.subscribe("$SYS/+/uptime", "uptime");
+        assert_eq!(sorted(root_topic.get_subscribers_id("$SYS/broker/uptime")), vec!["monitor", "uptime"]);
+        assert_eq!(sorted(root_topic.get_subscribers_id("a/broker/b")), vec!["everything", "plus"]);
+    }
+
+    #[test]
+    fn topic_filter_validation() {
+        for topic_filter in ["a/b", "#", "+", "a/+/c", "a/#", "+/+", "/", "$SYS/#"] {
+            assert!(is_valid_topic_filter(topic_filter), "{}", topic_filter);
+        }
+        for topic_filter in ["", "a/#/b", "a+/b", "a/b#", "##", "a/++"] {
+            assert!(!is_valid_topic_filter(topic_filter), "{}", topic_filter);
+        }
+    }
+
+    #[test]
+    fn covers_test() {
+        assert!(covers("a/#", "a/+/c"));
+        assert!(covers("a/#", "a/#"));
+        assert!(covers("a/+/c", "a/b/c"));
+        assert!(covers("a/+/c", "a/+/c"));
+        assert!(!covers("a/b/c", "a/+/c"));
+        assert!(!covers("a/+", "a/#"));
+        assert!(!covers("a/+", "a"));
+        assert!(!covers("#", "$SYS/#"));
+        assert!(covers("$SYS/#", "$SYS/broker/+"));
+    }
 }